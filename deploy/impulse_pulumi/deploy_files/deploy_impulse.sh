@@ -20,6 +20,9 @@ sudo cp /root/prew /opt/impulse/bin/prew
 sudo systemctl stop impulse.service
 sudo cp /root/impulse /opt/impulse/bin/impulse
 
+# the partitions for the coming months, before reports start landing in them
+./impulse reports create-partitions
+
 sudo ufw allow 80
 
 # install certbot
@@ -27,7 +30,7 @@ sudo snap install --classic certbot
 sudo ln -sf /snap/bin/certbot /usr/bin/certbot
 sudo certbot certonly -d "${IMPULSE_HOSTNAME}" -m "${EMAIL_ADDRESS}" --agree-tos -n --nginx
 
-# install envoy certificates
+# install prew certificates
 sudo cp "/etc/letsencrypt/live/${IMPULSE_HOSTNAME}/fullchain.pem" /etc/ssl/certs/ssl-cert.pem
 sudo chown prew:prew /etc/ssl/certs/ssl-cert.pem
 sudo chmod 644 /etc/ssl/certs/ssl-cert.pem
@@ -35,17 +38,18 @@ sudo cp "/etc/letsencrypt/live/${IMPULSE_HOSTNAME}/privkey.pem" /etc/ssl/private
 sudo chown prew:prew /etc/ssl/private/ssl-cert.key
 sudo chmod 600 /etc/ssl/private/ssl-cert.key
 
-# deploy envoy configuration file
-cat /root/envoy-postgres.yaml.tmpl | envsubst > envoy-postgres.yaml
-sudo cp envoy-postgres.yaml /opt/envoy/etc/envoy-postgres.yaml
+# prew terminates TLS itself now, in place of envoy
+if systemctl list-unit-files envoy.service >/dev/null; then
+    sudo systemctl disable --now envoy.service
+fi
 
 # start the services
-sudo systemctl enable envoy.service
-sudo systemctl restart envoy.service
 sudo systemctl enable prew.service
 sudo systemctl restart prew.service
 sudo systemctl enable impulse.timer
 sudo systemctl restart impulse.timer
+sudo systemctl enable impulse-reports.timer
+sudo systemctl restart impulse-reports.timer
 
 # ensure firewall is open on Postgresql port
-sudo ufw allow ${ENVOY_PORT}
+sudo ufw allow ${PGINCOMING_PORT}
@@ -20,6 +20,17 @@ if [[ ${SETUP_BLOCK_STORAGE} -eq 1 ]]; then
   sudo -u postgres psql -c "CREATE TABLESPACE userdata LOCATION '/mnt/data/postgres'"
 fi
 
+# Trust prew's connections by its certificate, for it to authenticate clients
+# itself. The CA its certificate is signed by has to be installed first.
+if [[ -n "${PREW_HOST:-}" ]]; then
+  test -f /etc/postgresql/14/main/prew-ca.pem
+  sudo sed --in-place "s|^#prew hostssl\(.*\)PREW_HOST|hostssl\1${PREW_HOST}|" /etc/postgresql/14/main/pg_hba.conf
+  sudo sed --in-place \
+    -e "s|^ssl = off|ssl = on|" \
+    -e "s|^#ssl_ca_file = ''|ssl_ca_file = '/etc/postgresql/14/main/prew-ca.pem'|" \
+    /etc/postgresql/14/main/postgresql.conf
+fi
+
 sudo fluent-gem install fluent-plugin-postgresql-csvlog --no-document
 sudo fluent-gem install fluent-plugin-sql --no-document
 sudo fluent-gem install pg --no-document
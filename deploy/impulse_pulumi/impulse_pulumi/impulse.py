@@ -91,11 +91,42 @@ EOT
         prew_toml_write_cmd = pulumi.Output.format(
             """
 cat <<EOT >/opt/impulse/etc/prew.toml
-bind_addr = "0.0.0.0:6432"
+bind_addr = "0.0.0.0:{}"
 server_addr = "{}:5432"
+
+# clients' connections are encrypted by prew itself; with require set,
+# clients that don't ask for TLS are refused
+[tls]
+cert_file = "/etc/ssl/certs/ssl-cert.pem"
+key_file = "/etc/ssl/private/ssl-cert.key"
+require = false
+
+# connections to the server, whose pg_hba.conf has to trust prew's
+# certificate (see deploy_managed_pg.sh); needs authenticate_clients
+#[server_tls]
+#ca_file = "/etc/ssl/certs/server-ca.pem"
+#cert_file = "/etc/ssl/certs/prew.pem"
+#key_file = "/etc/ssl/private/prew.key"
+
+# statements users may not run through the proxy; plans can make exceptions
+# under [policy.plans.<plan name>] with allow, deny and allowed_extensions.
+# DO blocks and procedural (e.g. plpgsql) functions are denied along with
+# any of these, unless a plan allows "do".
+[policy]
+deny = [
+    "create_extension",
+    "alter_system",
+    "copy_program",
+    "copy_file",
+    "load",
+    "create_language",
+    "set_session_authorization",
+]
+allowed_extensions = ["citext", "hstore", "pg_trgm", "pgcrypto", "uuid-ossp"]
 EOT
 systemctl restart prew
 """,
+            config.require("pgincoming_port"),
             managed_inst.instance.internal_ip,
         )
         pulumi_command.remote.Command(
@@ -162,18 +193,6 @@ systemctl restart prew
             )
         )
         deploy_impulse_script = "deploy_files/deploy_impulse.sh"
-        copy_envoy_template = pulumi_command.remote.CopyFile(
-            "copy_envoy_template",
-            pulumi_command.remote.CopyFileArgs(
-                connection=connection,
-                local_path="deploy_files/envoy-postgres.yaml.tmpl",
-                remote_path="/root/envoy-postgres.yaml.tmpl",
-                triggers=[os.path.getmtime("deploy_files/envoy-postgres.yaml.tmpl")],
-            ),
-            pulumi.ResourceOptions(
-                parent=self.instance,
-            )
-        )
         copy_deploy_script = pulumi_command.remote.CopyFile(
             "copy_deploy_impulse",
             pulumi_command.remote.CopyFileArgs(
@@ -190,11 +209,10 @@ systemctl restart prew
             "run_deploy_impulse",
             pulumi_command.remote.CommandArgs(
                 connection=connection,
-                create=f"""ENVOY_PORT="{config.require("pgincoming_port")}" EMAIL_ADDRESS="{config.require("email_address")}" IMPULSE_HOSTNAME="{config.require("impulse_hostname")}" bash /root/deploy_impulse.sh""",
+                create=f"""PGINCOMING_PORT="{config.require("pgincoming_port")}" EMAIL_ADDRESS="{config.require("email_address")}" IMPULSE_HOSTNAME="{config.require("impulse_hostname")}" bash /root/deploy_impulse.sh""",
                 triggers=[
                     copy_deploy_script,
                     copy_tarball,
-                    copy_envoy_template,
                     copy_prew_binary,
                     copy_impulse_binary
                 ],
@@ -203,7 +221,6 @@ systemctl restart prew
                 depends_on=[
                     copy_deploy_script,
                     copy_tarball,
-                    copy_envoy_template,
                     copy_prew_binary,
                     copy_impulse_binary
                 ],
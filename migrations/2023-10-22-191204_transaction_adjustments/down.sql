@@ -0,0 +1,6 @@
+DROP FUNCTION add_adjustment;
+ALTER TABLE transactions
+    DROP CONSTRAINT transactions_reason_check,
+    DROP COLUMN reason,
+    DROP COLUMN txn_type;
+DROP TYPE transactiontype;
@@ -0,0 +1,77 @@
+-- distinguish charge-backed transactions from manual adjustments
+CREATE TYPE transactiontype AS ENUM (
+    'Charge',
+    'Credit',
+    'Refund',
+    'Goodwill'
+);
+
+ALTER TABLE transactions
+    ADD COLUMN txn_type transactiontype NOT NULL DEFAULT 'Charge',
+    ADD COLUMN reason text,
+    ADD CONSTRAINT transactions_reason_check
+        CHECK (txn_type = 'Charge' OR length(trim(reason)) > 0);
+
+-- Adjustments are paid by the default payee (the nil user) to the adjusted
+-- user. Refunds reverse specific charges, which are left in place; a charge
+-- may only be refunded once.
+CREATE OR REPLACE FUNCTION add_adjustment(
+    p_user uuid,
+    p_txn_type transactiontype,
+    p_amount double precision,
+    p_charge_ids bigint[],
+    p_reason text
+)
+    RETURNS bigint
+    LANGUAGE plpgsql
+AS $BODY$
+DECLARE
+    amount_adjusted double precision;
+    new_txn_id bigint;
+BEGIN
+    IF p_txn_type = 'Charge' THEN
+        RAISE EXCEPTION 'Charge transactions must be created from charges';
+    END IF;
+    IF p_reason IS NULL OR length(trim(p_reason)) = 0 THEN
+        RAISE EXCEPTION 'A reason is required for adjustments';
+    END IF;
+    IF p_txn_type = 'Refund' THEN
+        IF p_charge_ids IS NULL OR cardinality(p_charge_ids) = 0 THEN
+            RAISE EXCEPTION 'Refunds must reference at least one charge';
+        END IF;
+        -- lock the charges so concurrent refunds can't both pass the checks
+        PERFORM 1 FROM charges WHERE charge_id = ANY(p_charge_ids) FOR UPDATE;
+        IF (
+            SELECT count(*) FROM charges
+            WHERE charge_id = ANY(p_charge_ids) AND user_id = p_user AND transacted
+        ) <> cardinality(p_charge_ids) THEN
+            RAISE EXCEPTION 'Refunded charges must be distinct, transacted charges of user %', p_user;
+        END IF;
+        IF EXISTS (
+            SELECT 1 FROM transactions
+            WHERE txn_type = 'Refund' AND charge_ids && p_charge_ids
+        ) THEN
+            RAISE EXCEPTION 'One or more charges have already been refunded: %', p_charge_ids;
+        END IF;
+        SELECT SUM(amount) INTO STRICT amount_adjusted FROM charges WHERE charge_id = ANY(p_charge_ids);
+    ELSE
+        IF p_charge_ids IS NOT NULL THEN
+            RAISE EXCEPTION 'Only refunds may reference charges';
+        END IF;
+        amount_adjusted := p_amount;
+    END IF;
+    IF amount_adjusted IS NULL OR amount_adjusted < 0 THEN
+        RAISE EXCEPTION 'Adjustment amount must be non-negative: %', amount_adjusted;
+    END IF;
+    INSERT INTO transactions (from_user, to_user, charge_ids, amount, txn_type, reason)
+        VALUES (uuid_nil(), p_user, p_charge_ids, amount_adjusted, p_txn_type, p_reason)
+        RETURNING txn_id INTO new_txn_id;
+    UPDATE users
+        SET balance = balance - amount_adjusted
+        WHERE user_id = uuid_nil();
+    UPDATE users
+        SET balance = balance + amount_adjusted
+        WHERE user_id = p_user;
+    RETURN new_txn_id;
+END;
+$BODY$;
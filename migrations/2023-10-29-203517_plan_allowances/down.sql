@@ -0,0 +1,8 @@
+ALTER TABLE charges DROP COLUMN amount;
+ALTER TABLE charges DROP COLUMN free_quantity;
+ALTER TABLE charges
+    ADD COLUMN amount double precision NOT NULL GENERATED ALWAYS AS (quantity * rate) STORED;
+DROP TABLE usage_periods;
+DROP TABLE plan_allowances;
+ALTER TABLE users DROP COLUMN plan_name;
+DROP TABLE plans;
@@ -0,0 +1,40 @@
+CREATE TABLE plans (
+    plan_name text PRIMARY KEY,
+    created_at timestamptz NOT NULL DEFAULT current_timestamp,
+    updated_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+SELECT diesel_manage_updated_at('plans');
+
+-- plan assigned to users unless another one is chosen; no free allowances
+INSERT INTO plans (plan_name) VALUES ('default');
+
+ALTER TABLE users
+    ADD COLUMN plan_name text NOT NULL DEFAULT 'default' REFERENCES plans (plan_name);
+
+-- quantity of each charge type (in the charge type's units) that is free
+-- each calendar month
+CREATE TABLE plan_allowances (
+    plan_name text NOT NULL REFERENCES plans (plan_name) ON DELETE CASCADE,
+    charge_type chargetype NOT NULL,
+    free_quantity double precision NOT NULL CHECK (free_quantity >= 0),
+    PRIMARY KEY (plan_name, charge_type)
+);
+
+-- quantity charged per user and charge type in each calendar month (UTC),
+-- updated in the same transaction as the charge so that each allowance is
+-- consumed exactly once
+CREATE TABLE usage_periods (
+    user_id uuid NOT NULL,
+    charge_type chargetype NOT NULL,
+    period_start timestamptz NOT NULL,
+    quantity double precision NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, charge_type, period_start)
+);
+
+-- the generated amount column can't be altered in place
+ALTER TABLE charges DROP COLUMN amount;
+ALTER TABLE charges
+    ADD COLUMN free_quantity double precision NOT NULL DEFAULT 0 CHECK (free_quantity >= 0);
+ALTER TABLE charges
+    ADD COLUMN amount double precision NOT NULL
+        GENERATED ALWAYS AS ((quantity - free_quantity) * rate) STORED;
@@ -0,0 +1,2 @@
+ALTER TABLE charges DROP COLUMN tiers;
+DROP TABLE price_tiers;
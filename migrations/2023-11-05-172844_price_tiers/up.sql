@@ -0,0 +1,13 @@
+-- volume pricing per charge type: usage at or above start_quantity (counted
+-- cumulatively within the calendar month) is charged at rate, until the next
+-- tier begins
+CREATE TABLE price_tiers (
+    charge_type chargetype NOT NULL,
+    start_quantity double precision NOT NULL CHECK (start_quantity >= 0),
+    rate double precision NOT NULL CHECK (rate >= 0),
+    PRIMARY KEY (charge_type, start_quantity)
+);
+
+-- breakdown of the charged quantity by tier; NULL for charges priced at a
+-- single rate
+ALTER TABLE charges ADD COLUMN tiers jsonb;
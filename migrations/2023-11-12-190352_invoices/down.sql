@@ -0,0 +1,2 @@
+DROP TABLE invoices;
+ALTER TABLE users DROP COLUMN billing_period_months;
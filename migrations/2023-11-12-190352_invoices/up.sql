@@ -0,0 +1,28 @@
+-- length of each user's billing period, in calendar months
+ALTER TABLE users
+    ADD COLUMN billing_period_months integer NOT NULL DEFAULT 1
+        CHECK (billing_period_months > 0);
+
+CREATE TABLE invoices (
+    invoice_id bigserial PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users (user_id),
+    period_start timestamptz NOT NULL,
+    period_end timestamptz NOT NULL,
+    -- balance recomputed from history at period_start
+    opening_balance double precision NOT NULL,
+    -- external deposits made during the period
+    deposits double precision NOT NULL,
+    -- transactions paid by the user during the period
+    payments double precision NOT NULL,
+    -- credits, refunds and goodwill paid to the user during the period
+    adjustments double precision NOT NULL,
+    -- balance recomputed from history at period_end
+    closing_balance double precision NOT NULL,
+    -- total amount of charges incurred during the period
+    charges_total double precision NOT NULL,
+    -- charges incurred during the period summarized by charge type
+    charge_lines jsonb NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT current_timestamp,
+    UNIQUE (user_id, period_start),
+    CHECK (period_end > period_start)
+);
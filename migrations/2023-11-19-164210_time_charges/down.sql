@@ -0,0 +1,27 @@
+DROP VIEW reports_to_charge;
+CREATE VIEW reports_to_charge AS
+    SELECT packet_id as report_id, user_id, packet_type, direction, length(packet_bytes) as num_bytes
+    FROM reports r
+    LEFT OUTER JOIN users u ON u.pg_name = r.username
+    WHERE NOT CHARGED;
+
+-- enum values can't be dropped, so recreate the type without them
+DELETE FROM charges WHERE charge_type IN ('ConnectionSeconds', 'QueryExecutionSeconds');
+DELETE FROM plan_allowances WHERE charge_type IN ('ConnectionSeconds', 'QueryExecutionSeconds');
+DELETE FROM usage_periods WHERE charge_type IN ('ConnectionSeconds', 'QueryExecutionSeconds');
+DELETE FROM price_tiers WHERE charge_type IN ('ConnectionSeconds', 'QueryExecutionSeconds');
+ALTER TYPE chargetype RENAME TO chargetype_old;
+CREATE TYPE chargetype AS ENUM (
+    'DataTransferInBytes',
+    'DataTransferOutBytes',
+    'DataStorageByteHours'
+);
+ALTER TABLE charges
+    ALTER COLUMN charge_type TYPE chargetype USING charge_type::text::chargetype;
+ALTER TABLE plan_allowances
+    ALTER COLUMN charge_type TYPE chargetype USING charge_type::text::chargetype;
+ALTER TABLE usage_periods
+    ALTER COLUMN charge_type TYPE chargetype USING charge_type::text::chargetype;
+ALTER TABLE price_tiers
+    ALTER COLUMN charge_type TYPE chargetype USING charge_type::text::chargetype;
+DROP TYPE chargetype_old;
@@ -0,0 +1,19 @@
+ALTER TYPE chargetype ADD VALUE 'ConnectionSeconds';
+ALTER TYPE chargetype ADD VALUE 'QueryExecutionSeconds';
+
+-- time reports (packet_type 'ConnectionTime' or 'QueryTime') are recorded by
+-- the proxy with the measured duration in packet_info
+DROP VIEW reports_to_charge;
+CREATE VIEW reports_to_charge AS
+    SELECT
+        packet_id as report_id,
+        user_id,
+        packet_type,
+        direction,
+        length(packet_bytes) as num_bytes,
+        CASE WHEN packet_type IN ('ConnectionTime', 'QueryTime')
+            THEN (packet_info->>'seconds')::double precision
+        END as seconds
+    FROM reports r
+    LEFT OUTER JOIN users u ON u.pg_name = r.username
+    WHERE NOT CHARGED;
@@ -0,0 +1,19 @@
+-- one row per statement run through the proxy
+CREATE TABLE query_stats (
+    query_stat_id bigserial PRIMARY KEY,
+    username text NOT NULL,
+    -- when the statement started running
+    query_time timestamptz NOT NULL,
+    -- pg_query fingerprint of the statement, NULL if it couldn't be parsed
+    fingerprint text,
+    -- statement with constants replaced by parameters
+    query text NOT NULL,
+    duration_seconds double precision NOT NULL,
+    rows_returned bigint NOT NULL,
+    bytes_returned bigint NOT NULL,
+    -- command tag from CommandComplete, e.g. 'SELECT 3'
+    command_tag text,
+    -- SQLSTATE from ErrorResponse
+    error_code text
+);
+CREATE INDEX query_stats_user_fingerprint_index ON query_stats (username, fingerprint);
@@ -0,0 +1,3 @@
+DROP TABLE fingerprint_usage;
+DROP INDEX reports_unfingerprinted_index;
+ALTER TABLE reports DROP COLUMN fingerprinted;
@@ -0,0 +1,18 @@
+-- set once a Query report has been counted in fingerprint_usage
+ALTER TABLE reports ADD COLUMN fingerprinted boolean NOT NULL DEFAULT false;
+CREATE INDEX reports_unfingerprinted_index ON reports (packet_id)
+    WHERE packet_type = 'Query' AND NOT fingerprinted;
+
+-- Query reports grouped by statement shape, per user per hour
+CREATE TABLE fingerprint_usage (
+    username text NOT NULL,
+    period_start timestamptz NOT NULL,
+    -- pg_query fingerprint of the query
+    fingerprint text NOT NULL,
+    -- query with constants replaced by parameters, as first seen
+    query text NOT NULL,
+    calls bigint NOT NULL,
+    -- size of the Query messages
+    bytes bigint NOT NULL,
+    PRIMARY KEY (username, period_start, fingerprint)
+);
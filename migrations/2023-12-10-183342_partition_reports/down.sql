@@ -0,0 +1,42 @@
+DROP VIEW reports_to_charge;
+DROP FUNCTION remove_report_partition(text, boolean);
+DROP FUNCTION report_partition_pending(text);
+DROP FUNCTION create_report_partitions(timestamptz, timestamptz);
+DROP FUNCTION report_partitions();
+
+ALTER TABLE reports RENAME TO reports_partitioned;
+ALTER TABLE reports_partitioned DROP CONSTRAINT reports_pkey;
+DROP INDEX reports_not_charged_index;
+DROP INDEX reports_unfingerprinted_index;
+ALTER SEQUENCE reports_packet_id_seq OWNED BY NONE;
+CREATE TABLE reports (
+    packet_id bigint PRIMARY KEY DEFAULT nextval('reports_packet_id_seq'),
+    username text,
+    packet_type text NOT NULL,
+    packet_time timestamptz NOT NULL DEFAULT now(),
+    direction text,
+    packet_info jsonb,
+    packet_bytes bytea,
+    charged boolean NOT NULL DEFAULT false,
+    fingerprinted boolean NOT NULL DEFAULT false
+);
+ALTER SEQUENCE reports_packet_id_seq OWNED BY reports.packet_id;
+INSERT INTO reports SELECT * FROM reports_partitioned;
+DROP TABLE reports_partitioned;
+CREATE INDEX reports_not_charged_index ON reports (packet_id) WHERE NOT charged;
+CREATE INDEX reports_unfingerprinted_index ON reports (packet_id)
+    WHERE packet_type = 'Query' AND NOT fingerprinted;
+
+CREATE VIEW reports_to_charge AS
+    SELECT
+        packet_id as report_id,
+        user_id,
+        packet_type,
+        direction,
+        length(packet_bytes) as num_bytes,
+        CASE WHEN packet_type IN ('ConnectionTime', 'QueryTime')
+            THEN (packet_info->>'seconds')::double precision
+        END as seconds
+    FROM reports r
+    LEFT OUTER JOIN users u ON u.pg_name = r.username
+    WHERE NOT CHARGED;
@@ -0,0 +1,154 @@
+-- Partition reports by month of packet_time. The primary key of a
+-- partitioned table has to include the partition key.
+DROP VIEW reports_to_charge;
+ALTER TABLE reports RENAME TO reports_unpartitioned;
+ALTER TABLE reports_unpartitioned DROP CONSTRAINT reports_pkey;
+DROP INDEX reports_not_charged_index;
+DROP INDEX reports_unfingerprinted_index;
+ALTER SEQUENCE reports_packet_id_seq OWNED BY NONE;
+
+CREATE TABLE reports (
+    packet_id bigint NOT NULL DEFAULT nextval('reports_packet_id_seq'),
+    username text,
+    packet_type text NOT NULL,
+    packet_time timestamptz NOT NULL DEFAULT now(),
+    direction text,
+    packet_info jsonb,
+    packet_bytes bytea,
+    charged boolean NOT NULL DEFAULT false,
+    fingerprinted boolean NOT NULL DEFAULT false,
+    PRIMARY KEY (packet_id, packet_time)
+) PARTITION BY RANGE (packet_time);
+ALTER SEQUENCE reports_packet_id_seq OWNED BY reports.packet_id;
+-- catches reports outside of every monthly partition, so the proxy never
+-- fails to record a packet
+CREATE TABLE reports_default PARTITION OF reports DEFAULT;
+CREATE INDEX reports_not_charged_index ON reports (packet_id) WHERE NOT charged;
+CREATE INDEX reports_unfingerprinted_index ON reports (packet_id)
+    WHERE packet_type = 'Query' AND NOT fingerprinted;
+
+-- Monthly partitions of reports with their bounds, plus the default
+-- partition (with NULL bounds).
+CREATE FUNCTION report_partitions()
+RETURNS TABLE (partition_name text, range_start timestamptz, range_end timestamptz)
+LANGUAGE sql STABLE
+-- so bounds are printed, and parsed back, in a fixed time zone
+SET TimeZone = 'UTC'
+AS $$
+    SELECT
+        c.relname::text,
+        substring(pg_get_expr(c.relpartbound, c.oid) FROM $re$FROM \('([^']+)'\)$re$)::timestamptz,
+        substring(pg_get_expr(c.relpartbound, c.oid) FROM $re$TO \('([^']+)'\)$re$)::timestamptz
+    FROM pg_inherits i
+    JOIN pg_class c ON c.oid = i.inhrelid
+    WHERE i.inhparent = 'reports'::regclass
+    ORDER BY 2 NULLS LAST;
+$$;
+
+-- Create the monthly partitions (months in UTC) from the month of
+-- `range_from` through the month of `range_through`, moving any of their
+-- reports out of the default partition. Returns the number of partitions
+-- created.
+CREATE FUNCTION create_report_partitions(range_from timestamptz, range_through timestamptz)
+RETURNS integer
+LANGUAGE plpgsql
+SET TimeZone = 'UTC'
+AS $$
+DECLARE
+    month_start timestamptz := date_trunc('month', range_from);
+    month_end timestamptz;
+    name text;
+    created integer := 0;
+BEGIN
+    WHILE month_start <= range_through LOOP
+        month_end := month_start + interval '1 month';
+        name := 'reports_' || to_char(month_start, 'YYYY_MM');
+        IF to_regclass(name) IS NULL THEN
+            EXECUTE format(
+                'CREATE TABLE %I (LIKE reports INCLUDING DEFAULTS INCLUDING CONSTRAINTS)',
+                name
+            );
+            EXECUTE format(
+                'WITH moved AS (
+                    DELETE FROM reports_default WHERE packet_time >= $1 AND packet_time < $2
+                    RETURNING *
+                ) INSERT INTO %I SELECT * FROM moved',
+                name
+            ) USING month_start, month_end;
+            EXECUTE format(
+                'ALTER TABLE reports ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+                name, month_start, month_end
+            );
+            created := created + 1;
+        END IF;
+        month_start := month_end;
+    END LOOP;
+    RETURN created;
+END;
+$$;
+
+-- Reports in a partition that still have to be processed: not yet charged,
+-- or Query reports not yet counted in fingerprint_usage.
+CREATE FUNCTION report_partition_pending(partition_name text)
+RETURNS bigint
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    pending bigint;
+BEGIN
+    EXECUTE format(
+        'SELECT count(*) FROM %I WHERE NOT charged OR (packet_type = ''Query'' AND NOT fingerprinted)',
+        partition_name
+    ) INTO pending;
+    RETURN pending;
+END;
+$$;
+
+-- Detach a monthly partition of reports, and drop it unless `keep_table`.
+-- Refuses to remove a partition with pending reports, or the default one.
+CREATE FUNCTION remove_report_partition(partition_name text, keep_table boolean)
+RETURNS void
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    pending bigint;
+BEGIN
+    IF NOT EXISTS (
+        SELECT 1 FROM report_partitions() p
+        WHERE p.partition_name = remove_report_partition.partition_name
+            AND p.range_start IS NOT NULL
+    ) THEN
+        RAISE EXCEPTION '% is not a monthly partition of reports', partition_name;
+    END IF;
+    EXECUTE format('LOCK TABLE %I IN ACCESS EXCLUSIVE MODE', partition_name);
+    pending := report_partition_pending(partition_name);
+    IF pending > 0 THEN
+        RAISE EXCEPTION 'Partition % has % pending reports', partition_name, pending;
+    END IF;
+    EXECUTE format('ALTER TABLE reports DETACH PARTITION %I', partition_name);
+    IF NOT keep_table THEN
+        EXECUTE format('DROP TABLE %I', partition_name);
+    END IF;
+END;
+$$;
+
+SELECT create_report_partitions(
+    COALESCE((SELECT min(packet_time) FROM reports_unpartitioned), now()),
+    now() + interval '3 months'
+);
+INSERT INTO reports SELECT * FROM reports_unpartitioned;
+DROP TABLE reports_unpartitioned;
+
+CREATE VIEW reports_to_charge AS
+    SELECT
+        packet_id as report_id,
+        user_id,
+        packet_type,
+        direction,
+        length(packet_bytes) as num_bytes,
+        CASE WHEN packet_type IN ('ConnectionTime', 'QueryTime')
+            THEN (packet_info->>'seconds')::double precision
+        END as seconds
+    FROM reports r
+    LEFT OUTER JOIN users u ON u.pg_name = r.username
+    WHERE NOT CHARGED;
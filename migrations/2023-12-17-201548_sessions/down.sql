@@ -0,0 +1,3 @@
+DROP INDEX reports_session_index;
+ALTER TABLE reports DROP COLUMN session_id;
+DROP TABLE sessions;
@@ -0,0 +1,19 @@
+-- one row per client connection through the proxy
+CREATE TABLE sessions (
+    session_id uuid PRIMARY KEY,
+    client_addr text,
+    -- from the client's startup message
+    username text,
+    database text,
+    application_name text,
+    started_at timestamptz NOT NULL DEFAULT now(),
+    ended_at timestamptz,
+    -- bytes received from the client and from the server
+    bytes_in bigint NOT NULL DEFAULT 0,
+    bytes_out bigint NOT NULL DEFAULT 0,
+    termination_reason text
+);
+CREATE INDEX sessions_username_index ON sessions (username, started_at);
+
+ALTER TABLE reports ADD COLUMN session_id uuid;
+CREATE INDEX reports_session_index ON reports (session_id);
@@ -0,0 +1 @@
+ALTER TABLE plans DROP COLUMN max_connections;
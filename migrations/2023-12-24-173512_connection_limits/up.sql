@@ -0,0 +1,4 @@
+-- maximum number of concurrent connections of each user on the plan, enforced
+-- on the user's role and by the proxy; NULL for no limit
+ALTER TABLE plans
+    ADD COLUMN max_connections integer CHECK (max_connections >= 0);
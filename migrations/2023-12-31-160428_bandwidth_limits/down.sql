@@ -0,0 +1,7 @@
+ALTER TABLE sessions
+    DROP COLUMN throttled_in_ms,
+    DROP COLUMN throttled_out_ms;
+
+ALTER TABLE plans
+    DROP COLUMN max_bytes_in_per_second,
+    DROP COLUMN max_bytes_out_per_second;
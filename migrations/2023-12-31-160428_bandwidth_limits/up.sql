@@ -0,0 +1,11 @@
+-- bytes per second each user on the plan may send (in) and receive (out)
+-- through the proxy, across all of their connections; NULL for no limit
+ALTER TABLE plans
+    ADD COLUMN max_bytes_in_per_second bigint CHECK (max_bytes_in_per_second > 0),
+    ADD COLUMN max_bytes_out_per_second bigint CHECK (max_bytes_out_per_second > 0);
+
+-- time the proxy held back traffic from the client and from the server to
+-- keep the user within their plan's bandwidth
+ALTER TABLE sessions
+    ADD COLUMN throttled_in_ms bigint NOT NULL DEFAULT 0,
+    ADD COLUMN throttled_out_ms bigint NOT NULL DEFAULT 0;
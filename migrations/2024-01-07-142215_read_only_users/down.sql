@@ -0,0 +1,13 @@
+-- enum values can't be dropped, so the type is recreated without it
+UPDATE users SET user_status = 'Disabled', status_synced = false WHERE user_status = 'ReadOnly';
+
+ALTER TYPE userstatus RENAME TO userstatus_old;
+CREATE TYPE userstatus AS ENUM (
+    'Active',
+    'Disabled',
+    'Deleted'
+);
+ALTER TABLE users ALTER COLUMN user_status DROP DEFAULT;
+ALTER TABLE users ALTER COLUMN user_status TYPE userstatus USING user_status::text::userstatus;
+ALTER TABLE users ALTER COLUMN user_status SET DEFAULT 'Active';
+DROP TYPE userstatus_old;
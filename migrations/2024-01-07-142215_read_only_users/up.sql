@@ -0,0 +1,2 @@
+-- users in arrears can still read (and export) their data, but not write
+ALTER TYPE userstatus ADD VALUE 'ReadOnly' AFTER 'Active';
@@ -0,0 +1,15 @@
+-- managed Postgres clusters that users' roles and databases can be placed on,
+-- besides the cluster the managed database config points to
+CREATE TABLE clusters (
+    cluster_name text PRIMARY KEY,
+    pg_host text NOT NULL,
+    pg_port integer NOT NULL CHECK (pg_port > 0 AND pg_port < 65536),
+    created_at timestamptz NOT NULL DEFAULT current_timestamp,
+    updated_at timestamptz NOT NULL DEFAULT current_timestamp
+);
+SELECT diesel_manage_updated_at('clusters');
+
+-- cluster the user's role and databases are on; NULL for the configured one
+ALTER TABLE users
+    ADD COLUMN cluster_name text REFERENCES clusters (cluster_name);
+CREATE INDEX users_cluster_name_idx ON users (cluster_name);
@@ -1,15 +1,19 @@
-use std::sync::Arc;
 use std::env;
 use std::fs;
+use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use futures::lock::Mutex;
 use log::info;
-use prew::{NoTransform, PacketRules, RewriteReverseProxy, RuleSetProcessor};
+use prew::NoTransform;
 use serde::{Serialize, Deserialize};
+use tokio::net::TcpListener;
 
-use impulse::prew::{AppendUserNameTransformer, ImpulseReporter, RemoveAppendedUserNameTransformer};
+use impulse::prew::{AppendUserNameTransformer, RemoveAppendedUserNameTransformer, session_factory};
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::pool::{PoolConfig, ServerPools};
+use impulse::prew::proxy::{serve_with_options, ProxyOptions};
+use impulse::prew::tls::{ProxyTls, ServerTlsConfig, TlsConfig};
 
 
 #[derive(Debug, Parser)]
@@ -32,6 +36,45 @@ struct PrewConfig {
     bind_addr: Option<String>,
     server_addr: Option<String>,
     report_connstr: Option<String>,
+    /// Statements denied to users, per plan
+    #[serde(default)]
+    policy: StatementPolicy,
+    /// TLS of clients' connections, which are only offered plaintext if
+    /// not set
+    tls: Option<TlsConfig>,
+    /// TLS of connections to the server, plaintext if not set
+    server_tls: Option<ServerTlsConfig>,
+    /// Whether prew authenticates clients itself, against the users table,
+    /// rather than leaving it to the server. The server has to accept prew's
+    /// connections for any user then, e.g. by its certificate.
+    #[serde(default)]
+    authenticate_clients: bool,
+    /// Pooling of server connections, which clients share for the length
+    /// of a transaction if set
+    pool: Option<PoolConfig>,
+    /// Whether clients are connected to the cluster their user is placed
+    /// on, rather than all to `server_addr`, which users on no cluster
+    /// are still connected to
+    #[serde(default)]
+    route_by_user: bool,
+}
+
+impl PrewConfig {
+    /// Refuse settings that would let clients reach the server without
+    /// being authenticated by either.
+    fn check(&self) -> Result<()> {
+        if self.authenticate_clients {
+            return Ok(());
+        }
+        if self.pool.is_some() {
+            bail!("Pooling server connections needs authenticate_clients");
+        }
+        // the server lets the proxy's certificate log in as anyone
+        if self.server_tls.as_ref().is_some_and(|tls| tls.cert_file.is_some()) {
+            bail!("Presenting a certificate to the server needs authenticate_clients");
+        }
+        Ok(())
+    }
 }
 
 fn parse_config(config_file: Option<String>) -> Result<Option<PrewConfig>> {
@@ -50,61 +93,60 @@ pub async fn main() -> Result<()> {
     dotenvy::dotenv().ok();
     let mut args = PrewArgs::parse();
     let opt_config = parse_config(args.config_file)?;
+    let mut policy = StatementPolicy::default();
+    let (mut tls_config, mut server_tls_config) = (None, None);
+    let mut authenticate_clients = false;
+    let mut pool_config = None;
+    let mut route_by_user = false;
     if let Some(config) = opt_config {
         println!("Loaded config: {:?}", &config);
+        config.check()?;
         args.bind_addr = args.bind_addr.or(config.bind_addr);
         args.server_addr = args.server_addr.or(config.server_addr);
         args.report_connstr = args.report_connstr.or(config.report_connstr);
+        policy = config.policy;
+        tls_config = config.tls;
+        server_tls_config = config.server_tls;
+        authenticate_clients = config.authenticate_clients;
+        pool_config = config.pool;
+        route_by_user = config.route_by_user;
     }
-    let parser = prew::PostgresParser::new();
-    let filter = prew::NoFilter::new();
-    let transformer = AppendUserNameTransformer::new();
-    let remover_xformer = RemoveAppendedUserNameTransformer::new();
-    let notransform = NoTransform::new();
-    let encoder = prew::MessageEncoder::new();
-    let reporter = ImpulseReporter::new();
     let report_connstr = args.report_connstr.or(env::var("DATABASE_URL").ok())
         .context("No impulse database connection string specified")?;
     let server_addr = args.server_addr.context("No server address specified")?;
-    let create_context = move || {
-        impulse::prew::Context::new(report_connstr.clone()).unwrap()
-    };
-    let packet_rules = if args.enable_outgoing_transformer {
-        let prew_rules = RuleSetProcessor::new(
-            &parser,
-            &filter,
-            &transformer,
-            &remover_xformer,
-            &encoder,
-            &reporter,
-            &create_context,
-        );
-        let processor = Arc::new(Mutex::new(prew_rules));
-        PacketRules  {
-            bind_addr: args.bind_addr.context("Bind address not specified")?,
-            server_addr,
-            processor,
-        }
+    let bind_addr = args.bind_addr.context("Bind address not specified")?;
+    let tls = ProxyTls::from_config(tls_config.as_ref(), server_tls_config.as_ref())?;
+    let pools = pool_config.map(|config| Arc::new(ServerPools::new(tls.clone(), config)));
+    let transformer = AppendUserNameTransformer::new();
+    let create_session = if args.enable_outgoing_transformer {
+        session_factory(report_connstr, transformer, RemoveAppendedUserNameTransformer::new(), policy)
     } else {
-        let prew_rules = RuleSetProcessor::new(
-            &parser,
-            &filter,
-            &transformer,
-            &notransform,
-            &encoder,
-            &reporter,
-            &create_context,
-        );
-        let processor = Arc::new(Mutex::new(prew_rules));
-        PacketRules  {
-            bind_addr: args.bind_addr.context("Bind address not specified")?,
-            server_addr,
-            processor,
-        }
+        session_factory(report_connstr, transformer, NoTransform::new(), policy)
     };
-    let mut proxy = RewriteReverseProxy::new();
-    proxy.add_proxy(Box::new(packet_rules)).await;
+    let listener = TcpListener::bind(&bind_addr)
+        .await
+        .with_context(|| format!("Unable to bind to {}", &bind_addr))?;
     info!("Starting proxy");
-    proxy.run().await;
+    let options = ProxyOptions { tls, authenticate_clients, pools, route_by_user, ..ProxyOptions::default() };
+    serve_with_options(listener, server_addr, create_session, Arc::new(options)).await;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_config_test() {
+        let config: PrewConfig = toml::from_str("[pool]\nsize = 5").unwrap();
+        assert!(config.check().is_err());
+        let server_tls = "[server_tls]\nca_file = \"ca.pem\"\n";
+        let config: PrewConfig = toml::from_str(server_tls).unwrap();
+        assert!(config.check().is_ok());
+        let with_cert = format!("{}cert_file = \"prew.pem\"\nkey_file = \"prew.key\"\n", server_tls);
+        let config: PrewConfig = toml::from_str(&with_cert).unwrap();
+        assert!(config.check().is_err());
+        let config: PrewConfig = toml::from_str(&format!("authenticate_clients = true\n{}", with_cert)).unwrap();
+        assert!(config.check().is_ok());
+    }
+}
@@ -1,31 +1,305 @@
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use anyhow::Result;
-use chrono::Utc;
-use clap::Parser;
-use diesel::PgConnection;
-use log::{debug, info, trace};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Months, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use diesel::{Connection, PgConnection};
+use log::{debug, error, info, trace};
+use uuid::Uuid;
 
 use super::ManagementConfig;
 use super::postgres::PostgresManager;
+use crate::models::audit::BalanceAudit;
 use crate::models::charges::{Charge, NewTimeCharge, TimeChargeType};
+use crate::models::clusters::{Cluster, NewCluster};
+use crate::models::fingerprint_usage::{FingerprintRanking, FingerprintTotal, FingerprintUsage};
+use crate::models::invoices::Invoice;
+use crate::models::plans::Plan;
+use crate::models::report_archive::ReportArchive;
+use crate::models::report_partitions::{ReportPartition, RetentionAction, RetentionPolicy};
 use crate::models::reports::{ReportToCharge};
+use crate::models::statements::Statement;
 use crate::models::transactions::NewTransaction;
 use crate::models::users::{User, UserStatus};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about=None)]
 pub struct ImpulseArgs {
+    #[command(subcommand)]
+    command: Option<ImpulseCommand>,
     #[arg(short='c', long)]
     generate_charges: bool,
     #[arg(short='t', long)]
     generate_transactions: bool,
     #[arg(short, long)]
     process_timecharges: bool,
-    #[arg(short, long)]
+    // -c has always been taken by --generate-charges, which it resolves to,
+    // and clap refuses to build the command with two of them
+    #[arg(short='S', long)]
     compute_storage: bool,
     #[arg(short, long)]
     sync_users: bool,
+    /// Copy users' password verifiers from their clusters, for prew to
+    /// authenticate them against
+    #[arg(short='w', long)]
+    sync_passwords: bool,
+    #[arg(short='f', long)]
+    aggregate_fingerprints: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ImpulseCommand {
+    /// Check billing data for internal consistency
+    #[command(subcommand)]
+    Audit(AuditCommand),
+    /// Record a manual adjustment to a user's balance
+    #[command(subcommand)]
+    Adjust(AdjustCommand),
+    /// Close billing periods and render invoices
+    #[command(subcommand)]
+    Invoices(InvoicesCommand),
+    /// Export a user's charges, transactions and deposits
+    Statement {
+        #[arg(short, long)]
+        user_id: Uuid,
+        /// Start of the statement (inclusive), in RFC 3339 format
+        #[arg(short, long)]
+        start: DateTime<Utc>,
+        /// End of the statement (exclusive), in RFC 3339 format; defaults to now
+        #[arg(short, long)]
+        end: Option<DateTime<Utc>>,
+        #[arg(short, long, value_enum, default_value_t = StatementFormat::Csv)]
+        format: StatementFormat,
+        /// Write the statement to this file instead of standard output
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Maintain the partitions of the reports table
+    #[command(subcommand)]
+    Reports(ReportsCommand),
+    /// Register the managed clusters users can be placed on
+    #[command(subcommand)]
+    Clusters(ClustersCommand),
+    /// Change a user's status, applied to their role on the next user sync
+    #[command(subcommand)]
+    Users(UsersCommand),
+    /// Show a user's most used query fingerprints
+    TopQueries {
+        #[arg(short, long)]
+        user_id: Uuid,
+        /// Start of the window (inclusive), in RFC 3339 format
+        #[arg(short, long)]
+        start: DateTime<Utc>,
+        /// End of the window (exclusive), in RFC 3339 format; defaults to now
+        #[arg(short, long)]
+        end: Option<DateTime<Utc>>,
+        /// Number of fingerprints to show
+        #[arg(short='n', long, default_value_t = 10)]
+        limit: i64,
+        #[arg(short, long, value_enum, default_value_t = QueryRanking::Calls)]
+        by: QueryRanking,
+        #[arg(short, long, value_enum, default_value_t = TopQueriesFormat::Text)]
+        format: TopQueriesFormat,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AuditCommand {
+    /// Recompute user balances from history and report any drift
+    Balances,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdjustCommand {
+    /// Grant promotional credit
+    Credit {
+        #[arg(short, long)]
+        user_id: Uuid,
+        #[arg(short, long)]
+        amount: f64,
+        #[arg(short, long)]
+        reason: String,
+    },
+    /// Refund specific charges
+    Refund {
+        #[arg(short, long)]
+        user_id: Uuid,
+        #[arg(short='i', long="charge-id", required=true)]
+        charge_ids: Vec<i64>,
+        #[arg(short, long)]
+        reason: String,
+    },
+    /// Grant discretionary credit
+    Goodwill {
+        #[arg(short, long)]
+        user_id: Uuid,
+        #[arg(short, long)]
+        amount: f64,
+        #[arg(short, long)]
+        reason: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClustersCommand {
+    /// List the registered clusters with their number of users
+    List,
+    /// Register a cluster, which has to have the same management role as
+    /// the configured one, setting up its permissions and role procedures
+    Add {
+        #[arg(short, long)]
+        name: String,
+        #[arg(long)]
+        host: String,
+        #[arg(short, long, default_value_t = 5432)]
+        port: i32,
+    },
+    /// Place a user on a cluster, before their role and database are
+    /// created there; users whose role exists already aren't moved
+    Place {
+        #[arg(short, long)]
+        user_id: Uuid,
+        /// Name of the cluster; the configured cluster if not given
+        #[arg(short, long)]
+        cluster: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UsersCommand {
+    /// Only let a user read their data, e.g. while they're in arrears
+    ReadOnly {
+        #[arg(short, long)]
+        user_id: Uuid,
+    },
+    /// Let a read-only or disabled user use their data again, e.g. once
+    /// they've paid their balance
+    Activate {
+        #[arg(short, long)]
+        user_id: Uuid,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReportsCommand {
+    /// List partitions with their number of pending reports
+    Partitions,
+    /// Create monthly partitions ahead of time
+    CreatePartitions {
+        /// Number of months after the current one to create partitions for
+        #[arg(short, long, default_value_t = 3)]
+        months_ahead: u32,
+    },
+    /// Remove partitions older than the retention period whose reports have
+    /// all been charged and aggregated
+    Retention {
+        /// Keep partitions that ended less than this many days ago
+        #[arg(short, long)]
+        days: i64,
+        /// Detach partitions and keep their tables instead of dropping them
+        #[arg(long)]
+        detach: bool,
+        /// Only list the partitions that would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move charged reports older than a cutoff into compressed files, then
+    /// delete them from the database
+    Archive {
+        /// Directory of the archive
+        #[arg(short='o', long)]
+        dir: PathBuf,
+        /// Archive reports recorded more than this many days ago
+        #[arg(short, long)]
+        days: i64,
+    },
+    /// Load archived reports recorded within a time range back into the
+    /// database
+    Restore {
+        /// Directory of the archive
+        #[arg(short='o', long)]
+        dir: PathBuf,
+        /// Start of the range (inclusive), in RFC 3339 format
+        #[arg(short, long)]
+        start: DateTime<Utc>,
+        /// End of the range (exclusive), in RFC 3339 format
+        #[arg(short, long)]
+        end: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InvoicesCommand {
+    /// Create invoices for all billing periods that have ended
+    Close {
+        /// Only close periods for this user
+        #[arg(short, long)]
+        user_id: Option<Uuid>,
+        /// Write each new invoice into this directory
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+        #[arg(short, long, value_enum, default_value_t = InvoiceFormat::Json)]
+        format: InvoiceFormat,
+    },
+    /// Print an existing invoice
+    Show {
+        #[arg(short, long)]
+        invoice_id: i64,
+        #[arg(short, long, value_enum, default_value_t = InvoiceFormat::Text)]
+        format: InvoiceFormat,
+    },
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum InvoiceFormat {
+    Json,
+    Text,
+    Html,
+}
+impl InvoiceFormat {
+    fn render(&self, invoice: &Invoice) -> Result<String> {
+        match self {
+            InvoiceFormat::Json => invoice.to_json(),
+            InvoiceFormat::Text => Ok(invoice.render_text()),
+            InvoiceFormat::Html => Ok(invoice.render_html()),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            InvoiceFormat::Json => "json",
+            InvoiceFormat::Text => "txt",
+            InvoiceFormat::Html => "html",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum StatementFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum QueryRanking {
+    Calls,
+    Bytes,
+}
+impl From<QueryRanking> for FingerprintRanking {
+    fn from(ranking: QueryRanking) -> Self {
+        match ranking {
+            QueryRanking::Calls => FingerprintRanking::Calls,
+            QueryRanking::Bytes => FingerprintRanking::Bytes,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+pub enum TopQueriesFormat {
+    Text,
+    Json,
 }
 
 pub async fn impulse(args: &ImpulseArgs) -> Result<()> {
@@ -82,30 +356,286 @@ pub async fn impulse(args: &ImpulseArgs) -> Result<()> {
             trace!("Created timecharge: {:?}", &timecharge);
         }
     }
+    if args.aggregate_fingerprints {
+        info!("Aggregating query fingerprints from reports");
+        let count = FingerprintUsage::aggregate_reports(&mut impulse_conn)?;
+        info!("Aggregated {} query reports", count);
+    }
     if args.sync_users {
         info!("Syncing user status");
         let synced_count = sync_users(&mut impulse_conn)?;
         info!("{} users synced", synced_count);
     }
+    if args.sync_passwords {
+        info!("Syncing user password verifiers");
+        let count = managed_db_manager()?.sync_password_verifiers(&mut impulse_conn)?;
+        info!("{} user passwords updated", count);
+    }
+    if let Some(command) = &args.command {
+        match command {
+            ImpulseCommand::Audit(AuditCommand::Balances) => {
+                audit_balances(&mut impulse_conn)?;
+            }
+            ImpulseCommand::Adjust(adjustment) => {
+                let txn = match adjustment {
+                    AdjustCommand::Credit { user_id, amount, reason } =>
+                        NewTransaction::credit(&mut impulse_conn, *user_id, *amount, reason)?,
+                    AdjustCommand::Refund { user_id, charge_ids, reason } =>
+                        NewTransaction::refund(&mut impulse_conn, *user_id, charge_ids.clone(), reason)?,
+                    AdjustCommand::Goodwill { user_id, amount, reason } =>
+                        NewTransaction::goodwill(&mut impulse_conn, *user_id, *amount, reason)?,
+                };
+                info!(
+                    "Recorded {:?} transaction {} of {} for user {}",
+                    txn.txn_type,
+                    txn.transaction_id,
+                    txn.amount,
+                    &txn.to_user,
+                );
+            }
+            ImpulseCommand::Invoices(InvoicesCommand::Close { user_id, output_dir, format }) => {
+                close_invoices(&mut impulse_conn, user_id.as_ref(), output_dir.as_deref(), *format)?;
+            }
+            ImpulseCommand::Invoices(InvoicesCommand::Show { invoice_id, format }) => {
+                let invoice = Invoice::retrieve(&mut impulse_conn, *invoice_id)?;
+                println!("{}", format.render(&invoice)?);
+            }
+            ImpulseCommand::Statement { user_id, start, end, format, output } => {
+                let end = end.unwrap_or_else(Utc::now);
+                let statement = Statement::generate(&mut impulse_conn, user_id, start, &end)?;
+                let document = match format {
+                    StatementFormat::Csv => statement.to_csv(),
+                    StatementFormat::Json => statement.to_json()?,
+                };
+                match output {
+                    Some(path) => fs::write(path, document)?,
+                    None => print!("{}", document),
+                }
+            }
+            ImpulseCommand::Reports(command) => {
+                maintain_reports(&mut impulse_conn, command)?;
+            }
+            ImpulseCommand::Clusters(ClustersCommand::List) => {
+                let users = User::all(&mut impulse_conn)?;
+                for cluster in Cluster::all(&mut impulse_conn)? {
+                    let count = users.iter()
+                        .filter(|user| user.cluster_name.as_ref() == Some(&cluster.cluster_name))
+                        .count();
+                    println!("{}\t{}\t{} users", &cluster.cluster_name, cluster.server_addr(), count);
+                }
+            }
+            ImpulseCommand::Clusters(ClustersCommand::Add { name, host, port }) => {
+                // clusters are only registered once they're set up
+                let cluster = impulse_conn.transaction(|conn| {
+                    let cluster = NewCluster::create(conn, name.clone(), host.clone(), *port)?;
+                    managed_db_manager()?.for_cluster(Some(&cluster)).setup_database()?;
+                    Ok::<_, anyhow::Error>(cluster)
+                })?;
+                info!("Registered cluster {} at {}", &cluster.cluster_name, cluster.server_addr());
+            }
+            ImpulseCommand::Clusters(ClustersCommand::Place { user_id, cluster }) => {
+                let mut user = User::retrieve(&mut impulse_conn, user_id)?;
+                managed_db_manager()?.place_user(&mut impulse_conn, &mut user, cluster.as_deref())?;
+                info!("Placed user {} on cluster {}", user_id, cluster.as_deref().unwrap_or("(configured)"));
+            }
+            ImpulseCommand::Users(command) => {
+                let (UsersCommand::ReadOnly { user_id } | UsersCommand::Activate { user_id }) = command;
+                let mut user = User::retrieve(&mut impulse_conn, user_id)?;
+                match command {
+                    UsersCommand::ReadOnly { .. } => user.set_read_only(&mut impulse_conn)?,
+                    UsersCommand::Activate { .. } => user.activate(&mut impulse_conn)?,
+                }
+                info!("Set user {} to {:?}", user_id, user.user_status);
+            }
+            ImpulseCommand::TopQueries { user_id, start, end, limit, by, format } => {
+                let user = User::retrieve(&mut impulse_conn, user_id)?;
+                let end = end.unwrap_or_else(Utc::now);
+                let totals = FingerprintTotal::top_for_user(
+                    &mut impulse_conn,
+                    &user.pg_name,
+                    start,
+                    &end,
+                    (*by).into(),
+                    *limit,
+                )?;
+                match format {
+                    TopQueriesFormat::Json => println!("{}", serde_json::to_string_pretty(&totals)?),
+                    TopQueriesFormat::Text => {
+                        println!("{:>10} {:>12}  query", "calls", "bytes");
+                        for total in &totals {
+                            println!("{:>10} {:>12}  {}", total.calls, total.bytes, &total.query);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn audit_balances(impulse_conn: &mut PgConnection) -> Result<()> {
+    info!("Auditing user balances");
+    let audit = BalanceAudit::run(impulse_conn)?;
+    for drift in &audit.drifts {
+        println!(
+            "Balance drift for user {}: stored {}, expected {} (deposits {}, debits {}, credits {}), drift {}",
+            &drift.user_id,
+            drift.balance,
+            drift.expected_balance,
+            drift.deposits,
+            drift.debits,
+            drift.credits,
+            drift.drift(),
+        );
+    }
+    for charge in &audit.unmatched_charges {
+        println!(
+            "Transacted charge {} referenced by {} transactions: {:?}",
+            charge.charge_id,
+            charge.txn_ids.len(),
+            &charge.txn_ids,
+        );
+    }
+    for report in &audit.unmatched_reports {
+        println!(
+            "Charged report {} referenced by {} charges: {:?}",
+            report.report_id,
+            report.charge_ids.len(),
+            &report.charge_ids,
+        );
+    }
+    if audit.is_clean() {
+        info!("No inconsistencies found");
+        Ok(())
+    } else {
+        Err(anyhow!("Audit found {} inconsistencies", audit.issue_count()))
+    }
+}
+
+fn close_invoices(
+    impulse_conn: &mut PgConnection,
+    user_id: Option<&Uuid>,
+    output_dir: Option<&Path>,
+    format: InvoiceFormat,
+) -> Result<()> {
+    info!("Closing billing periods");
+    let users = match user_id {
+        Some(user_id) => vec![User::retrieve(impulse_conn, user_id)?],
+        None => User::all(impulse_conn)?,
+    };
+    let now = Utc::now();
+    let mut count = 0;
+    for user in users {
+        let invoices = Invoice::close_periods(impulse_conn, &user, &now)?;
+        count += invoices.len();
+        if let Some(dir) = output_dir {
+            for invoice in invoices {
+                let path = dir.join(
+                    format!("invoice-{}.{}", invoice.invoice_id, format.extension())
+                );
+                fs::write(&path, format.render(&invoice)?)?;
+                debug!("Wrote invoice {} to {}", invoice.invoice_id, path.display());
+            }
+        }
+    }
+    info!("Created {} invoices", count);
+    Ok(())
+}
+
+fn maintain_reports(impulse_conn: &mut PgConnection, command: &ReportsCommand) -> Result<()> {
+    match command {
+        ReportsCommand::Partitions => {
+            for partition in ReportPartition::all(impulse_conn)? {
+                let range = match (partition.range_start, partition.range_end) {
+                    (Some(start), Some(end)) => format!("{} - {}", start, end),
+                    _ => "default".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{} pending",
+                    &partition.partition_name,
+                    range,
+                    partition.pending_reports(impulse_conn)?,
+                );
+            }
+        }
+        ReportsCommand::CreatePartitions { months_ahead } => {
+            let now = Utc::now();
+            let through = now
+                .checked_add_months(Months::new(*months_ahead))
+                .ok_or_else(|| anyhow!("Invalid number of months: {}", months_ahead))?;
+            let created = ReportPartition::create(impulse_conn, &now, &through)?;
+            info!("Created {} report partitions", created);
+        }
+        ReportsCommand::Retention { days, detach, dry_run } => {
+            let policy = RetentionPolicy {
+                cutoff: Utc::now() - Duration::days(*days),
+                action: if *detach { RetentionAction::Detach } else { RetentionAction::Drop },
+            };
+            let outcome = policy.apply(impulse_conn, *dry_run)?;
+            for partition in &outcome.removed {
+                if *dry_run {
+                    println!("Would remove {}", &partition.partition_name);
+                } else {
+                    println!("Removed {}", &partition.partition_name);
+                }
+            }
+            for (partition, pending) in &outcome.retained {
+                println!("Kept {}: {} pending reports", &partition.partition_name, pending);
+            }
+        }
+        ReportsCommand::Archive { dir, days } => {
+            let mut archive = ReportArchive::open(dir)?;
+            let cutoff = Utc::now() - Duration::days(*days);
+            let files = archive.archive(impulse_conn, &cutoff)?;
+            let count: usize = files.iter().map(|file| file.report_count).sum();
+            info!("Archived {} reports into {} files", count, files.len());
+        }
+        ReportsCommand::Restore { dir, start, end } => {
+            let archive = ReportArchive::open(dir)?;
+            let count = archive.restore(impulse_conn, start, end)?;
+            info!("Restored {} reports", count);
+        }
+    }
     Ok(())
 }
 
 fn sync_users(impulse_conn: &mut PgConnection) -> Result<usize> {
     let unsynced = User::unsynced(impulse_conn)?;
-    let manager = managed_db_manager()?;
-    let count = unsynced.len();
+    let managed_db_manager = managed_db_manager()?;
+    let mut count = 0;
+    // one user failing to sync doesn't hold up the others, who are synced
+    // regardless; they're tried again on the next run
     for mut user in unsynced {
-        match user.user_status {
-            UserStatus::Active => manager.enable_pg_user(&user.pg_name)?,
-            UserStatus::Disabled => manager.disable_pg_user(&user.pg_name)?,
-            // FIXME: spec out user deletion
-            UserStatus::Deleted => {}
+        match sync_user(impulse_conn, &managed_db_manager, &mut user) {
+            Ok(()) => count += 1,
+            Err(error) => error!("Couldn't sync user {}: {:?}", &user.pg_name, error),
         }
-        user.mark_synced(impulse_conn)?;
     }
     Ok(count)
 }
 
+fn sync_user(impulse_conn: &mut PgConnection, managed_db_manager: &PostgresManager, user: &mut User) -> Result<()> {
+    let manager = managed_db_manager.for_user(impulse_conn, user)?;
+    match user.user_status {
+        UserStatus::Active => {
+            manager.enable_pg_user(&user.pg_name)?;
+            manager.set_read_only(&user.pg_name, false)?;
+        }
+        UserStatus::ReadOnly => {
+            manager.enable_pg_user(&user.pg_name)?;
+            manager.set_read_only(&user.pg_name, true)?;
+        }
+        UserStatus::Disabled => manager.disable_pg_user(&user.pg_name)?,
+        // FIXME: spec out user deletion
+        UserStatus::Deleted => {}
+    }
+    if user.user_status != UserStatus::Deleted {
+        let connection_limit = Plan::retrieve(impulse_conn, &user.plan_name)?.max_connections;
+        manager.set_connection_limit(&user.pg_name, connection_limit)?;
+    }
+    user.mark_synced(impulse_conn)
+}
+
 fn managed_db_manager() -> Result<PostgresManager> {
     let config = Rc::new(ManagementConfig::from_env()?);
     Ok(PostgresManager::new(config.clone()))
@@ -1,5 +1,7 @@
 use anyhow::Result;
 
+use crate::models::clusters::Cluster;
+
 pub mod postgres;
 pub mod cli;
 pub mod container;
@@ -27,6 +29,17 @@ impl ManagementConfig {
         }
     }
 
+    /// Config of the same management role on `cluster`, which every
+    /// cluster is expected to have.
+    pub fn for_cluster(&self, cluster: &Cluster) -> ManagementConfig {
+        ManagementConfig {
+            pg_host: cluster.pg_host.clone(),
+            pg_port: cluster.pg_port as u32,
+            pg_user: self.pg_user.clone(),
+            pg_pw: self.pg_pw.clone(),
+        }
+    }
+
     pub fn from_env() -> Result<ManagementConfig> {
         Ok(
             ManagementConfig {
@@ -3,6 +3,7 @@ use std::fmt::{Display, Formatter};
 use std::rc::Rc;
 
 use anyhow::{anyhow, Result};
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::sql_query;
 use diesel::sql_types::Text;
@@ -12,7 +13,9 @@ use regex::Regex;
 use uuid::Uuid;
 
 use crate::manage::ManagementConfig;
+use crate::models::clusters::Cluster;
 use crate::models::users::User;
+use crate::prew::auth::ScramVerifier;
 
 sql_function!(
     fn create_pg_user(p_username: Text, p_password: Text);
@@ -22,6 +25,83 @@ sql_function!(
 );
 const DB_SEPARATOR: &str = "_";
 
+/// Procedures letting users create, drop and rename roles suffixed with
+/// `__` and their own name, without having CREATEROLE. Only roles the user
+/// created (which they're the admin of) and that can't log in can be
+/// dropped or renamed, as usernames can't contain `__` but other users'
+/// roles may still end in the user's suffix.
+const ROLE_PROCEDURES: &str = r#"
+-- a schema of the same name that someone else owns could have its functions
+-- swapped out from under the procedures
+DO $$
+BEGIN
+    IF EXISTS (SELECT FROM pg_namespace WHERE nspname = 'impulse' AND nspowner <> current_user::regrole) THEN
+        RAISE EXCEPTION 'schema impulse is not owned by %', current_user;
+    END IF;
+END
+$$;
+CREATE SCHEMA IF NOT EXISTS impulse;
+GRANT USAGE ON SCHEMA impulse TO PUBLIC;
+
+CREATE OR REPLACE FUNCTION impulse.check_role_namespace(p_role text) RETURNS void
+LANGUAGE plpgsql SET search_path = pg_catalog AS $$
+BEGIN
+    IF length(p_role) <= length(session_user) + 2 OR right(p_role, length(session_user) + 2) <> '__' || session_user THEN
+        RAISE EXCEPTION 'permission denied to manage role "%"', p_role USING ERRCODE = '42501';
+    END IF;
+END
+$$;
+
+CREATE OR REPLACE FUNCTION impulse.check_role_owned(p_role text) RETURNS void
+LANGUAGE plpgsql SET search_path = pg_catalog AS $$
+BEGIN
+    IF NOT EXISTS (
+        SELECT FROM pg_roles
+        WHERE rolname = p_role AND NOT rolcanlogin AND pg_has_role(session_user, oid, 'MEMBER WITH ADMIN OPTION')
+    ) THEN
+        RAISE EXCEPTION 'permission denied to manage role "%"', p_role USING ERRCODE = '42501';
+    END IF;
+END
+$$;
+
+CREATE OR REPLACE PROCEDURE impulse.create_role(p_role text)
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = pg_catalog AS $$
+BEGIN
+    PERFORM impulse.check_role_namespace(p_role);
+    EXECUTE format('CREATE ROLE %I NOLOGIN', p_role);
+    EXECUTE format('GRANT %I TO %I WITH ADMIN OPTION', p_role, session_user);
+END
+$$;
+
+CREATE OR REPLACE PROCEDURE impulse.drop_role(p_role text, p_missing_ok boolean DEFAULT false)
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = pg_catalog AS $$
+BEGIN
+    PERFORM impulse.check_role_namespace(p_role);
+    IF p_missing_ok AND NOT EXISTS (SELECT FROM pg_roles WHERE rolname = p_role) THEN
+        RAISE NOTICE 'role "%" does not exist, skipping', p_role;
+        RETURN;
+    END IF;
+    PERFORM impulse.check_role_owned(p_role);
+    EXECUTE format('DROP ROLE %I', p_role);
+END
+$$;
+
+CREATE OR REPLACE PROCEDURE impulse.rename_role(p_role text, p_new_name text)
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = pg_catalog AS $$
+BEGIN
+    PERFORM impulse.check_role_namespace(p_role);
+    PERFORM impulse.check_role_namespace(p_new_name);
+    PERFORM impulse.check_role_owned(p_role);
+    EXECUTE format('ALTER ROLE %I RENAME TO %I', p_role, p_new_name);
+END
+$$;
+
+REVOKE ALL ON PROCEDURE impulse.create_role(text), impulse.drop_role(text, boolean),
+    impulse.rename_role(text, text) FROM PUBLIC;
+GRANT EXECUTE ON PROCEDURE impulse.create_role(text), impulse.drop_role(text, boolean),
+    impulse.rename_role(text, text) TO PUBLIC;
+"#;
+
 pub struct PostgresManager {
     pub config: Rc<ManagementConfig>,
 }
@@ -30,6 +110,12 @@ impl<'a> Display for PostgresManager {
         write!(f, "{}", self.base_url())
     }
 }
+#[derive(QueryableByName, Debug)]
+pub struct PgRoleName {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub role_name: String,
+}
+
 impl PostgresManager {
     pub fn pg_connect(&self) -> Result<PgConnection> {
         Ok(self.pg_connect_db(&self.config.pg_user)?)
@@ -58,7 +144,16 @@ impl PostgresManager {
         )
     }
 
+    /// URI of the database `db_name`, which is percent-encoded, as users
+    /// name their own databases.
     fn create_uri(&self, db_name: &str) -> String {
+        let db_name: String = db_name
+            .bytes()
+            .map(|byte| match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b'.' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect();
         format!(
             "postgres://{}:{}@{}:{}/{}",
             self.config.pg_user,
@@ -73,6 +168,10 @@ impl PostgresManager {
 pub struct PgUserInfo {
     pub username: String,
     pub password: String,
+    /// SCRAM-SHA-256 verifier of the password, which the role's password is
+    /// set to, for the user's `pg_password_enc` so that prew can
+    /// authenticate them
+    pub password_verifier: String,
 }
 
 #[derive(QueryableByName, Debug)]
@@ -83,11 +182,64 @@ pub struct PgDatabaseSize {
     pub db_bytes: i64,
 }
 
+#[derive(QueryableByName, Debug)]
+pub struct PgPasswordVerifier {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub role_name: String,
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub verifier: String,
+}
+
+#[derive(QueryableByName, Debug)]
+pub struct PgDatabaseName {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    pub db_name: String,
+}
+
 impl PostgresManager {
     pub fn new(config: Rc<ManagementConfig>) -> PostgresManager {
         PostgresManager { config }
     }
 
+    /// Manager of `cluster` as the same management role, or of this
+    /// manager's own cluster if None.
+    pub fn for_cluster(&self, cluster: Option<&Cluster>) -> PostgresManager {
+        match cluster {
+            Some(cluster) => PostgresManager::new(Rc::new(self.config.for_cluster(cluster))),
+            None => PostgresManager::new(self.config.clone()),
+        }
+    }
+
+    /// Manager of the cluster `user` is placed on, which their role and
+    /// databases are created on, dropped from and synced on.
+    pub fn for_user(&self, impulse_conn: &mut PgConnection, user: &User) -> Result<PostgresManager> {
+        let cluster = match &user.cluster_name {
+            Some(cluster_name) => Some(Cluster::retrieve(impulse_conn, cluster_name)?),
+            None => None,
+        };
+        Ok(self.for_cluster(cluster.as_ref()))
+    }
+
+    /// Place `user` on the cluster `cluster_name` (the configured one if
+    /// None), which their role and databases are created on from then on.
+    /// Users whose role already exists on their cluster aren't moved, as
+    /// their role and databases would stay behind.
+    pub fn place_user(&self, impulse_conn: &mut PgConnection, user: &mut User, cluster_name: Option<&str>) -> Result<()> {
+        if user.cluster_name.as_deref() != cluster_name && self.for_user(impulse_conn, user)?.has_role(&user.pg_name)? {
+            return Err(anyhow!("User {} already has a role on their cluster", &user.pg_name));
+        }
+        user.set_cluster(impulse_conn, cluster_name)
+    }
+
+    /// Whether the role `username` exists on this manager's cluster.
+    pub fn has_role(&self, username: &str) -> Result<bool> {
+        let mut conn = self.pg_connect()?;
+        let roles = sql_query("SELECT quote_ident(rolname) AS role_name FROM pg_roles WHERE rolname = $1")
+            .bind::<Text, _>(username)
+            .load::<PgRoleName>(&mut conn)?;
+        Ok(!roles.is_empty())
+    }
+
     /// Initialize permissions on a newly created Postgres instance.
     ///
     /// Only needs to be run once to initialize a Postgres instance, but
@@ -110,12 +262,38 @@ impl PostgresManager {
         sql_query("REVOKE ALL ON pg_tablespace FROM public;").execute(&mut conn)?;
         sql_query("REVOKE ALL ON pg_settings FROM public;").execute(&mut conn)?;
 
+        // databases created by users from template1 get the procedures too,
+        // and existing databases get their current version
+        self.install_role_procedures("template1")?;
+        let databases = sql_query(
+            "SELECT datname AS db_name FROM pg_database WHERE datallowconn AND NOT datistemplate"
+        ).load::<PgDatabaseName>(&mut conn)?;
+        for database in databases {
+            if let Err(error) = self.install_role_procedures(&database.db_name) {
+                error!("Couldn't install role procedures in {}: {:?}", &database.db_name, error);
+            }
+        }
         Ok(())
     }
 
-    pub fn create_pg_user_and_database(&self, username: &str) -> Result<PgUserInfo> {
+    /// Install the procedures managing users' own roles in a database,
+    /// replacing any earlier versions.
+    pub fn install_role_procedures(&self, db_name: &str) -> Result<()> {
+        let mut conn = self.pg_connect_db(db_name)?;
+        conn.batch_execute(ROLE_PROCEDURES)?;
+        Ok(())
+    }
+
+    /// Create a role and its database, allowing the role at most
+    /// `connection_limit` concurrent connections (None for no limit).
+    pub fn create_pg_user_and_database(&self, username: &str, connection_limit: Option<i32>) -> Result<PgUserInfo> {
         // enforce strict naming conventions to prevent SQL injection
         Self::validate_identifier(username)?;
+        // `__` separates the names of users' roles from their own, so a
+        // username containing it could be in another user's namespace
+        if username.contains("__") {
+            return Err(anyhow!("Username can't contain \"__\": {}", username));
+        }
         let mut conn = self.pg_connect()?;
         let password_gen = passwords::PasswordGenerator::new()
             .length(16)
@@ -131,12 +309,14 @@ impl PostgresManager {
         {
             Err(err_msg) => Err(anyhow!("Couldn't generate password: {}", err_msg)),
             Ok(password) => {
+                let password_verifier = ScramVerifier::new(&password).to_string();
                 trace!("Creating PG user account: {}", username);
                 let row_count = sql_query(
                     format!(
-                        r#"CREATE ROLE "{}" WITH LOGIN CREATEDB NOSUPERUSER NOINHERIT NOCREATEROLE PASSWORD '{}'"#,
+                        r#"CREATE ROLE "{}" WITH LOGIN CREATEDB NOSUPERUSER NOINHERIT NOCREATEROLE CONNECTION LIMIT {} PASSWORD '{}'"#,
                         username,
-                        password,
+                        Self::connection_limit_value(connection_limit),
+                        password_verifier,
                     )
                 ).execute(&mut conn)?;
                 trace!("{} rows affected", row_count);
@@ -162,9 +342,12 @@ impl PostgresManager {
                     format!("GRANT ALL ON SCHEMA public TO {} WITH GRANT OPTION", username)
                 ).execute(&mut user_conn)?;
                 trace!("{} rows affected", row_count);
+                trace!("Installing role procedures on database '{}'", username);
+                user_conn.batch_execute(ROLE_PROCEDURES)?;
                 Ok(PgUserInfo {
                     username: username.to_string(),
-                    password
+                    password,
+                    password_verifier,
                 })
             }
         }
@@ -175,6 +358,22 @@ impl PostgresManager {
         let mut conn = self.pg_connect()?;
         trace!("Dropping user database '{}'", username);
         self.drop_database(username)?;
+        // the roles the user created are named like "role__username", and
+        // can't log in and are administered by the user. Users dropped
+        // before have none left, and pg_has_role() fails without their role.
+        if self.has_role(username)? {
+            let roles = sql_query(
+                "SELECT quote_ident(rolname) AS role_name FROM pg_roles \
+                 WHERE length(rolname) > length($1) + 2 AND right(rolname, length($1) + 2) = '__' || $1 \
+                 AND NOT rolcanlogin AND pg_has_role($1::name, oid, 'MEMBER WITH ADMIN OPTION')"
+            )
+                .bind::<Text, _>(username)
+                .load::<PgRoleName>(&mut conn)?;
+            for role in roles {
+                trace!("Dropping role {} of user '{}'", &role.role_name, username);
+                sql_query(format!("DROP ROLE IF EXISTS {}", &role.role_name)).execute(&mut conn)?;
+            }
+        }
         trace!("Dropping user '{}'", username);
         // let row_count = diesel::select(
         //     drop_pg_user(username)
@@ -209,17 +408,78 @@ impl PostgresManager {
         Ok(())
     }
 
+    /// Bytes stored in each user's databases, on the cluster the user is
+    /// placed on. This manager's cluster is the one of users placed on no
+    /// cluster.
     pub fn compute_storage(&self, impulse_conn: &mut PgConnection) -> Result<HashMap<Uuid, i64>> {
-        let mut conn = self.pg_connect()?;
+        let mut placements: HashMap<Option<String>, HashMap<String, Uuid>> = HashMap::new();
+        for user in User::all(impulse_conn)? {
+            placements.entry(user.cluster_name).or_default().insert(user.pg_name, user.user_id);
+        }
         let mut result: HashMap<Uuid, i64> = HashMap::new();
+        for (cluster_name, name2uuid) in placements {
+            let cluster = match cluster_name {
+                Some(cluster_name) => Some(Cluster::retrieve(impulse_conn, &cluster_name)?),
+                None => None,
+            };
+            self.for_cluster(cluster.as_ref()).add_storage(&name2uuid, &mut result)?;
+        }
+        Ok(result)
+    }
+
+    /// Copy the SCRAM-SHA-256 verifiers of users' passwords from the
+    /// clusters they're placed on to `users.pg_password_enc`, for prew to
+    /// authenticate them against, returning the number of users updated.
+    /// Picks up passwords users changed themselves, and those of roles
+    /// created before prew authenticated clients.
+    ///
+    /// Roles whose password isn't a SCRAM verifier (i.e. md5) are left
+    /// alone, and can't log in through prew until their password is set
+    /// again.
+    pub fn sync_password_verifiers(&self, impulse_conn: &mut PgConnection) -> Result<usize> {
+        let mut placements: HashMap<Option<String>, Vec<User>> = HashMap::new();
+        for user in User::all(impulse_conn)? {
+            placements.entry(user.cluster_name.clone()).or_default().push(user);
+        }
+        let mut count = 0;
+        for (cluster_name, users) in placements {
+            let cluster = match cluster_name {
+                Some(cluster_name) => Some(Cluster::retrieve(impulse_conn, &cluster_name)?),
+                None => None,
+            };
+            let verifiers = self.for_cluster(cluster.as_ref()).password_verifiers()?;
+            for mut user in users {
+                match verifiers.get(&user.pg_name) {
+                    Some(verifier) if user.pg_password_enc.as_deref() != Some(verifier.as_bytes()) => {
+                        user.set_pg_password_enc(impulse_conn, verifier)?;
+                        count += 1;
+                    }
+                    Some(_) => {}
+                    None => trace!("No SCRAM verifier for user {}", &user.pg_name),
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// SCRAM-SHA-256 verifiers of the passwords of the roles on this
+    /// manager's cluster that can log in, by role name.
+    fn password_verifiers(&self) -> Result<HashMap<String, String>> {
+        let mut conn = self.pg_connect()?;
+        let verifiers = sql_query(
+            "SELECT rolname AS role_name, rolpassword AS verifier FROM pg_authid \
+             WHERE rolcanlogin AND rolpassword LIKE 'SCRAM-SHA-256$%'"
+        ).load::<PgPasswordVerifier>(&mut conn)?;
+        Ok(verifiers.into_iter().map(|verifier| (verifier.role_name, verifier.verifier)).collect())
+    }
+
+    /// Add the sizes of the databases of the users in `name2uuid` on this
+    /// manager's cluster to their totals in `result`.
+    fn add_storage(&self, name2uuid: &HashMap<String, Uuid>, result: &mut HashMap<Uuid, i64>) -> Result<()> {
+        let mut conn = self.pg_connect()?;
         let db_sizes = sql_query(
             "SELECT datname as db_name, pg_database_size(datname) as db_bytes FROM pg_database"
         ).load::<PgDatabaseSize>(&mut conn)?;
-        let name2uuid = User::all(impulse_conn)?
-            .iter()
-            .map(|user| (user.pg_name.clone(), user.user_id))
-            .into_iter()
-            .collect::<HashMap<_,_>>();
         for db_size in db_sizes {
             if let Some(pg_name_index) = db_size.db_name.rfind(DB_SEPARATOR) {
                 let pg_name = &db_size.db_name[pg_name_index+1..];
@@ -228,7 +488,7 @@ impl PostgresManager {
                 }
             }
         }
-        Ok(result)
+        Ok(())
     }
 
     pub fn disable_pg_user(&self, pg_username: &str) -> Result<()> {
@@ -247,6 +507,60 @@ impl PostgresManager {
         Ok(())
     }
 
+    /// Limit the role to `connection_limit` concurrent connections, or
+    /// remove its limit if None. Existing connections are left open.
+    pub fn set_connection_limit(&self, pg_username: &str, connection_limit: Option<i32>) -> Result<()> {
+        Self::validate_identifier(pg_username)?;
+        let mut conn = self.pg_connect()?;
+        sql_query(format!(
+            r#"ALTER ROLE "{}" WITH CONNECTION LIMIT {}"#,
+            pg_username,
+            Self::connection_limit_value(connection_limit),
+        ))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Make the databases owned by the role default to read-only
+    /// transactions, or back to read-write if `read_only` is false.
+    ///
+    /// Only new sessions pick up the change, and the setting can be
+    /// overridden within a session, so it's backed by the proxy refusing
+    /// write statements of read-only users.
+    pub fn set_read_only(&self, pg_username: &str, read_only: bool) -> Result<()> {
+        Self::validate_identifier(pg_username)?;
+        let mut conn = self.pg_connect()?;
+        // the user names their own databases, so their names are quoted
+        // rather than validated
+        let databases = sql_query(
+            "SELECT quote_ident(datname) AS db_name FROM pg_database \
+             JOIN pg_roles ON pg_roles.oid = pg_database.datdba \
+             WHERE rolname = $1"
+        )
+            .bind::<Text, _>(pg_username)
+            .load::<PgDatabaseName>(&mut conn)?;
+        for database in databases {
+            trace!("Setting read-only={} on database {}", read_only, &database.db_name);
+            let query = match read_only {
+                true => format!(
+                    "ALTER DATABASE {} SET default_transaction_read_only = on",
+                    &database.db_name,
+                ),
+                false => format!(
+                    "ALTER DATABASE {} RESET default_transaction_read_only",
+                    &database.db_name,
+                ),
+            };
+            sql_query(query).execute(&mut conn)?;
+        }
+        Ok(())
+    }
+
+    /// Postgres represents the lack of a connection limit as -1.
+    fn connection_limit_value(connection_limit: Option<i32>) -> i32 {
+        connection_limit.unwrap_or(-1)
+    }
+
     fn validate_identifier(identifier: &str) -> Result<()> {
         // We always quote user-provided identifiers so almost any character
         // string is valid by Postgres standards, but enforce much stricter
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt, Double};
+use uuid::Uuid;
+
+
+/// Balances are stored as floating point, so recomputing a balance from
+/// history in a different order than it was accumulated can differ by a
+/// rounding error. Anything within this tolerance is not considered drift.
+pub const BALANCE_TOLERANCE: f64 = 1e-6;
+
+/// A user whose stored balance does not match the balance recomputed from
+/// their deposits and transactions.
+#[derive(QueryableByName, Debug, PartialEq)]
+pub struct BalanceDrift {
+    #[diesel(sql_type = diesel::sql_types::Uuid)]
+    pub user_id: Uuid,
+    #[diesel(sql_type = Double)]
+    pub balance: f64,
+    /// Sum of all external deposits (`exttransactions`)
+    #[diesel(sql_type = Double)]
+    pub deposits: f64,
+    /// Sum of all transactions paid by the user (`transactions.from_user`)
+    #[diesel(sql_type = Double)]
+    pub debits: f64,
+    /// Sum of all transactions paid to the user (`transactions.to_user`)
+    #[diesel(sql_type = Double)]
+    pub credits: f64,
+    #[diesel(sql_type = Double)]
+    pub expected_balance: f64,
+}
+impl BalanceDrift {
+    pub fn find_all(conn: &mut PgConnection) -> Result<Vec<BalanceDrift>> {
+        Ok(
+            sql_query(r#"
+                SELECT
+                    u.user_id,
+                    u.balance,
+                    COALESCE(d.total, 0) AS deposits,
+                    COALESCE(f.total, 0) AS debits,
+                    COALESCE(t.total, 0) AS credits,
+                    COALESCE(d.total, 0) - COALESCE(f.total, 0) + COALESCE(t.total, 0)
+                        AS expected_balance
+                FROM users u
+                LEFT JOIN (
+                    SELECT user_id, SUM(amount) AS total
+                    FROM exttransactions
+                    GROUP BY user_id
+                ) d ON d.user_id = u.user_id
+                LEFT JOIN (
+                    SELECT from_user AS user_id, SUM(amount) AS total
+                    FROM transactions
+                    GROUP BY from_user
+                ) f ON f.user_id = u.user_id
+                LEFT JOIN (
+                    SELECT to_user AS user_id, SUM(amount) AS total
+                    FROM transactions
+                    GROUP BY to_user
+                ) t ON t.user_id = u.user_id
+                WHERE abs(
+                    u.balance
+                    - (COALESCE(d.total, 0) - COALESCE(f.total, 0) + COALESCE(t.total, 0))
+                ) > $1
+                ORDER BY u.user_id
+            "#)
+                .bind::<Double, _>(BALANCE_TOLERANCE)
+                .load::<BalanceDrift>(conn)?
+        )
+    }
+
+    pub fn drift(&self) -> f64 {
+        self.balance - self.expected_balance
+    }
+}
+
+/// A charge marked as transacted that is not referenced by exactly one
+/// charge transaction. Refunds also reference charges, but are not counted.
+#[derive(QueryableByName, Debug, PartialEq)]
+pub struct UnmatchedCharge {
+    #[diesel(sql_type = BigInt)]
+    pub charge_id: i64,
+    /// Transactions referencing this charge (empty if none do)
+    #[diesel(sql_type = Array<BigInt>)]
+    pub txn_ids: Vec<i64>,
+}
+impl UnmatchedCharge {
+    pub fn find_all(conn: &mut PgConnection) -> Result<Vec<UnmatchedCharge>> {
+        Ok(
+            sql_query(r#"
+                WITH refs AS (
+                    SELECT unnest(charge_ids) AS charge_id, txn_id
+                    FROM transactions
+                    WHERE txn_type = 'Charge'
+                )
+                SELECT
+                    c.charge_id,
+                    array_remove(array_agg(refs.txn_id ORDER BY refs.txn_id), NULL)
+                        AS txn_ids
+                FROM charges c
+                LEFT JOIN refs ON refs.charge_id = c.charge_id
+                WHERE c.transacted
+                GROUP BY c.charge_id
+                HAVING count(refs.txn_id) <> 1
+                ORDER BY c.charge_id
+            "#)
+                .load::<UnmatchedCharge>(conn)?
+        )
+    }
+}
+
+/// A report marked as charged that is not referenced by exactly one charge.
+#[derive(QueryableByName, Debug, PartialEq)]
+pub struct UnmatchedReport {
+    #[diesel(sql_type = BigInt)]
+    pub report_id: i64,
+    /// Charges referencing this report (empty if none do)
+    #[diesel(sql_type = Array<BigInt>)]
+    pub charge_ids: Vec<i64>,
+}
+impl UnmatchedReport {
+    pub fn find_all(conn: &mut PgConnection) -> Result<Vec<UnmatchedReport>> {
+        Ok(
+            sql_query(r#"
+                WITH refs AS (
+                    SELECT unnest(report_ids) AS report_id, charge_id FROM charges
+                )
+                SELECT
+                    r.packet_id AS report_id,
+                    array_remove(array_agg(refs.charge_id ORDER BY refs.charge_id), NULL)
+                        AS charge_ids
+                FROM reports r
+                LEFT JOIN refs ON refs.report_id = r.packet_id
+                WHERE r.charged
+                GROUP BY r.packet_id
+                HAVING count(refs.charge_id) <> 1
+                ORDER BY r.packet_id
+            "#)
+                .load::<UnmatchedReport>(conn)?
+        )
+    }
+}
+
+/// Result of reconciling stored balances and billing flags against history.
+#[derive(Debug)]
+pub struct BalanceAudit {
+    pub drifts: Vec<BalanceDrift>,
+    pub unmatched_charges: Vec<UnmatchedCharge>,
+    pub unmatched_reports: Vec<UnmatchedReport>,
+}
+impl BalanceAudit {
+    pub fn run(conn: &mut PgConnection) -> Result<BalanceAudit> {
+        Ok(
+            BalanceAudit {
+                drifts: BalanceDrift::find_all(conn)?,
+                unmatched_charges: UnmatchedCharge::find_all(conn)?,
+                unmatched_reports: UnmatchedReport::find_all(conn)?,
+            }
+        )
+    }
+
+    pub fn issue_count(&self) -> usize {
+        self.drifts.len() + self.unmatched_charges.len() + self.unmatched_reports.len()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.issue_count() == 0
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Double;
+use uuid::Uuid;
+
+use crate::models::charges::ChargeType;
+use crate::models::plans::{PlanAllowance, UsagePeriod};
+use crate::models::transactions::DISABLE_BELOW_BALANCE;
+use crate::models::users::User;
+
+
+#[derive(QueryableByName, Debug)]
+struct UnchargedUsage {
+    #[diesel(sql_type = Double)]
+    bytes_in: f64,
+    #[diesel(sql_type = Double)]
+    bytes_out: f64,
+    #[diesel(sql_type = Double)]
+    connection_seconds: f64,
+    #[diesel(sql_type = Double)]
+    query_seconds: f64,
+}
+impl UnchargedUsage {
+    fn quantities(&self) -> [(ChargeType, f64); 4] {
+        [
+            (ChargeType::DataTransferInBytes, self.bytes_in),
+            (ChargeType::DataTransferOutBytes, self.bytes_out),
+            (ChargeType::ConnectionSeconds, self.connection_seconds),
+            (ChargeType::QueryExecutionSeconds, self.query_seconds),
+        ]
+    }
+}
+
+/// Estimate of what a user can still spend, between runs that post their
+/// charges to their balance.
+///
+/// Usage that hasn't been charged yet is priced at the base rates, after
+/// what's left of the plan's allowances for the current month, so the
+/// estimate errs on the low side.
+#[derive(Debug, PartialEq)]
+pub struct BalanceEstimate {
+    pub user_id: Uuid,
+    /// Balance as of the last posted transaction
+    pub balance: f64,
+    /// Charges not yet posted as transactions
+    pub untransacted: f64,
+    /// Estimated cost of reports not yet charged
+    pub uncharged: f64,
+}
+impl BalanceEstimate {
+    /// Estimate for the user with the Postgres role `pg_name_`, None for
+    /// roles that don't belong to a user.
+    pub fn for_user(conn: &mut PgConnection, pg_name_: &str) -> Result<Option<BalanceEstimate>> {
+        let user = {
+            use crate::schema::users::dsl::*;
+            users
+                .filter(pg_name.eq(pg_name_))
+                .first::<User>(conn)
+                .optional()?
+        };
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let untransacted = {
+            use crate::schema::charges::dsl::*;
+            charges
+                .filter(user_id.eq(&user.user_id))
+                .filter(transacted.eq(false))
+                .select(diesel::dsl::sum(amount))
+                .first::<Option<f64>>(conn)?
+                .unwrap_or(0.)
+        };
+        let uncharged = sql_query(r#"
+            SELECT
+                COALESCE(SUM(num_bytes) FILTER (WHERE direction = 'Forward'), 0)::float8 AS bytes_in,
+                COALESCE(SUM(num_bytes) FILTER (WHERE direction = 'Backward'), 0)::float8 AS bytes_out,
+                COALESCE(SUM(seconds) FILTER (WHERE packet_type = 'ConnectionTime'), 0) AS connection_seconds,
+                COALESCE(SUM(seconds) FILTER (WHERE packet_type = 'QueryTime'), 0) AS query_seconds
+            FROM reports_to_charge
+            WHERE user_id = $1
+        "#)
+            .bind::<diesel::sql_types::Uuid, _>(&user.user_id)
+            .get_result::<UnchargedUsage>(conn)?;
+        let period_start = UsagePeriod::period_start(&Utc::now());
+        let mut cost = 0.;
+        for (charge_type, quantity) in uncharged.quantities() {
+            let allowance = PlanAllowance::free_quantity_for_user(conn, &user.user_id, charge_type)?;
+            let used = UsagePeriod::usage(conn, &user.user_id, charge_type, &period_start)?;
+            let free = (allowance - used).max(0.);
+            cost += (quantity - free).max(0.) * charge_type.rate();
+        }
+        Ok(Some(BalanceEstimate {
+            user_id: user.user_id,
+            balance: user.balance,
+            untransacted,
+            uncharged: cost,
+        }))
+    }
+
+    /// What the user can spend before their balance falls below the one
+    /// they're disabled at once their charges are posted.
+    pub fn remaining(&self) -> f64 {
+        self.balance - self.untransacted - self.uncharged - DISABLE_BELOW_BALANCE
+    }
+}
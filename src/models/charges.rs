@@ -8,22 +8,27 @@ use diesel::pg::Pg;
 use enum_iterator::Sequence;
 use itertools::Itertools;
 use log::{trace};
+use serde::{Deserialize, Serialize};
 use uuid::{Uuid};
 
-use crate::models::reports::{PacketDirection, ReportToCharge};
+use crate::models::plans::{PlanAllowance, UsagePeriod};
+use crate::models::pricing::{blended_rate, price_range, PriceTier, TierCharge};
+use crate::models::reports::{PacketDirection, PostgresqlPacketType, ReportToCharge};
 use crate::schema;
 use crate::schema::charges;
 use crate::schema::timecharges;
 use crate::models::reports::Report;
 
 
-#[derive(diesel_derive_enum::DbEnum, Debug, PartialEq, Eq, Hash, Copy, Clone, Sequence)]
+#[derive(diesel_derive_enum::DbEnum, Debug, PartialEq, Eq, Hash, Copy, Clone, Sequence, Serialize, Deserialize)]
 #[ExistingTypePath = "crate::schema::sql_types::Chargetype"]
 #[DbValueStyle = "verbatim"]
 pub enum ChargeType {
     DataTransferInBytes,
     DataTransferOutBytes,
     DataStorageByteHours,
+    ConnectionSeconds,
+    QueryExecutionSeconds,
 }
 impl From<TimeChargeType> for ChargeType {
     fn from(timecharge_type: TimeChargeType) -> Self {
@@ -38,6 +43,8 @@ impl ChargeType {
             ChargeType::DataTransferInBytes => 0.0,
             ChargeType::DataTransferOutBytes => 1.5e-15,  // $.15/Gb
             ChargeType::DataStorageByteHours => 2.0534e-13,  // $.15/Gb*mo
+            ChargeType::ConnectionSeconds => 2.7778e-6,  // $.01/connection*hr
+            ChargeType::QueryExecutionSeconds => 2.7778e-5,  // $.10/hr
         }
     }
 }
@@ -70,11 +77,13 @@ struct Charge_ {
     pub charge_type: ChargeType,
     pub quantity: f64,
     pub rate: f64,
-    pub amount: f64,
     pub report_ids: Option<Vec<Option<i64>>>,
     pub transacted: bool,
+    pub free_quantity: f64,
+    pub amount: f64,
+    pub tiers: Option<serde_json::Value>,
 }
-#[derive(Debug, PartialEq)]
+#[derive(Serialize, Debug, PartialEq)]
 pub struct Charge {
     pub charge_id: i64,
     pub charge_time: DateTime<Utc>,
@@ -85,6 +94,12 @@ pub struct Charge {
     pub amount: f64,
     pub report_ids: Option<Vec<i64>>,
     pub transacted: bool,
+    /// Portion of `quantity` covered by the user's plan allowance and
+    /// excluded from `amount`
+    pub free_quantity: f64,
+    /// Breakdown of the charged quantity by price tier, if tiered pricing
+    /// applied; `rate` is then the blended rate across the tiers
+    pub tiers: Option<Vec<TierCharge>>,
 }
 #[derive(QueryableByName, Debug)]
 pub struct LastChargeTime {
@@ -96,23 +111,39 @@ pub struct LastChargeTime {
 impl Charge {
     pub fn untransacted(conn: &mut PgConnection) -> Result<Vec<Charge>> {
         use crate::schema::charges::dsl::*;
-        Ok(charges
+        charges
             .filter(transacted.eq(false))
             .load::<Charge_>(conn)?
             .into_iter()
-            .map(|charge| charge.into())
-            .collect::<Vec<_>>()
-        )
+            .map(Charge::try_from)
+            .collect()
     }
     
     pub fn retrieve(conn: &mut PgConnection, charge_id_: i64) -> Result<Charge> {
         use crate::schema::charges::dsl::*;
-        Ok(
-            charges
-                .find(&charge_id_)
-                .first::<Charge_>(conn)?
-                .into()
-        )
+        charges
+            .find(&charge_id_)
+            .first::<Charge_>(conn)?
+            .try_into()
+    }
+
+    /// Charges for the user with `start <= charge_time < end`, oldest first.
+    pub fn for_user_between(
+        conn: &mut PgConnection,
+        user_id_: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<Charge>> {
+        use crate::schema::charges::dsl::*;
+        charges
+            .filter(user_id.eq(user_id_))
+            .filter(charge_time.ge(start))
+            .filter(charge_time.lt(end))
+            .order((charge_time.asc(), charge_id.asc()))
+            .load::<Charge_>(conn)?
+            .into_iter()
+            .map(Charge::try_from)
+            .collect()
     }
 
     pub fn from_reports(conn: &mut PgConnection, reports: Vec<ReportToCharge>) -> Result<Vec<Charge>> {
@@ -124,9 +155,9 @@ impl Charge {
             Self::append_report(type2charge, &report);
         }
         user2type2charge
-            .values()
-            .flat_map(|hashmap| hashmap.values())
-            .map(|new_charge| new_charge.commit(conn))
+            .into_values()
+            .flat_map(|hashmap| hashmap.into_values())
+            .map(|mut new_charge| new_charge.commit_for_period(conn))
             .collect::<Result<Vec<Charge>>>()
 
     }
@@ -197,7 +228,7 @@ impl Charge {
                             prev_charge_time,
                             &tc.timecharge_time,
                             final_charge_time)?
-                        .commit(conn)?;
+                        .commit_for_period(conn)?;
                     created_charges.push(new_charge);
                 }
                 opt_prev_charge_time = Some(&tc.timecharge_time);
@@ -215,7 +246,7 @@ impl Charge {
                                 &final_charge_time.unwrap_or(Utc::now()),
                                 final_charge_time,
                             )?
-                            .commit(conn)?
+                            .commit_for_period(conn)?
                 );
             }
         }
@@ -223,14 +254,20 @@ impl Charge {
     }
 
     fn append_report(existing_charges: &mut HashMap<ChargeType, NewCharge>, new_report: &ReportToCharge) {
-        if new_report.num_bytes == None {
-            return;
-        }
+        let quantity = match new_report.packet_type {
+            PostgresqlPacketType::ConnectionTime | PostgresqlPacketType::QueryTime =>
+                new_report.seconds,
+            _ => new_report.num_bytes.map(|num_bytes| num_bytes as f64),
+        };
+        let quantity = match quantity {
+            Some(quantity) => quantity,
+            None => return,
+        };
         if let Some(charge_type) = Self::report_charge_type(new_report) {
             let existing = existing_charges.get_mut(&charge_type);
             match existing {
                 Some(charge) => {
-                    charge.quantity += new_report.num_bytes.unwrap() as f64;
+                    charge.quantity += quantity;
                     charge.report_ids.as_mut().unwrap().push(new_report.report_id);
                 },
                 None => {
@@ -243,7 +280,7 @@ impl Charge {
                     let charge = NewCharge::new(
                         new_report.user_id.unwrap_or_else(|| Uuid::nil()),
                         charge_type,
-                        new_report.num_bytes.unwrap() as f64,
+                        quantity,
                         charge_type.rate(),
                         Some(vec![new_report.report_id]),
                         None,
@@ -255,6 +292,11 @@ impl Charge {
     }
 
     fn report_charge_type(report: &ReportToCharge) -> Option<ChargeType> {
+        match report.packet_type {
+            PostgresqlPacketType::ConnectionTime => return Some(ChargeType::ConnectionSeconds),
+            PostgresqlPacketType::QueryTime => return Some(ChargeType::QueryExecutionSeconds),
+            _ => {}
+        }
         match report.direction {
             None => None,
             Some(PacketDirection::Forward) => Some(ChargeType::DataTransferInBytes),
@@ -262,8 +304,10 @@ impl Charge {
         }
     }
 }
-impl From<Charge_> for Charge {
-    fn from(charge_: Charge_) -> Self {
+impl TryFrom<Charge_> for Charge {
+    type Error = anyhow::Error;
+
+    fn try_from(charge_: Charge_) -> Result<Self> {
         let report_ids = match charge_.report_ids {
             Some(reports) => Some(
                 reports
@@ -273,7 +317,7 @@ impl From<Charge_> for Charge {
             ),
             None => None
         };
-        Charge {
+        Ok(Charge {
             charge_id: charge_.charge_id,
             charge_time: charge_.charge_time,
             user_id: charge_.user_id,
@@ -283,7 +327,9 @@ impl From<Charge_> for Charge {
             amount: charge_.amount,
             report_ids,
             transacted: charge_.transacted,
-        }
+            free_quantity: charge_.free_quantity,
+            tiers: charge_.tiers.map(serde_json::from_value).transpose()?,
+        })
     }
 }
 
@@ -296,6 +342,8 @@ pub struct NewCharge {
     pub rate: f64,
     pub report_ids: Option<Vec<i64>>,
     pub charge_time: Option<DateTime<Utc>>,
+    pub free_quantity: f64,
+    pub tiers: Option<serde_json::Value>,
 }
 
 impl NewCharge {
@@ -314,14 +362,64 @@ impl NewCharge {
             rate,
             report_ids,
             charge_time,
+            free_quantity: 0.,
+            tiers: None,
         }
     }
 
+    /// Commit the charge after applying whatever is left of the user's plan
+    /// allowance for the calendar month of the charge, and pricing the rest
+    /// by the charge type's price tiers (if any) according to the user's
+    /// cumulative usage in that month.
+    ///
+    /// The usage is recorded in the same transaction that creates the
+    /// charge, so neither the allowance nor a tier can be applied twice.
+    pub fn commit_for_period(&mut self, conn: &mut PgConnection) -> Result<Charge> {
+        let charge_time = *self.charge_time.get_or_insert_with(Utc::now);
+        conn.transaction(|conn| {
+            let period_start = UsagePeriod::period_start(&charge_time);
+            let allowance = PlanAllowance::free_quantity_for_user(
+                conn,
+                &self.user_id,
+                self.charge_type,
+            )?;
+            let used = UsagePeriod::add_usage(
+                conn,
+                &self.user_id,
+                self.charge_type,
+                &period_start,
+                self.quantity,
+            )?;
+            self.free_quantity = (allowance - used).max(0.).min(self.quantity);
+            trace!(
+                "Applying {} free of {} {:?} (allowance {}, used {})",
+                self.free_quantity,
+                self.quantity,
+                self.charge_type,
+                allowance,
+                used,
+            );
+            let tiers = PriceTier::for_charge_type(conn, self.charge_type)?;
+            if !tiers.is_empty() {
+                let breakdown = price_range(
+                    &tiers,
+                    self.rate,
+                    used + self.free_quantity,
+                    used + self.quantity,
+                );
+                self.rate = blended_rate(&breakdown, self.rate);
+                trace!("Priced {:?} by tier: {:?}", self.charge_type, &breakdown);
+                self.tiers = Some(serde_json::to_value(breakdown)?);
+            }
+            self.commit(conn)
+        })
+    }
+
     pub fn commit(&self, conn: &mut PgConnection) -> Result<Charge> {
         let query = diesel::insert_into(charges::table)
             .values(self);
         trace!("Creating charge: {}", debug_query::<Pg, _>(&query));
-        let result: Charge = query.get_result::<Charge_>(conn)?.into();
+        let result: Charge = query.get_result::<Charge_>(conn)?.try_into()?;
         if let Some(reports) = &result.report_ids {
             reports
                 .iter()
@@ -0,0 +1,65 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+
+use crate::schema::clusters;
+
+
+/// A managed Postgres cluster that users can be placed on. Users placed on
+/// no cluster are on the one the managed database config points to.
+#[derive(Queryable, Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub cluster_name: String,
+    pub pg_host: String,
+    pub pg_port: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+impl Cluster {
+    pub fn retrieve(conn: &mut PgConnection, cluster_name_: &str) -> Result<Cluster> {
+        use crate::schema::clusters::dsl::*;
+        Ok(clusters.find(cluster_name_).first::<Cluster>(conn)?)
+    }
+
+    pub fn all(conn: &mut PgConnection) -> Result<Vec<Cluster>> {
+        use crate::schema::clusters::dsl::*;
+        Ok(clusters.order(cluster_name).load::<Cluster>(conn)?)
+    }
+
+    /// Cluster of the user with the Postgres role `pg_name_`, None for
+    /// users on the configured cluster and roles that don't belong to a
+    /// user.
+    pub fn for_user(conn: &mut PgConnection, pg_name_: &str) -> Result<Option<Cluster>> {
+        use crate::schema::users;
+        Ok(
+            clusters::table
+                .inner_join(users::table.on(users::cluster_name.eq(clusters::cluster_name.nullable())))
+                .filter(users::pg_name.eq(pg_name_))
+                .select(clusters::all_columns)
+                .first::<Cluster>(conn)
+                .optional()?
+        )
+    }
+
+    /// Address of the cluster's server, as `host:port`.
+    pub fn server_addr(&self) -> String {
+        format!("{}:{}", self.pg_host, self.pg_port)
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = clusters)]
+pub struct NewCluster {
+    pub cluster_name: String,
+    pub pg_host: String,
+    pub pg_port: i32,
+}
+impl NewCluster {
+    pub fn create(conn: &mut PgConnection, cluster_name: String, pg_host: String, pg_port: i32) -> Result<Cluster> {
+        Ok(
+            diesel::insert_into(clusters::table)
+                .values(&NewCluster { cluster_name, pg_host, pg_port })
+                .get_result::<Cluster>(conn)?
+        )
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Text, Timestamptz};
+use diesel::upsert::excluded;
+use log::{debug, trace};
+use serde::Serialize;
+
+use crate::models::reports::PostgresqlPacketType;
+use crate::schema::fingerprint_usage;
+
+/// Number of reports aggregated per transaction.
+const AGGREGATE_BATCH_SIZE: i64 = 10_000;
+
+
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = fingerprint_usage)]
+pub struct FingerprintUsage {
+    pub username: String,
+    /// Start of the hour the queries were sent in
+    pub period_start: DateTime<Utc>,
+    pub fingerprint: String,
+    pub query: String,
+    pub calls: i64,
+    pub bytes: i64,
+}
+impl FingerprintUsage {
+    pub fn for_user(conn: &mut PgConnection, username_: &str) -> Result<Vec<FingerprintUsage>> {
+        use crate::schema::fingerprint_usage::dsl::*;
+        Ok(
+            fingerprint_usage
+                .filter(username.eq(username_))
+                .order((period_start.asc(), fingerprint.asc()))
+                .load::<FingerprintUsage>(conn)?
+        )
+    }
+
+    /// Count Query reports that haven't been aggregated yet into the hourly
+    /// usage of their user and query fingerprint, returning the number of
+    /// reports processed. Each statement of a Query counts as a call of its
+    /// own, with the message's bytes split between them. Reports of
+    /// unauthenticated connections and queries that can't be parsed are
+    /// marked as processed without being counted.
+    pub fn aggregate_reports(conn: &mut PgConnection) -> Result<usize> {
+        let mut count = 0;
+        loop {
+            let batch = conn.transaction(aggregate_batch)?;
+            if batch == 0 {
+                return Ok(count);
+            }
+            count += batch;
+        }
+    }
+}
+
+fn aggregate_batch(conn: &mut PgConnection) -> Result<usize> {
+    use crate::schema::reports::dsl::*;
+    let pending = reports
+        .filter(packet_type.eq(PostgresqlPacketType::Query.to_string()))
+        .filter(fingerprinted.eq(false))
+        .order(packet_id.asc())
+        .limit(AGGREGATE_BATCH_SIZE)
+        .select((packet_id, username, packet_time, packet_info, packet_bytes))
+        .for_update()
+        .skip_locked()
+        .load::<(i64, Option<String>, DateTime<Utc>, Option<serde_json::Value>, Option<Vec<u8>>)>(conn)?;
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    let mut usage: HashMap<(String, DateTime<Utc>, String), FingerprintUsage> = HashMap::new();
+    let mut report_ids = Vec::with_capacity(pending.len());
+    for (report_id, report_user, report_time, info, report_bytes) in pending {
+        report_ids.push(report_id);
+        let report_user = match report_user {
+            Some(report_user) => report_user,
+            None => continue,
+        };
+        let query = match info
+            .as_ref()
+            .and_then(|info| info.pointer("/Query/query"))
+            .and_then(|query| query.as_str())
+        {
+            Some(query) => query,
+            None => continue,
+        };
+        let statements = match pg_query::split_with_parser(query) {
+            Ok(statements) => statements,
+            Err(error) => {
+                debug!("Not aggregating report {}: {:?}", report_id, &error);
+                continue;
+            }
+        };
+        let hour = report_time.duration_trunc(Duration::hours(1))?;
+        let message_bytes = report_bytes.map_or(0, |report_bytes| report_bytes.len() as i64);
+        let statement_bytes = share_bytes(message_bytes, &statements);
+        for (statement, statement_bytes) in statements.into_iter().zip(statement_bytes) {
+            let statement_fingerprint = match pg_query::fingerprint(statement) {
+                Ok(statement_fingerprint) => statement_fingerprint.hex,
+                Err(error) => {
+                    debug!("Not aggregating statement of report {}: {:?}", report_id, &error);
+                    continue;
+                }
+            };
+            let entry = usage
+                .entry((report_user.clone(), hour, statement_fingerprint.clone()))
+                .or_insert_with(|| FingerprintUsage {
+                    username: report_user.clone(),
+                    period_start: hour,
+                    fingerprint: statement_fingerprint,
+                    query: pg_query::normalize(statement).unwrap_or_else(|_| statement.to_string()),
+                    calls: 0,
+                    bytes: 0,
+                });
+            entry.calls += 1;
+            entry.bytes += statement_bytes;
+        }
+    }
+
+    let rows: Vec<FingerprintUsage> = usage.into_values().collect();
+    for chunk in rows.chunks(1000) {
+        use crate::schema::fingerprint_usage::dsl as fu;
+        diesel::insert_into(fu::fingerprint_usage)
+            .values(chunk)
+            .on_conflict((fu::username, fu::period_start, fu::fingerprint))
+            .do_update()
+            .set((
+                fu::calls.eq(fu::calls + excluded(fu::calls)),
+                fu::bytes.eq(fu::bytes + excluded(fu::bytes)),
+            ))
+            .execute(conn)?;
+    }
+    diesel::update(reports.filter(packet_id.eq_any(&report_ids)))
+        .set(fingerprinted.eq(true))
+        .execute(conn)?;
+    trace!("Aggregated {} reports into {} fingerprint usage rows", report_ids.len(), rows.len());
+    Ok(report_ids.len())
+}
+
+/// Split the `bytes` of a Query message between its `statements` by their
+/// length, so that they add up to the message's.
+fn share_bytes(bytes: i64, statements: &[&str]) -> Vec<i64> {
+    let total_length: usize = statements.iter().map(|statement| statement.len()).sum();
+    let mut unshared = bytes;
+    let mut shares = Vec::with_capacity(statements.len());
+    for (i, statement) in statements.iter().enumerate() {
+        let share = if i + 1 == statements.len() {
+            unshared
+        } else {
+            bytes * statement.len() as i64 / total_length.max(1) as i64
+        };
+        unshared -= share;
+        shares.push(share);
+    }
+    shares
+}
+
+/// How to rank fingerprints in `FingerprintTotal::top_for_user`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FingerprintRanking {
+    Calls,
+    Bytes,
+}
+
+/// Usage of a query fingerprint summed over a window of time.
+#[derive(QueryableByName, Serialize, Debug, PartialEq, Clone)]
+pub struct FingerprintTotal {
+    #[diesel(sql_type = Text)]
+    pub fingerprint: String,
+    #[diesel(sql_type = Text)]
+    pub query: String,
+    #[diesel(sql_type = BigInt)]
+    pub calls: i64,
+    #[diesel(sql_type = BigInt)]
+    pub bytes: i64,
+}
+impl FingerprintTotal {
+    /// The user's `limit` most used query fingerprints over the hours
+    /// starting within `[start, end)`.
+    pub fn top_for_user(
+        conn: &mut PgConnection,
+        username: &str,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        ranking: FingerprintRanking,
+        limit: i64,
+    ) -> Result<Vec<FingerprintTotal>> {
+        let order = match ranking {
+            FingerprintRanking::Calls => "calls DESC, bytes DESC",
+            FingerprintRanking::Bytes => "bytes DESC, calls DESC",
+        };
+        Ok(
+            sql_query(format!(r#"
+                SELECT
+                    fingerprint,
+                    (array_agg(query ORDER BY period_start))[1] AS query,
+                    sum(calls)::bigint AS calls,
+                    sum(bytes)::bigint AS bytes
+                FROM fingerprint_usage
+                WHERE username = $1 AND period_start >= $2 AND period_start < $3
+                GROUP BY fingerprint
+                ORDER BY {}, fingerprint
+                LIMIT $4
+            "#, order))
+                .bind::<Text, _>(username)
+                .bind::<Timestamptz, _>(start)
+                .bind::<Timestamptz, _>(end)
+                .bind::<BigInt, _>(limit)
+                .load::<FingerprintTotal>(conn)?
+        )
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Months, Utc};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Double, Timestamptz};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::charges::ChargeType;
+use crate::models::plans::UsagePeriod;
+use crate::models::users::User;
+use crate::schema::invoices;
+
+
+/// Charges of a single type incurred during an invoice's billing period.
+#[derive(QueryableByName, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct InvoiceLine {
+    #[diesel(sql_type = crate::schema::sql_types::Chargetype)]
+    pub charge_type: ChargeType,
+    #[diesel(sql_type = BigInt)]
+    pub charge_count: i64,
+    #[diesel(sql_type = Double)]
+    pub quantity: f64,
+    #[diesel(sql_type = Double)]
+    pub free_quantity: f64,
+    #[diesel(sql_type = Double)]
+    pub amount: f64,
+}
+impl InvoiceLine {
+    fn for_period(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        period_start: &DateTime<Utc>,
+        period_end: &DateTime<Utc>,
+    ) -> Result<Vec<InvoiceLine>> {
+        Ok(
+            sql_query(r#"
+                SELECT
+                    charge_type,
+                    count(*) AS charge_count,
+                    sum(quantity) AS quantity,
+                    sum(free_quantity) AS free_quantity,
+                    sum(amount) AS amount
+                FROM charges
+                WHERE user_id = $1 AND charge_time >= $2 AND charge_time < $3
+                GROUP BY charge_type
+                ORDER BY charge_type
+            "#)
+                .bind::<diesel::sql_types::Uuid, _>(user_id)
+                .bind::<Timestamptz, _>(period_start)
+                .bind::<Timestamptz, _>(period_end)
+                .load::<InvoiceLine>(conn)?
+        )
+    }
+}
+
+#[derive(QueryableByName, Debug)]
+struct PeriodTotals {
+    #[diesel(sql_type = Double)]
+    opening_balance: f64,
+    #[diesel(sql_type = Double)]
+    deposits: f64,
+    #[diesel(sql_type = Double)]
+    payments: f64,
+    #[diesel(sql_type = Double)]
+    adjustments: f64,
+}
+impl PeriodTotals {
+    /// Balance movements of the user during the period, recomputed from
+    /// deposits and transactions in the same way as the balance audit.
+    fn for_period(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        period_start: &DateTime<Utc>,
+        period_end: &DateTime<Utc>,
+    ) -> Result<PeriodTotals> {
+        Ok(
+            sql_query(r#"
+                SELECT
+                    COALESCE((
+                        SELECT sum(amount) FROM exttransactions
+                        WHERE user_id = $1 AND exttransaction_time < $2
+                    ), 0)
+                    - COALESCE((
+                        SELECT sum(amount) FROM transactions
+                        WHERE from_user = $1 AND txn_time < $2
+                    ), 0)
+                    + COALESCE((
+                        SELECT sum(amount) FROM transactions
+                        WHERE to_user = $1 AND txn_time < $2
+                    ), 0) AS opening_balance,
+                    COALESCE((
+                        SELECT sum(amount) FROM exttransactions
+                        WHERE user_id = $1
+                            AND exttransaction_time >= $2 AND exttransaction_time < $3
+                    ), 0) AS deposits,
+                    COALESCE((
+                        SELECT sum(amount) FROM transactions
+                        WHERE from_user = $1 AND txn_time >= $2 AND txn_time < $3
+                    ), 0) AS payments,
+                    COALESCE((
+                        SELECT sum(amount) FROM transactions
+                        WHERE to_user = $1 AND txn_time >= $2 AND txn_time < $3
+                    ), 0) AS adjustments
+            "#)
+                .bind::<diesel::sql_types::Uuid, _>(user_id)
+                .bind::<Timestamptz, _>(period_start)
+                .bind::<Timestamptz, _>(period_end)
+                .get_result::<PeriodTotals>(conn)?
+        )
+    }
+}
+
+// diesel translates jsonb as serde_json::Value, so `charge_lines` is
+// deserialized when converting `Invoice_` into `Invoice`.
+#[derive(Queryable, Debug)]
+struct Invoice_ {
+    pub invoice_id: i64,
+    pub user_id: Uuid,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub opening_balance: f64,
+    pub deposits: f64,
+    pub payments: f64,
+    pub adjustments: f64,
+    pub closing_balance: f64,
+    pub charges_total: f64,
+    pub charge_lines: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Invoice {
+    pub invoice_id: i64,
+    pub user_id: Uuid,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub opening_balance: f64,
+    /// External deposits made during the period
+    pub deposits: f64,
+    /// Transactions paid by the user during the period
+    pub payments: f64,
+    /// Credits, refunds and goodwill paid to the user during the period
+    pub adjustments: f64,
+    pub closing_balance: f64,
+    /// Total amount of the charges incurred during the period. Charges are
+    /// paid by transactions, which may fall in a later period.
+    pub charges_total: f64,
+    pub charge_lines: Vec<InvoiceLine>,
+    pub created_at: DateTime<Utc>,
+}
+impl TryFrom<Invoice_> for Invoice {
+    type Error = anyhow::Error;
+
+    fn try_from(invoice_: Invoice_) -> Result<Self> {
+        Ok(Invoice {
+            invoice_id: invoice_.invoice_id,
+            user_id: invoice_.user_id,
+            period_start: invoice_.period_start,
+            period_end: invoice_.period_end,
+            opening_balance: invoice_.opening_balance,
+            deposits: invoice_.deposits,
+            payments: invoice_.payments,
+            adjustments: invoice_.adjustments,
+            closing_balance: invoice_.closing_balance,
+            charges_total: invoice_.charges_total,
+            charge_lines: serde_json::from_value(invoice_.charge_lines)?,
+            created_at: invoice_.created_at,
+        })
+    }
+}
+impl Invoice {
+    pub fn retrieve(conn: &mut PgConnection, invoice_id_: i64) -> Result<Invoice> {
+        use crate::schema::invoices::dsl::*;
+        invoices
+            .find(invoice_id_)
+            .first::<Invoice_>(conn)?
+            .try_into()
+    }
+
+    pub fn for_user(conn: &mut PgConnection, user_id_: &Uuid) -> Result<Vec<Invoice>> {
+        use crate::schema::invoices::dsl::*;
+        invoices
+            .filter(user_id.eq(user_id_))
+            .order(period_start.asc())
+            .load::<Invoice_>(conn)?
+            .into_iter()
+            .map(Invoice::try_from)
+            .collect()
+    }
+
+    /// Start of the user's next billing period to be invoiced: the end of
+    /// their latest invoice, or the start of the month they were created in.
+    pub fn next_period_start(conn: &mut PgConnection, user: &User) -> Result<DateTime<Utc>> {
+        use crate::schema::invoices::dsl::*;
+        let last_end = invoices
+            .filter(user_id.eq(&user.user_id))
+            .select(diesel::dsl::max(period_end))
+            .first::<Option<DateTime<Utc>>>(conn)?;
+        Ok(last_end.unwrap_or_else(|| UsagePeriod::period_start(&user.created_at)))
+    }
+
+    /// Create invoices for all of the user's billing periods that ended at or
+    /// before `until`, returning the new invoices in order.
+    ///
+    /// Charges and transactions for a period should be generated before it
+    /// is closed; anything recorded afterwards with a time inside a closed
+    /// period is not reflected in its invoice. If closing any period fails,
+    /// none are closed.
+    pub fn close_periods(
+        conn: &mut PgConnection,
+        user: &User,
+        until: &DateTime<Utc>,
+    ) -> Result<Vec<Invoice>> {
+        let months = Months::new(user.billing_period_months as u32);
+        conn.transaction(|conn| {
+            let mut created = vec![];
+            let mut period_start = Self::next_period_start(conn, user)?;
+            loop {
+                let period_end = period_start
+                    .checked_add_months(months)
+                    .ok_or_else(|| anyhow!("Billing period starting {} is out of range", &period_start))?;
+                if &period_end > until {
+                    break;
+                }
+                let invoice = NewInvoice::for_period(conn, &user.user_id, period_start, period_end)?
+                    .commit(conn)?;
+                debug!("Closed invoice {} for user {}", invoice.invoice_id, &invoice.user_id);
+                created.push(invoice);
+                period_start = period_end;
+            }
+            Ok(created)
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut doc = String::new();
+        // writing to a String can't fail
+        writeln!(doc, "Invoice {}", self.invoice_id).unwrap();
+        writeln!(doc, "User: {}", &self.user_id).unwrap();
+        writeln!(doc, "Period: {} to {}", &self.period_start, &self.period_end).unwrap();
+        writeln!(doc).unwrap();
+        writeln!(
+            doc,
+            "{:<24} {:>8} {:>20} {:>20} {:>14}",
+            "Charge type", "Charges", "Quantity", "Free quantity", "Amount",
+        ).unwrap();
+        for line in &self.charge_lines {
+            writeln!(
+                doc,
+                "{:<24} {:>8} {:>20} {:>20} {:>14.6}",
+                format!("{:?}", line.charge_type),
+                line.charge_count,
+                line.quantity,
+                line.free_quantity,
+                line.amount,
+            ).unwrap();
+        }
+        writeln!(doc, "{:<24} {:>65.6}", "Total charges", self.charges_total).unwrap();
+        writeln!(doc).unwrap();
+        for (label, value) in self.balance_summary() {
+            writeln!(doc, "{:<24} {:>14.6}", label, value).unwrap();
+        }
+        doc
+    }
+
+    pub fn render_html(&self) -> String {
+        let mut doc = String::new();
+        writeln!(doc, "<!DOCTYPE html>").unwrap();
+        writeln!(doc, "<html><head><title>Invoice {}</title></head><body>", self.invoice_id).unwrap();
+        writeln!(doc, "<h1>Invoice {}</h1>", self.invoice_id).unwrap();
+        writeln!(doc, "<p>User: {}<br>", &self.user_id).unwrap();
+        writeln!(doc, "Period: {} to {}</p>", &self.period_start, &self.period_end).unwrap();
+        writeln!(doc, "<table>").unwrap();
+        writeln!(
+            doc,
+            "<tr><th>Charge type</th><th>Charges</th><th>Quantity</th><th>Free quantity</th><th>Amount</th></tr>",
+        ).unwrap();
+        for line in &self.charge_lines {
+            writeln!(
+                doc,
+                "<tr><td>{:?}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.6}</td></tr>",
+                line.charge_type,
+                line.charge_count,
+                line.quantity,
+                line.free_quantity,
+                line.amount,
+            ).unwrap();
+        }
+        writeln!(
+            doc,
+            "<tr><th colspan=\"4\">Total charges</th><td>{:.6}</td></tr>",
+            self.charges_total,
+        ).unwrap();
+        writeln!(doc, "</table>").unwrap();
+        writeln!(doc, "<table>").unwrap();
+        for (label, value) in self.balance_summary() {
+            writeln!(doc, "<tr><th>{}</th><td>{:.6}</td></tr>", label, value).unwrap();
+        }
+        writeln!(doc, "</table>").unwrap();
+        writeln!(doc, "</body></html>").unwrap();
+        doc
+    }
+
+    fn balance_summary(&self) -> [(&'static str, f64); 5] {
+        [
+            ("Opening balance", self.opening_balance),
+            ("Deposits", self.deposits),
+            ("Payments", -self.payments),
+            ("Adjustments", self.adjustments),
+            ("Closing balance", self.closing_balance),
+        ]
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = invoices)]
+pub struct NewInvoice {
+    pub user_id: Uuid,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub opening_balance: f64,
+    pub deposits: f64,
+    pub payments: f64,
+    pub adjustments: f64,
+    pub closing_balance: f64,
+    pub charges_total: f64,
+    pub charge_lines: serde_json::Value,
+}
+impl NewInvoice {
+    /// Summarize the user's charges and balance movements during the period.
+    pub fn for_period(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        period_start: DateTime<Utc>,
+        period_end: DateTime<Utc>,
+    ) -> Result<NewInvoice> {
+        let lines = InvoiceLine::for_period(conn, user_id, &period_start, &period_end)?;
+        let totals = PeriodTotals::for_period(conn, user_id, &period_start, &period_end)?;
+        Ok(
+            NewInvoice {
+                user_id: *user_id,
+                period_start,
+                period_end,
+                opening_balance: totals.opening_balance,
+                deposits: totals.deposits,
+                payments: totals.payments,
+                adjustments: totals.adjustments,
+                closing_balance: totals.opening_balance
+                    + totals.deposits
+                    - totals.payments
+                    + totals.adjustments,
+                charges_total: lines.iter().map(|line| line.amount).sum(),
+                charge_lines: serde_json::to_value(lines)?,
+            }
+        )
+    }
+
+    pub fn commit(&self, conn: &mut PgConnection) -> Result<Invoice> {
+        diesel::insert_into(invoices::table)
+            .values(self)
+            .get_result::<Invoice_>(conn)?
+            .try_into()
+    }
+}
@@ -1,4 +1,16 @@
 pub mod reports;
+pub mod sessions;
 pub mod charges;
 pub mod transactions;
-pub mod users;
\ No newline at end of file
+pub mod users;
+pub mod plans;
+pub mod clusters;
+pub mod pricing;
+pub mod invoices;
+pub mod query_stats;
+pub mod fingerprint_usage;
+pub mod report_partitions;
+pub mod report_archive;
+pub mod statements;
+pub mod audit;
+pub mod balances;
@@ -0,0 +1,230 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Double, Timestamptz};
+use uuid::Uuid;
+
+use crate::models::charges::ChargeType;
+use crate::schema::{plan_allowances, plans};
+
+
+/// Plan assigned to users that haven't been given another one.
+pub const DEFAULT_PLAN: &str = "default";
+
+#[derive(Queryable, Debug, PartialEq)]
+pub struct Plan {
+    pub plan_name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Maximum number of concurrent connections of each user on the plan,
+    /// None for no limit
+    pub max_connections: Option<i32>,
+    /// Bytes per second each user on the plan may send through the proxy,
+    /// None for no limit
+    pub max_bytes_in_per_second: Option<i64>,
+    /// Bytes per second each user on the plan may receive through the
+    /// proxy, None for no limit
+    pub max_bytes_out_per_second: Option<i64>,
+}
+impl Plan {
+    pub fn retrieve(conn: &mut PgConnection, plan_name_: &str) -> Result<Plan> {
+        use crate::schema::plans::dsl::*;
+        Ok(plans.find(plan_name_).first::<Plan>(conn)?)
+    }
+
+    pub fn all(conn: &mut PgConnection) -> Result<Vec<Plan>> {
+        use crate::schema::plans::dsl::*;
+        Ok(plans.load::<Plan>(conn)?)
+    }
+
+    /// Change the plan's connection limit, marking the users on the plan
+    /// unsynced so that the limit is applied to their roles.
+    pub fn set_max_connections(&mut self, conn: &mut PgConnection, max_connections_: Option<i32>) -> Result<()> {
+        use crate::schema::plans::dsl::*;
+        use crate::schema::users;
+        let result = conn.transaction(|conn| {
+            diesel::update(users::table.filter(users::plan_name.eq(&self.plan_name)))
+                .set(users::status_synced.eq(false))
+                .execute(conn)?;
+            diesel::update(plans.find(&self.plan_name))
+                .set(max_connections.eq(max_connections_))
+                .get_result::<Plan>(conn)
+        })?;
+        *self = result;
+        Ok(())
+    }
+
+    /// Change the bandwidth each user on the plan gets through the proxy.
+    /// Takes effect on the users' next connection.
+    pub fn set_bandwidth(
+        &mut self,
+        conn: &mut PgConnection,
+        max_bytes_in_per_second_: Option<i64>,
+        max_bytes_out_per_second_: Option<i64>,
+    ) -> Result<()> {
+        use crate::schema::plans::dsl::*;
+        let result = diesel::update(plans.find(&self.plan_name))
+            .set((
+                max_bytes_in_per_second.eq(max_bytes_in_per_second_),
+                max_bytes_out_per_second.eq(max_bytes_out_per_second_),
+            ))
+            .get_result::<Plan>(conn)?;
+        *self = result;
+        Ok(())
+    }
+
+    /// Plan of the user with the Postgres role `pg_name_`, None for roles
+    /// that don't belong to a user.
+    pub fn for_user(conn: &mut PgConnection, pg_name_: &str) -> Result<Option<Plan>> {
+        use crate::schema::users;
+        Ok(
+            plans::table
+                .inner_join(users::table.on(users::plan_name.eq(plans::plan_name)))
+                .filter(users::pg_name.eq(pg_name_))
+                .select(plans::all_columns)
+                .first::<Plan>(conn)
+                .optional()?
+        )
+    }
+
+    /// Connection limit of the user with the Postgres role `pg_name_`.
+    ///
+    /// Roles that don't belong to a user have no limit.
+    pub fn max_connections_for_user(conn: &mut PgConnection, pg_name_: &str) -> Result<Option<i32>> {
+        Ok(Plan::for_user(conn, pg_name_)?.and_then(|plan| plan.max_connections))
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = plans)]
+pub struct NewPlan {
+    pub plan_name: String,
+}
+impl NewPlan {
+    pub fn create(conn: &mut PgConnection, plan_name: String) -> Result<Plan> {
+        Ok(
+            diesel::insert_into(plans::table)
+                .values(&NewPlan { plan_name })
+                .get_result::<Plan>(conn)?
+        )
+    }
+}
+
+/// Quantity of a charge type that is free each calendar month, in the units
+/// of the charge type (e.g. bytes for transfer, byte-hours for storage).
+#[derive(Queryable, Insertable, Debug, PartialEq)]
+#[diesel(table_name = plan_allowances)]
+pub struct PlanAllowance {
+    pub plan_name: String,
+    pub charge_type: ChargeType,
+    pub free_quantity: f64,
+}
+impl PlanAllowance {
+    pub fn for_plan(conn: &mut PgConnection, plan_name_: &str) -> Result<Vec<PlanAllowance>> {
+        use crate::schema::plan_allowances::dsl::*;
+        Ok(
+            plan_allowances
+                .filter(plan_name.eq(plan_name_))
+                .load::<PlanAllowance>(conn)?
+        )
+    }
+
+    /// Monthly free quantity of `charge_type_` for the user's plan.
+    ///
+    /// Users without an allowance for the charge type (including charges
+    /// assigned to users that don't exist) get no free quantity.
+    pub fn free_quantity_for_user(
+        conn: &mut PgConnection,
+        user_id_: &Uuid,
+        charge_type_: ChargeType,
+    ) -> Result<f64> {
+        use crate::schema::{plan_allowances, users};
+        Ok(
+            plan_allowances::table
+                .inner_join(users::table.on(users::plan_name.eq(plan_allowances::plan_name)))
+                .filter(users::user_id.eq(user_id_))
+                .filter(plan_allowances::charge_type.eq(charge_type_))
+                .select(plan_allowances::free_quantity)
+                .first::<f64>(conn)
+                .optional()?
+                .unwrap_or(0.)
+        )
+    }
+
+    /// Create or replace the allowance for a plan and charge type.
+    pub fn set(&self, conn: &mut PgConnection) -> Result<PlanAllowance> {
+        use crate::schema::plan_allowances::dsl::*;
+        Ok(
+            diesel::insert_into(plan_allowances)
+                .values(self)
+                .on_conflict((plan_name, charge_type))
+                .do_update()
+                .set(free_quantity.eq(self.free_quantity))
+                .get_result::<PlanAllowance>(conn)?
+        )
+    }
+}
+
+#[derive(QueryableByName, Debug)]
+struct PeriodQuantity {
+    #[diesel(sql_type = Double)]
+    quantity: f64,
+}
+
+/// Total quantity charged to a user for a charge type within a calendar
+/// month.
+pub struct UsagePeriod {}
+impl UsagePeriod {
+    /// Start of the calendar month (UTC) containing `time`.
+    pub fn period_start(time: &DateTime<Utc>) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(time.year(), time.month(), 1, 0, 0, 0).unwrap()
+    }
+
+    /// Add `quantity` to the user's usage for the period, returning the usage
+    /// recorded *before* this addition.
+    ///
+    /// The usage row stays locked until the surrounding transaction ends, so
+    /// concurrent charges for the same period are serialized.
+    pub fn add_usage(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        charge_type: ChargeType,
+        period_start: &DateTime<Utc>,
+        quantity: f64,
+    ) -> Result<f64> {
+        let total = sql_query(r#"
+            INSERT INTO usage_periods (user_id, charge_type, period_start, quantity)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, charge_type, period_start)
+                DO UPDATE SET quantity = usage_periods.quantity + EXCLUDED.quantity
+            RETURNING quantity
+        "#)
+            .bind::<diesel::sql_types::Uuid, _>(user_id)
+            .bind::<crate::schema::sql_types::Chargetype, _>(charge_type)
+            .bind::<Timestamptz, _>(period_start)
+            .bind::<Double, _>(quantity)
+            .get_result::<PeriodQuantity>(conn)?;
+        Ok(total.quantity - quantity)
+    }
+
+    /// Usage recorded for the user in the period.
+    pub fn usage(
+        conn: &mut PgConnection,
+        user_id_: &Uuid,
+        charge_type_: ChargeType,
+        period_start_: &DateTime<Utc>,
+    ) -> Result<f64> {
+        use crate::schema::usage_periods::dsl::*;
+        Ok(
+            usage_periods
+                .filter(user_id.eq(user_id_))
+                .filter(charge_type.eq(charge_type_))
+                .filter(period_start.eq(period_start_))
+                .select(quantity)
+                .first::<f64>(conn)
+                .optional()?
+                .unwrap_or(0.)
+        )
+    }
+}
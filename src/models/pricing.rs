@@ -0,0 +1,93 @@
+use anyhow::Result;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::models::charges::ChargeType;
+use crate::schema::price_tiers;
+
+
+/// Rate applying to usage of a charge type once the user's cumulative usage
+/// in the billing period reaches `start_quantity`.
+#[derive(Queryable, Insertable, Debug, PartialEq, Clone)]
+#[diesel(table_name = price_tiers)]
+pub struct PriceTier {
+    pub charge_type: ChargeType,
+    pub start_quantity: f64,
+    pub rate: f64,
+}
+impl PriceTier {
+    /// Tiers for the charge type, ordered by `start_quantity`.
+    pub fn for_charge_type(conn: &mut PgConnection, charge_type_: ChargeType) -> Result<Vec<PriceTier>> {
+        use crate::schema::price_tiers::dsl::*;
+        Ok(
+            price_tiers
+                .filter(charge_type.eq(charge_type_))
+                .order(start_quantity.asc())
+                .load::<PriceTier>(conn)?
+        )
+    }
+
+    /// Create or replace the tier for a charge type and start quantity.
+    pub fn set(&self, conn: &mut PgConnection) -> Result<PriceTier> {
+        use crate::schema::price_tiers::dsl::*;
+        Ok(
+            diesel::insert_into(price_tiers)
+                .values(self)
+                .on_conflict((charge_type, start_quantity))
+                .do_update()
+                .set(rate.eq(self.rate))
+                .get_result::<PriceTier>(conn)?
+        )
+    }
+
+    pub fn delete(&self, conn: &mut PgConnection) -> Result<usize> {
+        use crate::schema::price_tiers::dsl::*;
+        Ok(
+            diesel::delete(price_tiers.find((self.charge_type, self.start_quantity)))
+                .execute(conn)?
+        )
+    }
+}
+
+/// Portion of a charge priced at a single tier's rate.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TierCharge {
+    /// Cumulative period usage at which the tier begins
+    pub start_quantity: f64,
+    pub quantity: f64,
+    pub rate: f64,
+    pub amount: f64,
+}
+
+/// Split the usage between cumulative period quantities `from` and `to`
+/// across `tiers` (ordered by `start_quantity`). Usage below the first tier
+/// is charged at `base_rate`.
+pub fn price_range(tiers: &[PriceTier], base_rate: f64, from: f64, to: f64) -> Vec<TierCharge> {
+    let mut bounds = vec![(0., base_rate)];
+    bounds.extend(tiers.iter().map(|tier| (tier.start_quantity, tier.rate)));
+    let mut result = vec![];
+    for (i, (start, rate)) in bounds.iter().enumerate() {
+        let end = bounds.get(i + 1).map_or(f64::INFINITY, |(next, _)| *next);
+        let quantity = to.min(end) - from.max(*start);
+        if quantity > 0. {
+            result.push(TierCharge {
+                start_quantity: *start,
+                quantity,
+                rate: *rate,
+                amount: quantity * rate,
+            });
+        }
+    }
+    result
+}
+
+/// Rate that charges `quantity` at the same total as the tier breakdown.
+/// Falls back to `base_rate` when nothing is charged.
+pub fn blended_rate(breakdown: &[TierCharge], base_rate: f64) -> f64 {
+    let quantity: f64 = breakdown.iter().map(|tier| tier.quantity).sum();
+    if quantity > 0. {
+        breakdown.iter().map(|tier| tier.amount).sum::<f64>() / quantity
+    } else {
+        base_rate
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+
+use crate::schema::query_stats;
+
+
+#[derive(Queryable, Debug, PartialEq)]
+pub struct QueryStat {
+    pub query_stat_id: i64,
+    pub username: String,
+    pub query_time: DateTime<Utc>,
+    pub fingerprint: Option<String>,
+    pub query: String,
+    pub duration_seconds: f64,
+    pub rows_returned: i64,
+    pub bytes_returned: i64,
+    pub command_tag: Option<String>,
+    pub error_code: Option<String>,
+}
+impl QueryStat {
+    pub fn for_user(conn: &mut PgConnection, username_: &str) -> Result<Vec<QueryStat>> {
+        use crate::schema::query_stats::dsl::*;
+        Ok(
+            query_stats
+                .filter(username.eq(username_))
+                .order(query_stat_id.asc())
+                .load::<QueryStat>(conn)?
+        )
+    }
+}
+
+#[derive(Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = query_stats)]
+pub struct NewQueryStat {
+    pub username: String,
+    pub query_time: DateTime<Utc>,
+    pub fingerprint: Option<String>,
+    pub query: String,
+    pub duration_seconds: f64,
+    pub rows_returned: i64,
+    pub bytes_returned: i64,
+    pub command_tag: Option<String>,
+    pub error_code: Option<String>,
+}
+impl NewQueryStat {
+    /// Stats for a statement that just started running, identified by its
+    /// fingerprint. Constants are stripped from the recorded query text so
+    /// that no data values are stored.
+    pub fn start(username: String, statement: &str) -> NewQueryStat {
+        let fingerprint = pg_query::fingerprint(statement)
+            .map(|fingerprint| fingerprint.hex)
+            .ok();
+        let query = pg_query::normalize(statement)
+            .unwrap_or_else(|_| statement.to_string());
+        NewQueryStat {
+            username,
+            query_time: Utc::now(),
+            fingerprint,
+            query,
+            duration_seconds: 0.,
+            rows_returned: 0,
+            bytes_returned: 0,
+            command_tag: None,
+            error_code: None,
+        }
+    }
+
+    pub fn commit(&self, conn: &mut PgConnection) -> Result<QueryStat> {
+        Ok(
+            diesel::insert_into(query_stats::table)
+                .values(self)
+                .get_result::<QueryStat>(conn)?
+        )
+    }
+}
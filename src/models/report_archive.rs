@@ -0,0 +1,275 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Date, Timestamptz};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::models::reports::PostgresqlPacketType;
+use crate::schema::reports;
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+
+/// A report as stored in an archive file, one JSON object per line.
+#[derive(Queryable, Insertable, Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[diesel(table_name = reports)]
+pub struct ArchivedReport {
+    pub packet_id: i64,
+    pub username: Option<String>,
+    pub packet_type: String,
+    pub packet_time: DateTime<Utc>,
+    pub direction: Option<String>,
+    pub packet_info: Option<serde_json::Value>,
+    /// Hex encoded
+    #[serde(with = "hex_bytes")]
+    pub packet_bytes: Option<Vec<u8>>,
+    pub charged: bool,
+    pub fingerprinted: bool,
+    #[serde(default)]
+    pub session_id: Option<Uuid>,
+}
+
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => serializer.serialize_some(&hex::encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|encoded| hex::decode(encoded).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// An archive file holding the reports of one day (in UTC).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ArchiveFile {
+    pub file_name: String,
+    pub day: NaiveDate,
+    pub report_count: usize,
+    pub first_report_id: i64,
+    pub last_report_id: i64,
+    /// SHA-256 of the compressed file, hex encoded
+    pub sha256: String,
+    pub archived_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ArchiveManifest {
+    pub files: Vec<ArchiveFile>,
+}
+
+/// A local directory of gzipped newline-delimited JSON files of reports,
+/// with a manifest listing each file and its checksum.
+///
+/// Archiving only takes reports that have been fully processed (charged,
+/// and aggregated by fingerprint for Query reports), and deletes them from
+/// the database once their file has been written and verified.
+#[derive(Debug)]
+pub struct ReportArchive {
+    dir: PathBuf,
+    pub manifest: ArchiveManifest,
+}
+impl ReportArchive {
+    /// Open the archive in `dir`, creating the directory if needed.
+    pub fn open(dir: &Path) -> Result<ReportArchive> {
+        fs::create_dir_all(dir)?;
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        let manifest = if manifest_path.exists() {
+            serde_json::from_slice(&fs::read(&manifest_path)?)?
+        } else {
+            ArchiveManifest::default()
+        };
+        Ok(ReportArchive { dir: dir.to_path_buf(), manifest })
+    }
+
+    /// Move processed reports recorded before `cutoff` out of the database
+    /// into one new file per day, returning the files written.
+    pub fn archive(&mut self, conn: &mut PgConnection, cutoff: &DateTime<Utc>) -> Result<Vec<ArchiveFile>> {
+        let mut archived = vec![];
+        for day in archivable_days(conn, cutoff)? {
+            let day_start = day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+            let day_end = std::cmp::min(day_start + Duration::days(1), *cutoff);
+            let file = conn.transaction(|conn| self.archive_range(conn, day, &day_start, &day_end))?;
+            if let Some(file) = file {
+                archived.push(file);
+            }
+        }
+        Ok(archived)
+    }
+
+    fn archive_range(
+        &mut self,
+        conn: &mut PgConnection,
+        day: NaiveDate,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Option<ArchiveFile>> {
+        use crate::schema::reports::dsl::*;
+        let rows = reports
+            .filter(packet_time.ge(start))
+            .filter(packet_time.lt(end))
+            .filter(charged)
+            .filter(packet_type.ne(PostgresqlPacketType::Query.to_string()).or(fingerprinted))
+            .order((packet_time.asc(), packet_id.asc()))
+            .for_update()
+            .load::<ArchivedReport>(conn)?;
+        let (first, last) = match (rows.first(), rows.last()) {
+            (Some(first), Some(last)) => (first.packet_id, last.packet_id),
+            _ => return Ok(None),
+        };
+        // never overwrite an earlier file, e.g. of reports that have been
+        // restored and archived again
+        let (file_name, path) = (1..)
+            .map(|sequence| {
+                let file_name = format!("reports-{}-{:03}.ndjson.gz", day, sequence);
+                let path = self.dir.join(&file_name);
+                (file_name, path)
+            })
+            .find(|(_, path)| !path.exists())
+            .unwrap();
+        write_reports(&path, &rows)?;
+        let sha256 = file_sha256(&path)?;
+        // make sure the file reads back before deleting anything
+        if read_reports(&path)? != rows {
+            return Err(anyhow!("Archive file {} doesn't match the archived reports", path.display()));
+        }
+        let file = ArchiveFile {
+            file_name,
+            day,
+            report_count: rows.len(),
+            first_report_id: first,
+            last_report_id: last,
+            sha256,
+            archived_at: Utc::now(),
+        };
+        self.manifest.files.push(file.clone());
+        self.write_manifest()?;
+
+        let ids: Vec<i64> = rows.iter().map(|row| row.packet_id).collect();
+        let deleted = diesel::delete(
+            reports
+                .filter(packet_id.eq_any(&ids))
+                .filter(packet_time.ge(start))
+                .filter(packet_time.lt(end))
+        ).execute(conn)?;
+        info!("Archived {} reports of {} to {}", deleted, day, path.display());
+        Ok(Some(file))
+    }
+
+    /// Load the archived reports recorded within `[start, end)` back into the
+    /// database, skipping reports that are already there. Each file's
+    /// checksum is verified first. Returns the number of reports restored.
+    pub fn restore(&self, conn: &mut PgConnection, start: &DateTime<Utc>, end: &DateTime<Utc>) -> Result<usize> {
+        let mut restored = 0;
+        for file in &self.manifest.files {
+            let day_start = file.day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+            if day_start >= *end || day_start + Duration::days(1) <= *start {
+                continue;
+            }
+            let path = self.dir.join(&file.file_name);
+            if file_sha256(&path)? != file.sha256 {
+                return Err(anyhow!("Checksum mismatch for archive file {}", path.display()));
+            }
+            let rows: Vec<ArchivedReport> = read_reports(&path)?
+                .into_iter()
+                .filter(|row| row.packet_time >= *start && row.packet_time < *end)
+                .collect();
+            for chunk in rows.chunks(1000) {
+                restored += diesel::insert_into(reports::table)
+                    .values(chunk)
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+            debug!("Restored reports from {}", path.display());
+        }
+        Ok(restored)
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        // write then rename, so the manifest is never left half written
+        let path = self.dir.join(MANIFEST_FILE_NAME);
+        let temp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+        fs::write(&temp_path, serde_json::to_vec_pretty(&self.manifest)?)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+}
+
+#[derive(QueryableByName, Debug)]
+struct ArchivableDay {
+    #[diesel(sql_type = Date)]
+    day: NaiveDate,
+}
+
+/// Days (in UTC) with processed reports recorded before `cutoff`.
+fn archivable_days(conn: &mut PgConnection, cutoff: &DateTime<Utc>) -> Result<Vec<NaiveDate>> {
+    Ok(
+        sql_query(r#"
+            SELECT DISTINCT (packet_time AT TIME ZONE 'UTC')::date AS day
+            FROM reports
+            WHERE packet_time < $1
+                AND charged AND (packet_type <> 'Query' OR fingerprinted)
+            ORDER BY day
+        "#)
+            .bind::<Timestamptz, _>(cutoff)
+            .load::<ArchivableDay>(conn)?
+            .into_iter()
+            .map(|row| row.day)
+            .collect()
+    )
+}
+
+fn write_reports(path: &Path, rows: &[ArchivedReport]) -> Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+    for row in rows {
+        serde_json::to_writer(&mut encoder, row)?;
+        encoder.write_all(b"\n")?;
+    }
+    let file = encoder.finish()?.into_inner()?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn read_reports(path: &Path) -> Result<Vec<ArchivedReport>> {
+    let reader = BufReader::new(GzDecoder::new(File::open(path)?));
+    let mut rows = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            rows.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(rows)
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
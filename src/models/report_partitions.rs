@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Text, Timestamptz};
+use log::{debug, info};
+
+
+/// Advisory lock held while applying a retention policy, so that two runs
+/// can't remove partitions at the same time.
+const RETENTION_LOCK_KEY: &str = "impulse.report_retention";
+
+#[derive(QueryableByName, Debug)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+#[derive(QueryableByName, Debug)]
+struct Created {
+    #[diesel(sql_type = Integer)]
+    created: i32,
+}
+
+#[derive(QueryableByName, Debug)]
+struct Locked {
+    #[diesel(sql_type = Bool)]
+    locked: bool,
+}
+
+/// A partition of the `reports` table, covering one month (in UTC) of
+/// `packet_time`. The default partition, which holds reports outside of
+/// every monthly partition, has no range.
+#[derive(QueryableByName, Debug, PartialEq, Clone)]
+pub struct ReportPartition {
+    #[diesel(sql_type = Text)]
+    pub partition_name: String,
+    #[diesel(sql_type = Nullable<Timestamptz>)]
+    pub range_start: Option<DateTime<Utc>>,
+    #[diesel(sql_type = Nullable<Timestamptz>)]
+    pub range_end: Option<DateTime<Utc>>,
+}
+impl ReportPartition {
+    /// All partitions ordered by range, the default partition last.
+    pub fn all(conn: &mut PgConnection) -> Result<Vec<ReportPartition>> {
+        Ok(
+            sql_query("SELECT * FROM report_partitions()")
+                .load::<ReportPartition>(conn)?
+        )
+    }
+
+    /// Create the missing monthly partitions from the month of `from`
+    /// through the month of `through`, returning how many were created.
+    /// Reports already recorded for those months move out of the default
+    /// partition.
+    pub fn create(conn: &mut PgConnection, from: &DateTime<Utc>, through: &DateTime<Utc>) -> Result<i32> {
+        let result = sql_query("SELECT create_report_partitions($1, $2) AS created")
+            .bind::<Timestamptz, _>(from)
+            .bind::<Timestamptz, _>(through)
+            .get_result::<Created>(conn)?;
+        Ok(result.created)
+    }
+
+    /// Reports in the partition that haven't been charged, or Query reports
+    /// that haven't been aggregated by fingerprint yet.
+    pub fn pending_reports(&self, conn: &mut PgConnection) -> Result<i64> {
+        let result = sql_query("SELECT report_partition_pending($1) AS count")
+            .bind::<Text, _>(&self.partition_name)
+            .get_result::<Count>(conn)?;
+        Ok(result.count)
+    }
+
+    /// Detach the partition from `reports`, dropping its table unless
+    /// `keep_table`. Fails without removing anything if the partition has
+    /// pending reports.
+    pub fn remove(&self, conn: &mut PgConnection, keep_table: bool) -> Result<()> {
+        sql_query("SELECT remove_report_partition($1, $2)")
+            .bind::<Text, _>(&self.partition_name)
+            .bind::<Bool, _>(keep_table)
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+/// What to do with partitions past the retention period.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RetentionAction {
+    /// Drop the partition and its reports
+    Drop,
+    /// Detach the partition but keep it as a standalone table
+    Detach,
+}
+
+/// Removes monthly partitions of `reports` that ended before `cutoff` once
+/// all their reports have been processed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    pub cutoff: DateTime<Utc>,
+    pub action: RetentionAction,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RetentionOutcome {
+    /// Partitions removed (or that would be, on a dry run)
+    pub removed: Vec<ReportPartition>,
+    /// Partitions past the cutoff kept because of pending reports, with
+    /// their number of pending reports
+    pub retained: Vec<(ReportPartition, i64)>,
+}
+
+impl RetentionPolicy {
+    pub fn expired_partitions(&self, conn: &mut PgConnection) -> Result<Vec<ReportPartition>> {
+        Ok(
+            ReportPartition::all(conn)?
+                .into_iter()
+                .filter(|partition| match partition.range_end {
+                    Some(range_end) => range_end <= self.cutoff,
+                    None => false,
+                })
+                .collect()
+        )
+    }
+
+    /// Apply the policy, only reporting what would be removed if `dry_run`.
+    pub fn apply(&self, conn: &mut PgConnection, dry_run: bool) -> Result<RetentionOutcome> {
+        let lock = sql_query("SELECT pg_try_advisory_lock(hashtext($1)) AS locked")
+            .bind::<Text, _>(RETENTION_LOCK_KEY)
+            .get_result::<Locked>(conn)?;
+        if !lock.locked {
+            return Err(anyhow!("Report retention is already running"));
+        }
+        let result = self.apply_locked(conn, dry_run);
+        sql_query("SELECT pg_advisory_unlock(hashtext($1))")
+            .bind::<Text, _>(RETENTION_LOCK_KEY)
+            .execute(conn)?;
+        result
+    }
+
+    fn apply_locked(&self, conn: &mut PgConnection, dry_run: bool) -> Result<RetentionOutcome> {
+        let mut outcome = RetentionOutcome::default();
+        for partition in self.expired_partitions(conn)? {
+            let pending = partition.pending_reports(conn)?;
+            if pending > 0 {
+                debug!("Keeping {} with {} pending reports", &partition.partition_name, pending);
+                outcome.retained.push((partition, pending));
+                continue;
+            }
+            if !dry_run {
+                partition.remove(conn, self.action == RetentionAction::Detach)?;
+                info!("Removed report partition {} ({:?})", &partition.partition_name, self.action);
+            }
+            outcome.removed.push(partition);
+        }
+        Ok(outcome)
+    }
+}
@@ -18,7 +18,13 @@ pub enum PostgresqlPacketType {
     Query,
     SslRequest,
     DataRow,
-    Other
+    Other,
+    /// Not a packet: time an authenticated connection was open, recorded by
+    /// the proxy when the connection closes
+    ConnectionTime,
+    /// Not a packet: time between a query being sent and the server being
+    /// ready for the next one
+    QueryTime,
 }
 impl From<&prew::postgresql::PostgresqlPacketInfo> for PostgresqlPacketType {
     fn from(prew_packet_type: &prew::postgresql::PostgresqlPacketInfo) -> Self {
@@ -47,6 +53,8 @@ impl FromStr for PostgresqlPacketType {
             "Query" => Ok(PostgresqlPacketType::Query),
             "Other" => Ok(PostgresqlPacketType::Other),
             "DataRow" => Ok(PostgresqlPacketType::DataRow),
+            "ConnectionTime" => Ok(PostgresqlPacketType::ConnectionTime),
+            "QueryTime" => Ok(PostgresqlPacketType::QueryTime),
             _ => Err(()),
         }
     }
@@ -92,6 +100,8 @@ pub struct Report_ {
     pub packet_info: Option<serde_json::Value>,
     pub packet_bytes: Option<Vec<u8>>,
     pub charged: bool,
+    pub fingerprinted: bool,
+    pub session_id: Option<Uuid>,
 }
 pub struct Report {
     pub report_id: i64,
@@ -102,6 +112,10 @@ pub struct Report {
     pub packet_info: Option<serde_json::Value>,
     pub packet_bytes: Option<Vec<u8>>,
     pub charged: bool,
+    /// Whether a Query report has been counted in `fingerprint_usage`
+    pub fingerprinted: bool,
+    /// Proxy session the packet was sent in
+    pub session_id: Option<Uuid>,
 }
 impl Report {
     pub fn for_user<S: Into<String>>(conn: &mut PgConnection, username_: S) -> Result<Vec<Report>>{
@@ -117,7 +131,7 @@ impl Report {
 
     pub fn mark_charged(report_id: i64, conn: &mut PgConnection) -> Result<()> {
         use crate::schema::reports::dsl::*;
-        diesel::update(reports.find(report_id))
+        diesel::update(reports.filter(packet_id.eq(report_id)))
             .set(charged.eq(true))
             .execute(conn)?;
         Ok(())
@@ -138,6 +152,8 @@ impl From<Report_> for Report {
             packet_info: value.packet_info,
             packet_bytes: value.packet_bytes,
             charged: value.charged,
+            fingerprinted: value.fingerprinted,
+            session_id: value.session_id,
         }
     }
 }
@@ -153,6 +169,7 @@ mod views {
             packet_type -> Text,
             direction -> Nullable<Text>,
             num_bytes -> Nullable<Int4>,
+            seconds -> Nullable<Float8>,
         }
     }
 }
@@ -164,6 +181,7 @@ pub struct ReportToCharge_ {
     pub packet_type: String,
     pub direction: Option<String>,
     pub num_bytes: Option<i32>,
+    pub seconds: Option<f64>,
 }
 #[derive(Debug, PartialEq)]
 pub struct ReportToCharge {
@@ -172,6 +190,8 @@ pub struct ReportToCharge {
     pub packet_type: PostgresqlPacketType,
     pub direction: Option<PacketDirection>,
     pub num_bytes: Option<i32>,
+    /// Measured duration of time reports
+    pub seconds: Option<f64>,
 }
 impl ReportToCharge {
     pub fn uncharged(conn: &mut PgConnection) -> Result<Vec<ReportToCharge>> {
@@ -186,16 +206,9 @@ impl ReportToCharge {
     }
 
     pub fn with_userid(report: Report, user_id: Uuid) -> ReportToCharge {
-        let num_bytes: Option<i32> = match report.packet_bytes {
-            Some(byte_arr) => i32::try_from(byte_arr.len()).ok(),
-            None => None,
-        };
         ReportToCharge {
-            report_id: report.report_id,
             user_id: Some(user_id),
-            packet_type: report.packet_type,
-            direction: report.direction,
-            num_bytes
+            ..report.into()
         }
     }
 }
@@ -211,6 +224,7 @@ impl From<ReportToCharge_> for ReportToCharge {
             packet_type: PostgresqlPacketType::from_str(&value.packet_type).unwrap(),
             direction,
             num_bytes: value.num_bytes,
+            seconds: value.seconds,
         }
     }
 }
@@ -220,12 +234,21 @@ impl From<Report> for ReportToCharge {
             Some(byte_arr) => i32::try_from(byte_arr.len()).ok(),
             None => None,
         };
+        let seconds = match value.packet_type {
+            PostgresqlPacketType::ConnectionTime | PostgresqlPacketType::QueryTime => value
+                .packet_info
+                .as_ref()
+                .and_then(|info| info.get("seconds"))
+                .and_then(|seconds| seconds.as_f64()),
+            _ => None,
+        };
         return ReportToCharge {
             report_id: value.report_id,
             user_id: None,
             packet_type: value.packet_type,
             direction: value.direction,
-            num_bytes
+            num_bytes,
+            seconds,
         }
     }
 }
@@ -239,6 +262,7 @@ pub struct NewReport {
     pub packet_info: Option<serde_json::Value>,
     pub packet_bytes: Option<Vec<u8>>,
     pub charged: bool,
+    pub session_id: Option<Uuid>,
 }
 
 impl NewReport {
@@ -260,10 +284,28 @@ impl NewReport {
             direction,
             packet_info,
             packet_bytes,
-            charged
+            charged,
+            session_id: None,
         }
     }
 
+    pub fn with_session(self, session_id: Uuid) -> NewReport {
+        NewReport { session_id: Some(session_id), ..self }
+    }
+
+    /// Report for a duration measured by the proxy, charged to the user
+    /// like packets are.
+    pub fn time(username: String, packet_type: PostgresqlPacketType, seconds: f64) -> NewReport {
+        NewReport::create(
+            Some(username),
+            packet_type,
+            None,
+            Some(serde_json::json!({ "seconds": seconds })),
+            None,
+            false,
+        )
+    }
+
     pub fn commit(&self, conn: &mut PgConnection) -> Result<Report> {
         let query = diesel::insert_into(reports::table)
             .values(self);
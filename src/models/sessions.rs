@@ -0,0 +1,218 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::schema::sessions;
+
+
+/// Why a proxied connection ended.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TerminationReason {
+    /// The client closed the connection
+    ClientClosed,
+    /// The server closed the connection
+    ServerClosed,
+    /// Reading from or writing to the client failed
+    ClientError,
+    /// Reading from or writing to the server failed
+    ServerError,
+    /// The proxy couldn't process a message
+    ProtocolError,
+    /// The proxy couldn't connect to the server
+    ServerUnavailable,
+    /// The proxy refused the connection because the user reached their
+    /// connection limit
+    ConnectionLimit,
+    /// The proxy refused the connection because the client didn't ask for
+    /// TLS while it's required
+    TlsRequired,
+    /// The proxy refused the connection because the client failed to
+    /// authenticate
+    AuthenticationFailed,
+    /// The proxy refused the connection because the user is disabled or
+    /// deleted
+    UserDisabled,
+    /// The connection ended in a way the proxy didn't observe
+    Unknown,
+}
+impl Display for TerminationReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl FromStr for TerminationReason {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ClientClosed" => Ok(TerminationReason::ClientClosed),
+            "ServerClosed" => Ok(TerminationReason::ServerClosed),
+            "ClientError" => Ok(TerminationReason::ClientError),
+            "ServerError" => Ok(TerminationReason::ServerError),
+            "ProtocolError" => Ok(TerminationReason::ProtocolError),
+            "ServerUnavailable" => Ok(TerminationReason::ServerUnavailable),
+            "ConnectionLimit" => Ok(TerminationReason::ConnectionLimit),
+            "TlsRequired" => Ok(TerminationReason::TlsRequired),
+            "AuthenticationFailed" => Ok(TerminationReason::AuthenticationFailed),
+            "UserDisabled" => Ok(TerminationReason::UserDisabled),
+            "Unknown" => Ok(TerminationReason::Unknown),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Traffic of a session through the proxy, in each direction.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SessionTraffic {
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub throttled_in_ms: i64,
+    pub throttled_out_ms: i64,
+}
+
+#[derive(Queryable, Debug, PartialEq)]
+pub struct Session_ {
+    pub session_id: Uuid,
+    pub client_addr: Option<String>,
+    pub username: Option<String>,
+    pub database: Option<String>,
+    pub application_name: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub bytes_in: i64,
+    pub bytes_out: i64,
+    pub termination_reason: Option<String>,
+    pub throttled_in_ms: i64,
+    pub throttled_out_ms: i64,
+}
+#[derive(Debug, PartialEq)]
+pub struct Session {
+    pub session_id: Uuid,
+    pub client_addr: Option<String>,
+    pub username: Option<String>,
+    pub database: Option<String>,
+    pub application_name: Option<String>,
+    pub started_at: DateTime<Utc>,
+    /// None while the connection is open
+    pub ended_at: Option<DateTime<Utc>>,
+    /// Bytes received from the client
+    pub bytes_in: i64,
+    /// Bytes received from the server
+    pub bytes_out: i64,
+    pub termination_reason: Option<TerminationReason>,
+    /// Time traffic from the client was held back by the proxy
+    pub throttled_in_ms: i64,
+    /// Time traffic from the server was held back by the proxy
+    pub throttled_out_ms: i64,
+}
+impl From<Session_> for Session {
+    fn from(value: Session_) -> Self {
+        Session {
+            session_id: value.session_id,
+            client_addr: value.client_addr,
+            username: value.username,
+            database: value.database,
+            application_name: value.application_name,
+            started_at: value.started_at,
+            ended_at: value.ended_at,
+            bytes_in: value.bytes_in,
+            bytes_out: value.bytes_out,
+            termination_reason: value.termination_reason
+                .and_then(|reason| TerminationReason::from_str(&reason).ok()),
+            throttled_in_ms: value.throttled_in_ms,
+            throttled_out_ms: value.throttled_out_ms,
+        }
+    }
+}
+impl Session {
+    pub fn retrieve(conn: &mut PgConnection, session_id_: &Uuid) -> Result<Session> {
+        use crate::schema::sessions::dsl::*;
+        Ok(
+            sessions
+                .find(session_id_)
+                .first::<Session_>(conn)?
+                .into()
+        )
+    }
+
+    pub fn for_user(conn: &mut PgConnection, username_: &str) -> Result<Vec<Session>> {
+        use crate::schema::sessions::dsl::*;
+        Ok(
+            sessions
+                .filter(username.eq(username_))
+                .order(started_at.asc())
+                .load::<Session_>(conn)?
+                .into_iter()
+                .map(Session::from)
+                .collect()
+        )
+    }
+
+    /// Record the parameters of the client's startup message.
+    pub fn record_startup(
+        conn: &mut PgConnection,
+        session_id_: &Uuid,
+        username_: Option<&str>,
+        database_: Option<&str>,
+        application_name_: Option<&str>,
+    ) -> Result<()> {
+        use crate::schema::sessions::dsl::*;
+        diesel::update(sessions.find(session_id_))
+            .set((
+                username.eq(username_),
+                database.eq(database_),
+                application_name.eq(application_name_),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn record_end(
+        conn: &mut PgConnection,
+        session_id_: &Uuid,
+        ended_at_: &DateTime<Utc>,
+        traffic: &SessionTraffic,
+        reason: TerminationReason,
+    ) -> Result<()> {
+        use crate::schema::sessions::dsl::*;
+        diesel::update(sessions.find(session_id_))
+            .set((
+                ended_at.eq(ended_at_),
+                bytes_in.eq(traffic.bytes_in),
+                bytes_out.eq(traffic.bytes_out),
+                throttled_in_ms.eq(traffic.throttled_in_ms),
+                throttled_out_ms.eq(traffic.throttled_out_ms),
+                termination_reason.eq(reason.to_string()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = sessions)]
+pub struct NewSession {
+    pub session_id: Uuid,
+    pub client_addr: Option<String>,
+}
+impl NewSession {
+    pub fn create(client_addr: Option<String>) -> NewSession {
+        NewSession {
+            session_id: Uuid::new_v4(),
+            client_addr,
+        }
+    }
+
+    pub fn commit(&self, conn: &mut PgConnection) -> Result<Session> {
+        Ok(
+            diesel::insert_into(sessions::table)
+                .values(self)
+                .get_result::<Session_>(conn)?
+                .into()
+        )
+    }
+}
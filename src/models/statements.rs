@@ -0,0 +1,135 @@
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::models::charges::Charge;
+use crate::models::transactions::{ExtTransaction, Transaction};
+
+
+/// Column headers of the CSV export.
+pub const CSV_HEADER: [&str; 9] = [
+    "record_type",
+    "id",
+    "time",
+    "type",
+    "quantity",
+    "free_quantity",
+    "rate",
+    "amount",
+    "detail",
+];
+
+/// Usage detail for a user within `[start, end)`.
+#[derive(Serialize, Debug)]
+pub struct Statement {
+    pub user_id: Uuid,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub charges: Vec<Charge>,
+    pub transactions: Vec<Transaction>,
+    pub deposits: Vec<ExtTransaction>,
+}
+impl Statement {
+    pub fn generate(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Statement> {
+        Ok(
+            Statement {
+                user_id: *user_id,
+                start: *start,
+                end: *end,
+                charges: Charge::for_user_between(conn, user_id, start, end)?,
+                transactions: Transaction::for_user_between(conn, user_id, start, end)?,
+                deposits: ExtTransaction::for_user_between(conn, user_id, start, end)?,
+            }
+        )
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Render all records as a single CSV document ordered by time.
+    ///
+    /// Transaction amounts are signed from the user's point of view: negative
+    /// when paid by the user, positive when paid to them. The `detail` column
+    /// holds the reason of a transaction or the external ID of a deposit.
+    pub fn to_csv(&self) -> String {
+        let mut rows: Vec<(DateTime<Utc>, Vec<String>)> = vec![];
+        for charge in &self.charges {
+            rows.push((charge.charge_time, vec![
+                "charge".to_string(),
+                charge.charge_id.to_string(),
+                format_time(&charge.charge_time),
+                format!("{:?}", charge.charge_type),
+                charge.quantity.to_string(),
+                charge.free_quantity.to_string(),
+                charge.rate.to_string(),
+                charge.amount.to_string(),
+                String::new(),
+            ]));
+        }
+        for txn in &self.transactions {
+            let amount = if txn.from_user == self.user_id { -txn.amount } else { txn.amount };
+            rows.push((txn.txn_time, vec![
+                "transaction".to_string(),
+                txn.transaction_id.to_string(),
+                format_time(&txn.txn_time),
+                format!("{:?}", txn.txn_type),
+                String::new(),
+                String::new(),
+                String::new(),
+                amount.to_string(),
+                txn.reason.clone().unwrap_or_default(),
+            ]));
+        }
+        for deposit in &self.deposits {
+            rows.push((deposit.exttransaction_time, vec![
+                "deposit".to_string(),
+                deposit.exttransaction_id.to_string(),
+                format_time(&deposit.exttransaction_time),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                deposit.amount.to_string(),
+                deposit.exttransaction_extid.clone(),
+            ]));
+        }
+        // stable, so records at the same time keep their type order
+        rows.sort_by_key(|(time, _)| *time);
+
+        let mut csv = csv_line(CSV_HEADER.iter());
+        for (_, row) in rows {
+            csv.push_str(&csv_line(row.iter()));
+        }
+        csv
+    }
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn csv_line<S: AsRef<str>>(fields: impl Iterator<Item = S>) -> String {
+    let mut line = fields
+        .map(|field| csv_field(field.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// Quote a field if needed, per RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
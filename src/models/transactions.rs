@@ -3,14 +3,25 @@ use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use itertools::Itertools;
 use log::trace;
+use serde::Serialize;
 use uuid::Uuid;
 use crate::models::charges::Charge;
+use crate::models::users::UserStatus;
 
-use crate::schema::{transactions, exttransactions};
+use crate::schema::{transactions, exttransactions, users};
+
+/// Users whose balance falls below this when their charges are posted are
+/// disabled.
+pub const DISABLE_BELOW_BALANCE: f64 = -1.0;
+
+/// Active users whose balance falls below this when their charges are posted,
+/// but not below [DISABLE_BELOW_BALANCE], are made read-only.
+pub const READ_ONLY_BELOW_BALANCE: f64 = 0.;
 
 mod functions {
     use diesel::sql_types::*;
     use diesel::prelude::*;
+    use crate::schema::sql_types::Transactiontype;
 
     sql_function!(
         fn add_internal_transaction_from_reports(
@@ -20,10 +31,34 @@ mod functions {
             disable_at: Float8,
         ) -> Int8;
     );
+
+    sql_function!(
+        fn add_adjustment(
+            p_user: Uuid,
+            p_txn_type: Transactiontype,
+            p_amount: Float8,
+            p_charge_ids: Nullable<Array<Int8>>,
+            p_reason: Text,
+        ) -> Int8;
+    );
+}
+
+#[derive(diesel_derive_enum::DbEnum, Debug, PartialEq, Eq, Copy, Clone, Serialize)]
+#[ExistingTypePath = "crate::schema::sql_types::Transactiontype"]
+#[DbValueStyle = "verbatim"]
+pub enum TransactionType {
+    /// Payment for usage, backed by charges
+    Charge,
+    /// Promotional credit
+    Credit,
+    /// Reversal of specific charges
+    Refund,
+    /// Discretionary credit granted by support
+    Goodwill,
 }
 
 
-#[derive(Queryable, Debug, PartialEq)]
+#[derive(Queryable, Serialize, Debug, PartialEq)]
 pub struct ExtTransaction {
     pub exttransaction_id: i64,
     pub user_id: Uuid,
@@ -40,6 +75,25 @@ impl ExtTransaction {
                 .first::<ExtTransaction>(conn)?
         )
     }
+
+    /// Deposits by the user with `start <= exttransaction_time < end`, oldest
+    /// first.
+    pub fn for_user_between(
+        conn: &mut PgConnection,
+        user_id_: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<ExtTransaction>> {
+        use crate::schema::exttransactions::dsl::*;
+        Ok(
+            exttransactions
+                .filter(user_id.eq(user_id_))
+                .filter(exttransaction_time.ge(start))
+                .filter(exttransaction_time.lt(end))
+                .order((exttransaction_time.asc(), exttransaction_id.asc()))
+                .load::<ExtTransaction>(conn)?
+        )
+    }
 }
 #[derive(Insertable, Debug)]
 #[diesel(table_name = exttransactions)]
@@ -79,8 +133,10 @@ pub struct Transaction_ {
     pub to_user: Uuid,
     pub charge_ids: Option<Vec<Option<i64>>>,
     pub amount: f64,
+    pub txn_type: TransactionType,
+    pub reason: Option<String>,
 }
-#[derive(PartialEq, Debug)]
+#[derive(Serialize, PartialEq, Debug)]
 pub struct Transaction {
     pub transaction_id: i64,
     pub txn_time: DateTime<Utc>,
@@ -88,6 +144,8 @@ pub struct Transaction {
     pub to_user: Uuid,
     pub charge_ids: Option<Vec<i64>>,
     pub amount: f64,
+    pub txn_type: TransactionType,
+    pub reason: Option<String>,
 }
 impl Transaction {
     pub fn retrieve(conn: &mut PgConnection, txn_id_: i64) -> Result<Transaction> {
@@ -99,6 +157,28 @@ impl Transaction {
                 .into()
         )
     }
+
+    /// Transactions paid by or to the user with `start <= txn_time < end`,
+    /// oldest first.
+    pub fn for_user_between(
+        conn: &mut PgConnection,
+        user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<Transaction>> {
+        use crate::schema::transactions::dsl::*;
+        Ok(
+            transactions
+                .filter(from_user.eq(user_id).or(to_user.eq(user_id)))
+                .filter(txn_time.ge(start))
+                .filter(txn_time.lt(end))
+                .order((txn_time.asc(), txn_id.asc()))
+                .load::<Transaction_>(conn)?
+                .into_iter()
+                .map(|txn| txn.into())
+                .collect()
+        )
+    }
 }
 impl From<Transaction_> for Transaction {
     fn from(txn_: Transaction_) -> Self {
@@ -119,6 +199,8 @@ impl From<Transaction_> for Transaction {
             to_user: txn_.to_user,
             charge_ids,
             amount: txn_.amount,
+            txn_type: txn_.txn_type,
+            reason: txn_.reason,
         }
     }
 }
@@ -176,11 +258,74 @@ impl NewTransaction {
                     &from_user,
                     &to_user,
                     &charge_ids,
-                    -1.0  // FIXME
+                    DISABLE_BELOW_BALANCE
                 )
             ).first::<i64>(conn)?;
+            // users in arrears can still export their data, but not add to it
+            diesel::update(users::table.find(from_user))
+                .filter(users::user_status.eq(UserStatus::Active))
+                .filter(users::balance.lt(READ_ONLY_BELOW_BALANCE))
+                .set((
+                    users::user_status.eq(UserStatus::ReadOnly),
+                    users::status_synced.eq(false),
+                ))
+                .execute(conn)?;
             txns.push(Transaction::retrieve(conn, txn_id)?);
         }
         Ok(txns)
     }
+
+    /// Grant promotional credit to a user.
+    pub fn credit(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        amount: f64,
+        reason: &str,
+    ) -> Result<Transaction> {
+        Self::adjustment(conn, user_id, TransactionType::Credit, amount, None, reason)
+    }
+
+    /// Grant discretionary credit to a user, e.g. to make up for an outage.
+    pub fn goodwill(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        amount: f64,
+        reason: &str,
+    ) -> Result<Transaction> {
+        Self::adjustment(conn, user_id, TransactionType::Goodwill, amount, None, reason)
+    }
+
+    /// Refund the full amount of the given (transacted) charges to a user.
+    ///
+    /// The charges themselves are left in place; the refund transaction
+    /// references them, and each charge can only be refunded once.
+    pub fn refund(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        charge_ids: Vec<i64>,
+        reason: &str,
+    ) -> Result<Transaction> {
+        Self::adjustment(conn, user_id, TransactionType::Refund, 0., Some(charge_ids), reason)
+    }
+
+    fn adjustment(
+        conn: &mut PgConnection,
+        user_id: Uuid,
+        txn_type: TransactionType,
+        amount: f64,
+        charge_ids: Option<Vec<i64>>,
+        reason: &str,
+    ) -> Result<Transaction> {
+        trace!("Calling add_adjustment PG function");
+        let txn_id = diesel::select(
+            functions::add_adjustment(
+                &user_id,
+                txn_type,
+                amount,
+                charge_ids,
+                reason,
+            )
+        ).first::<i64>(conn)?;
+        Transaction::retrieve(conn, txn_id)
+    }
 }
@@ -12,6 +12,8 @@ use crate::schema::users;
 #[DbValueStyle = "verbatim"]
 pub enum UserStatus {
     Active,
+    /// Can still connect and read their data, but not write to it
+    ReadOnly,
     Disabled,
     Deleted
 }
@@ -26,6 +28,11 @@ pub struct User {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub pg_password_enc: Option<Vec<u8>>,
+    pub plan_name: String,
+    pub billing_period_months: i32,
+    /// Cluster the user's role and databases are on, None for the
+    /// configured one
+    pub cluster_name: Option<String>,
 }
 impl User {
     pub fn retrieve(conn: &mut PgConnection, user_id_: &Uuid) -> Result<User>
@@ -45,6 +52,18 @@ impl User {
         Ok(users.load::<User>(conn)?)
     }
 
+    /// The user with the Postgres role `pg_name_`, if any.
+    pub fn for_pg_name(conn: &mut PgConnection, pg_name_: &str) -> Result<Option<User>>
+    {
+        use crate::schema::users::dsl::*;
+        Ok(
+            users
+                .filter(pg_name.eq(pg_name_))
+                .first::<User>(conn)
+                .optional()?
+        )
+    }
+
     pub fn unsynced(conn: &mut PgConnection) -> Result<Vec<User>>
     {
         use crate::schema::users::dsl::*;
@@ -67,6 +86,76 @@ impl User {
         Ok(())
     }
 
+    /// Only let the user read their data, e.g. while they're in arrears,
+    /// marking them unsynced so that their databases are made read-only.
+    pub fn set_read_only(&mut self, conn: &mut PgConnection) -> Result<()> {
+        self.set_status(conn, UserStatus::ReadOnly)
+    }
+
+    /// Let a read-only or disabled user use their data again.
+    pub fn activate(&mut self, conn: &mut PgConnection) -> Result<()> {
+        self.set_status(conn, UserStatus::Active)
+    }
+
+    fn set_status(&mut self, conn: &mut PgConnection, status: UserStatus) -> Result<()> {
+        use crate::schema::users::dsl::*;
+        let result = diesel::update(users.find(&self.user_id))
+            .set((
+                user_status.eq(status),
+                status_synced.eq(false),
+            ))
+            .get_result::<User>(conn)?;
+        *self = result;
+        Ok(())
+    }
+
+    /// Move the user to another plan, marking them unsynced so that the
+    /// plan's connection limit is applied to their role.
+    pub fn set_plan(&mut self, conn: &mut PgConnection, plan_name_: &str) -> Result<()> {
+        use crate::schema::users::dsl::*;
+        let result = diesel::update(users.find(&self.user_id))
+            .set((
+                plan_name.eq(plan_name_),
+                status_synced.eq(false),
+            ))
+            .get_result::<User>(conn)?;
+        *self = result;
+        Ok(())
+    }
+
+    /// Set the SCRAM-SHA-256 verifier of the user's password, which prew
+    /// authenticates them against.
+    pub fn set_pg_password_enc(&mut self, conn: &mut PgConnection, verifier: &str) -> Result<()> {
+        use crate::schema::users::dsl::*;
+        let result = diesel::update(users.find(&self.user_id))
+            .set(pg_password_enc.eq(verifier.as_bytes()))
+            .get_result::<User>(conn)?;
+        *self = result;
+        Ok(())
+    }
+
+    pub fn set_billing_period(&mut self, conn: &mut PgConnection, months: i32) -> Result<()> {
+        use crate::schema::users::dsl::*;
+        let result = diesel::update(users.find(&self.user_id))
+            .set(billing_period_months.eq(months))
+            .get_result::<User>(conn)?;
+        *self = result;
+        Ok(())
+    }
+
+    /// Place the user on the cluster `cluster_name_` (None for the
+    /// configured one), which their role and databases are created on and
+    /// the proxy connects them to. Roles and databases that already exist
+    /// aren't moved.
+    pub fn set_cluster(&mut self, conn: &mut PgConnection, cluster_name_: Option<&str>) -> Result<()> {
+        use crate::schema::users::dsl::*;
+        let result = diesel::update(users.find(&self.user_id))
+            .set(cluster_name.eq(cluster_name_))
+            .get_result::<User>(conn)?;
+        *self = result;
+        Ok(())
+    }
+
     pub fn mark_synced(&mut self, conn: &mut PgConnection) -> Result<()> {
         use crate::schema::users::dsl::*;
         let result = diesel::update(users.find(&self.user_id))
@@ -0,0 +1,306 @@
+//! Authentication of clients by the proxy itself, so that connections of
+//! users who are unknown, disabled or deleted never reach the server.
+//!
+//! Clients authenticate with SCRAM-SHA-256 against the verifier kept in
+//! `users.pg_password_enc`, in the format Postgres keeps in
+//! `pg_authid.rolpassword`, which is also what their role's password is set
+//! to. The proxy then connects to the server with credentials of its own
+//! (the client certificate of `[server_tls]`, or from a host the server
+//! trusts), and the server's AuthenticationOk completes the client's
+//! authentication. The managed clusters' `pg_hba.conf` trusts the proxy's
+//! host for any user only over TLS with a certificate signed by the CA in
+//! `ssl_ca_file`, which `deploy_managed_pg.sh` sets up when `PREW_HOST` is
+//! set.
+//!
+//! Passwords aren't normalized with SASLprep, which makes no difference to
+//! the ASCII passwords `PostgresManager` generates.
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::prew::protocol::{
+    authentication, sasl_response, SaslInitialResponse, AUTHENTICATION_SASL, AUTHENTICATION_SASL_CONTINUE,
+    AUTHENTICATION_SASL_FINAL,
+};
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+/// Iterations of new verifiers, as Postgres uses by default
+const ITERATIONS: u32 = 4096;
+const SALT_LENGTH: usize = 16;
+/// Random bytes of the server's part of an exchange's nonce
+const NONCE_LENGTH: usize = 18;
+/// Longest SASL message accepted from clients
+const MAX_SASL_MESSAGE_LENGTH: usize = 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = vec![0; count];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// SCRAM-SHA-256 secret of a password, formatted as
+/// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScramVerifier {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+impl ScramVerifier {
+    /// Verifier of `password`, with a random salt.
+    pub fn new(password: &str) -> ScramVerifier {
+        ScramVerifier::with_salt(password, random_bytes(SALT_LENGTH), ITERATIONS)
+    }
+
+    pub fn with_salt(password: &str, salt: Vec<u8>, iterations: u32) -> ScramVerifier {
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        ScramVerifier {
+            iterations,
+            salt,
+            stored_key: Sha256::digest(client_key).to_vec(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Verifier no password matches, for users without one. Its salt
+    /// depends only on `username`, so that it doesn't give away that the
+    /// user is unknown by changing between attempts.
+    pub fn unmatchable(username: &str) -> ScramVerifier {
+        let salt = Sha256::digest(format!("impulse:{}", username))[..SALT_LENGTH].to_vec();
+        ScramVerifier {
+            iterations: ITERATIONS,
+            salt,
+            stored_key: random_bytes(32),
+            server_key: random_bytes(32),
+        }
+    }
+
+    pub fn parse(verifier: &str) -> Option<ScramVerifier> {
+        let (method, rest) = verifier.split_once('$')?;
+        if method != SCRAM_SHA_256 {
+            return None;
+        }
+        let (parameters, keys) = rest.split_once('$')?;
+        let (iterations, salt) = parameters.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        Some(ScramVerifier {
+            iterations: iterations.parse().ok()?,
+            salt: BASE64.decode(salt).ok()?,
+            stored_key: BASE64.decode(stored_key).ok()?,
+            server_key: BASE64.decode(server_key).ok()?,
+        })
+    }
+}
+impl Display for ScramVerifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}${}:{}${}:{}",
+            SCRAM_SHA_256,
+            self.iterations,
+            BASE64.encode(&self.salt),
+            BASE64.encode(&self.stored_key),
+            BASE64.encode(&self.server_key),
+        )
+    }
+}
+
+/// Server side of a SCRAM-SHA-256 exchange, between the client's first
+/// message and its final one.
+pub struct ScramExchange {
+    verifier: ScramVerifier,
+    /// GS2 header of the client's first message, which its final one echoes
+    gs2_header: String,
+    nonce: String,
+    client_first_bare: String,
+    server_first: String,
+}
+impl ScramExchange {
+    /// Start an exchange with the client's first message, returning the
+    /// server's first message along with it.
+    pub fn start(verifier: ScramVerifier, client_first: &str) -> Result<(ScramExchange, String)> {
+        ScramExchange::start_with_nonce(verifier, client_first, &BASE64.encode(random_bytes(NONCE_LENGTH)))
+    }
+
+    fn start_with_nonce(
+        verifier: ScramVerifier,
+        client_first: &str,
+        server_nonce: &str,
+    ) -> Result<(ScramExchange, String)> {
+        let mut parts = client_first.splitn(3, ',');
+        let (binding, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(binding), Some(authzid), Some(bare)) => (binding, authzid, bare),
+            _ => return Err(anyhow!("Malformed SCRAM client-first-message")),
+        };
+        // the proxy doesn't offer SCRAM-SHA-256-PLUS, so clients supporting
+        // channel binding say so ("y") but don't use it
+        if binding != "n" && binding != "y" {
+            return Err(anyhow!("Unsupported SCRAM channel binding {}", binding));
+        }
+        if !authzid.is_empty() {
+            return Err(anyhow!("SCRAM authorization identities aren't supported"));
+        }
+        // the user name is the startup message's, so n= is ignored
+        let mut attributes = bare.split(',');
+        let client_nonce = match (attributes.next(), attributes.next()) {
+            (Some(name), Some(nonce)) if name.starts_with("n=") => nonce.strip_prefix("r="),
+            _ => None,
+        };
+        let client_nonce = client_nonce
+            .filter(|nonce| !nonce.is_empty())
+            .context("Malformed SCRAM client-first-message")?;
+        let nonce = format!("{}{}", client_nonce, server_nonce);
+        let server_first = format!("r={},s={},i={}", &nonce, BASE64.encode(&verifier.salt), verifier.iterations);
+        let exchange = ScramExchange {
+            verifier,
+            gs2_header: format!("{},{},", binding, authzid),
+            nonce,
+            client_first_bare: bare.to_string(),
+            server_first: server_first.clone(),
+        };
+        Ok((exchange, server_first))
+    }
+
+    /// Check the client's final message, returning the server's final
+    /// message if the client proved it knows the password, or None if it
+    /// didn't.
+    pub fn finish(&self, client_final: &str) -> Result<Option<String>> {
+        let (without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .context("Malformed SCRAM client-final-message")?;
+        let mut attributes = without_proof.split(',');
+        let binding = attributes.next().and_then(|binding| binding.strip_prefix("c="));
+        if binding.and_then(|binding| BASE64.decode(binding).ok()).as_deref() != Some(self.gs2_header.as_bytes()) {
+            return Err(anyhow!("SCRAM channel binding doesn't match"));
+        }
+        let nonce = attributes.next().and_then(|nonce| nonce.strip_prefix("r="));
+        if nonce != Some(self.nonce.as_str()) {
+            return Err(anyhow!("SCRAM nonce doesn't match"));
+        }
+        let proof = BASE64.decode(proof).context("Malformed SCRAM proof")?;
+        let auth_message = format!("{},{},{}", &self.client_first_bare, &self.server_first, without_proof);
+        let client_signature = hmac(&self.verifier.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Ok(None);
+        }
+        let client_key: Vec<u8> = proof.iter().zip(&client_signature).map(|(a, b)| a ^ b).collect();
+        // compared in constant time
+        let stored_key = Sha256::digest(client_key);
+        let difference = stored_key.iter()
+            .zip(&self.verifier.stored_key)
+            .fold((stored_key.len() != self.verifier.stored_key.len()) as u8, |difference, (a, b)| difference | (a ^ b));
+        if difference != 0 {
+            return Ok(None);
+        }
+        let server_signature = hmac(&self.verifier.server_key, auth_message.as_bytes());
+        Ok(Some(format!("v={}", BASE64.encode(server_signature))))
+    }
+}
+
+/// Read a message of a client during authentication.
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; 5];
+    stream.read_exact(&mut bytes).await?;
+    let length = u32::from_be_bytes(bytes[1..5].try_into()?) as usize;
+    if !(4..=MAX_SASL_MESSAGE_LENGTH).contains(&length) {
+        return Err(anyhow!("Invalid message length {}", length));
+    }
+    bytes.resize(length + 1, 0);
+    stream.read_exact(&mut bytes[5..]).await?;
+    Ok(bytes)
+}
+
+/// Run a SCRAM-SHA-256 exchange with the client of `stream`, returning
+/// whether it proved it knows the password of `verifier`. Fails if the
+/// client breaks off or doesn't follow the protocol.
+pub async fn authenticate<S>(stream: &mut S, verifier: ScramVerifier) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mechanisms = format!("{}\0\0", SCRAM_SHA_256);
+    stream.write_all(&authentication(AUTHENTICATION_SASL, mechanisms.as_bytes())).await?;
+    let message = read_message(stream).await?;
+    let initial = SaslInitialResponse::from_bytes(&message).context("Expected SASLInitialResponse")?;
+    if initial.mechanism != SCRAM_SHA_256 {
+        return Err(anyhow!("Unsupported SASL mechanism {}", &initial.mechanism));
+    }
+    let (exchange, server_first) = ScramExchange::start(verifier, std::str::from_utf8(&initial.data)?)?;
+    stream.write_all(&authentication(AUTHENTICATION_SASL_CONTINUE, server_first.as_bytes())).await?;
+    let message = read_message(stream).await?;
+    let client_final = sasl_response(&message).context("Expected SASLResponse")?;
+    match exchange.finish(std::str::from_utf8(client_final)?)? {
+        Some(server_final) => {
+            stream.write_all(&authentication(AUTHENTICATION_SASL_FINAL, server_final.as_bytes())).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifier_test() {
+        let verifier = ScramVerifier::new("pencil");
+        assert_eq!(verifier.salt.len(), SALT_LENGTH);
+        assert_eq!(ScramVerifier::parse(&verifier.to_string()), Some(verifier.clone()));
+        assert_ne!(ScramVerifier::new("pencil").salt, verifier.salt);
+        assert_eq!(ScramVerifier::parse("md5e8a48653851e28c69d0506508fb27fc5"), None);
+        assert_eq!(ScramVerifier::parse("SCRAM-SHA-256$4096:c2FsdA==$bm90IGJhc2U2NA"), None);
+        assert_eq!(ScramVerifier::unmatchable("alice").salt, ScramVerifier::unmatchable("alice").salt);
+    }
+
+    /// The example exchange of RFC 7677.
+    #[test]
+    fn exchange_test() -> Result<()> {
+        let salt = BASE64.decode("W22ZaJ0SNY7soEsUEjb6gQ==")?;
+        let verifier = ScramVerifier::with_salt("pencil", salt, 4096);
+        let client_first = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+        let (exchange, server_first) = ScramExchange::start_with_nonce(
+            verifier.clone(),
+            client_first,
+            "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0",
+        )?;
+        assert_eq!(
+            server_first,
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+        );
+        let client_final = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+            p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+        assert_eq!(
+            exchange.finish(client_final)?,
+            Some("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_string())
+        );
+        // a proof made with another password
+        let wrong_proof = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+            p=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        assert_eq!(exchange.finish(wrong_proof)?, None);
+        // or for another exchange
+        assert!(exchange.finish("c=biws,r=rOprNGfwEbeRWgbNEkqO,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=").is_err());
+
+        assert!(ScramExchange::start(verifier.clone(), "p=tls-server-end-point,,n=,r=abc").is_err());
+        assert!(ScramExchange::start(verifier.clone(), "n,a=admin,n=,r=abc").is_err());
+        assert!(ScramExchange::start(verifier, "n,,n=").is_err());
+        Ok(())
+    }
+}
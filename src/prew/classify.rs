@@ -0,0 +1,378 @@
+//! Classification of the statements clients send through the proxy, from
+//! their `pg_query` parse tree.
+
+use std::fmt::{Display, Formatter};
+
+use pg_query::protobuf::{ScanToken, Token, VariableSetKind};
+use pg_query::protobuf::a_const::Val;
+use pg_query::{Node, NodeEnum, NodeRef};
+use serde::{Deserialize, Serialize};
+
+/// Settings that would let a session write to a read-only database
+const READ_ONLY_SETTINGS: [&str; 2] = ["default_transaction_read_only", "transaction_read_only"];
+
+/// Classes of statements that a `StatementPolicy` can deny, named in
+/// snake_case in its configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementClass {
+    /// CREATE EXTENSION, of an extension outside the policy's allowlist
+    CreateExtension,
+    AlterSystem,
+    /// COPY to or from a program run on the server
+    CopyProgram,
+    /// COPY to or from a file on the server
+    CopyFile,
+    Load,
+    CreateLanguage,
+    SetSessionAuthorization,
+    SetRole,
+    /// DO blocks, which can run statements of any class dynamically, out of
+    /// sight of the other classes' rules, and so can functions and
+    /// procedures in procedural languages
+    Do,
+}
+
+impl StatementClass {
+    /// The SQL of statements of the class, for messages.
+    pub fn sql(&self) -> &'static str {
+        match self {
+            StatementClass::CreateExtension => "CREATE EXTENSION",
+            StatementClass::AlterSystem => "ALTER SYSTEM",
+            StatementClass::CopyProgram => "COPY ... PROGRAM",
+            StatementClass::CopyFile => "COPY with a server file",
+            StatementClass::Load => "LOAD",
+            StatementClass::CreateLanguage => "CREATE LANGUAGE",
+            StatementClass::SetSessionAuthorization => "SET SESSION AUTHORIZATION",
+            StatementClass::SetRole => "SET ROLE",
+            StatementClass::Do => "DO",
+        }
+    }
+}
+
+/// A statement of one of the `StatementClass`es.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassifiedStatement {
+    pub class: StatementClass,
+    /// Name of the extension, for CreateExtension
+    pub extension: Option<String>,
+    /// Language of the function or procedure, for those classed as Do
+    pub language: Option<String>,
+}
+impl Display for ClassifiedStatement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (&self.extension, &self.language) {
+            (Some(extension), _) => write!(f, "{} \"{}\"", self.class.sql(), extension),
+            (_, Some(language)) => write!(f, "CREATE FUNCTION or PROCEDURE in \"{}\"", language),
+            _ => write!(f, "{}", self.class.sql()),
+        }
+    }
+}
+impl ClassifiedStatement {
+    fn new(class: StatementClass) -> ClassifiedStatement {
+        ClassifiedStatement { class, extension: None, language: None }
+    }
+}
+
+/// Statements of `query` that belong to one of the `StatementClass`es,
+/// None if it can't be parsed. Calls of set_config() count as the SET they
+/// amount to, and as both SET ROLE and SET SESSION AUTHORIZATION if the
+/// setting isn't a literal.
+pub fn classify(query: &str) -> Option<Vec<ClassifiedStatement>> {
+    let parsed = pg_query::parse(query).ok()?;
+    let mut statements: Vec<ClassifiedStatement> = parsed.protobuf.stmts.iter()
+        .filter_map(|raw_stmt| raw_stmt.stmt.as_ref().and_then(|stmt| stmt.node.as_ref()))
+        .filter_map(statement_class)
+        .collect();
+    for setting in set_config_settings(query) {
+        let classes = match setting.as_deref() {
+            Some("role") => &[StatementClass::SetRole][..],
+            Some("session_authorization") => &[StatementClass::SetSessionAuthorization],
+            Some(_) => &[],
+            None => &[StatementClass::SetRole, StatementClass::SetSessionAuthorization],
+        };
+        statements.extend(classes.iter().copied().map(ClassifiedStatement::new));
+    }
+    Some(statements)
+}
+
+fn statement_class(stmt: &NodeEnum) -> Option<ClassifiedStatement> {
+    match stmt {
+        NodeEnum::CreateExtensionStmt(create) => Some(ClassifiedStatement {
+            class: StatementClass::CreateExtension,
+            extension: Some(create.extname.clone()),
+            language: None,
+        }),
+        NodeEnum::AlterSystemStmt(_) => Some(ClassifiedStatement::new(StatementClass::AlterSystem)),
+        NodeEnum::CopyStmt(copy) if copy.is_program => Some(ClassifiedStatement::new(StatementClass::CopyProgram)),
+        // STDIN and STDOUT have no file name
+        NodeEnum::CopyStmt(copy) if !copy.filename.is_empty() => {
+            Some(ClassifiedStatement::new(StatementClass::CopyFile))
+        }
+        NodeEnum::LoadStmt(_) => Some(ClassifiedStatement::new(StatementClass::Load)),
+        NodeEnum::CreatePlangStmt(_) => Some(ClassifiedStatement::new(StatementClass::CreateLanguage)),
+        NodeEnum::DoStmt(_) => Some(ClassifiedStatement::new(StatementClass::Do)),
+        // SQL functions can't run statements built at runtime
+        NodeEnum::CreateFunctionStmt(create) => {
+            let language = create.options.iter().find_map(|option| match option.node.as_ref() {
+                Some(NodeEnum::DefElem(option)) if option.defname == "language" => {
+                    match option.arg.as_ref().and_then(|arg| arg.node.as_ref()) {
+                        Some(NodeEnum::String(language)) => Some(language.sval.to_lowercase()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })?;
+            (language != "sql").then_some(ClassifiedStatement {
+                class: StatementClass::Do,
+                extension: None,
+                language: Some(language),
+            })
+        }
+        // RESET only goes back to the session's own role
+        NodeEnum::VariableSetStmt(set) if set.kind != VariableSetKind::VarReset as i32 => {
+            match set.name.as_str() {
+                "session_authorization" => Some(ClassifiedStatement::new(StatementClass::SetSessionAuthorization)),
+                "role" => Some(ClassifiedStatement::new(StatementClass::SetRole)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether every statement of `query` only reads data, None if it can't be
+/// parsed (the server will reject it anyway).
+///
+/// Read-only statements are queries without `INTO` or locking clauses,
+/// `EXPLAIN`, `COPY ... TO`, prepared statements and cursors of those, and
+/// session statements (`SHOW`, `SET`, transaction control, `LISTEN`...),
+/// except for those that switch the session or transaction to read-write.
+/// Everything else, including any data-modifying statement nested in a
+/// query, is a write.
+///
+/// Functions can still write, so this backs, rather than replaces,
+/// `default_transaction_read_only` on the user's databases.
+pub fn is_read_only(query: &str) -> Option<bool> {
+    let parsed = pg_query::parse(query).ok()?;
+    Some(
+        parsed.protobuf.stmts.iter().all(|raw_stmt| {
+            raw_stmt.stmt.as_ref().and_then(|stmt| stmt.node.as_ref()).is_none_or(statement_is_read_only)
+        }) && !calls_set_config(query)
+    )
+}
+
+/// Whether `query` calls set_config(), which can change any setting,
+/// including the read-only ones, from anywhere in a query.
+fn calls_set_config(query: &str) -> bool {
+    !set_config_settings(query).is_empty()
+}
+
+/// Settings that the set_config() calls of `query` change, lowercased, with
+/// None for those whose setting isn't a string literal. Found from the
+/// query's tokens, since `nodes()` doesn't walk every kind of expression.
+fn set_config_settings(query: &str) -> Vec<Option<String>> {
+    let tokens = match pg_query::scan(query) {
+        Ok(scanned) => scanned.tokens,
+        Err(_) => return vec![],
+    };
+    let text = |token: &ScanToken| query.get(token.start as usize..token.end as usize);
+    let is = |token: Option<&ScanToken>, kind: Token| token.is_some_and(|token| token.token == kind as i32);
+    tokens.iter().enumerate()
+        .filter(|(_, token)| {
+            token.token == Token::Ident as i32
+                && text(token).is_some_and(|name| name.trim_matches('"').eq_ignore_ascii_case("set_config"))
+        })
+        .map(|(i, _)| {
+            let setting = tokens.get(i + 2);
+            let (open, comma) = (tokens.get(i + 1), tokens.get(i + 3));
+            if !is(open, Token::Ascii40) || !is(setting, Token::Sconst) || !is(comma, Token::Ascii44) {
+                return None;
+            }
+            // plain literals only, not escape strings or dollar quotes
+            let literal = text(setting?)?.strip_prefix('\'')?.strip_suffix('\'')?;
+            (!literal.contains('\'')).then(|| literal.to_ascii_lowercase())
+        })
+        .collect()
+}
+
+/// Whether a statement (or the statement it explains, prepares or declares
+/// a cursor for) only reads data.
+fn statement_is_read_only(stmt: &NodeEnum) -> bool {
+    let inner = |query: &Option<Box<Node>>| {
+        query.as_ref().and_then(|query| query.node.as_ref()).is_some_and(statement_is_read_only)
+    };
+    match stmt {
+        NodeEnum::SelectStmt(_) => !stmt.nodes().iter().any(|(node, _, _)| writes(node)),
+        NodeEnum::ExplainStmt(explain) => inner(&explain.query),
+        NodeEnum::PrepareStmt(prepare) => inner(&prepare.query),
+        NodeEnum::DeclareCursorStmt(declare) => inner(&declare.query),
+        NodeEnum::CopyStmt(copy) => {
+            !copy.is_from && !copy.is_program && (copy.query.is_none() || inner(&copy.query))
+        }
+        NodeEnum::VariableSetStmt(set) => {
+            !READ_ONLY_SETTINGS.contains(&set.name.as_str()) && !set.args.iter().any(sets_read_write)
+        }
+        NodeEnum::TransactionStmt(transaction) => !transaction.options.iter().any(sets_read_write),
+        NodeEnum::VariableShowStmt(_)
+        | NodeEnum::ExecuteStmt(_)
+        | NodeEnum::FetchStmt(_)
+        | NodeEnum::ClosePortalStmt(_)
+        | NodeEnum::DeallocateStmt(_)
+        | NodeEnum::DiscardStmt(_)
+        | NodeEnum::ListenStmt(_)
+        | NodeEnum::UnlistenStmt(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether a node within a query writes data.
+fn writes(node: &NodeRef) -> bool {
+    match node {
+        // e.g. in a WITH clause
+        NodeRef::InsertStmt(_)
+        | NodeRef::UpdateStmt(_)
+        | NodeRef::DeleteStmt(_)
+        | NodeRef::MergeStmt(_) => true,
+        NodeRef::SelectStmt(select) => select.into_clause.is_some() || !select.locking_clause.is_empty(),
+        _ => false,
+    }
+}
+
+/// Whether a transaction option is READ WRITE.
+fn sets_read_write(option: &Node) -> bool {
+    match &option.node {
+        Some(NodeEnum::DefElem(option)) => {
+            option.defname == "transaction_read_only"
+                && matches!(
+                    option.arg.as_ref().and_then(|arg| arg.node.as_ref()),
+                    Some(NodeEnum::AConst(value)) if matches!(&value.val, Some(Val::Ival(ival)) if ival.ival == 0),
+                )
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_test() {
+        let class = |query: &str| {
+            classify(query).unwrap().into_iter().map(|statement| statement.class).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            classify("CREATE EXTENSION IF NOT EXISTS pgcrypto"),
+            Some(vec![ClassifiedStatement {
+                class: StatementClass::CreateExtension,
+                extension: Some("pgcrypto".to_string()),
+                language: None,
+            }])
+        );
+        assert_eq!(class("ALTER SYSTEM SET work_mem = '1GB'"), [StatementClass::AlterSystem]);
+        assert_eq!(class("COPY t TO PROGRAM 'cat'"), [StatementClass::CopyProgram]);
+        assert_eq!(class("COPY t FROM '/etc/passwd'"), [StatementClass::CopyFile]);
+        assert_eq!(class("LOAD 'auto_explain'"), [StatementClass::Load]);
+        assert_eq!(class("CREATE LANGUAGE pl HANDLER pl_handler"), [StatementClass::CreateLanguage]);
+        // without a handler, languages are created as extensions
+        assert_eq!(class("CREATE LANGUAGE plperlu"), [StatementClass::CreateExtension]);
+        assert_eq!(class("SET SESSION AUTHORIZATION postgres"), [StatementClass::SetSessionAuthorization]);
+        assert_eq!(class("SET ROLE postgres"), [StatementClass::SetRole]);
+        assert_eq!(
+            class("SELECT 1; LOAD 'x'; SET LOCAL ROLE postgres"),
+            [StatementClass::Load, StatementClass::SetRole]
+        );
+        assert_eq!(class("SELECT set_config('role', 'postgres', false)"), [StatementClass::SetRole]);
+        assert_eq!(
+            class(r#"SELECT 1 WHERE pg_catalog."set_config"('Session_Authorization', 'postgres', true) IS NULL"#),
+            [StatementClass::SetSessionAuthorization]
+        );
+        assert_eq!(
+            class("SELECT set_config(name, 'postgres', false) FROM t"),
+            [StatementClass::SetRole, StatementClass::SetSessionAuthorization]
+        );
+        assert_eq!(
+            class("SELECT set_config(E'role', 'postgres', false)"),
+            [StatementClass::SetRole, StatementClass::SetSessionAuthorization]
+        );
+        assert_eq!(class("DO $$ BEGIN EXECUTE 'SET ROLE postgres'; END $$"), [StatementClass::Do]);
+        // functions and procedures that could do the same
+        let function = "CREATE FUNCTION f() RETURNS void LANGUAGE plpgsql AS $$ BEGIN EXECUTE 'LOAD ''x'''; END $$";
+        assert_eq!(
+            classify(function),
+            Some(vec![ClassifiedStatement {
+                class: StatementClass::Do,
+                extension: None,
+                language: Some("plpgsql".to_string()),
+            }])
+        );
+        assert_eq!(class("CREATE OR REPLACE PROCEDURE p() AS 'BEGIN END' LANGUAGE PLPGSQL"), [StatementClass::Do]);
+        assert_eq!(class("CREATE FUNCTION f() RETURNS int LANGUAGE sql AS 'SELECT 1'"), []);
+        assert_eq!(class("CREATE FUNCTION f() RETURNS int LANGUAGE sql RETURN 1"), []);
+        assert_eq!(class("SELECT set_config('search_path', 'x', false)"), []);
+        for query in ["SELECT 1", "COPY t TO STDOUT", "COPY t FROM STDIN", "RESET ROLE", "SET search_path = x"] {
+            assert_eq!(class(query), [], "{}", query);
+        }
+        assert_eq!(classify("SELEC 1"), None);
+    }
+
+    #[test]
+    fn read_only_test() {
+        for query in [
+            "SELECT * FROM t WHERE a = 1",
+            "WITH x AS (SELECT 1) SELECT * FROM x UNION SELECT 2",
+            "EXPLAIN SELECT * FROM t",
+            "COPY t TO STDOUT",
+            "COPY (SELECT * FROM t) TO STDOUT WITH (FORMAT csv)",
+            "SHOW search_path",
+            "SET search_path TO public",
+            "BEGIN; SELECT 1; COMMIT",
+            "BEGIN READ ONLY",
+            "PREPARE q AS SELECT $1::int",
+            "EXECUTE q(1)",
+            "DECLARE c CURSOR FOR SELECT * FROM t; FETCH 10 FROM c; CLOSE c",
+            "DEALLOCATE ALL",
+            "DISCARD ALL",
+            "LISTEN events",
+        ] {
+            assert_eq!(is_read_only(query), Some(true), "{}", query);
+        }
+    }
+
+    #[test]
+    fn write_test() {
+        for query in [
+            "INSERT INTO t VALUES (1)",
+            "UPDATE t SET a = 2",
+            "DELETE FROM t",
+            "SELECT 1; DELETE FROM t",
+            "WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d",
+            "SELECT * INTO u FROM t",
+            "SELECT * FROM t FOR UPDATE",
+            "SELECT * FROM (SELECT * FROM t FOR SHARE) s",
+            "EXPLAIN ANALYZE INSERT INTO t VALUES (1)",
+            "EXPLAIN CREATE TABLE u AS SELECT 1",
+            "COPY t FROM STDIN",
+            "CREATE TABLE u (a int)",
+            "DROP TABLE t",
+            "TRUNCATE t",
+            "VACUUM t",
+            "GRANT SELECT ON t TO public",
+            "PREPARE q AS INSERT INTO t VALUES ($1)",
+            "DECLARE c CURSOR FOR SELECT * FROM t FOR UPDATE",
+            "DO $$ BEGIN END $$",
+            "CALL p()",
+            "SET default_transaction_read_only = off",
+            "SET SESSION CHARACTERISTICS AS TRANSACTION READ WRITE",
+            "BEGIN READ WRITE",
+            "SET TRANSACTION READ WRITE",
+            "SELECT set_config('default_transaction_read_only', 'off', false)",
+            "SELECT 1 WHERE set_config('transaction_read_only', 'off', true) IS NOT NULL",
+            r#"SELECT pg_catalog."set_config"('transaction_read_only', 'off', true)"#,
+        ] {
+            assert_eq!(is_read_only(query), Some(false), "{}", query);
+        }
+        assert_eq!(is_read_only("SELEC 1"), None);
+    }
+}
@@ -0,0 +1,238 @@
+//! Rewriting of the database named by libpq connection strings, in either
+//! the keyword/value (`host=... dbname=...`) or the URI
+//! (`postgresql://host/dbname`) format.
+
+const URI_SCHEMES: [&str; 2] = ["postgresql://", "postgres://"];
+
+/// Replace the database named by `conninfo` with `rename(dbname)`, returning
+/// the new connection string. None if it isn't a connection string, names
+/// no database, or may connect to another server than the local one.
+pub fn rename_database<F>(conninfo: &str, rename: F) -> Option<String>
+where
+    F: Fn(&str) -> String,
+{
+    match URI_SCHEMES.iter().find(|scheme| conninfo.starts_with(*scheme)) {
+        Some(scheme) => rename_uri_database(scheme, &conninfo[scheme.len()..], rename),
+        None => rename_keyword_database(conninfo, rename),
+    }
+}
+
+/// Whether a host (or hostaddr) setting only names the local server, as a
+/// list of hosts, Unix socket directories or addresses.
+fn is_local_host(hosts: &str) -> bool {
+    hosts.split(',').all(|host| {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        host.is_empty() || host.starts_with('/') || ["localhost", "127.0.0.1", "::1"].contains(&host)
+    })
+}
+
+fn rename_keyword_database<F>(conninfo: &str, rename: F) -> Option<String>
+where
+    F: Fn(&str) -> String,
+{
+    let mut settings = parse_keywords(conninfo)?;
+    let is_local = settings.iter()
+        .filter(|(keyword, _)| keyword == "host" || keyword == "hostaddr")
+        .all(|(_, value)| is_local_host(value));
+    let (_, dbname) = settings.iter_mut().find(|(keyword, _)| keyword == "dbname")?;
+    if !is_local || dbname.contains('=') {
+        // dbname can itself be a connection string
+        return None;
+    }
+    *dbname = rename(dbname);
+    Some(
+        settings.iter()
+            .map(|(keyword, value)| format!("{}='{}'", keyword, value.replace('\\', "\\\\").replace('\'', "\\'")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    )
+}
+
+/// Keywords and values of a keyword/value connection string, None if it
+/// isn't one.
+fn parse_keywords(conninfo: &str) -> Option<Vec<(String, String)>> {
+    let mut settings = vec![];
+    let mut chars = conninfo.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut keyword = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+            keyword.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if keyword.is_empty() || chars.next() != Some('=') {
+            return None;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            loop {
+                match chars.next()? {
+                    '\'' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                if c == '\\' {
+                    value.push(chars.next()?);
+                } else {
+                    value.push(c);
+                }
+            }
+        }
+        settings.push((keyword, value));
+    }
+    (!settings.is_empty()).then_some(settings)
+}
+
+fn rename_uri_database<F>(scheme: &str, rest: &str, rename: F) -> Option<String>
+where
+    F: Fn(&str) -> String,
+{
+    let (location, params) = match rest.split_once('?') {
+        Some((location, params)) => (location, Some(params)),
+        None => (rest, None),
+    };
+    let (authority, path) = match location.split_once('/') {
+        Some((authority, path)) => (authority, Some(path)),
+        None => (location, None),
+    };
+    let hosts = authority.rsplit_once('@').map_or(authority, |(_, hosts)| hosts);
+    let hosts = hosts.split(',')
+        .map(|host| match host.rfind(':') {
+            // leave IPv6 addresses alone
+            Some(index) if !host[index..].contains(']') => &host[..index],
+            _ => host,
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut params = params.map(|params| {
+        params.split('&')
+            .map(|param| match param.split_once('=') {
+                Some((name, value)) => (name.to_string(), percent_decode(value)),
+                None => (param.to_string(), String::new()),
+            })
+            .collect::<Vec<_>>()
+    });
+    let is_local = is_local_host(&percent_decode(&hosts))
+        && params.iter().flatten()
+            .filter(|(name, _)| name == "host" || name == "hostaddr")
+            .all(|(_, value)| is_local_host(value));
+    if !is_local {
+        return None;
+    }
+    // a dbname parameter overrides the path
+    if let Some((_, dbname)) = params.iter_mut().flatten().find(|(name, _)| name == "dbname") {
+        *dbname = rename(dbname);
+    } else {
+        match path {
+            Some(path) if !path.is_empty() => {}
+            _ => return None,
+        }
+    }
+    let path = path
+        .filter(|path| !path.is_empty())
+        .map(|path| percent_encode(&rename(&percent_decode(path))));
+    let mut uri = format!("{}{}", scheme, authority);
+    if let Some(path) = path {
+        uri.push('/');
+        uri.push_str(&path);
+    }
+    if let Some(params) = params {
+        let params = params.iter()
+            .map(|(name, value)| format!("{}={}", name, percent_encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        uri.push('?');
+        uri.push_str(&params);
+    }
+    Some(uri)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = bytes.get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suffix(dbname: &str) -> String {
+        format!("{}__alice", dbname)
+    }
+
+    #[test]
+    fn keyword_conninfo_test() {
+        assert_eq!(rename_database("dbname=shop", suffix), Some("dbname='shop__alice'".to_string()));
+        assert_eq!(
+            rename_database("host=localhost port=5432 dbname = 'my shop' password='it\\'s'", suffix),
+            Some("host='localhost' port='5432' dbname='my shop__alice' password='it\\'s'".to_string())
+        );
+        assert_eq!(
+            rename_database("host=/var/run/postgresql dbname=shop", suffix),
+            Some("host='/var/run/postgresql' dbname='shop__alice'".to_string())
+        );
+        // other servers, no database, and strings that aren't connection strings
+        assert_eq!(rename_database("host=db.example.com dbname=shop", suffix), None);
+        assert_eq!(rename_database("hostaddr=10.0.0.1 dbname=shop", suffix), None);
+        assert_eq!(rename_database("user=alice", suffix), None);
+        assert_eq!(rename_database("SELECT a = 1", suffix), None);
+        assert_eq!(rename_database("myconnection", suffix), None);
+        assert_eq!(rename_database("dbname='unterminated", suffix), None);
+    }
+
+    #[test]
+    fn uri_conninfo_test() {
+        assert_eq!(
+            rename_database("postgresql://localhost/shop", suffix),
+            Some("postgresql://localhost/shop__alice".to_string())
+        );
+        assert_eq!(
+            rename_database("postgres://alice:pw@127.0.0.1:5432/my%20shop?sslmode=disable", suffix),
+            Some("postgres://alice:pw@127.0.0.1:5432/my%20shop__alice?sslmode=disable".to_string())
+        );
+        assert_eq!(
+            rename_database("postgresql:///shop?host=%2Fvar%2Frun%2Fpostgresql", suffix),
+            Some("postgresql:///shop__alice?host=%2Fvar%2Frun%2Fpostgresql".to_string())
+        );
+        assert_eq!(
+            rename_database("postgresql://[::1]:5432?dbname=shop", suffix),
+            Some("postgresql://[::1]:5432?dbname=shop__alice".to_string())
+        );
+        assert_eq!(rename_database("postgresql://db.example.com/shop", suffix), None);
+        assert_eq!(rename_database("postgresql://localhost/shop?host=db.example.com", suffix), None);
+        assert_eq!(rename_database("postgresql://localhost", suffix), None);
+    }
+}
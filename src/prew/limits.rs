@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::models::charges::ChargeType;
+use crate::models::reports::PacketDirection;
+
+/// How long a user's quota is trusted before it's estimated again from
+/// their balance
+const QUOTA_REFRESH: Duration = Duration::from_secs(60);
+
+
+/// Counts the open connections of each user across all sessions of the
+/// proxy, so that users can be held to their plan's connection limit.
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    connections: SyncMutex<HashMap<String, i32>>,
+}
+impl ConnectionLimiter {
+    pub fn new() -> ConnectionLimiter {
+        ConnectionLimiter::default()
+    }
+
+    /// Count a new connection of `username`, unless they already have
+    /// `limit` connections open (None for no limit).
+    ///
+    /// The connection is counted until the returned permit is dropped.
+    pub fn acquire(self: &Arc<Self>, username: &str, limit: Option<i32>) -> Option<ConnectionPermit> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(username.to_string()).or_insert(0);
+        if let Some(limit) = limit {
+            if *count >= limit {
+                debug!("{} reached their limit of {} connections", username, limit);
+                return None;
+            }
+        }
+        *count += 1;
+        Some(ConnectionPermit { limiter: self.clone(), username: username.to_string() })
+    }
+
+    /// Number of connections of `username` currently open.
+    pub fn connections(&self, username: &str) -> i32 {
+        self.connections.lock().unwrap().get(username).copied().unwrap_or(0)
+    }
+}
+
+/// One connection counted by a `ConnectionLimiter`.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    username: String,
+}
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.username) {
+            *count -= 1;
+            if *count <= 0 {
+                connections.remove(&self.username);
+            }
+        }
+    }
+}
+
+/// Token bucket limiting a byte rate, allowing bursts of up to one second of
+/// traffic.
+///
+/// Bytes are always taken from the bucket, which can go into debt; the
+/// caller then waits for the debt to be paid off before passing the bytes
+/// on. Concurrent callers sharing the bucket queue up behind each other's
+/// debt.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_second: i64,
+    /// Available bytes, negative while in debt, as of the given instant
+    state: SyncMutex<(f64, Instant)>,
+}
+impl TokenBucket {
+    pub fn new(bytes_per_second: i64) -> TokenBucket {
+        TokenBucket {
+            bytes_per_second,
+            state: SyncMutex::new((bytes_per_second as f64, Instant::now())),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> i64 {
+        self.bytes_per_second
+    }
+
+    /// Take `count` bytes, returning how long to wait before passing them on.
+    pub fn take(&self, count: usize) -> Duration {
+        self.take_at(count, Instant::now())
+    }
+
+    fn take_at(&self, count: usize, now: Instant) -> Duration {
+        let rate = self.bytes_per_second as f64;
+        let mut state = self.state.lock().unwrap();
+        let (available, updated) = *state;
+        let refilled = now.saturating_duration_since(updated).as_secs_f64() * rate;
+        let available = (available + refilled).min(rate) - count as f64;
+        *state = (available, now.max(updated));
+        if available >= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-available / rate)
+        }
+    }
+}
+
+/// Bandwidth of one user through the proxy, shared by all of their
+/// connections.
+#[derive(Debug)]
+pub struct UserBandwidth {
+    incoming: Option<TokenBucket>,
+    outgoing: Option<TokenBucket>,
+}
+impl UserBandwidth {
+    /// Take `count` bytes received from the client (`Forward`) or the
+    /// server (`Backward`), returning how long to wait before passing them
+    /// on.
+    pub fn take(&self, direction: PacketDirection, count: usize) -> Duration {
+        let bucket = match direction {
+            PacketDirection::Forward => &self.incoming,
+            PacketDirection::Backward => &self.outgoing,
+        };
+        bucket.as_ref().map_or(Duration::ZERO, |bucket| bucket.take(count))
+    }
+
+    fn has_rates(&self, bytes_in_per_second: Option<i64>, bytes_out_per_second: Option<i64>) -> bool {
+        self.incoming.as_ref().map(TokenBucket::bytes_per_second) == bytes_in_per_second
+            && self.outgoing.as_ref().map(TokenBucket::bytes_per_second) == bytes_out_per_second
+    }
+}
+
+/// Bandwidth of each user across all sessions of the proxy.
+#[derive(Debug, Default)]
+pub struct BandwidthLimiter {
+    users: SyncMutex<HashMap<String, Arc<UserBandwidth>>>,
+}
+impl BandwidthLimiter {
+    pub fn new() -> BandwidthLimiter {
+        BandwidthLimiter::default()
+    }
+
+    /// Bandwidth of `username` with the given rates (None for no limit), or
+    /// None if neither direction is limited.
+    ///
+    /// The user's connections share their bandwidth for as long as their
+    /// rates stay the same.
+    pub fn for_user(
+        &self,
+        username: &str,
+        bytes_in_per_second: Option<i64>,
+        bytes_out_per_second: Option<i64>,
+    ) -> Option<Arc<UserBandwidth>> {
+        let mut users = self.users.lock().unwrap();
+        if bytes_in_per_second.is_none() && bytes_out_per_second.is_none() {
+            users.remove(username);
+            return None;
+        }
+        match users.get(username) {
+            Some(bandwidth) if bandwidth.has_rates(bytes_in_per_second, bytes_out_per_second) => {
+                Some(bandwidth.clone())
+            }
+            _ => {
+                let bandwidth = Arc::new(UserBandwidth {
+                    incoming: bytes_in_per_second.map(TokenBucket::new),
+                    outgoing: bytes_out_per_second.map(TokenBucket::new),
+                });
+                users.insert(username.to_string(), bandwidth.clone());
+                Some(bandwidth)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct QuotaState {
+    remaining: f64,
+    estimated_at: Option<Instant>,
+}
+
+/// What a user can still spend, counted down live as their traffic passes
+/// through the proxy and shared by all of their connections.
+///
+/// The quota starts from an estimate of the user's remaining balance, which
+/// is refreshed once it's older than `QUOTA_REFRESH`, so that new charges
+/// and deposits are taken into account.
+#[derive(Debug, Default)]
+pub struct UserQuota {
+    state: SyncMutex<QuotaState>,
+}
+impl UserQuota {
+    pub fn new() -> UserQuota {
+        UserQuota::default()
+    }
+
+    /// Whether the quota needs a new estimate of the user's balance.
+    pub fn is_stale(&self) -> bool {
+        self.state.lock().unwrap().estimated_at
+            .is_none_or(|estimated_at| estimated_at.elapsed() >= QUOTA_REFRESH)
+    }
+
+    pub fn set_estimate(&self, remaining: f64) {
+        *self.state.lock().unwrap() = QuotaState { remaining, estimated_at: Some(Instant::now()) };
+    }
+
+    /// Deduct the cost of `count` bytes received from the client
+    /// (`Forward`) or the server (`Backward`).
+    pub fn spend(&self, direction: PacketDirection, count: usize) {
+        let charge_type = match direction {
+            PacketDirection::Forward => ChargeType::DataTransferInBytes,
+            PacketDirection::Backward => ChargeType::DataTransferOutBytes,
+        };
+        self.state.lock().unwrap().remaining -= count as f64 * charge_type.rate();
+    }
+
+    pub fn remaining(&self) -> f64 {
+        self.state.lock().unwrap().remaining
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining() < 0.
+    }
+}
+/// Quota of each user across all sessions of the proxy.
+#[derive(Debug, Default)]
+pub struct QuotaLimiter {
+    users: SyncMutex<HashMap<String, Arc<UserQuota>>>,
+}
+impl QuotaLimiter {
+    pub fn new() -> QuotaLimiter {
+        QuotaLimiter::default()
+    }
+
+    pub fn for_user(&self, username: &str) -> Arc<UserQuota> {
+        self.users.lock().unwrap().entry(username.to_string()).or_default().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_test() {
+        let bucket = TokenBucket::new(1000);
+        let start = *bucket.state.lock().unwrap();
+        let start = start.1;
+        // a full second of burst
+        assert_eq!(bucket.take_at(1000, start), Duration::ZERO);
+        // then bytes have to wait for the bucket to refill
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(1000));
+        // the debt is paid off over time
+        assert_eq!(bucket.take_at(0, start + Duration::from_millis(1000)), Duration::ZERO);
+        // and the bucket never holds more than a second of traffic
+        assert_eq!(bucket.take_at(1500, start + Duration::from_secs(10)), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn bandwidth_limiter_test() {
+        let limiter = BandwidthLimiter::new();
+        assert!(limiter.for_user("alice", None, None).is_none());
+        let bandwidth = limiter.for_user("alice", Some(100), None).unwrap();
+        assert!(Arc::ptr_eq(&bandwidth, &limiter.for_user("alice", Some(100), None).unwrap()));
+        assert_eq!(bandwidth.take(PacketDirection::Backward, 1_000_000), Duration::ZERO);
+        // changed rates start a new bucket
+        let changed = limiter.for_user("alice", Some(100), Some(100)).unwrap();
+        assert!(!Arc::ptr_eq(&bandwidth, &changed));
+    }
+
+    #[test]
+    fn user_quota_test() {
+        let limiter = QuotaLimiter::new();
+        let quota = limiter.for_user("alice");
+        assert!(Arc::ptr_eq(&quota, &limiter.for_user("alice")));
+        assert!(quota.is_stale());
+        let rate = ChargeType::DataTransferOutBytes.rate();
+        quota.set_estimate(3. * rate);
+        assert!(!quota.is_stale());
+        assert!(!quota.is_exhausted());
+        quota.spend(PacketDirection::Backward, 2);
+        assert!(!quota.is_exhausted());
+        quota.spend(PacketDirection::Backward, 2);
+        assert!(quota.is_exhausted());
+    }
+}
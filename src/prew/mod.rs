@@ -0,0 +1,715 @@
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+// use diesel::pg::Pg;
+use diesel::prelude::*;
+// use diesel::r2d2::{ConnectionManager, Pool};
+use futures::lock::Mutex;
+use log::{debug, error, info};
+use pg_query::protobuf::a_const::Val;
+use pg_query::protobuf::{AConst, FuncCall, ObjectType, ReindexObjectType};
+use pg_query::{Node, NodeEnum, NodeMut};
+
+use prew::{MessageEncoder, NoFilter, Parser, PostgresParser, Reporter, PostgresqlPacket, Transformer};
+use prew::packet::{Direction, Packet};
+use prew::rule::{AuthenticationContext, RuleSetSession};
+use prew::postgresql::{PostgresqlPacketInfo, QueryMessage};
+
+use crate::models::reports::{NewReport, PacketDirection, PostgresqlPacketType};
+use crate::prew::policy::StatementPolicy;
+use crate::prew::protocol::{data_row_values, ParseMessage};
+use crate::prew::proxy::{ProxyLimits, ProxySession, SessionFactory};
+use crate::prew::results::{ResultColumn, ResultRewriter, ResultTracker, rewrite_data_row};
+use crate::prew::sessions::SessionTracker;
+use crate::prew::stats::QueryTracker;
+use crate::prew::timing::SessionTimer;
+
+pub mod auth;
+pub mod classify;
+pub mod conninfo;
+pub mod limits;
+pub mod policy;
+pub mod pool;
+pub mod protocol;
+pub mod proxy;
+pub mod results;
+pub mod roles;
+pub mod sessions;
+pub mod stats;
+pub mod timing;
+pub mod tls;
+
+/// Templates that new databases of any user can be created from
+const SHARED_TEMPLATES: [&str; 2] = ["template0", "template1"];
+
+/// Run a database write for a session in the background so the connection
+/// isn't held up by it.
+pub(crate) fn spawn_db_write<F>(conn: &Arc<Mutex<PgConnection>>, description: String, write: F)
+where
+    F: FnOnce(&mut PgConnection) -> Result<()> + Send + 'static
+{
+    let mutex = conn.clone();
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn(async move {
+                let mut conn = mutex.lock().await;
+                if let Err(error) = write(&mut conn) {
+                    error!("Unable to record {} - {:?}", &description, &error);
+                }
+            });
+        }
+        Err(_) => error!("No runtime to record {}", &description),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ReporterContext {
+    // pool: Pool<ConnectionManager<PgConnection>>,
+    conn: Arc<Mutex<PgConnection>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Context {
+    authinfo: AuthenticationContext,
+    reporter_context: ReporterContext,
+    session: Arc<SessionTracker>,
+    timer: Arc<SessionTimer>,
+    query_tracker: Arc<QueryTracker>,
+    result_tracker: Arc<ResultTracker>,
+}
+impl prew::rule::WithAuthenticationContext for Context {
+    fn authinfo(&mut self) -> &mut AuthenticationContext {
+        &mut self.authinfo
+    }
+}
+impl Context {
+    /// Context of a new proxy session for a client connecting from
+    /// `client_addr`.
+    pub fn new(conn_str: String, client_addr: Option<String>) -> Result<Context> {
+        // let manager = ConnectionManager::<PgConnection>::new(conn_str);
+        // let pool = Pool::builder().build(manager)?;
+        let mut conn = PgConnection::establish(&conn_str)?;
+        let session = SessionTracker::start(&mut conn, client_addr)?;
+        let conn = Arc::new(Mutex::new(conn));
+        Ok(
+            Context{
+                authinfo: AuthenticationContext {
+                    authenticated: false,
+                    username: None,
+                },
+                session: Arc::new(SessionTracker::new(conn.clone(), session.session_id)),
+                timer: Arc::new(SessionTimer::new(conn.clone(), session.session_id)),
+                query_tracker: Arc::new(QueryTracker::new(conn.clone())),
+                result_tracker: Arc::new(ResultTracker::new()),
+                reporter_context: ReporterContext {
+                    conn,
+                },
+            }
+        )
+    }
+
+    pub fn session(&self) -> Arc<SessionTracker> {
+        self.session.clone()
+    }
+}
+
+/// prew's parser, except that messages typed `D` which aren't DataRows are
+/// left unparsed: prew reads them all as DataRows, and panics on the
+/// client's Describe messages.
+#[derive(Clone, Default)]
+pub struct ImpulseParser {
+}
+impl ImpulseParser {
+    pub fn new() -> ImpulseParser {
+        ImpulseParser {}
+    }
+}
+impl Parser<PostgresqlPacket, Context> for ImpulseParser {
+    fn parse(&self, packet: &Packet, context: &mut Context) -> Result<PostgresqlPacket> {
+        if packet.bytes.first() == Some(&b'D') && data_row_values(&packet.bytes).is_none() {
+            return Ok(PostgresqlPacket::new(PostgresqlPacketInfo::Other, Some(packet.bytes.clone())));
+        }
+        PostgresParser::new().parse(packet, context)
+    }
+}
+
+/// Create the sessions of proxied connections, reporting to the impulse
+/// database at `report_connstr`, rewriting messages with `transformer`
+/// (from the client) and `out_transformer` (from the server), and refusing
+/// statements that `policy` denies.
+pub fn session_factory<X, OX>(
+    report_connstr: String,
+    transformer: X,
+    out_transformer: OX,
+    policy: StatementPolicy,
+) -> Arc<SessionFactory>
+where
+    X: Transformer<PostgresqlPacket, Context> + Clone + Send + Sync + 'static,
+    OX: Transformer<PostgresqlPacket, Context> + Clone + Send + Sync + 'static,
+{
+    let parser = ImpulseParser::new();
+    let filter = NoFilter::new();
+    let encoder = MessageEncoder::new();
+    let reporter = ImpulseReporter::new();
+    let limits = Arc::new(ProxyLimits::with_policy(policy));
+    Arc::new(move |client_addr| {
+        let context = Context::new(report_connstr.clone(), client_addr.map(|addr| addr.to_string()))?;
+        let tracker = context.session();
+        let session = RuleSetSession::new(
+            &parser,
+            &filter,
+            &transformer,
+            &out_transformer,
+            &encoder,
+            &reporter,
+            &context,
+        );
+        Ok(ProxySession::new(
+            Arc::new(Mutex::new(session)),
+            tracker,
+            context.reporter_context.conn.clone(),
+            limits.clone(),
+        ))
+    })
+}
+
+#[derive(Clone)]
+pub struct ImpulseReporter {
+}
+
+impl ImpulseReporter {
+    pub fn new() -> ImpulseReporter {
+        ImpulseReporter { }
+    }
+}
+#[async_trait]
+impl Reporter<PostgresqlPacket, Context> for ImpulseReporter {
+    fn report(
+        &self,
+        message: &PostgresqlPacket,
+        direction: Direction,
+        context: &Context
+    ) -> Result<()> {
+        let packet_info = serde_json::to_value(&message.info)?;
+        let bytes = message.bytes.clone();
+        let packet_type: PostgresqlPacketType = (&message.info).into();
+        let direction: PacketDirection = direction.into();
+        let authinfo = &context.authinfo;
+        let username;
+        if authinfo.authenticated {
+            username = authinfo.username.clone();
+            info!("User is authenticated, username {}", username.as_ref().unwrap());
+        } else {
+            username = None;
+        }
+        context.session.observe(message);
+        context.timer.observe(message, direction, username.as_ref());
+        context.query_tracker.observe(message, direction, username.as_ref());
+        if let Some(bytes) = &message.bytes {
+            context.result_tracker.observe(bytes, direction);
+        }
+        // let mut conn = context.reporter_context.pool.get()?;
+        let mutex = context.reporter_context.conn.clone();
+        let session_id = context.session.session_id();
+        tokio::spawn(async move {
+            let report = NewReport::create(
+                username,
+                packet_type,
+                Some(direction),
+                Some(packet_info),
+                bytes,
+                false
+            ).with_session(session_id);
+            {
+                // mutex scope
+                let mut conn = mutex.lock().await;
+                if let Err(error) = report.commit(&mut conn) {
+                    error!("Unable to report on packet: {:?} - {:?}", &report.packet_info, &error);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Removes the `__username` suffix added by `AppendUserNameTransformer` from
+/// the database and role names in the rows returned to the user, leaving
+/// the user's own data alone.
+#[derive(Clone)]
+pub struct RemoveAppendedUserNameTransformer {
+}
+
+impl RemoveAppendedUserNameTransformer {
+    pub fn new() -> RemoveAppendedUserNameTransformer {
+        RemoveAppendedUserNameTransformer {}
+    }
+}
+impl ResultRewriter for RemoveAppendedUserNameTransformer {
+    /// Names are sent as is in both text and binary format.
+    fn rewrites(&self, column: &ResultColumn) -> bool {
+        column.holds_names()
+    }
+
+    fn rewrite(&self, _column: &ResultColumn, value: &[u8], username: &str) -> Option<Vec<u8>> {
+        let suffix = format!("__{}", username).into_bytes();
+        value.strip_suffix(suffix.as_slice()).map(|name| name.to_vec())
+    }
+}
+impl Transformer<PostgresqlPacket, Context> for RemoveAppendedUserNameTransformer {
+    fn transform(&self, packet: &PostgresqlPacket, context: &Context) -> Result<PostgresqlPacket> {
+        rewrite_results(packet, context, &[self])
+    }
+}
+
+/// Rewrite the DataRow messages among the packets from the server with
+/// `rewriters`, passing other packets on as they are.
+pub fn rewrite_results(
+    packet: &PostgresqlPacket,
+    context: &Context,
+    rewriters: &[&dyn ResultRewriter],
+) -> Result<PostgresqlPacket> {
+    if let (Some(username), PostgresqlPacketInfo::DataRow(_), Some(bytes)) =
+        (&context.authinfo.username, &packet.info, &packet.bytes)
+    {
+        let columns = context.result_tracker.columns();
+        if let Some(bytes) = rewrite_data_row(bytes, &columns, rewriters, username) {
+            return Ok(PostgresqlPacket::new(packet.info.clone(), Some(bytes)));
+        }
+    }
+    Ok(packet.clone())
+}
+
+#[derive(Clone)]
+pub struct AppendUserNameTransformer {
+}
+
+impl AppendUserNameTransformer {
+    pub fn new() -> AppendUserNameTransformer {
+        AppendUserNameTransformer {}
+    }
+
+    /// Rewrite the database and role names in `query` into the namespace
+    /// of `username`, returning the new query, or None if it names neither.
+    fn transform_query(&self, query: &str, username: &str) -> Result<Option<String>> {
+        let mut parsed = pg_query::parse(query)
+            .map_err(|_| anyhow!("Couldn't parser query: {}", query))?;
+        let mut modified = roles::replace_role_statements(&mut parsed.protobuf.stmts, username);
+        unsafe {
+            for (node, _depth, _context) in parsed.protobuf.nodes_mut().into_iter() {
+                modified = self.transform_stmt(node, username) || modified;
+                modified = roles::transform_role_names(node, username) || modified;
+            }
+        }
+        match modified {
+            true => Ok(Some(parsed.deparse()?)),
+            false => Ok(None),
+        }
+    }
+
+    unsafe fn transform_stmt(&self, node: NodeMut, username: &str) -> bool {
+        let mut modified = false;
+        match node {
+            NodeMut::CreatedbStmt(stmt) => {
+                let new_dbname = self.transform_dbname(&(*stmt).dbname, username);
+                (*stmt).dbname = new_dbname;
+                for option in (*stmt).options.iter_mut() {
+                    if let Some(NodeEnum::DefElem(option)) = option.node.as_mut() {
+                        if option.defname == "template" {
+                            if let Some(template) = option.arg.as_mut() {
+                                self.transform_template_node(template, username);
+                            }
+                        }
+                    }
+                }
+                modified = true;
+            }
+            NodeMut::DropdbStmt(stmt) => {
+                let new_dbname = self.transform_dbname(&(*stmt).dbname, username);
+                (*stmt).dbname = new_dbname;
+                modified = true;
+            }
+            NodeMut::AlterDatabaseSetStmt(stmt) => {
+                let new_dbname = self.transform_dbname(&(*stmt).dbname, username);
+                (*stmt).dbname = new_dbname;
+                modified = true;
+            },
+            NodeMut::AlterDatabaseStmt(stmt) => {
+                let new_dbname = self.transform_dbname(&(*stmt).dbname, username);
+                (*stmt).dbname = new_dbname;
+                modified = true;
+            }
+            // ALTER DATABASE ... REFRESH COLLATION VERSION can't be deparsed,
+            // so it's passed on as is; only the owner can run it anyway
+            NodeMut::RenameStmt(stmt) if (*stmt).rename_type == ObjectType::ObjectDatabase as i32 => {
+                let stmt = &mut *stmt;
+                stmt.subname = self.transform_dbname(&stmt.subname, username);
+                stmt.newname = self.transform_dbname(&stmt.newname, username);
+                modified = true;
+            }
+            NodeMut::AlterOwnerStmt(stmt) if (*stmt).object_type == ObjectType::ObjectDatabase as i32 => {
+                let stmt = &mut *stmt;
+                if let Some(object) = stmt.object.as_mut() {
+                    modified = self.transform_dbname_node(object, username);
+                }
+            }
+            NodeMut::GrantStmt(stmt) if (*stmt).objtype == ObjectType::ObjectDatabase as i32 => {
+                let stmt = &mut *stmt;
+                for object in stmt.objects.iter_mut() {
+                    modified = self.transform_dbname_node(object, username) || modified;
+                }
+            }
+            NodeMut::CommentStmt(stmt) if (*stmt).objtype == ObjectType::ObjectDatabase as i32 => {
+                let stmt = &mut *stmt;
+                if let Some(object) = stmt.object.as_mut() {
+                    modified = self.transform_dbname_node(object, username);
+                }
+            }
+            NodeMut::SecLabelStmt(stmt) if (*stmt).objtype == ObjectType::ObjectDatabase as i32 => {
+                let stmt = &mut *stmt;
+                if let Some(object) = stmt.object.as_mut() {
+                    modified = self.transform_dbname_node(object, username);
+                }
+            }
+            NodeMut::AlterRoleSetStmt(stmt) => {
+                let stmt = &mut *stmt;
+                // the database is optional, and means all of them
+                if !stmt.database.is_empty() {
+                    stmt.database = self.transform_dbname(&stmt.database, username);
+                    modified = true;
+                }
+            }
+            NodeMut::ReindexStmt(stmt) => {
+                let stmt = &mut *stmt;
+                let kind = stmt.kind;
+                // the name is optional, and means the current database
+                let names_database = kind == ReindexObjectType::ReindexObjectDatabase as i32
+                    || kind == ReindexObjectType::ReindexObjectSystem as i32;
+                if names_database && !stmt.name.is_empty() {
+                    stmt.name = self.transform_dbname(&stmt.name, username);
+                    modified = true;
+                }
+            }
+            NodeMut::CreateForeignServerStmt(stmt) => {
+                let stmt = &mut *stmt;
+                modified = self.transform_server_options(&mut stmt.options, username);
+            }
+            NodeMut::AlterForeignServerStmt(stmt) => {
+                let stmt = &mut *stmt;
+                modified = self.transform_server_options(&mut stmt.options, username);
+            }
+            NodeMut::CreateSubscriptionStmt(stmt) => {
+                let stmt = &mut *stmt;
+                if let Some(conninfo) = self.transform_conninfo(&stmt.conninfo, username) {
+                    stmt.conninfo = conninfo;
+                    modified = true;
+                }
+            }
+            NodeMut::AlterSubscriptionStmt(stmt) => {
+                let stmt = &mut *stmt;
+                if let Some(conninfo) = self.transform_conninfo(&stmt.conninfo, username) {
+                    stmt.conninfo = conninfo;
+                    modified = true;
+                }
+            }
+            // dblink functions take connection strings or names of
+            // connections, and only the former are rewritten
+            NodeMut::FuncCall(call) if Self::is_dblink_call(&*call) => {
+                let call = &mut *call;
+                for arg in call.args.iter_mut() {
+                    if let Some(NodeEnum::AConst(AConst { val: Some(Val::Sval(value)), .. })) = arg.node.as_mut() {
+                        if let Some(conninfo) = self.transform_conninfo(&value.sval, username) {
+                            value.sval = conninfo;
+                            modified = true;
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        modified
+    }
+
+    /// Rename the database named by a String node, returning whether it
+    /// was one.
+    fn transform_dbname_node(&self, node: &mut Node, username: &str) -> bool {
+        match node.node.as_mut() {
+            Some(NodeEnum::String(name)) => {
+                name.sval = self.transform_dbname(&name.sval, username);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Rename the template database named by a String node, unless it's
+    /// one of the templates shared by all users.
+    fn transform_template_node(&self, node: &mut Node, username: &str) {
+        if let Some(NodeEnum::String(name)) = node.node.as_mut() {
+            if !SHARED_TEMPLATES.contains(&name.sval.as_str()) {
+                name.sval = self.transform_dbname(&name.sval, username);
+            }
+        }
+    }
+
+    /// Rename the database of a foreign server's options, if they don't
+    /// point it to another server.
+    fn transform_server_options(&self, options: &mut [Node], username: &str) -> bool {
+        let is_local = options.iter().all(|option| match option.node.as_ref() {
+            Some(NodeEnum::DefElem(option)) if option.defname == "host" || option.defname == "hostaddr" => {
+                let value = match option.arg.as_ref().and_then(|arg| arg.node.as_ref()) {
+                    Some(NodeEnum::String(value)) => value.sval.as_str(),
+                    _ => "",
+                };
+                ["", "localhost", "127.0.0.1", "::1"].contains(&value) || value.starts_with('/')
+            }
+            _ => true,
+        });
+        let mut modified = false;
+        for option in options.iter_mut() {
+            if let Some(NodeEnum::DefElem(option)) = option.node.as_mut() {
+                if is_local && option.defname == "dbname" {
+                    if let Some(dbname) = option.arg.as_mut() {
+                        modified = self.transform_dbname_node(dbname, username) || modified;
+                    }
+                }
+            }
+        }
+        modified
+    }
+
+    fn transform_conninfo(&self, conninfo: &str, username: &str) -> Option<String> {
+        conninfo::rename_database(conninfo, |dbname| self.transform_dbname(dbname, username))
+    }
+
+    fn is_dblink_call(call: &FuncCall) -> bool {
+        matches!(
+            call.funcname.last().and_then(|name| name.node.as_ref()),
+            Some(NodeEnum::String(name)) if name.sval.starts_with("dblink"),
+        )
+    }
+
+    /// Rewrite the query of a Parse message like a simple query's,
+    /// returning the new message if the query changed.
+    fn transform_parse(&self, bytes: &[u8], username: &str) -> Result<Option<Vec<u8>>> {
+        let parse = match ParseMessage::from_bytes(bytes) {
+            Some(parse) => parse,
+            None => return Ok(None),
+        };
+        let new_query = match self.transform_query(&parse.query, username)? {
+            Some(new_query) => new_query,
+            None => return Ok(None),
+        };
+        debug!("New prepared query: {}", &new_query);
+        Ok(ParseMessage::replace_query(bytes, &new_query))
+    }
+
+    fn transform_dbname(&self, database_name: &str, username: &str) -> String {
+        if database_name.eq(username) {
+            // don't modify database name if the user is connecting to
+            // or modifying their own database
+            database_name.to_string()
+        } else {
+            format!("{}__{}", database_name, username)
+        }
+    }
+}
+
+impl Transformer<PostgresqlPacket, Context> for AppendUserNameTransformer {
+    fn transform(&self, packet: &PostgresqlPacket, context: &Context) -> Result<PostgresqlPacket> {
+        if let PostgresqlPacketInfo::Startup(message) = &packet.info {
+            let dbname = message.get_parameter("database")
+                .ok_or_else(|| anyhow!("Database name missing from startup message"))?;
+            let username = message.get_parameter("user")
+                .ok_or_else(|| anyhow!("Username missing from startup message"))?;
+            let newdbname = self.transform_dbname(&dbname, &username);
+            let mut message = message.clone();
+            message.set_parameter("database", newdbname);
+            Ok(PostgresqlPacket { info: PostgresqlPacketInfo::Startup(message), bytes: None })
+        } else if let PostgresqlPacketInfo::Query(message) = &packet.info {
+            if let Some(username) = &context.authinfo.username {
+                match self.transform_query(&message.query, username)? {
+                    Some(new_query) => {
+                        debug!("New query: {}", &new_query);
+                        Ok(PostgresqlPacket {
+                            info: PostgresqlPacketInfo::Query(QueryMessage::from_query(new_query)),
+                            bytes: None,
+                        })
+                    }
+                    None => Ok(packet.clone()),
+                }
+            } else {
+                Err(anyhow!("Expected auth context to be set for query message: {}", message.query))
+            }
+        } else if let Some(bytes) = packet.bytes.as_ref().filter(|bytes| bytes.first() == Some(&b'P')) {
+            let username = context.authinfo.username.as_ref()
+                .ok_or_else(|| anyhow!("Expected auth context to be set for parse message"))?;
+            match self.transform_parse(bytes, username)? {
+                Some(bytes) => Ok(PostgresqlPacket { info: PostgresqlPacketInfo::Other, bytes: Some(bytes) }),
+                None => Ok(packet.clone()),
+            }
+        } else {
+            Ok(packet.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(query: &str) -> Option<String> {
+        AppendUserNameTransformer::new().transform_query(query, "alice").unwrap()
+    }
+
+    /// Parse message preparing `query` as the unnamed statement.
+    fn parse_message(query: &str) -> Vec<u8> {
+        let mut body = vec![0];
+        body.extend(query.as_bytes());
+        body.extend([0, 0, 0]);
+        let mut bytes = vec![b'P'];
+        bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    fn transform_parse(query: &str) -> Option<Vec<u8>> {
+        AppendUserNameTransformer::new().transform_parse(&parse_message(query), "alice").unwrap()
+    }
+
+    #[test]
+    fn database_statements_test() {
+        for (query, expected) in [
+            ("CREATE DATABASE shop", "CREATE DATABASE shop__alice"),
+            ("CREATE DATABASE shop TEMPLATE base", "CREATE DATABASE shop__alice TEMPLATE base__alice"),
+            ("CREATE DATABASE shop TEMPLATE template0", "CREATE DATABASE shop__alice TEMPLATE template0"),
+            ("DROP DATABASE shop", "DROP DATABASE shop__alice"),
+            ("ALTER DATABASE shop SET search_path = public", "ALTER DATABASE shop__alice SET search_path TO public"),
+            ("ALTER DATABASE shop CONNECTION LIMIT 5", "ALTER DATABASE shop__alice CONNECTION LIMIT 5"),
+            ("ALTER DATABASE shop RENAME TO store", "ALTER DATABASE shop__alice RENAME TO store__alice"),
+            ("ALTER DATABASE shop OWNER TO bob", "ALTER DATABASE shop__alice OWNER TO bob__alice"),
+            ("GRANT CONNECT ON DATABASE shop, store TO bob", "GRANT connect ON DATABASE shop__alice, store__alice TO bob__alice"),
+            ("REVOKE ALL ON DATABASE shop FROM bob", "REVOKE ALL ON DATABASE shop__alice FROM bob__alice"),
+            ("COMMENT ON DATABASE shop IS 'mine'", "COMMENT ON DATABASE shop__alice IS 'mine'"),
+            ("SECURITY LABEL ON DATABASE shop IS 'mine'", "SECURITY LABEL ON DATABASE shop__alice IS 'mine'"),
+            ("REINDEX DATABASE shop", "REINDEX DATABASE shop__alice"),
+            ("REINDEX SYSTEM shop", "REINDEX SYSTEM shop__alice"),
+            // the user's own database keeps its name
+            ("DROP DATABASE alice", "DROP DATABASE alice"),
+        ] {
+            assert_eq!(transform(query).as_deref(), Some(expected), "{}", query);
+        }
+        // prepared statements are rewritten the same
+        assert_eq!(transform_parse("DROP DATABASE shop"), Some(parse_message("DROP DATABASE shop__alice")));
+        assert_eq!(transform_parse("SELECT $1"), None);
+    }
+
+    #[test]
+    fn connection_strings_test() {
+        for (query, expected) in [
+            (
+                "SELECT * FROM dblink('dbname=shop', 'SELECT 1') AS t(a int)",
+                "SELECT * FROM dblink('dbname=''shop__alice''', 'SELECT 1') t (a int)",
+            ),
+            (
+                "SELECT dblink_connect('conn', 'postgresql://localhost/shop')",
+                "SELECT dblink_connect('conn', 'postgresql://localhost/shop__alice')",
+            ),
+            (
+                "CREATE SERVER s FOREIGN DATA WRAPPER postgres_fdw OPTIONS (dbname 'shop')",
+                "CREATE SERVER s FOREIGN DATA WRAPPER postgres_fdw OPTIONS (dbname 'shop__alice')",
+            ),
+            (
+                "ALTER SERVER s OPTIONS (SET dbname 'shop')",
+                "ALTER SERVER s OPTIONS (SET dbname 'shop__alice')",
+            ),
+            (
+                "CREATE SUBSCRIPTION s CONNECTION 'dbname=shop' PUBLICATION p",
+                "CREATE SUBSCRIPTION s CONNECTION 'dbname=''shop__alice''' PUBLICATION p",
+            ),
+            (
+                "ALTER SUBSCRIPTION s CONNECTION 'dbname=shop'",
+                "ALTER SUBSCRIPTION s CONNECTION 'dbname=''shop__alice'''",
+            ),
+        ] {
+            assert_eq!(transform(query).as_deref(), Some(expected), "{}", query);
+        }
+    }
+
+    #[test]
+    fn role_statements_test() {
+        for (query, expected) in [
+            // creating, dropping and renaming roles goes through the procedures
+            ("CREATE ROLE reader", "CALL impulse.create_role('reader__alice')"),
+            ("CREATE GROUP reader NOLOGIN", "CALL impulse.create_role('reader__alice')"),
+            (
+                "DROP ROLE reader, writer",
+                "CALL impulse.drop_role('reader__alice', false); CALL impulse.drop_role('writer__alice', false)",
+            ),
+            ("DROP ROLE IF EXISTS reader", "CALL impulse.drop_role('reader__alice', true)"),
+            (
+                "ALTER ROLE reader RENAME TO \"it's\"",
+                "CALL impulse.rename_role('reader__alice', 'it''s__alice')",
+            ),
+            (
+                "CREATE ROLE a; DROP ROLE b; SELECT 1",
+                "CALL impulse.create_role('a__alice'); CALL impulse.drop_role('b__alice', false); SELECT 1",
+            ),
+            // anything else is left to the server to refuse
+            ("CREATE ROLE reader LOGIN", "CREATE ROLE reader__alice WITH LOGIN"),
+            ("CREATE USER bob", "CREATE USER bob__alice"),
+            (
+                "CREATE ROLE reader IN ROLE writer ROLE bob ADMIN carol",
+                "CREATE ROLE reader__alice WITH IN ROLE writer__alice ROLE bob__alice ADMIN carol__alice",
+            ),
+            ("ALTER ROLE reader NOINHERIT", "ALTER ROLE reader__alice WITH NOINHERIT"),
+            (
+                "ALTER ROLE reader IN DATABASE shop SET search_path = public",
+                "ALTER ROLE reader__alice IN DATABASE shop__alice SET search_path TO public",
+            ),
+            ("GRANT reader TO bob WITH ADMIN OPTION", "GRANT reader__alice TO bob__alice WITH ADMIN OPTION"),
+            ("GRANT pg_read_all_data TO reader", "GRANT pg_read_all_data TO reader__alice"),
+            ("GRANT SELECT ON TABLE shop TO bob, PUBLIC", "GRANT select ON shop TO bob__alice, public"),
+            ("ALTER TABLE shop OWNER TO bob", "ALTER TABLE shop OWNER TO bob__alice"),
+            ("SET ROLE reader", "SET role TO reader__alice"),
+            ("SET SESSION AUTHORIZATION reader", "SET session_authorization TO reader__alice"),
+            ("REASSIGN OWNED BY reader TO bob", "REASSIGN OWNED BY reader__alice TO bob__alice"),
+            ("DROP OWNED BY reader", "DROP OWNED BY reader__alice"),
+            ("CREATE SCHEMA s AUTHORIZATION reader", "CREATE SCHEMA s AUTHORIZATION reader__alice"),
+            (
+                "ALTER DEFAULT PRIVILEGES FOR ROLE reader GRANT SELECT ON TABLES TO bob",
+                "ALTER DEFAULT PRIVILEGES FOR ROLE reader__alice GRANT select ON TABLES TO bob__alice",
+            ),
+            ("CREATE POLICY p ON shop TO reader USING (true)", "CREATE POLICY p ON shop TO reader__alice USING (true) "),
+            ("CREATE USER MAPPING FOR reader SERVER s", "CREATE USER MAPPING FOR reader__alice SERVER s"),
+            ("COMMENT ON ROLE reader IS 'mine'", "COMMENT ON ROLE reader__alice IS 'mine'"),
+            // the user's own role keeps its name
+            ("ALTER ROLE alice SET search_path = public", "ALTER ROLE alice SET search_path TO public"),
+        ] {
+            assert_eq!(transform(query).as_deref(), Some(expected), "{}", query);
+        }
+        for query in ["DROP ROLE CURRENT_USER", "SET ROLE NONE", "RESET ROLE"] {
+            assert_eq!(transform(query), None, "{}", query);
+        }
+        // prepared statements are rewritten the same
+        assert_eq!(
+            transform_parse("CREATE ROLE reader"),
+            Some(parse_message("CALL impulse.create_role('reader__alice')"))
+        );
+        assert_eq!(transform_parse("GRANT reader TO bob"), Some(parse_message("GRANT reader__alice TO bob__alice")));
+    }
+
+    #[test]
+    fn unchanged_statements_test() {
+        for query in [
+            "SELECT 1",
+            "COMMENT ON TABLE shop IS 'mine'",
+            "ALTER TABLE shop RENAME TO store",
+            "SELECT * FROM dblink('conn', 'SELECT 1') AS t(a int)",
+            "SELECT * FROM dblink('host=db.example.com dbname=shop', 'SELECT 1') AS t(a int)",
+            "CREATE SERVER s FOREIGN DATA WRAPPER postgres_fdw OPTIONS (host 'db.example.com', dbname 'shop')",
+        ] {
+            assert_eq!(transform(query), None, "{}", query);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Which classes of statements users may send through the proxy, per plan.
+//!
+//! Policies are configured in the `[policy]` section of the proxy's TOML
+//! config:
+//!
+//! ```toml
+//! [policy]
+//! deny = ["create_extension", "alter_system", "copy_program", "load"]
+//! allowed_extensions = ["pgcrypto", "citext"]
+//!
+//! [policy.plans.enterprise]
+//! allow = ["load"]
+//! allowed_extensions = ["pgcrypto", "citext", "postgis"]
+//! ```
+//!
+//! DO blocks can run statements of any class dynamically, so they're denied
+//! wherever any class is, unless a plan allows `do` outright, and so are
+//! functions and procedures in procedural languages like PL/pgSQL.
+//! Likewise, set_config() calls count as the `SET`s they amount to.
+//!
+//! prew's filters can't tell whose statement they're looking at or answer
+//! the client, so denied statements are refused by the proxy session
+//! instead.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::prew::classify::{ClassifiedStatement, StatementClass};
+
+/// Statement classes denied to every user, unless their plan allows them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatementPolicy {
+    pub deny: Vec<StatementClass>,
+    /// Extensions that can be created even if CREATE EXTENSION is denied
+    pub allowed_extensions: Vec<String>,
+    /// Exceptions for users on the named plans
+    pub plans: HashMap<String, PlanPolicy>,
+}
+
+/// Exceptions to a `StatementPolicy` for the users on one plan.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlanPolicy {
+    /// Classes allowed despite the policy denying them
+    pub allow: Vec<StatementClass>,
+    /// Classes denied on top of those the policy denies
+    pub deny: Vec<StatementClass>,
+    /// Replaces the policy's allowed extensions if set
+    pub allowed_extensions: Option<Vec<String>>,
+}
+
+impl StatementPolicy {
+    pub fn from_toml(toml_str: &str) -> Result<StatementPolicy> {
+        Ok(toml::from_str(toml_str)?)
+    }
+
+    /// Whether users on the plan `plan_name` (None for roles without a
+    /// plan) may run `statement`. DO blocks could run statements of any
+    /// class, so they're denied wherever another class is too, unless the
+    /// plan allows them.
+    pub fn allows(&self, plan_name: Option<&str>, statement: &ClassifiedStatement) -> bool {
+        let plan = plan_name.and_then(|plan_name| self.plans.get(plan_name));
+        let denies = |class: &StatementClass| match plan {
+            Some(plan) => plan.deny.contains(class) || (self.deny.contains(class) && !plan.allow.contains(class)),
+            None => self.deny.contains(class),
+        };
+        let denied = match statement.class {
+            StatementClass::Do => {
+                let allowed = plan.is_some_and(|plan| plan.allow.contains(&StatementClass::Do));
+                denies(&StatementClass::Do)
+                    || (!allowed && self.deny.iter().chain(plan.iter().flat_map(|plan| &plan.deny)).any(denies))
+            }
+            _ => denies(&statement.class),
+        };
+        if !denied {
+            return true;
+        }
+        match (&statement.class, &statement.extension) {
+            (StatementClass::CreateExtension, Some(extension)) => {
+                plan.and_then(|plan| plan.allowed_extensions.as_ref())
+                    .unwrap_or(&self.allowed_extensions)
+                    .contains(extension)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prew::classify::classify;
+
+    fn statement(class: StatementClass) -> ClassifiedStatement {
+        ClassifiedStatement { class, extension: None, language: None }
+    }
+
+    fn extension(name: &str) -> ClassifiedStatement {
+        ClassifiedStatement { class: StatementClass::CreateExtension, extension: Some(name.to_string()), language: None }
+    }
+
+    #[test]
+    fn policy_test() -> Result<()> {
+        let policy = StatementPolicy::from_toml(r#"
+            deny = ["create_extension", "load"]
+            allowed_extensions = ["pgcrypto"]
+
+            [plans.pro]
+            allow = ["load"]
+            deny = ["set_role"]
+
+            [plans.enterprise]
+            allowed_extensions = ["pgcrypto", "postgis"]
+        "#)?;
+        for plan in [None, Some("basic"), Some("enterprise")] {
+            assert!(!policy.allows(plan, &statement(StatementClass::Load)));
+            assert!(policy.allows(plan, &statement(StatementClass::SetRole)));
+            assert!(policy.allows(plan, &extension("pgcrypto")));
+        }
+        assert!(policy.allows(Some("pro"), &statement(StatementClass::Load)));
+        assert!(!policy.allows(Some("pro"), &statement(StatementClass::SetRole)));
+        assert!(!policy.allows(Some("basic"), &extension("postgis")));
+        assert!(policy.allows(Some("enterprise"), &extension("postgis")));
+
+        // DO blocks, and procedural functions that could do the same, are
+        // denied along with any other class
+        let function = "CREATE FUNCTION f() RETURNS void LANGUAGE plpgsql AS $$ BEGIN EXECUTE 'LOAD ''x'''; END $$";
+        let function = &classify(function).unwrap()[0];
+        for plan in [None, Some("basic"), Some("pro"), Some("enterprise")] {
+            assert!(!policy.allows(plan, &statement(StatementClass::Do)));
+            assert!(!policy.allows(plan, function));
+        }
+        let policy = StatementPolicy::from_toml(r#"
+            deny = ["set_role"]
+
+            [plans.pro]
+            allow = ["set_role"]
+
+            [plans.enterprise]
+            allow = ["do"]
+        "#)?;
+        assert!(!policy.allows(None, &statement(StatementClass::Do)));
+        assert!(policy.allows(Some("pro"), &statement(StatementClass::Do)));
+        assert!(policy.allows(Some("enterprise"), &statement(StatementClass::Do)));
+        assert!(policy.allows(Some("enterprise"), function));
+
+        // nothing is denied by default
+        assert!(StatementPolicy::default().allows(None, &statement(StatementClass::AlterSystem)));
+        assert!(StatementPolicy::default().allows(None, &statement(StatementClass::Do)));
+        assert!(StatementPolicy::from_toml(r#"deny = ["drop_everything"]"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,512 @@
+//! Transaction-level pooling of server connections, so that clients can
+//! outnumber the connections the server has room for.
+//!
+//! A pooled client is lent a server connection from its first message
+//! while the server is idle until the server is idle again, outside of a
+//! transaction and with every query and Sync answered. Clients starting up
+//! with the same parameters (user and database, but also e.g. `TimeZone`
+//! or `application_name`) on the same server share up to `size`
+//! connections, which are started with those parameters and reset with
+//! `DISCARD ALL` before they're lent again, so session state (prepared
+//! statements, `SET`s, temporary tables, advisory locks) doesn't outlive a
+//! transaction. Connections of clients that leave while they're lent are
+//! answered out, rolled back and reset as well, rather than closed.
+//!
+//! Pooling is configured in the `[pool]` section of the proxy's TOML
+//! config, and needs the proxy to authenticate clients itself:
+//!
+//! ```toml
+//! authenticate_clients = true
+//!
+//! [pool]
+//! size = 20
+//! wait_timeout_secs = 120
+//! ```
+//!
+//! The session's pipes run as they do for unpooled clients, connected to a
+//! stand-in for the server that answers the startup message itself, so
+//! messages are rewritten, limited and reported the same way. Pooled
+//! clients aren't sent BackendKeyData, and can't cancel their queries.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use log::{debug, error, trace};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::prew::protocol::{
+    authentication, authentication_code, error_response, query_message, ready_for_query, startup_message,
+    startup_parameters, sync_message, take_message, transaction_status, AUTHENTICATION_OK,
+};
+use crate::prew::proxy::{connect_server, read_startup, Stream};
+use crate::prew::tls::ProxyTls;
+
+/// SQLSTATE of connection_failure
+const CONNECTION_FAILURE: &str = "08006";
+/// SQLSTATE of too_many_connections
+const TOO_MANY_CONNECTIONS: &str = "53300";
+/// Query resetting the session state of a connection before it's lent again
+const RESET_QUERY: &str = "DISCARD ALL";
+/// Query ending the transaction a client left open
+const ROLLBACK_QUERY: &str = "ROLLBACK";
+/// How long the server has to answer a client that left before its
+/// connection is closed instead of being lent again
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many server connections clients share.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Most connections to the server for each user and database
+    pub size: usize,
+    /// Seconds a client waits to be lent a connection before it's
+    /// disconnected
+    pub wait_timeout_secs: u64,
+}
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig { size: 10, wait_timeout_secs: 120 }
+    }
+}
+
+/// Server address and startup parameters, sorted by name, of a pool.
+type PoolKey = (String, Vec<(String, String)>);
+
+/// Pools of connections to servers, one for each server and set of startup
+/// parameters.
+pub struct ServerPools {
+    tls: ProxyTls,
+    config: PoolConfig,
+    pools: SyncMutex<HashMap<PoolKey, Arc<Pool>>>,
+}
+impl ServerPools {
+    /// Pools of connections secured by `tls`.
+    pub fn new(tls: ProxyTls, config: PoolConfig) -> ServerPools {
+        ServerPools { tls, config, pools: SyncMutex::new(HashMap::new()) }
+    }
+
+    /// The pool of connections to `server_addr` started with `parameters`,
+    /// which are sorted by name.
+    fn pool(&self, server_addr: &str, parameters: Vec<(String, String)>) -> Arc<Pool> {
+        let mut pools = self.pools.lock().unwrap();
+        pools
+            .entry((server_addr.to_string(), parameters))
+            .or_insert_with_key(|(_, parameters)| {
+                debug!("Creating pool for {:?} at {}", parameters, server_addr);
+                let parameters = parameters.iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect::<Vec<_>>();
+                Arc::new(Pool {
+                    server_addr: server_addr.to_string(),
+                    startup: startup_message(&parameters),
+                    permits: Arc::new(Semaphore::new(self.config.size.max(1))),
+                    idle: SyncMutex::new(vec![]),
+                })
+            })
+            .clone()
+    }
+
+    /// Borrow a connection from `pool`, or the error to end the client's
+    /// session with if none is lent in time.
+    async fn borrow(&self, pool: &Pool) -> Result<Lease, Vec<u8>> {
+        let wait = Duration::from_secs(self.config.wait_timeout_secs);
+        match tokio::time::timeout(wait, pool.borrow(&self.tls)).await {
+            Ok(lease) => lease,
+            Err(_) => Err(error_response("FATAL", TOO_MANY_CONNECTIONS, "no server connection became available")),
+        }
+    }
+
+    /// Stand in for the server at `server_addr` of one client's session on
+    /// `stream`, the other end of the session's pipes, lending the session
+    /// a pooled connection for each of its transactions.
+    pub async fn serve(&self, server_addr: &str, stream: DuplexStream) {
+        if let Err(error) = self.serve_session(server_addr, stream).await {
+            trace!("Pooled session ended: {:?}", error);
+        }
+    }
+
+    async fn serve_session(&self, server_addr: &str, mut client: DuplexStream) -> Result<()> {
+        let startup = read_startup(&mut client, vec![]).await?;
+        // there's no connection to cancel the query of
+        let mut parameters = match startup_parameters(&startup) {
+            Some(parameters) => parameters,
+            None => return Ok(()),
+        };
+        // the server defaults the database to the user's name
+        if !parameters.iter().any(|(name, _)| name == "database") {
+            let username = parameters.iter().find_map(|(name, value)| (name == "user").then(|| value.clone()));
+            parameters.push(("database".to_string(), username.unwrap_or_default()));
+        }
+        parameters.sort();
+        let pool = self.pool(server_addr, parameters);
+        // a connection is borrowed right away to check that the user can
+        // connect and to learn the server's parameters
+        let lease = match self.borrow(&pool).await {
+            Ok(lease) => lease,
+            Err(error) => return Ok(client.write_all(&error).await?),
+        };
+        let ready = [
+            authentication(AUTHENTICATION_OK, b""),
+            lease.connection.parameters.clone(),
+            ready_for_query(b'I'),
+        ];
+        pool.give_back(lease, false);
+        client.write_all(&ready.concat()).await?;
+
+        let mut buffer = vec![];
+        loop {
+            let first = match next_message(&mut buffer)? {
+                // the client closes the connection next
+                Some(message) if message[0] == b'X' => continue,
+                Some(message) => message,
+                None => {
+                    if client.read_buf(&mut buffer).await? == 0 {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            let mut lease = match self.borrow(&pool).await {
+                Ok(lease) => lease,
+                Err(error) => return Ok(client.write_all(&error).await?),
+            };
+            match lend(&mut client, &mut buffer, &mut lease.connection, first).await? {
+                Lent::Done => pool.give_back(lease, true),
+                Lent::Abandoned(exchange) => {
+                    pool.give_back_abandoned(lease, exchange);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// How a connection's lend to a client ended.
+enum Lent {
+    /// The server answered everything, outside of a transaction
+    Done,
+    /// The client left first, with what the server still owes it
+    Abandoned(Exchange),
+}
+
+/// Pass `first` and the client's messages after it to `server`, and the
+/// server's messages back, until the server has answered all of them and
+/// is outside of a transaction, or the client left.
+async fn lend(
+    client: &mut DuplexStream,
+    buffer: &mut Vec<u8>,
+    server: &mut ServerConnection,
+    first: Vec<u8>,
+) -> Result<Lent> {
+    let mut exchange = Exchange::default();
+    exchange.sent(&first);
+    let mut messages = first;
+    loop {
+        let mut closing = false;
+        while let Some(message) = next_message(buffer)? {
+            if message[0] == b'X' {
+                closing = true;
+                break;
+            }
+            exchange.sent(&message);
+            messages.extend(message);
+        }
+        if !messages.is_empty() {
+            server.stream.write_all(&messages).await?;
+            messages.clear();
+        }
+        if closing {
+            return Ok(Lent::Abandoned(exchange));
+        }
+        tokio::select! {
+            read = client.read_buf(buffer) => {
+                if read? == 0 {
+                    return Ok(Lent::Abandoned(exchange));
+                }
+            }
+            read = server.stream.read_buf(&mut server.buffer) => {
+                if read? == 0 {
+                    return Err(anyhow!("Server closed a pooled connection"));
+                }
+                let mut answers = vec![];
+                let mut done = false;
+                while !done {
+                    match next_message(&mut server.buffer)? {
+                        Some(message) => {
+                            done = exchange.received(&message);
+                            answers.extend(message);
+                        }
+                        None => break,
+                    }
+                }
+                if client.write_all(&answers).await.is_err() {
+                    return Ok(Lent::Abandoned(exchange));
+                }
+                if done {
+                    return Ok(Lent::Done);
+                }
+            }
+        }
+    }
+}
+
+/// Split the first message off `buffer` if it holds all of it.
+fn next_message(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>> {
+    take_message(buffer).map_err(|_| anyhow!("Invalid message length"))
+}
+
+/// What a server connection still owes the client it's lent to.
+#[derive(Debug, Default)]
+struct Exchange {
+    /// Queries, Syncs and function calls not answered with ReadyForQuery
+    unanswered: usize,
+    /// Whether messages of an extended query were sent since the last Sync
+    unsynced: bool,
+    /// Transaction status of the last ReadyForQuery
+    status: Option<u8>,
+}
+impl Exchange {
+    fn sent(&mut self, message: &[u8]) {
+        match message.first() {
+            Some(b'Q' | b'S' | b'F') => {
+                self.unanswered += 1;
+                self.unsynced = false;
+            }
+            Some(b'P' | b'B' | b'D' | b'E' | b'C' | b'H') => self.unsynced = true,
+            _ => {}
+        }
+    }
+
+    /// Whether the server is done with the client once it sent `message`.
+    fn received(&mut self, message: &[u8]) -> bool {
+        match transaction_status(message) {
+            Some(status) => {
+                self.unanswered = self.unanswered.saturating_sub(1);
+                self.status = Some(status);
+                status == b'I' && self.unanswered == 0 && !self.unsynced
+            }
+            None => false,
+        }
+    }
+}
+
+/// Connections to a server for one user and database.
+struct Pool {
+    server_addr: String,
+    /// Startup message new connections are opened with
+    startup: Vec<u8>,
+    /// One permit for each connection that may be open
+    permits: Arc<Semaphore>,
+    /// Connections ready to be lent
+    idle: SyncMutex<Vec<ServerConnection>>,
+}
+impl Pool {
+    /// Lend an idle connection, or a new one if the pool isn't full,
+    /// waiting for one to be given back otherwise.
+    async fn borrow(&self, tls: &ProxyTls) -> Result<Lease, Vec<u8>> {
+        let permit = self.permits.clone().acquire_owned().await.expect("pool permits are never closed");
+        let idle = self.idle.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => ServerConnection::open(&self.server_addr, tls, &self.startup).await?,
+        };
+        Ok(Lease { connection, _permit: permit })
+    }
+
+    /// Take a lent connection back, once reset if `reset` is set. Connections
+    /// that fail to reset are closed.
+    fn give_back(self: &Arc<Self>, lease: Lease, reset: bool) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let Lease { mut connection, _permit } = lease;
+            if !reset || connection.reset().await {
+                pool.idle.lock().unwrap().push(connection);
+            }
+        });
+    }
+
+    /// Take back a connection whose client left while it was lent, once
+    /// the server answered what it was sent and the client's transaction
+    /// is rolled back. Connections that don't settle in time are closed.
+    fn give_back_abandoned(self: &Arc<Self>, lease: Lease, exchange: Exchange) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let Lease { mut connection, _permit } = lease;
+            let settled = tokio::time::timeout(SETTLE_TIMEOUT, connection.settle(exchange)).await;
+            if settled == Ok(true) && connection.reset().await {
+                pool.idle.lock().unwrap().push(connection);
+            }
+        });
+    }
+}
+
+/// A connection lent to a client, counted against its pool's size until
+/// it's dropped.
+struct Lease {
+    connection: ServerConnection,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// A connection to the server that's done starting up.
+struct ServerConnection {
+    stream: Box<dyn Stream>,
+    /// ParameterStatus messages the server started the connection with
+    parameters: Vec<u8>,
+    /// Bytes read from the server that aren't a whole message yet
+    buffer: Vec<u8>,
+}
+impl ServerConnection {
+    /// Connect to the server and start up with `startup`, returning the
+    /// connection once the server is ready for queries, or the error to
+    /// end the client's session with.
+    async fn open(server_addr: &str, tls: &ProxyTls, startup: &[u8]) -> Result<ServerConnection, Vec<u8>> {
+        let failed = |error: anyhow::Error| {
+            error!("Opening pooled connection to {} failed: {:?}", server_addr, error);
+            error_response("FATAL", CONNECTION_FAILURE, "could not connect to server")
+        };
+        let stream = connect_server(server_addr, tls).await.map_err(failed)?;
+        let mut connection = ServerConnection { stream, parameters: vec![], buffer: vec![] };
+        connection.stream.write_all(startup).await.map_err(|error| failed(error.into()))?;
+        loop {
+            let message = connection.read_message().await.map_err(|error| failed(error.into()))?;
+            match message[0] {
+                b'R' if authentication_code(&message) == Some(AUTHENTICATION_OK) => {}
+                b'R' => return Err(failed(anyhow!("Server asked the proxy to authenticate"))),
+                b'S' => connection.parameters.extend(message),
+                // e.g. the database doesn't exist
+                b'E' => return Err(message),
+                b'Z' => return Ok(connection),
+                _ => {}
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let message = take_message(&mut self.buffer)
+                .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "invalid message length"))?;
+            if let Some(message) = message {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Read the server's answers to what a client that left sent, syncing
+    /// its extended query and rolling back its transaction, returning
+    /// whether the connection is idle again.
+    async fn settle(&mut self, mut exchange: Exchange) -> bool {
+        if exchange.unsynced {
+            exchange.sent(&sync_message());
+            if let Err(error) = self.stream.write_all(&sync_message()).await {
+                trace!("Couldn't sync abandoned connection: {}", error);
+                return false;
+            }
+        }
+        while exchange.unanswered > 0 {
+            match self.read_message().await {
+                Ok(message) => {
+                    exchange.received(&message);
+                }
+                Err(error) => {
+                    trace!("Couldn't settle abandoned connection: {}", error);
+                    return false;
+                }
+            }
+        }
+        if exchange.status == Some(b'I') {
+            return true;
+        }
+        if let Err(error) = self.stream.write_all(&query_message(ROLLBACK_QUERY)).await {
+            trace!("Couldn't roll back abandoned connection: {}", error);
+            return false;
+        }
+        loop {
+            match self.read_message().await {
+                Ok(message) if message[0] == b'Z' => return transaction_status(&message) == Some(b'I'),
+                Ok(_) => {}
+                Err(error) => {
+                    trace!("Couldn't roll back abandoned connection: {}", error);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Reset the session state of the connection, returning whether it
+    /// can be lent again.
+    async fn reset(&mut self) -> bool {
+        if let Err(error) = self.stream.write_all(&query_message(RESET_QUERY)).await {
+            trace!("Couldn't reset pooled connection: {}", error);
+            return false;
+        }
+        let mut failed = false;
+        loop {
+            match self.read_message().await {
+                Ok(message) if message[0] == b'E' => failed = true,
+                Ok(message) if message[0] == b'Z' => return !failed && transaction_status(&message) == Some(b'I'),
+                Ok(_) => {}
+                Err(error) => {
+                    trace!("Couldn't reset pooled connection: {}", error);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id];
+        bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn exchange_test() {
+        // a simple query outside of a transaction
+        let mut exchange = Exchange::default();
+        exchange.sent(&query_message("SELECT 1"));
+        assert!(!exchange.received(&message(b'C', b"SELECT 1\0")));
+        assert!(exchange.received(&ready_for_query(b'I')));
+
+        // the connection stays lent until the transaction ends
+        let mut exchange = Exchange::default();
+        exchange.sent(&query_message("BEGIN"));
+        assert!(!exchange.received(&ready_for_query(b'T')));
+        exchange.sent(&query_message("COMMIT"));
+        assert!(exchange.received(&ready_for_query(b'I')));
+
+        // and until every Sync of a pipeline is answered
+        let mut exchange = Exchange::default();
+        for id in [b'P', b'B', b'E', b'S', b'P', b'B', b'E', b'S'] {
+            exchange.sent(&message(id, b""));
+        }
+        assert!(!exchange.received(&ready_for_query(b'I')));
+        assert!(exchange.received(&ready_for_query(b'I')));
+
+        // or while an extended query isn't synced yet
+        let mut exchange = Exchange::default();
+        exchange.sent(&query_message("SELECT 1"));
+        exchange.sent(&message(b'P', b""));
+        assert!(!exchange.received(&ready_for_query(b'I')));
+    }
+
+    #[test]
+    fn config_test() {
+        let config: PoolConfig = toml::from_str("size = 20").unwrap();
+        assert_eq!(config, PoolConfig { size: 20, wait_timeout_secs: 120 });
+        assert!(toml::from_str::<PoolConfig>("max = 20").is_err());
+    }
+}
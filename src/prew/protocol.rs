@@ -0,0 +1,641 @@
+//! Decoding of the PostgreSQL protocol messages that prew's parser doesn't
+//! recognize (they arrive as `PostgresqlPacketInfo::Other` with their bytes).
+
+/// Bytes of a message after its type and length.
+fn body(bytes: &[u8]) -> &[u8] {
+    bytes.get(5..).unwrap_or_default()
+}
+
+/// Read a null-terminated string starting at `offset`, returning it and the
+/// offset just past its terminator.
+fn read_cstring(bytes: &[u8], offset: usize) -> Option<(String, usize)> {
+    let rest = bytes.get(offset..)?;
+    let end = rest.iter().position(|byte| *byte == 0)?;
+    Some((String::from_utf8_lossy(&rest[..end]).into_owned(), offset + end + 1))
+}
+
+/// Protocol version 3.0, as sent at the start of a StartupMessage
+const PROTOCOL_VERSION: u32 = 196_608;
+/// Request code of a CancelRequest, in place of the protocol version
+const CANCEL_REQUEST_CODE: u32 = 80_877_102;
+/// Request code of an SSLRequest, in place of the protocol version
+const SSL_REQUEST_CODE: u32 = 80_877_103;
+/// Request code of a GSSENCRequest, in place of the protocol version
+const GSSENC_REQUEST_CODE: u32 = 80_877_104;
+
+/// Message a client opens its connection with, before any startup message.
+#[derive(Debug, PartialEq)]
+pub enum InitialRequest {
+    /// SSLRequest: asks for the connection to be encrypted with TLS
+    Ssl,
+    /// GSSENCRequest: asks for the connection to be encrypted with GSSAPI
+    GssEnc,
+    /// CancelRequest: asks for the query of another connection to be
+    /// canceled, which is all the connection is for
+    Cancel,
+}
+impl InitialRequest {
+    /// The request that the first 8 bytes of a connection start, if
+    /// they're not the start of a StartupMessage.
+    pub fn from_bytes(bytes: &[u8]) -> Option<InitialRequest> {
+        let length = u32::from_be_bytes(bytes.get(0..4)?.try_into().ok()?);
+        match (length, u32::from_be_bytes(bytes.get(4..8)?.try_into().ok()?)) {
+            (8, SSL_REQUEST_CODE) => Some(InitialRequest::Ssl),
+            (8, GSSENC_REQUEST_CODE) => Some(InitialRequest::GssEnc),
+            (16, CANCEL_REQUEST_CODE) => Some(InitialRequest::Cancel),
+            _ => None,
+        }
+    }
+}
+
+/// SSLRequest (frontend) message.
+pub fn ssl_request() -> Vec<u8> {
+    [8_u32.to_be_bytes(), SSL_REQUEST_CODE.to_be_bytes()].concat()
+}
+
+/// Process ID and secret key of a server connection, which CancelRequests
+/// name the connection they cancel the query of by.
+pub type BackendKey = [u8; 8];
+
+/// CancelRequest (frontend) message for the connection of `key`.
+pub fn cancel_request(key: &BackendKey) -> Vec<u8> {
+    [&16_u32.to_be_bytes(), &CANCEL_REQUEST_CODE.to_be_bytes(), key.as_slice()].concat()
+}
+
+/// Key of the connection a CancelRequest (frontend) cancels the query of.
+pub fn cancel_key(bytes: &[u8]) -> Option<BackendKey> {
+    match InitialRequest::from_bytes(bytes)? {
+        InitialRequest::Cancel => bytes.get(8..16)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Key of the connection of a BackendKeyData (backend) message.
+pub fn backend_key(bytes: &[u8]) -> Option<BackendKey> {
+    match bytes.first() {
+        Some(b'K') => body(bytes).get(0..8)?.try_into().ok(),
+        _ => None,
+    }
+}
+
+/// StartupMessage (frontend) with the given parameters, for protocol 3.0.
+pub fn startup_message(parameters: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+    for (name, value) in parameters {
+        body.extend(name.as_bytes());
+        body.push(0);
+        body.extend(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+    let mut bytes = ((body.len() + 4) as u32).to_be_bytes().to_vec();
+    bytes.extend(body);
+    bytes
+}
+
+/// Parameters of a StartupMessage (frontend), which unlike other messages
+/// has no type byte. None for other messages, including SSLRequest and
+/// CancelRequest, which share its layout but not its protocol version.
+pub fn startup_parameters(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    let version = u32::from_be_bytes(bytes.get(4..8)?.try_into().ok()?);
+    if version != PROTOCOL_VERSION {
+        return None;
+    }
+    let mut parameters = vec![];
+    let mut offset = 8;
+    while bytes.get(offset).is_some_and(|byte| *byte != 0) {
+        let (name, next) = read_cstring(bytes, offset)?;
+        let (value, next) = read_cstring(bytes, next)?;
+        parameters.push((name, value));
+        offset = next;
+    }
+    Some(parameters)
+}
+
+/// Query string of a Query (frontend) message, which runs a simple query.
+pub fn simple_query(bytes: &[u8]) -> Option<String> {
+    if bytes.first() != Some(&b'Q') {
+        return None;
+    }
+    read_cstring(body(bytes), 0).map(|(query, _)| query)
+}
+
+/// Query (frontend) message running `query` as a simple query.
+pub fn query_message(query: &str) -> Vec<u8> {
+    let mut bytes = vec![b'Q'];
+    bytes.extend(((query.len() + 5) as u32).to_be_bytes());
+    bytes.extend(query.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// Sync (frontend) message, ending an extended query.
+pub fn sync_message() -> Vec<u8> {
+    vec![b'S', 0, 0, 0, 4]
+}
+
+/// Split the first message off `buffer` if it holds all of it. Messages
+/// declaring a length shorter than their own length field are an error.
+pub fn take_message(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, InvalidLength> {
+    let length = match buffer.get(1..5) {
+        Some(length) => u32::from_be_bytes(length.try_into().unwrap()) as usize,
+        None => return Ok(None),
+    };
+    if length < 4 {
+        return Err(InvalidLength);
+    }
+    if buffer.len() < length + 1 {
+        return Ok(None);
+    }
+    let rest = buffer.split_off(length + 1);
+    Ok(Some(std::mem::replace(buffer, rest)))
+}
+
+/// A message's length field is too short to be valid.
+#[derive(Debug, PartialEq)]
+pub struct InvalidLength;
+
+/// Parse (frontend): prepares `query` as the statement `statement`.
+#[derive(Debug, PartialEq)]
+pub struct ParseMessage {
+    pub statement: String,
+    pub query: String,
+}
+impl ParseMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Option<ParseMessage> {
+        let body = body(bytes);
+        let (statement, offset) = read_cstring(body, 0)?;
+        let (query, _) = read_cstring(body, offset)?;
+        Some(ParseMessage { statement, query })
+    }
+
+    /// The Parse message `bytes` with its query replaced by `query`.
+    pub fn replace_query(bytes: &[u8], query: &str) -> Option<Vec<u8>> {
+        let body = body(bytes);
+        let (_, offset) = read_cstring(body, 0)?;
+        let (_, end) = read_cstring(body, offset)?;
+        let mut new_body = body[..offset].to_vec();
+        new_body.extend(query.as_bytes());
+        new_body.push(0);
+        new_body.extend(&body[end..]);
+        let mut bytes = vec![b'P'];
+        bytes.extend(((new_body.len() + 4) as u32).to_be_bytes());
+        bytes.extend(new_body);
+        Some(bytes)
+    }
+}
+
+/// Bind (frontend): binds the prepared `statement` to the portal `portal`.
+#[derive(Debug, PartialEq)]
+pub struct BindMessage {
+    pub portal: String,
+    pub statement: String,
+    /// Format codes of the result columns: none if they're all text, one
+    /// for all of them, or one per column
+    pub result_formats: Vec<i16>,
+}
+impl BindMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Option<BindMessage> {
+        let body = body(bytes);
+        let (portal, offset) = read_cstring(body, 0)?;
+        let (statement, mut offset) = read_cstring(body, offset)?;
+        let read_i16 = |offset: usize| Some(i16::from_be_bytes(body.get(offset..offset + 2)?.try_into().ok()?));
+        // parameter format codes
+        offset += 2 + 2 * read_i16(offset)?.max(0) as usize;
+        // parameter values
+        let parameters = read_i16(offset)?;
+        offset += 2;
+        for _ in 0..parameters {
+            let length = i32::from_be_bytes(body.get(offset..offset + 4)?.try_into().ok()?);
+            offset += 4 + length.max(0) as usize;
+        }
+        let result_formats = (0..read_i16(offset)?)
+            .map(|index| read_i16(offset + 2 + 2 * index as usize))
+            .collect::<Option<Vec<_>>>()?;
+        Some(BindMessage { portal, statement, result_formats })
+    }
+
+    /// Format code of the result column at `index`.
+    pub fn result_format(result_formats: &[i16], index: usize) -> i16 {
+        match result_formats {
+            [] => 0,
+            [format] => *format,
+            formats => formats.get(index).copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Execute (frontend): runs the portal `portal`.
+#[derive(Debug, PartialEq)]
+pub struct ExecuteMessage {
+    pub portal: String,
+    /// Maximum number of rows to return, 0 for no limit
+    pub max_rows: i32,
+}
+impl ExecuteMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Option<ExecuteMessage> {
+        let body = body(bytes);
+        let (portal, offset) = read_cstring(body, 0)?;
+        let max_rows = i32::from_be_bytes(body.get(offset..offset + 4)?.try_into().ok()?);
+        Some(ExecuteMessage { portal, max_rows })
+    }
+}
+
+/// Close (frontend): closes a prepared statement or a portal.
+#[derive(Debug, PartialEq)]
+pub enum CloseMessage {
+    Statement(String),
+    Portal(String),
+}
+impl CloseMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Option<CloseMessage> {
+        let body = body(bytes);
+        let (name, _) = read_cstring(body, 1)?;
+        match body.first()? {
+            b'S' => Some(CloseMessage::Statement(name)),
+            b'P' => Some(CloseMessage::Portal(name)),
+            _ => None,
+        }
+    }
+}
+
+/// Describe (frontend): asks for the parameters and/or result columns of a
+/// prepared statement or a portal.
+#[derive(Debug, PartialEq)]
+pub enum DescribeMessage {
+    Statement(String),
+    Portal(String),
+}
+impl DescribeMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Option<DescribeMessage> {
+        let body = body(bytes);
+        let (name, _) = read_cstring(body, 1)?;
+        match body.first()? {
+            b'S' => Some(DescribeMessage::Statement(name)),
+            b'P' => Some(DescribeMessage::Portal(name)),
+            _ => None,
+        }
+    }
+}
+
+/// A result column, as described by a RowDescription (backend) message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDescription {
+    pub name: String,
+    /// OID of the table the column comes from, 0 if it isn't a table column
+    pub table_oid: u32,
+    /// Attribute number of the column in its table, 0 if it isn't one
+    pub column: i16,
+    pub type_oid: u32,
+    /// Format code of the column's values, 0 for text and 1 for binary
+    pub format: i16,
+}
+
+/// Result columns of a RowDescription (backend) message.
+pub fn row_description(bytes: &[u8]) -> Option<Vec<FieldDescription>> {
+    if bytes.first() != Some(&b'T') {
+        return None;
+    }
+    let body = body(bytes);
+    let count = i16::from_be_bytes(body.get(0..2)?.try_into().ok()?);
+    let mut fields = vec![];
+    let mut offset = 2;
+    for _ in 0..count {
+        let (name, next) = read_cstring(body, offset)?;
+        // table OID, attribute number, type OID, type size, type modifier
+        // and format code
+        let field = body.get(next..next + 18)?;
+        fields.push(FieldDescription {
+            name,
+            table_oid: u32::from_be_bytes(field[0..4].try_into().ok()?),
+            column: i16::from_be_bytes(field[4..6].try_into().ok()?),
+            type_oid: u32::from_be_bytes(field[6..10].try_into().ok()?),
+            format: i16::from_be_bytes(field[16..18].try_into().ok()?),
+        });
+        offset = next + 18;
+    }
+    Some(fields)
+}
+
+/// Column values of a DataRow (backend) message, None for NULLs.
+///
+/// prew's `DataRowMessage` drops NULL columns and encodes its column count
+/// with the wrong width, so rows are decoded and encoded here instead.
+pub fn data_row_values(bytes: &[u8]) -> Option<Vec<Option<Vec<u8>>>> {
+    if bytes.first() != Some(&b'D') {
+        return None;
+    }
+    let body = body(bytes);
+    let count = i16::from_be_bytes(body.get(0..2)?.try_into().ok()?);
+    let mut values = vec![];
+    let mut offset = 2;
+    for _ in 0..count {
+        let length = i32::from_be_bytes(body.get(offset..offset + 4)?.try_into().ok()?);
+        offset += 4;
+        if length < 0 {
+            values.push(None);
+        } else {
+            values.push(Some(body.get(offset..offset + length as usize)?.to_vec()));
+            offset += length as usize;
+        }
+    }
+    (offset == body.len()).then_some(values)
+}
+
+/// DataRow (backend) message with the given column values.
+pub fn data_row(values: &[Option<Vec<u8>>]) -> Vec<u8> {
+    let mut body = (values.len() as i16).to_be_bytes().to_vec();
+    for value in values {
+        match value {
+            Some(value) => {
+                body.extend((value.len() as i32).to_be_bytes());
+                body.extend(value);
+            }
+            None => body.extend((-1_i32).to_be_bytes()),
+        }
+    }
+    let mut bytes = vec![b'D'];
+    bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+/// Authentication (backend) message code of AuthenticationOk
+pub const AUTHENTICATION_OK: u32 = 0;
+/// Authentication (backend) message code of AuthenticationSASL
+pub const AUTHENTICATION_SASL: u32 = 10;
+/// Authentication (backend) message code of AuthenticationSASLContinue
+pub const AUTHENTICATION_SASL_CONTINUE: u32 = 11;
+/// Authentication (backend) message code of AuthenticationSASLFinal
+pub const AUTHENTICATION_SASL_FINAL: u32 = 12;
+
+/// Authentication (backend) message of type `code`, followed by `data`.
+pub fn authentication(code: u32, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![b'R'];
+    bytes.extend(((data.len() + 8) as u32).to_be_bytes());
+    bytes.extend(code.to_be_bytes());
+    bytes.extend(data);
+    bytes
+}
+
+/// Type code of an Authentication (backend) message.
+pub fn authentication_code(bytes: &[u8]) -> Option<u32> {
+    if bytes.first() != Some(&b'R') {
+        return None;
+    }
+    Some(u32::from_be_bytes(body(bytes).get(0..4)?.try_into().ok()?))
+}
+
+/// SASLInitialResponse (frontend): picks a SASL mechanism and starts the
+/// exchange.
+#[derive(Debug, PartialEq)]
+pub struct SaslInitialResponse {
+    pub mechanism: String,
+    pub data: Vec<u8>,
+}
+impl SaslInitialResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Option<SaslInitialResponse> {
+        if bytes.first() != Some(&b'p') {
+            return None;
+        }
+        let body = body(bytes);
+        let (mechanism, offset) = read_cstring(body, 0)?;
+        let length = i32::from_be_bytes(body.get(offset..offset + 4)?.try_into().ok()?);
+        let data = match usize::try_from(length) {
+            Ok(length) => body.get(offset + 4..offset + 4 + length)?.to_vec(),
+            Err(_) => vec![],
+        };
+        Some(SaslInitialResponse { mechanism, data })
+    }
+}
+
+/// Data of a SASLResponse (frontend), which continues a SASL exchange.
+pub fn sasl_response(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.first() != Some(&b'p') {
+        return None;
+    }
+    Some(body(bytes))
+}
+
+/// Command tag of a CommandComplete (backend) message, e.g. `SELECT 3`.
+pub fn command_tag(bytes: &[u8]) -> Option<String> {
+    read_cstring(body(bytes), 0).map(|(tag, _)| tag)
+}
+
+/// Fields of an ErrorResponse or NoticeResponse (backend) message, keyed by
+/// their field type byte.
+pub fn error_fields(bytes: &[u8]) -> Vec<(u8, String)> {
+    let body = body(bytes);
+    let mut fields = vec![];
+    let mut offset = 0;
+    while let Some(&field_type) = body.get(offset) {
+        if field_type == 0 {
+            break;
+        }
+        match read_cstring(body, offset + 1) {
+            Some((value, next)) => {
+                fields.push((field_type, value));
+                offset = next;
+            }
+            None => break,
+        }
+    }
+    fields
+}
+
+/// SQLSTATE code of an ErrorResponse (backend) message.
+pub fn error_code(bytes: &[u8]) -> Option<String> {
+    error_fields(bytes)
+        .into_iter()
+        .find(|(field_type, _)| *field_type == b'C')
+        .map(|(_, code)| code)
+}
+
+/// ErrorResponse (backend) message with the given severity, SQLSTATE code
+/// and message, as sent by the proxy itself.
+pub fn error_response(severity: &str, code: &str, message: &str) -> Vec<u8> {
+    let mut body = vec![];
+    for (field_type, value) in [(b'S', severity), (b'V', severity), (b'C', code), (b'M', message)] {
+        body.push(field_type);
+        body.extend(value.as_bytes());
+        body.push(0);
+    }
+    body.push(0);
+    let mut bytes = vec![b'E'];
+    bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+/// ReadyForQuery (backend) message with the given transaction status
+/// (`I`dle, in a `T`ransaction block or in a `E`rror-ed one).
+pub fn ready_for_query(status: u8) -> Vec<u8> {
+    vec![b'Z', 0, 0, 0, 5, status]
+}
+
+/// Transaction status of a ReadyForQuery (backend) message.
+pub fn transaction_status(bytes: &[u8]) -> Option<u8> {
+    match bytes.first() {
+        Some(b'Z') => body(bytes).first().copied(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id];
+        bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn extended_query_messages_test() {
+        let parse = message(b'P', b"stmt\0SELECT $1\0\0\0");
+        assert_eq!(
+            ParseMessage::from_bytes(&parse),
+            Some(ParseMessage { statement: "stmt".to_string(), query: "SELECT $1".to_string() })
+        );
+        // with one parameter type
+        let typed = message(b'P', b"stmt\0SELECT $1\0\0\x01\0\0\0\x17");
+        assert_eq!(
+            ParseMessage::replace_query(&typed, "SELECT $1::int"),
+            Some(message(b'P', b"stmt\0SELECT $1::int\0\0\x01\0\0\0\x17"))
+        );
+        let bind = message(b'B', b"\0stmt\0\0\0\0\0\0\0");
+        assert_eq!(
+            BindMessage::from_bytes(&bind),
+            Some(BindMessage { portal: "".to_string(), statement: "stmt".to_string(), result_formats: vec![] })
+        );
+        // one text parameter, NULL parameter, binary results
+        let bind = message(b'B', b"p\0stmt\0\0\x01\0\0\0\x02\0\0\0\x011\xff\xff\xff\xff\0\x01\0\x01");
+        let bind = BindMessage::from_bytes(&bind).unwrap();
+        assert_eq!(bind.result_formats, vec![1]);
+        assert_eq!(BindMessage::result_format(&bind.result_formats, 3), 1);
+        assert_eq!(BindMessage::result_format(&[0, 1], 1), 1);
+        assert_eq!(BindMessage::result_format(&[], 1), 0);
+        let execute = message(b'E', b"\0\0\0\0\x0a");
+        assert_eq!(
+            ExecuteMessage::from_bytes(&execute),
+            Some(ExecuteMessage { portal: "".to_string(), max_rows: 10 })
+        );
+        let close = message(b'C', b"Sstmt\0");
+        assert_eq!(CloseMessage::from_bytes(&close), Some(CloseMessage::Statement("stmt".to_string())));
+        let describe = message(b'D', b"P\0");
+        assert_eq!(DescribeMessage::from_bytes(&describe), Some(DescribeMessage::Portal("".to_string())));
+        // truncated
+        assert_eq!(ParseMessage::from_bytes(&message(b'P', b"stmt")), None);
+        assert_eq!(simple_query(&message(b'Q', b"SELECT 1\0")), Some("SELECT 1".to_string()));
+        assert_eq!(simple_query(&parse), None);
+    }
+
+    #[test]
+    fn authentication_messages_test() {
+        let sasl = authentication(AUTHENTICATION_SASL, b"SCRAM-SHA-256\0\0");
+        assert_eq!(&sasl[..9], [b'R', 0, 0, 0, 23, 0, 0, 0, 10]);
+        assert_eq!(authentication_code(&sasl), Some(AUTHENTICATION_SASL));
+        assert_eq!(authentication_code(&authentication(AUTHENTICATION_OK, b"")), Some(AUTHENTICATION_OK));
+        assert_eq!(authentication_code(&message(b'Z', b"I")), None);
+
+        let mut body = b"SCRAM-SHA-256\0".to_vec();
+        body.extend(5_i32.to_be_bytes());
+        body.extend(b"n,,r=");
+        assert_eq!(
+            SaslInitialResponse::from_bytes(&message(b'p', &body)),
+            Some(SaslInitialResponse { mechanism: "SCRAM-SHA-256".to_string(), data: b"n,,r=".to_vec() })
+        );
+        // truncated
+        assert_eq!(SaslInitialResponse::from_bytes(&message(b'p', &body[..20])), None);
+        assert_eq!(sasl_response(&message(b'p', b"c=biws")), Some(b"c=biws".as_slice()));
+    }
+
+    #[test]
+    fn startup_message_test() {
+        let mut startup = 34_u32.to_be_bytes().to_vec();
+        startup.extend(PROTOCOL_VERSION.to_be_bytes());
+        startup.extend(b"user\0alice\0database\0shop\0\0");
+        assert_eq!(
+            startup_parameters(&startup),
+            Some(vec![
+                ("user".to_string(), "alice".to_string()),
+                ("database".to_string(), "shop".to_string()),
+            ])
+        );
+        let ssl_request = [0, 0, 0, 8, 4, 210, 22, 47];
+        assert_eq!(startup_parameters(&ssl_request), None);
+        assert_eq!(ssl_request, super::ssl_request().as_slice());
+        assert_eq!(InitialRequest::from_bytes(&ssl_request), Some(InitialRequest::Ssl));
+        assert_eq!(InitialRequest::from_bytes(&[0, 0, 0, 8, 4, 210, 22, 48]), Some(InitialRequest::GssEnc));
+        assert_eq!(InitialRequest::from_bytes(&[0, 0, 0, 16, 4, 210, 22, 46]), Some(InitialRequest::Cancel));
+        assert_eq!(InitialRequest::from_bytes(&startup), None);
+        assert_eq!(startup_message(&[("user", "alice"), ("database", "shop")]), startup);
+        let key = [0, 0, 0, 42, 1, 2, 3, 4];
+        assert_eq!(cancel_key(&cancel_request(&key)), Some(key));
+        assert_eq!(cancel_key(&ssl_request), None);
+        assert_eq!(backend_key(&message(b'K', &key)), Some(key));
+        assert_eq!(backend_key(&message(b'K', &key[..4])), None);
+    }
+
+    #[test]
+    fn take_message_test() {
+        let query = query_message("SELECT 1");
+        assert_eq!(query, message(b'Q', b"SELECT 1\0"));
+        let mut buffer = [query.clone(), ready_for_query(b'I')[..3].to_vec()].concat();
+        assert_eq!(take_message(&mut buffer), Ok(Some(query)));
+        assert_eq!(take_message(&mut buffer), Ok(None));
+        buffer.extend(&ready_for_query(b'I')[3..]);
+        assert_eq!(take_message(&mut buffer), Ok(Some(ready_for_query(b'I'))));
+        assert!(buffer.is_empty());
+        assert_eq!(take_message(&mut vec![b'Z', 0, 0, 0, 3]), Err(InvalidLength));
+    }
+
+    #[test]
+    fn backend_messages_test() {
+        assert_eq!(command_tag(&message(b'C', b"SELECT 3\0")), Some("SELECT 3".to_string()));
+        let error = message(b'E', b"SERROR\0C42P01\0Mrelation \"x\" does not exist\0\0");
+        assert_eq!(error_code(&error), Some("42P01".to_string()));
+        assert_eq!(error_fields(&error).len(), 3);
+        let error = error_response("FATAL", "53300", "too many connections");
+        assert_eq!(error_code(&error), Some("53300".to_string()));
+        assert_eq!(error_fields(&error)[3], (b'M', "too many connections".to_string()));
+        assert_eq!(error.len(), 1 + u32::from_be_bytes(error[1..5].try_into().unwrap()) as usize);
+        assert_eq!(ready_for_query(b'T'), message(b'Z', b"T"));
+        assert_eq!(transaction_status(&ready_for_query(b'T')), Some(b'T'));
+        assert_eq!(transaction_status(&error), None);
+    }
+
+    #[test]
+    fn row_description_test() {
+        let mut body = 2_i16.to_be_bytes().to_vec();
+        for (name, table_oid, column, type_oid) in [("datname", 1262_u32, 2_i16, 19_u32), ("n", 0, 0, 23)] {
+            body.extend(name.as_bytes());
+            body.push(0);
+            body.extend(table_oid.to_be_bytes());
+            body.extend(column.to_be_bytes());
+            body.extend(type_oid.to_be_bytes());
+            body.extend([0; 8]);
+        }
+        let description = row_description(&message(b'T', &body)).unwrap();
+        assert_eq!(
+            description[0],
+            FieldDescription { name: "datname".to_string(), table_oid: 1262, column: 2, type_oid: 19, format: 0 }
+        );
+        assert_eq!(description[1].type_oid, 23);
+        assert_eq!(row_description(&message(b'T', &body[..body.len() - 1])), None);
+        assert_eq!(row_description(&message(b'D', &body)), None);
+    }
+
+    #[test]
+    fn data_row_test() {
+        let values = vec![Some(b"shop".to_vec()), None, Some(vec![])];
+        let row = data_row(&values);
+        assert_eq!(row, message(b'D', b"\0\x03\0\0\0\x04shop\xff\xff\xff\xff\0\0\0\0"));
+        assert_eq!(data_row_values(&row), Some(values));
+        assert_eq!(data_row_values(&row[..row.len() - 5]), None);
+        // a Describe (frontend) message shares DataRow's type byte
+        assert_eq!(data_row_values(&message(b'D', b"P\0")), None);
+    }
+}
@@ -0,0 +1,981 @@
+//! Accept loop and pipes for proxied connections, in place of prew's
+//! `RewriteReverseProxy`, so that each session knows its client's address
+//! and how its connection ended, and so that clients' SSLRequests are
+//! answered by the proxy.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as SyncMutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use diesel::PgConnection;
+use futures::lock::Mutex;
+use log::{debug, error, info, trace};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use prew::packet::PacketProcessingSession;
+
+use crate::models::balances::BalanceEstimate;
+use crate::models::clusters::Cluster;
+use crate::models::plans::Plan;
+use crate::models::reports::PacketDirection;
+use crate::models::sessions::TerminationReason;
+use crate::models::users::{User, UserStatus};
+use crate::prew::auth;
+use crate::prew::auth::ScramVerifier;
+use crate::prew::classify;
+use crate::prew::classify::ClassifiedStatement;
+use crate::prew::limits::{
+    BandwidthLimiter, ConnectionLimiter, ConnectionPermit, QuotaLimiter, UserBandwidth, UserQuota,
+};
+use crate::prew::protocol::{
+    authentication_code, backend_key, cancel_key, error_response, ready_for_query, simple_query, ssl_request,
+    startup_parameters, transaction_status, BackendKey, InitialRequest, ParseMessage, AUTHENTICATION_OK,
+};
+use crate::prew::policy::StatementPolicy;
+use crate::prew::pool::ServerPools;
+use crate::prew::sessions::SessionTracker;
+use crate::prew::tls::ProxyTls;
+
+/// SQLSTATE of too_many_connections
+const TOO_MANY_CONNECTIONS: &str = "53300";
+/// SQLSTATE of configuration_limit_exceeded
+const QUOTA_EXCEEDED: &str = "53400";
+/// SQLSTATE of read_only_sql_transaction
+const READ_ONLY: &str = "25006";
+/// SQLSTATE of insufficient_privilege
+const INSUFFICIENT_PRIVILEGE: &str = "42501";
+/// SQLSTATE of invalid_authorization_specification
+const INVALID_AUTHORIZATION: &str = "28000";
+/// SQLSTATE of invalid_password
+const INVALID_PASSWORD: &str = "28P01";
+/// SQLSTATE of protocol_violation
+const PROTOCOL_VIOLATION: &str = "08P01";
+/// SQLSTATE of cannot_connect_now
+const CANNOT_CONNECT_NOW: &str = "57P03";
+/// Longest startup message the server accepts
+const MAX_STARTUP_LENGTH: usize = 10_000;
+/// Bytes buffered between a pooled session's pipes and its stand-in server
+const POOLED_BUFFER_SIZE: usize = 64 * 1024;
+/// How long clients have to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits on the users of all sessions of the proxy.
+#[derive(Debug, Default)]
+pub struct ProxyLimits {
+    pub connections: Arc<ConnectionLimiter>,
+    pub bandwidth: BandwidthLimiter,
+    pub quotas: QuotaLimiter,
+    pub policy: StatementPolicy,
+}
+impl ProxyLimits {
+    pub fn new() -> ProxyLimits {
+        ProxyLimits::default()
+    }
+
+    pub fn with_policy(policy: StatementPolicy) -> ProxyLimits {
+        ProxyLimits { policy, ..ProxyLimits::default() }
+    }
+}
+
+/// Part of the proxy's answer to a query it refused.
+#[derive(Debug, Clone, PartialEq)]
+enum Refusal {
+    Error(Vec<u8>),
+    /// ReadyForQuery with the transaction status it's sent with
+    Ready,
+    /// Error refusing a message of an extended query, sent ahead of the
+    /// ReadyForQuery the server answers the query's Sync with
+    BeforeSync(Vec<u8>),
+}
+
+/// What the client is owed, in the order it's owed it.
+#[derive(Debug, PartialEq)]
+enum Owed {
+    /// ReadyForQuery messages from the server, for messages passed on to it
+    Server(usize),
+    Refusal(Refusal),
+}
+
+/// Replies to the client that the proxy and the server take turns at, so
+/// that refusals reach the client after the server's answers to the
+/// messages before them.
+#[derive(Debug, Default)]
+struct ReplyQueue {
+    owed: VecDeque<Owed>,
+}
+impl ReplyQueue {
+    /// Expect a ReadyForQuery from the server, for a message passed on to
+    /// it.
+    fn expect_server(&mut self) {
+        match self.owed.back_mut() {
+            Some(Owed::Server(count)) => *count += 1,
+            _ => self.owed.push_back(Owed::Server(1)),
+        }
+    }
+
+    /// Send `refusal` after whatever the server still owes the client,
+    /// returning the part of it that can be sent right away.
+    fn refuse(&mut self, refusal: Vec<Refusal>, status: u8) -> Vec<u8> {
+        let waits_on_sync = refusal.iter().any(|part| matches!(part, Refusal::BeforeSync(_)));
+        if self.owed.is_empty() && !waits_on_sync {
+            return render(refusal, status);
+        }
+        self.owed.extend(refusal.into_iter().map(Owed::Refusal));
+        vec![]
+    }
+
+    /// Count a ReadyForQuery from the server, returning the refusals to send
+    /// ahead of it and the ones that were waiting on it.
+    fn server_ready(&mut self, status: u8) -> (Vec<u8>, Vec<u8>) {
+        let mut ahead = vec![];
+        while let Some(Owed::Refusal(Refusal::BeforeSync(_))) = self.owed.front() {
+            if let Some(Owed::Refusal(refusal)) = self.owed.pop_front() {
+                ahead.push(refusal);
+            }
+        }
+        match self.owed.front_mut() {
+            Some(Owed::Server(count)) if *count > 1 => *count -= 1,
+            Some(Owed::Server(_)) => {
+                self.owed.pop_front();
+            }
+            // e.g. the one ending the startup
+            _ => {}
+        }
+        let mut released = vec![];
+        while let Some(Owed::Refusal(Refusal::Error(_) | Refusal::Ready)) = self.owed.front() {
+            if let Some(Owed::Refusal(refusal)) = self.owed.pop_front() {
+                released.push(refusal);
+            }
+        }
+        (render(ahead, status), render(released, status))
+    }
+}
+
+fn render(refusal: Vec<Refusal>, status: u8) -> Vec<u8> {
+    refusal
+        .into_iter()
+        .flat_map(|part| match part {
+            Refusal::Error(error) | Refusal::BeforeSync(error) => error,
+            Refusal::Ready => ready_for_query(status),
+        })
+        .collect()
+}
+
+/// A prew session for a new connection, with the tracker of its session.
+pub struct ProxySession {
+    pub session: Arc<Mutex<dyn PacketProcessingSession + Send>>,
+    pub tracker: Arc<SessionTracker>,
+    /// Connection to the impulse database
+    conn: Arc<Mutex<PgConnection>>,
+    limits: Arc<ProxyLimits>,
+    /// Connection of the session's user, counted against their plan's
+    /// limit once they're admitted, for as long as the client is connected
+    permit: OnceLock<ConnectionPermit>,
+    /// Bandwidth of the session's user, once admitted, if their plan limits
+    /// it
+    bandwidth: OnceLock<Arc<UserBandwidth>>,
+    /// Quota of the session's user, once admitted, if they're a user
+    quota: OnceLock<Arc<UserQuota>>,
+    /// Plan of the session's user, once admitted, if they're a user
+    plan_name: OnceLock<String>,
+    /// Whether the session's user is read-only, as of their admission
+    read_only: AtomicBool,
+    /// Transaction status of the last ReadyForQuery from the server
+    transaction_status: AtomicU8,
+    /// Whether the proxy authenticated the client itself
+    authenticated: AtomicBool,
+    replies: SyncMutex<ReplyQueue>,
+    /// Routes of CancelRequests and the server the session was routed to,
+    /// if it was routed by user
+    cancel_route: OnceLock<(Arc<CancelRoutes>, String)>,
+    /// Key of the session's server connection, once the server sent it
+    backend_key: OnceLock<BackendKey>,
+}
+impl ProxySession {
+    pub fn new(
+        session: Arc<Mutex<dyn PacketProcessingSession + Send>>,
+        tracker: Arc<SessionTracker>,
+        conn: Arc<Mutex<PgConnection>>,
+        limits: Arc<ProxyLimits>,
+    ) -> ProxySession {
+        ProxySession {
+            session,
+            tracker,
+            conn,
+            limits,
+            permit: OnceLock::new(),
+            bandwidth: OnceLock::new(),
+            quota: OnceLock::new(),
+            plan_name: OnceLock::new(),
+            read_only: AtomicBool::new(false),
+            transaction_status: AtomicU8::new(b'I'),
+            authenticated: AtomicBool::new(false),
+            replies: SyncMutex::new(ReplyQueue::default()),
+            cancel_route: OnceLock::new(),
+            backend_key: OnceLock::new(),
+        }
+    }
+
+    /// Authenticate the client of `stream` as the user of its startup
+    /// message, which `start` begins, returning the whole message. Clients
+    /// failing to, and users who are disabled or deleted, are refused.
+    /// CancelRequests aren't authenticated.
+    async fn authenticate<S>(
+        &self,
+        stream: &mut S,
+        client_addr: &SocketAddr,
+        start: Vec<u8>,
+    ) -> Result<Vec<u8>, TerminationReason>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let startup = read_startup(stream, start).await.map_err(|error| client_error(client_addr, error))?;
+        let username = match startup_parameters(&startup) {
+            Some(parameters) => parameters
+                .into_iter()
+                .find_map(|(name, value)| (name == "user").then_some(value))
+                .unwrap_or_default(),
+            None => return Ok(startup),
+        };
+        let user = {
+            let mut conn = self.conn.lock().await;
+            User::for_pg_name(&mut conn, &username).unwrap_or_else(|error| {
+                error!("Couldn't look up user {}: {:?}", &username, error);
+                None
+            })
+        };
+        let verifier = user.as_ref()
+            .and_then(|user| user.pg_password_enc.as_deref())
+            .and_then(|verifier| std::str::from_utf8(verifier).ok())
+            .and_then(ScramVerifier::parse)
+            .unwrap_or_else(|| ScramVerifier::unmatchable(&username));
+        let status = user.map(|user| user.user_status);
+        let (reason, error) = match auth::authenticate(stream, verifier).await {
+            Ok(true) if matches!(status, Some(UserStatus::Disabled | UserStatus::Deleted)) => {
+                info!("Refusing client {}: user {} is {:?}", client_addr, &username, status);
+                let message = format!("role \"{}\" is not permitted to log in", &username);
+                (TerminationReason::UserDisabled, error_response("FATAL", INVALID_AUTHORIZATION, &message))
+            }
+            Ok(true) => {
+                self.authenticated.store(true, Ordering::Relaxed);
+                return Ok(startup);
+            }
+            Ok(false) => {
+                info!("Refusing client {}: authentication of {} failed", client_addr, &username);
+                let message = format!("password authentication failed for user \"{}\"", &username);
+                (TerminationReason::AuthenticationFailed, error_response("FATAL", INVALID_PASSWORD, &message))
+            }
+            Err(error) => match error.downcast::<std::io::Error>() {
+                Ok(error) => return Err(client_error(client_addr, error)),
+                Err(error) => {
+                    info!("Refusing client {}: {:?}", client_addr, error);
+                    let message = format!("invalid authentication: {}", error);
+                    (TerminationReason::ProtocolError, error_response("FATAL", PROTOCOL_VIOLATION, &message))
+                }
+            },
+        };
+        if let Err(error) = stream.write_all(&error).await {
+            trace!("Couldn't send authentication error: {}", error);
+        }
+        Err(reason)
+    }
+
+    /// Address of the server for the user of `startup`: that of the cluster
+    /// they're placed on, or `default_addr` for users on no cluster.
+    /// CancelRequests go to the server of the connection they cancel the
+    /// query of, or to `default_addr` if it's unknown. None if the user's
+    /// placement can't be looked up.
+    async fn route(&self, startup: &[u8], default_addr: &str, routes: &Arc<CancelRoutes>) -> Option<String> {
+        if let Some(key) = cancel_key(startup) {
+            return Some(routes.server_addr(&key).unwrap_or_else(|| default_addr.to_string()));
+        }
+        let username = startup_parameters(startup).and_then(|parameters| {
+            parameters.into_iter().find_map(|(name, value)| (name == "user").then_some(value))
+        });
+        let username = match username {
+            Some(username) => username,
+            None => return Some(default_addr.to_string()),
+        };
+        let cluster = {
+            let mut conn = self.conn.lock().await;
+            match Cluster::for_user(&mut conn, &username) {
+                Ok(cluster) => cluster,
+                Err(error) => {
+                    error!("Couldn't look up cluster of {}: {:?}", &username, error);
+                    return None;
+                }
+            }
+        };
+        let server_addr = match cluster {
+            Some(cluster) => {
+                debug!("Routing {} to cluster {}", &username, &cluster.cluster_name);
+                cluster.server_addr()
+            }
+            None => default_addr.to_string(),
+        };
+        let _ = self.cancel_route.set((routes.clone(), server_addr.clone()));
+        Some(server_addr)
+    }
+
+    /// Route CancelRequests for `key`, the key of the session's server
+    /// connection, to the server the session was routed to.
+    fn register_backend(&self, key: BackendKey) {
+        if let Some((routes, server_addr)) = self.cancel_route.get() {
+            if self.backend_key.set(key).is_ok() {
+                routes.insert(key, server_addr.clone());
+            }
+        }
+    }
+
+    /// Stop routing CancelRequests for the session's server connection,
+    /// once it's closed.
+    fn forget_backend(&self) {
+        if let (Some((routes, _)), Some(key)) = (self.cancel_route.get(), self.backend_key.get()) {
+            routes.remove(key);
+        }
+    }
+
+    /// Count a connection of `username`, once they're authenticated,
+    /// against their plan's connection limit, returning false if they have
+    /// no connection left. Admitted users are held to their plan's
+    /// bandwidth and to their quota from then on, and read-only users can't
+    /// write for as long as they're connected.
+    ///
+    /// Users whose plan can't be looked up are let through without limits.
+    async fn admit(&self, username: &str) -> bool {
+        let (plan, user) = {
+            let mut conn = self.conn.lock().await;
+            let plan = Plan::for_user(&mut conn, username).unwrap_or_else(|error| {
+                error!("Couldn't look up plan of {}: {:?}", username, error);
+                None
+            });
+            let user = User::for_pg_name(&mut conn, username).unwrap_or_else(|error| {
+                error!("Couldn't look up user {}: {:?}", username, error);
+                None
+            });
+            (plan, user)
+        };
+        let read_only = user.is_some_and(|user| user.user_status == UserStatus::ReadOnly);
+        self.read_only.store(read_only, Ordering::Relaxed);
+        let limit = plan.as_ref().and_then(|plan| plan.max_connections);
+        match self.limits.connections.acquire(username, limit) {
+            Some(permit) => {
+                let _ = self.permit.set(permit);
+            }
+            None => return false,
+        }
+        if let Some(plan) = plan {
+            let _ = self.plan_name.set(plan.plan_name.clone());
+            let bandwidth = self.limits.bandwidth.for_user(
+                username,
+                plan.max_bytes_in_per_second,
+                plan.max_bytes_out_per_second,
+            );
+            if let Some(bandwidth) = bandwidth {
+                let _ = self.bandwidth.set(bandwidth);
+            }
+            let _ = self.quota.set(self.limits.quotas.for_user(username));
+        }
+        true
+    }
+
+    fn admitted(&self) -> bool {
+        self.permit.get().is_some()
+    }
+
+    /// Wait until `count` bytes received in `direction` fit within the
+    /// user's bandwidth, and deduct them from the user's quota.
+    async fn throttle(&self, direction: PacketDirection, count: usize) {
+        if let Some(quota) = self.quota.get() {
+            quota.spend(direction, count);
+        }
+        if let Some(bandwidth) = self.bandwidth.get() {
+            let wait = bandwidth.take(direction, count);
+            if !wait.is_zero() {
+                trace!("Throttling {:?} traffic for {:?}", direction, wait);
+                tokio::time::sleep(wait).await;
+                self.tracker.add_throttled(direction, wait);
+            }
+        }
+    }
+
+    /// Whether the user has used up their quota, estimating it again from
+    /// their balance first if it's stale.
+    ///
+    /// Queries are let through if the balance can't be looked up.
+    async fn quota_exhausted(&self) -> bool {
+        let (quota, username) = match (self.quota.get(), self.tracker.username()) {
+            (Some(quota), Some(username)) => (quota, username),
+            _ => return false,
+        };
+        if quota.is_stale() {
+            let estimate = {
+                let mut conn = self.conn.lock().await;
+                BalanceEstimate::for_user(&mut conn, &username)
+            };
+            match estimate {
+                Ok(Some(estimate)) => {
+                    debug!("Estimated remaining balance of {}: {:?}", &username, &estimate);
+                    quota.set_estimate(estimate.remaining());
+                }
+                Ok(None) => return false,
+                Err(error) => {
+                    error!("Couldn't estimate balance of {}: {:?}", &username, error);
+                    return false;
+                }
+            }
+        }
+        quota.is_exhausted()
+    }
+
+    /// The first statement of a query that the policy denies the user.
+    fn denied_statement(&self, query: &str) -> Option<ClassifiedStatement> {
+        let plan_name = self.plan_name.get().map(String::as_str);
+        classify::classify(query)?
+            .into_iter()
+            .find(|statement| !self.limits.policy.allows(plan_name, statement))
+    }
+
+    /// Whether a query writes, if the user is read-only.
+    fn writes_when_read_only(&self, query: &str) -> bool {
+        self.read_only.load(Ordering::Relaxed) && classify::is_read_only(query) == Some(false)
+    }
+
+    /// Answer a message from the client in place of the server if it starts
+    /// a query and the user's quota is used up, or it runs or prepares a
+    /// statement the policy denies them or a write while they're read-only,
+    /// returning the reply. Once an extended query is refused, its messages
+    /// are dropped up to the next Sync, which is passed on so that the
+    /// server answers the messages before the refused one.
+    async fn refuse_query(&self, message: &[u8], refusing: &mut bool) -> Option<Vec<Refusal>> {
+        let message_type = *message.first()?;
+        if *refusing {
+            if message_type == b'S' {
+                *refusing = false;
+                return None;
+            }
+            return Some(vec![]);
+        }
+        if !matches!(message_type, b'Q' | b'P' | b'B' | b'E') {
+            return None;
+        }
+        let username = self.tracker.username().unwrap_or_default();
+        let query = match message_type {
+            b'Q' => simple_query(message),
+            b'P' => ParseMessage::from_bytes(message).map(|parse| parse.query),
+            _ => None,
+        };
+        let query = query.as_deref().unwrap_or_default();
+        let error = if self.quota_exhausted().await {
+            info!("Refusing query of {}: quota exhausted", &username);
+            error_response(
+                "ERROR",
+                QUOTA_EXCEEDED,
+                &format!("usage quota exceeded for role \"{}\": remaining balance is used up", username),
+            )
+        } else if let Some(statement) = self.denied_statement(query) {
+            info!("Refusing query of {}: {} denied by policy", &username, &statement);
+            error_response(
+                "ERROR",
+                INSUFFICIENT_PRIVILEGE,
+                &format!("{} is not allowed for role \"{}\"", statement, username),
+            )
+        } else if self.writes_when_read_only(query) {
+            info!("Refusing query of {}: role is read-only", &username);
+            error_response(
+                "ERROR",
+                READ_ONLY,
+                &format!("cannot execute write statements as role \"{}\": role is read-only", username),
+            )
+        } else {
+            return None;
+        };
+        if message_type == b'Q' {
+            Some(vec![Refusal::Error(error), Refusal::Ready])
+        } else {
+            *refusing = true;
+            Some(vec![Refusal::BeforeSync(error)])
+        }
+    }
+
+    /// Queue the reply to a refused query behind the server's answers to
+    /// the messages before it, returning what can be sent right away.
+    fn reply(&self, refusal: Vec<Refusal>) -> Vec<u8> {
+        let status = self.transaction_status.load(Ordering::Relaxed);
+        self.replies.lock().unwrap().refuse(refusal, status)
+    }
+}
+
+/// Creates the session of a new client connection from its address.
+pub type SessionFactory = dyn Fn(Option<SocketAddr>) -> Result<ProxySession> + Send + Sync;
+
+/// Servers of the connections of sessions routed by user, by their key, for
+/// clients' CancelRequests to reach the server of the connection they
+/// cancel the query of.
+#[derive(Debug, Default)]
+pub struct CancelRoutes {
+    servers: SyncMutex<HashMap<BackendKey, String>>,
+}
+impl CancelRoutes {
+    fn insert(&self, key: BackendKey, server_addr: String) {
+        self.servers.lock().unwrap().insert(key, server_addr);
+    }
+
+    fn remove(&self, key: &BackendKey) {
+        self.servers.lock().unwrap().remove(key);
+    }
+
+    fn server_addr(&self, key: &BackendKey) -> Option<String> {
+        self.servers.lock().unwrap().get(key).cloned()
+    }
+}
+
+/// How the proxy secures its connections and connects to the server.
+#[derive(Default)]
+pub struct ProxyOptions {
+    pub tls: ProxyTls,
+    /// Whether the proxy authenticates clients itself, connecting to the
+    /// server only for active users who proved their password
+    pub authenticate_clients: bool,
+    /// Server connections that clients share for the length of a
+    /// transaction, rather than each getting their own. Needs
+    /// `authenticate_clients`.
+    pub pools: Option<Arc<ServerPools>>,
+    /// Whether clients are connected to the cluster their user is placed
+    /// on, going by their startup message, rather than all to the proxy's
+    /// server. Users on no cluster are still connected to the proxy's
+    /// server.
+    pub route_by_user: bool,
+    /// Where the CancelRequests of clients routed by user go
+    pub cancel_routes: Arc<CancelRoutes>,
+}
+
+/// Accept clients on `listener` forever, proxying each of them to a new
+/// connection to `server_addr`.
+pub async fn serve(listener: TcpListener, server_addr: String, create_session: Arc<SessionFactory>) {
+    serve_with_options(listener, server_addr, create_session, Arc::new(ProxyOptions::default())).await
+}
+
+/// Like `serve`, securing connections as set by `options`.
+pub async fn serve_with_options(
+    listener: TcpListener,
+    server_addr: String,
+    create_session: Arc<SessionFactory>,
+    options: Arc<ProxyOptions>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, client_addr)) => {
+                debug!("New client from {}", &client_addr);
+                tokio::spawn(proxy_connection(
+                    socket,
+                    client_addr,
+                    server_addr.clone(),
+                    create_session.clone(),
+                    options.clone(),
+                ));
+            }
+            Err(error) => error!("Couldn't accept client: {}", error),
+        }
+    }
+}
+
+/// Connection to a client or the server, encrypted or not.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+async fn proxy_connection(
+    client_socket: TcpStream,
+    client_addr: SocketAddr,
+    server_addr: String,
+    create_session: Arc<SessionFactory>,
+    options: Arc<ProxyOptions>,
+) {
+    let proxied = match create_session(Some(client_addr)) {
+        Ok(proxied) => proxied,
+        Err(error) => {
+            error!("Couldn't start session for {}: {:?}", &client_addr, error);
+            return;
+        }
+    };
+    // unless clients have to be authenticated or routed by their startup
+    // message first, the server is connected to right away
+    let mut server_stream = None;
+    if !options.authenticate_clients && !options.route_by_user {
+        match open_server(&proxied, &server_addr, &options).await {
+            Some(stream) => server_stream = Some(stream),
+            None => return,
+        }
+    }
+    let accepted = match accept_client(client_socket, &client_addr, &options.tls).await {
+        Ok((mut client_stream, startup)) if options.authenticate_clients => {
+            proxied.authenticate(&mut client_stream, &client_addr, startup).await
+                .map(|startup| (client_stream, startup))
+        }
+        Ok((mut client_stream, start)) if options.route_by_user => {
+            read_startup(&mut client_stream, start).await
+                .map(|startup| (client_stream, startup))
+                .map_err(|error| client_error(&client_addr, error))
+        }
+        accepted => accepted,
+    };
+    let (mut client_stream, startup) = match accepted {
+        Ok(accepted) => accepted,
+        Err(reason) => {
+            debug!("Closing connection from {}: {}", &client_addr, reason);
+            proxied.tracker.end(reason);
+            return;
+        }
+    };
+    let server_addr = match options.route_by_user {
+        true => match proxied.route(&startup, &server_addr, &options.cancel_routes).await {
+            Some(server_addr) => server_addr,
+            None => {
+                let error = error_response("FATAL", CANNOT_CONNECT_NOW, "the role's server couldn't be looked up");
+                if let Err(error) = client_stream.write_all(&error).await {
+                    trace!("Couldn't send routing error: {}", error);
+                }
+                proxied.tracker.end(TerminationReason::ServerUnavailable);
+                return;
+            }
+        },
+        false => server_addr,
+    };
+    let server_stream = match server_stream {
+        Some(stream) => stream,
+        None => match open_server(&proxied, &server_addr, &options).await {
+            Some(stream) => stream,
+            None => return,
+        },
+    };
+    let (client_reader, client_writer) = tokio::io::split(client_stream);
+    let (server_reader, server_writer) = tokio::io::split(server_stream);
+    // the bytes read past the client's requests are the start of its
+    // startup message
+    let client_reader = Cursor::new(startup).chain(client_reader);
+    let (client_writer, server_writer) = (Mutex::new(client_writer), Mutex::new(server_writer));
+    // the connection closes as soon as either pipe stops
+    let reason = tokio::select! {
+        reason = pipe(&proxied, PacketDirection::Forward, client_reader, &server_writer, &client_writer) => reason,
+        reason = pipe(&proxied, PacketDirection::Backward, server_reader, &client_writer, &server_writer) => reason,
+    };
+    debug!("Closing connection from {}: {}", &client_addr, reason);
+    proxied.forget_backend();
+    if reason == TerminationReason::ConnectionLimit {
+        let message = format!(
+            "too many connections for role \"{}\"",
+            proxied.tracker.username().unwrap_or_default(),
+        );
+        let error = error_response("FATAL", TOO_MANY_CONNECTIONS, &message);
+        if let Err(error) = client_writer.lock().await.write_all(&error).await {
+            trace!("Couldn't send connection limit error: {}", error);
+        }
+    }
+    proxied.tracker.end(reason);
+}
+
+/// Why negotiating with a client failed, from the error that it failed
+/// with.
+fn client_error(client_addr: &SocketAddr, error: std::io::Error) -> TerminationReason {
+    trace!("Negotiating with {} failed: {}", client_addr, error);
+    match error.kind() {
+        ErrorKind::UnexpectedEof => TerminationReason::ClientClosed,
+        _ => TerminationReason::ClientError,
+    }
+}
+
+/// Answer the requests a client opens its connection with, encrypting the
+/// connection if it asks for TLS and TLS is configured. Returns the
+/// connection along with the bytes read from it past the requests, or why
+/// it was closed.
+async fn accept_client(
+    mut socket: TcpStream,
+    client_addr: &SocketAddr,
+    tls: &ProxyTls,
+) -> Result<(Box<dyn Stream>, Vec<u8>), TerminationReason> {
+    let client_error = |error| client_error(client_addr, error);
+    // every request and startup message is at least as long as this
+    let mut start = [0_u8; 8];
+    loop {
+        socket.read_exact(&mut start).await.map_err(client_error)?;
+        match InitialRequest::from_bytes(&start) {
+            Some(InitialRequest::Ssl) => break,
+            // clients fall back to asking for TLS or to plaintext
+            Some(InitialRequest::GssEnc) => socket.write_all(b"N").await.map_err(client_error)?,
+            Some(InitialRequest::Cancel) => return Ok((Box::new(socket), start.to_vec())),
+            None if tls.required() => {
+                info!("Refusing client {}: TLS is required", client_addr);
+                // read the rest of the startup message, or closing the
+                // connection resets it before the client reads the error
+                read_startup(&mut socket, start.to_vec()).await.map_err(client_error)?;
+                let error = error_response("FATAL", INVALID_AUTHORIZATION, "SSL connection is required");
+                socket.write_all(&error).await.map_err(client_error)?;
+                return Err(TerminationReason::TlsRequired);
+            }
+            None => return Ok((Box::new(socket), start.to_vec())),
+        }
+    }
+    match tls.acceptor() {
+        Some(acceptor) => {
+            socket.write_all(b"S").await.map_err(client_error)?;
+            let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                .await
+                .map_err(|_| client_error(std::io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out")))?
+                .map_err(client_error)?;
+            Ok((Box::new(stream), vec![]))
+        }
+        None => {
+            // the client goes on in plaintext, or closes the connection
+            socket.write_all(b"N").await.map_err(client_error)?;
+            Ok((Box::new(socket), vec![]))
+        }
+    }
+}
+
+/// Read the rest of the startup message (or CancelRequest) that `start`
+/// begins.
+pub(crate) async fn read_startup<S: AsyncRead + Unpin>(stream: &mut S, mut start: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if start.len() < 4 {
+        let mut rest = vec![0_u8; 4 - start.len()];
+        stream.read_exact(&mut rest).await?;
+        start.extend(rest);
+    }
+    let length = u32::from_be_bytes([start[0], start[1], start[2], start[3]]) as usize;
+    if !(8..=MAX_STARTUP_LENGTH).contains(&length) {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "invalid startup message length"));
+    }
+    if start.len() < length {
+        let mut rest = vec![0_u8; length - start.len()];
+        stream.read_exact(&mut rest).await?;
+        start.extend(rest);
+    }
+    Ok(start)
+}
+
+/// Connect to the server for a session, ending the session if it can't be.
+/// Pooled sessions are connected to a stand-in for the server instead,
+/// which lends them the pool's connections.
+async fn open_server(proxied: &ProxySession, server_addr: &str, options: &ProxyOptions) -> Option<Box<dyn Stream>> {
+    if let Some(pools) = &options.pools {
+        let (stream, stand_in) = tokio::io::duplex(POOLED_BUFFER_SIZE);
+        let (pools, server_addr) = (pools.clone(), server_addr.to_string());
+        tokio::spawn(async move { pools.serve(&server_addr, stand_in).await });
+        return Some(Box::new(stream));
+    }
+    match connect_server(server_addr, &options.tls).await {
+        Ok(stream) => Some(stream),
+        Err(error) => {
+            error!("Connecting to {} failed: {:?}", server_addr, error);
+            proxied.tracker.end(TerminationReason::ServerUnavailable);
+            None
+        }
+    }
+}
+
+/// Connect to the server at `server_addr`, over TLS if `tls` is configured
+/// for it.
+pub(crate) async fn connect_server(server_addr: &str, tls: &ProxyTls) -> Result<Box<dyn Stream>> {
+    let mut socket = TcpStream::connect(server_addr).await?;
+    let connector = match tls.connector() {
+        Some(connector) => connector,
+        None => return Ok(Box::new(socket)),
+    };
+    let server_name = tls.server_name(server_addr)?;
+    socket.write_all(&ssl_request()).await?;
+    let mut answer = [0_u8; 1];
+    socket.read_exact(&mut answer).await?;
+    if answer[0] != b'S' {
+        return Err(anyhow!("Server declined TLS"));
+    }
+    Ok(Box::new(connector.connect(server_name, socket).await?))
+}
+
+/// Pass messages read from `source` through the session to `sink` until
+/// either side fails or closes, returning why. Messages the proxy answers
+/// itself are answered through `reply`, the other side of `source`.
+async fn pipe<R, W, RW>(
+    proxied: &ProxySession,
+    direction: PacketDirection,
+    mut source: R,
+    sink: &Mutex<W>,
+    reply: &Mutex<RW>,
+) -> TerminationReason
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    RW: AsyncWrite + Unpin,
+{
+    let (source_closed, source_error, sink_error) = match direction {
+        PacketDirection::Forward => (
+            TerminationReason::ClientClosed,
+            TerminationReason::ClientError,
+            TerminationReason::ServerError,
+        ),
+        PacketDirection::Backward => (
+            TerminationReason::ServerClosed,
+            TerminationReason::ServerError,
+            TerminationReason::ClientError,
+        ),
+    };
+    let mut read_buf = vec![0_u8; 4096];
+    let mut packet_buf: Vec<u8> = Vec::with_capacity(4096);
+    let mut write_buf: Vec<u8> = Vec::with_capacity(4096);
+    let mut reply_buf: Vec<u8> = vec![];
+    // whether the client's startup message was passed on
+    let mut started = false;
+    // whether an extended query is being refused
+    let mut refusing = false;
+    loop {
+        let count = match source.read(&mut read_buf).await {
+            Ok(0) => return source_closed,
+            Ok(count) => count,
+            Err(error) => {
+                trace!("{:?} read failed: {}", direction, error);
+                return source_error;
+            }
+        };
+        proxied.tracker.add_bytes(direction, count);
+        proxied.throttle(direction, count).await;
+        packet_buf.extend_from_slice(&read_buf[..count]);
+        {
+            let mut session = proxied.session.lock().await;
+            loop {
+                let packet = match session.parse(&mut packet_buf) {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(error) => {
+                        error!("Couldn't read {:?} message: {:?}", direction, error);
+                        return TerminationReason::ProtocolError;
+                    }
+                };
+                // refusals of the extended query this ReadyForQuery ends
+                // precede it, and those waiting on it follow it
+                let (mut ahead, mut released) = (vec![], vec![]);
+                if let Some(status) = transaction_status(&packet.bytes) {
+                    if direction == PacketDirection::Backward {
+                        proxied.transaction_status.store(status, Ordering::Relaxed);
+                        (ahead, released) = proxied.replies.lock().unwrap().server_ready(status);
+                    }
+                }
+                if let Some(key) = backend_key(&packet.bytes) {
+                    if direction == PacketDirection::Backward {
+                        proxied.register_backend(key);
+                    }
+                }
+                // clients the proxy authenticated have nothing to answer the
+                // server with
+                if direction == PacketDirection::Backward
+                    && proxied.authenticated.load(Ordering::Relaxed)
+                    && authentication_code(&packet.bytes).is_some_and(|code| code != AUTHENTICATION_OK)
+                {
+                    error!("Server asked to authenticate a client the proxy authenticated");
+                    return TerminationReason::ServerError;
+                }
+                // users are admitted once authenticated, so that clients
+                // can't take up others' connections by naming them
+                if direction == PacketDirection::Backward
+                    && !proxied.admitted()
+                    && authentication_code(&packet.bytes) == Some(AUTHENTICATION_OK)
+                    && !proxied.admit(&proxied.tracker.username().unwrap_or_default()).await
+                {
+                    return TerminationReason::ConnectionLimit;
+                }
+                if direction == PacketDirection::Forward && proxied.admitted() {
+                    if let Some(refusal) = proxied.refuse_query(&packet.bytes, &mut refusing).await {
+                        reply_buf.extend(proxied.reply(refusal));
+                        continue;
+                    }
+                }
+                let processed = match direction {
+                    PacketDirection::Forward => session.process_incoming(&packet),
+                    PacketDirection::Backward => session.process_outgoing(&packet),
+                };
+                let startup = direction == PacketDirection::Forward
+                    && !started
+                    && startup_parameters(&packet.bytes).is_some();
+                started |= direction == PacketDirection::Forward;
+                // the server answers each query, Sync, function call and
+                // the startup with a ReadyForQuery
+                let answered = direction == PacketDirection::Forward
+                    && (matches!(packet.bytes.first(), Some(b'Q' | b'S' | b'F')) || startup);
+                match processed {
+                    Ok(Some(processed)) => {
+                        if answered {
+                            proxied.replies.lock().unwrap().expect_server();
+                        }
+                        write_buf.extend(ahead);
+                        write_buf.extend_from_slice(&processed.bytes);
+                        write_buf.extend(released);
+                    }
+                    Ok(None) => {
+                        write_buf.extend(ahead);
+                        write_buf.extend(released);
+                    }
+                    Err(error) => {
+                        error!("Couldn't process {:?} message: {:?}", direction, error);
+                        return TerminationReason::ProtocolError;
+                    }
+                }
+            }
+        }
+        if let Err(error) = sink.lock().await.write_all(&write_buf).await {
+            trace!("{:?} write failed: {}", direction, error);
+            return sink_error;
+        }
+        write_buf.clear();
+        if !reply_buf.is_empty() {
+            if let Err(error) = reply.lock().await.write_all(&reply_buf).await {
+                trace!("{:?} reply failed: {}", direction, error);
+                return source_error;
+            }
+            reply_buf.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_queue_test() {
+        let error = error_response("ERROR", READ_ONLY, "read-only");
+        let refusal = vec![Refusal::Error(error.clone()), Refusal::Ready];
+        let mut queue = ReplyQueue::default();
+        // the ReadyForQuery ending the startup
+        queue.expect_server();
+        assert_eq!(queue.server_ready(b'I'), (vec![], vec![]));
+        // nothing to wait on
+        assert_eq!(queue.refuse(refusal.clone(), b'I'), [error.clone(), ready_for_query(b'I')].concat());
+
+        // a refusal waits on the queries before it, and goes out with the
+        // status of the last one
+        queue.expect_server();
+        queue.expect_server();
+        assert!(queue.refuse(refusal.clone(), b'I').is_empty());
+        queue.expect_server();
+        assert_eq!(queue.server_ready(b'T'), (vec![], vec![]));
+        assert_eq!(queue.server_ready(b'T'), (vec![], [error.clone(), ready_for_query(b'T')].concat()));
+        assert_eq!(queue.server_ready(b'I'), (vec![], vec![]));
+        assert!(queue.owed.is_empty());
+
+        // a refused extended query's error goes out ahead of the
+        // ReadyForQuery answering its Sync, after the queries before it
+        queue.expect_server();
+        assert!(queue.refuse(vec![Refusal::BeforeSync(error.clone())], b'I').is_empty());
+        queue.expect_server();
+        assert_eq!(queue.server_ready(b'I'), (vec![], vec![]));
+        assert_eq!(queue.server_ready(b'I'), (error.clone(), vec![]));
+        assert!(queue.owed.is_empty());
+        // even with nothing else to wait on
+        assert!(queue.refuse(vec![Refusal::BeforeSync(error.clone())], b'I').is_empty());
+        queue.expect_server();
+        assert_eq!(queue.server_ready(b'I'), (error, vec![]));
+        assert!(queue.owed.is_empty());
+    }
+}
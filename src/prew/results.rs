@@ -0,0 +1,416 @@
+//! Rewriting of the rows the server returns, column by column.
+//!
+//! Rows are only rewritten knowing the description of their columns,
+//! including whether their values are in text or binary format, and only
+//! in the columns a `ResultRewriter` declares it rewrites. Rewritten rows
+//! are encoded anew, so their lengths stay consistent whatever the
+//! rewriters do to the values.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex as SyncMutex;
+
+use crate::models::reports::PacketDirection;
+use crate::prew::protocol::{
+    BindMessage, CloseMessage, DescribeMessage, ExecuteMessage, FieldDescription, ParseMessage, data_row,
+    data_row_values, row_description,
+};
+
+/// OID of the `name` type, which the catalogs use for database and role
+/// names
+const NAME_TYPE_OID: u32 = 19;
+/// OIDs below this are of objects created with the cluster (PostgreSQL's
+/// FirstNormalObjectId), rather than by users
+const FIRST_NORMAL_OBJECT_ID: u32 = 16384;
+
+/// Format code of binary values
+const BINARY_FORMAT: i16 = 1;
+
+/// A column of the rows the server is returning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultColumn {
+    pub field: FieldDescription,
+    /// Whether the column's values are in binary rather than text format
+    pub binary: bool,
+}
+impl ResultColumn {
+    /// Whether the column holds names from the catalogs rather than data of
+    /// the user's own: `name` columns of system catalogs and views, and of
+    /// expressions such as `current_user` and `current_database()`.
+    ///
+    /// Names cast to another type, e.g. `datname::text`, aren't recognized.
+    pub fn holds_names(&self) -> bool {
+        self.field.type_oid == NAME_TYPE_OID && self.field.table_oid < FIRST_NORMAL_OBJECT_ID
+    }
+}
+
+/// Rewrites values of the result columns it declares.
+pub trait ResultRewriter {
+    /// Whether the rewriter may change values of `column`. Rows are passed
+    /// on untouched unless some rewriter rewrites one of their columns.
+    fn rewrites(&self, column: &ResultColumn) -> bool;
+
+    /// New value of a column the rewriter rewrites, in a row returned to
+    /// `username`, or None to leave it be.
+    fn rewrite(&self, column: &ResultColumn, value: &[u8], username: &str) -> Option<Vec<u8>>;
+}
+
+/// Rewrite the values of a DataRow (backend) message with `rewriters`,
+/// returning the new message, or None if none of its values changed (or it
+/// doesn't match its description).
+pub fn rewrite_data_row(
+    bytes: &[u8],
+    columns: &[ResultColumn],
+    rewriters: &[&dyn ResultRewriter],
+    username: &str,
+) -> Option<Vec<u8>> {
+    let rewritten = columns.iter()
+        .map(|column| rewriters.iter().filter(|rewriter| rewriter.rewrites(column)).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    if rewritten.iter().all(|rewriters| rewriters.is_empty()) {
+        return None;
+    }
+    let mut values = data_row_values(bytes)?;
+    if values.len() != columns.len() {
+        return None;
+    }
+    let mut modified = false;
+    for ((value, column), rewriters) in values.iter_mut().zip(columns).zip(&rewritten) {
+        for rewriter in rewriters {
+            if let Some(new_value) = value.as_ref().and_then(|value| rewriter.rewrite(column, value, username)) {
+                *value = Some(new_value);
+                modified = true;
+            }
+        }
+    }
+    modified.then(|| data_row(&values))
+}
+
+/// Messages sent to the server whose answers affect which rows are coming,
+/// in the order it answers them.
+#[derive(Debug)]
+enum Pending {
+    Query,
+    DescribeStatement(String),
+    DescribePortal(String),
+    Execute(String),
+    Sync,
+}
+
+/// A portal, as bound by the client.
+#[derive(Debug)]
+struct Portal {
+    statement: String,
+    result_formats: Vec<i16>,
+    /// Columns of its rows, if the portal itself was described
+    columns: Option<Vec<ResultColumn>>,
+}
+
+#[derive(Debug, Default)]
+struct ResultState {
+    /// Result fields of each described prepared statement, by statement
+    /// name. Their formats are only known once bound.
+    statements: HashMap<String, Vec<FieldDescription>>,
+    /// Bound portals, by portal name
+    portals: HashMap<String, Portal>,
+    pending: VecDeque<Pending>,
+    /// Columns of the last RowDescription of a simple query
+    columns: Vec<ResultColumn>,
+}
+impl ResultState {
+    /// Forget the messages the server skips after an error: the rest of
+    /// a simple query, or everything up to the next Sync.
+    fn skip_to_sync(&mut self) {
+        while !matches!(self.pending.front(), None | Some(Pending::Query) | Some(Pending::Sync)) {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Columns of the rows of a portal, if it or its statement was
+    /// described.
+    fn portal_columns(&self, name: &str) -> Option<Vec<ResultColumn>> {
+        let portal = self.portals.get(name)?;
+        if let Some(columns) = &portal.columns {
+            return Some(columns.clone());
+        }
+        let fields = self.statements.get(&portal.statement)?;
+        Some(
+            fields.iter()
+                .enumerate()
+                .map(|(index, field)| ResultColumn {
+                    field: field.clone(),
+                    binary: BindMessage::result_format(&portal.result_formats, index) == BINARY_FORMAT,
+                })
+                .collect()
+        )
+    }
+}
+
+/// Tracks the description of the rows the server is returning.
+///
+/// Simple queries describe their rows right before returning them. In the
+/// extended protocol, rows are described in answer to a Describe of their
+/// portal, or of the statement it was bound from, which may have been sent
+/// long before and leaves their formats to the Bind; rows of portals that
+/// were never described aren't known.
+#[derive(Debug, Default)]
+pub struct ResultTracker {
+    state: SyncMutex<ResultState>,
+}
+impl ResultTracker {
+    pub fn new() -> ResultTracker {
+        ResultTracker::default()
+    }
+
+    pub fn observe(&self, bytes: &[u8], direction: PacketDirection) {
+        let mut state = self.state.lock().unwrap();
+        match (direction, bytes.first()) {
+            (PacketDirection::Forward, Some(b'Q')) => {
+                // a simple query replaces the unnamed statement and portal
+                state.statements.remove("");
+                state.portals.remove("");
+                state.pending.push_back(Pending::Query);
+            }
+            (PacketDirection::Forward, Some(b'P')) => {
+                if let Some(parse) = ParseMessage::from_bytes(bytes) {
+                    state.statements.remove(&parse.statement);
+                }
+            }
+            (PacketDirection::Forward, Some(b'B')) => {
+                if let Some(bind) = BindMessage::from_bytes(bytes) {
+                    let portal = Portal {
+                        statement: bind.statement,
+                        result_formats: bind.result_formats,
+                        columns: None,
+                    };
+                    state.portals.insert(bind.portal, portal);
+                }
+            }
+            (PacketDirection::Forward, Some(b'D')) => {
+                match DescribeMessage::from_bytes(bytes) {
+                    Some(DescribeMessage::Statement(name)) => state.pending.push_back(Pending::DescribeStatement(name)),
+                    Some(DescribeMessage::Portal(name)) => state.pending.push_back(Pending::DescribePortal(name)),
+                    None => {}
+                }
+            }
+            (PacketDirection::Forward, Some(b'E')) => {
+                if let Some(execute) = ExecuteMessage::from_bytes(bytes) {
+                    state.pending.push_back(Pending::Execute(execute.portal));
+                }
+            }
+            (PacketDirection::Forward, Some(b'S')) => state.pending.push_back(Pending::Sync),
+            (PacketDirection::Forward, Some(b'C')) => {
+                match CloseMessage::from_bytes(bytes) {
+                    Some(CloseMessage::Statement(name)) => { state.statements.remove(&name); }
+                    Some(CloseMessage::Portal(name)) => { state.portals.remove(&name); }
+                    None => {}
+                }
+            }
+            (PacketDirection::Backward, Some(b'T')) => {
+                let fields = row_description(bytes).unwrap_or_default();
+                let columns = || {
+                    fields.iter()
+                        .map(|field| ResultColumn { field: field.clone(), binary: field.format == BINARY_FORMAT })
+                        .collect::<Vec<_>>()
+                };
+                match state.pending.front() {
+                    Some(Pending::DescribeStatement(name)) => {
+                        let name = name.clone();
+                        state.pending.pop_front();
+                        state.statements.insert(name, fields);
+                    }
+                    Some(Pending::DescribePortal(name)) => {
+                        let name = name.clone();
+                        state.pending.pop_front();
+                        if let Some(portal) = state.portals.get_mut(&name) {
+                            portal.columns = Some(columns());
+                        }
+                    }
+                    _ => state.columns = columns(),
+                }
+            }
+            (PacketDirection::Backward, Some(b'n')) => {
+                // NoData
+                if matches!(state.pending.front(), Some(Pending::DescribeStatement(_) | Pending::DescribePortal(_))) {
+                    state.pending.pop_front();
+                }
+            }
+            (PacketDirection::Backward, Some(b'C' | b'I' | b's')) => {
+                // CommandComplete, EmptyQueryResponse and PortalSuspended
+                // end an Execute, or a statement of a simple query
+                match state.pending.front() {
+                    Some(Pending::Execute(_)) => { state.pending.pop_front(); }
+                    Some(Pending::Query) => state.columns.clear(),
+                    _ => {}
+                }
+            }
+            (PacketDirection::Backward, Some(b'E')) => {
+                state.columns.clear();
+                state.skip_to_sync();
+            }
+            (PacketDirection::Backward, Some(b'Z')) => {
+                state.skip_to_sync();
+                state.pending.pop_front();
+            }
+            _ => {}
+        }
+    }
+
+    /// Columns of the DataRow the server is sending, empty if they aren't
+    /// known.
+    pub fn columns(&self) -> Vec<ResultColumn> {
+        let state = self.state.lock().unwrap();
+        match state.pending.front() {
+            Some(Pending::Execute(portal)) => state.portal_columns(portal).unwrap_or_default(),
+            Some(Pending::Query) => state.columns.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id];
+        bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    /// RowDescription of a `name` column of pg_database and a `name` column
+    /// of a user's table, in the given format.
+    fn row_description(format: i16) -> Vec<u8> {
+        let mut body = 2_i16.to_be_bytes().to_vec();
+        for (name, table_oid) in [("datname", 1262_u32), ("label", 16500)] {
+            body.extend(name.as_bytes());
+            body.push(0);
+            body.extend(table_oid.to_be_bytes());
+            body.extend(1_i16.to_be_bytes());
+            body.extend(NAME_TYPE_OID.to_be_bytes());
+            body.extend([0; 6]);
+            body.extend(format.to_be_bytes());
+        }
+        message(b'T', &body)
+    }
+
+    fn observe(tracker: &ResultTracker, direction: PacketDirection, messages: &[Vec<u8>]) {
+        for message in messages {
+            tracker.observe(message, direction);
+        }
+    }
+
+    /// Names and formats of the columns of the rows being returned.
+    fn columns(tracker: &ResultTracker) -> Vec<(String, bool)> {
+        tracker.columns().into_iter().map(|column| (column.field.name, column.binary)).collect()
+    }
+
+    fn described(binary: bool) -> Vec<(String, bool)> {
+        vec![("datname".to_string(), binary), ("label".to_string(), binary)]
+    }
+
+    #[test]
+    fn simple_query_test() {
+        let tracker = ResultTracker::new();
+        observe(&tracker, PacketDirection::Forward, &[message(b'Q', b"SELECT datname, label FROM t\0")]);
+        observe(&tracker, PacketDirection::Backward, &[row_description(0)]);
+        assert_eq!(columns(&tracker), described(false));
+        let holds_names = tracker.columns().iter().map(ResultColumn::holds_names).collect::<Vec<_>>();
+        assert_eq!(holds_names, vec![true, false]);
+        observe(&tracker, PacketDirection::Backward, &[message(b'C', b"SELECT 1\0"), message(b'Z', b"I")]);
+        assert_eq!(columns(&tracker), vec![]);
+    }
+
+    #[test]
+    fn extended_query_test() {
+        let tracker = ResultTracker::new();
+        // described portal, with binary results
+        observe(&tracker, PacketDirection::Forward, &[
+            message(b'P', b"\0SELECT datname, label FROM t\0\0\0"),
+            message(b'B', b"\0\0\0\0\0\0\0\x01\0\x01"),
+            message(b'D', b"P\0"),
+            message(b'E', b"\0\0\0\0\0"),
+            message(b'S', b""),
+        ]);
+        observe(&tracker, PacketDirection::Backward, &[message(b'1', b""), message(b'2', b""), row_description(1)]);
+        assert_eq!(columns(&tracker), described(true));
+        observe(&tracker, PacketDirection::Backward, &[message(b'C', b"SELECT 1\0"), message(b'Z', b"I")]);
+
+        // statement described once, then bound with binary results and
+        // executed without describing
+        observe(&tracker, PacketDirection::Forward, &[
+            message(b'P', b"s1\0SELECT datname, label FROM t\0\0\0"),
+            message(b'D', b"Ss1\0"),
+            message(b'S', b""),
+        ]);
+        observe(&tracker, PacketDirection::Backward, &[
+            message(b'1', b""),
+            message(b't', b"\0\0"),
+            row_description(0),
+            message(b'Z', b"I"),
+        ]);
+        observe(&tracker, PacketDirection::Forward, &[
+            message(b'B', b"\0s1\0\0\0\0\0\0\x02\0\0\0\x01"),
+            message(b'E', b"\0\0\0\0\0"),
+            message(b'S', b""),
+        ]);
+        observe(&tracker, PacketDirection::Backward, &[message(b'2', b"")]);
+        assert_eq!(columns(&tracker), vec![("datname".to_string(), false), ("label".to_string(), true)]);
+        observe(&tracker, PacketDirection::Backward, &[message(b'C', b"SELECT 1\0"), message(b'Z', b"I")]);
+
+        // a portal that was never described
+        observe(&tracker, PacketDirection::Forward, &[
+            message(b'P', b"s2\0SELECT datname FROM pg_database\0\0\0"),
+            message(b'B', b"\0s2\0\0\0\0\0\0\0"),
+            message(b'E', b"\0\0\0\0\0"),
+            message(b'S', b""),
+        ]);
+        observe(&tracker, PacketDirection::Backward, &[message(b'1', b""), message(b'2', b"")]);
+        assert_eq!(columns(&tracker), vec![]);
+
+        // the server skips everything up to the Sync after an error
+        observe(&tracker, PacketDirection::Backward, &[message(b'E', b"SERROR\0C42P01\0\0"), message(b'Z', b"I")]);
+        observe(&tracker, PacketDirection::Forward, &[message(b'Q', b"SELECT datname, label FROM t\0")]);
+        observe(&tracker, PacketDirection::Backward, &[row_description(0)]);
+        assert_eq!(columns(&tracker), described(false));
+    }
+
+    /// Rewrites text values of names to upper case.
+    struct Upper;
+    impl ResultRewriter for Upper {
+        fn rewrites(&self, column: &ResultColumn) -> bool {
+            column.holds_names() && !column.binary
+        }
+
+        fn rewrite(&self, _column: &ResultColumn, value: &[u8], _username: &str) -> Option<Vec<u8>> {
+            Some(value.to_ascii_uppercase())
+        }
+    }
+
+    #[test]
+    fn rewrite_data_row_test() {
+        let column = |name: &str, table_oid: u32, binary: bool| ResultColumn {
+            field: FieldDescription {
+                name: name.to_string(),
+                table_oid,
+                column: 1,
+                type_oid: NAME_TYPE_OID,
+                format: binary as i16,
+            },
+            binary,
+        };
+        let columns = vec![column("datname", 1262, false), column("label", 16500, false), column("b", 0, true)];
+        let row = data_row(&[Some(b"shop".to_vec()), Some(b"mine".to_vec()), Some(b"bin".to_vec())]);
+        assert_eq!(
+            rewrite_data_row(&row, &columns, &[&Upper], "alice"),
+            Some(data_row(&[Some(b"SHOP".to_vec()), Some(b"mine".to_vec()), Some(b"bin".to_vec())]))
+        );
+        // NULLs stay NULL
+        let row = data_row(&[None, Some(b"mine".to_vec()), None]);
+        assert_eq!(rewrite_data_row(&row, &columns, &[&Upper], "alice"), None);
+        // rows that don't match their description are left alone
+        let row = data_row(&[Some(b"shop".to_vec())]);
+        assert_eq!(rewrite_data_row(&row, &columns, &[&Upper], "alice"), None);
+        assert_eq!(rewrite_data_row(&row, &[], &[&Upper], "alice"), None);
+    }
+}
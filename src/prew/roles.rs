@@ -0,0 +1,247 @@
+//! Namespacing of the roles users create, with the same `__username` suffix
+//! as their databases, so that users can set up roles of their own without
+//! reaching anyone else's.
+//!
+//! Users' roles are NOCREATEROLE, so the statements that create, drop and
+//! rename roles are replaced by calls to the `impulse` procedures that
+//! `PostgresManager::install_role_procedures` installs, which only act on
+//! roles in the caller's namespace. Other statements naming roles are
+//! passed on with the names suffixed, for the server to allow or refuse.
+
+use pg_query::protobuf::{
+    RoleStmtType, GrantStmt, ObjectType, RawStmt, RoleSpec, RoleSpecType,
+};
+use pg_query::protobuf::a_const::Val;
+use pg_query::{Node, NodeEnum, NodeMut};
+
+/// Suffix `role` into the namespace of `username`, unless it's the user's
+/// own role or a predefined one.
+pub fn transform_rolename(role: &str, username: &str) -> String {
+    if role == username || role.starts_with("pg_") {
+        role.to_string()
+    } else {
+        format!("{}__{}", role, username)
+    }
+}
+
+/// Replace the statements of `stmts` that create, drop or rename roles with
+/// calls to the procedures doing so in the namespace of `username`,
+/// returning whether any were.
+pub fn replace_role_statements(stmts: &mut Vec<RawStmt>, username: &str) -> bool {
+    let mut modified = false;
+    let mut replaced = Vec::with_capacity(stmts.len());
+    for raw_stmt in stmts.drain(..) {
+        let stmt = raw_stmt.stmt.as_ref().and_then(|stmt| stmt.node.as_ref());
+        match stmt.and_then(|stmt| procedure_calls(stmt, username)) {
+            Some(calls) => {
+                replaced.extend(calls);
+                modified = true;
+            }
+            None => replaced.push(raw_stmt),
+        }
+    }
+    *stmts = replaced;
+    modified
+}
+
+/// Calls replacing a statement, if it creates, drops or renames roles in a
+/// way the procedures support.
+fn procedure_calls(stmt: &NodeEnum, username: &str) -> Option<Vec<RawStmt>> {
+    let calls = match stmt {
+        // the procedures only create roles that can't log in
+        NodeEnum::CreateRoleStmt(create) if create.stmt_type != RoleStmtType::RolestmtUser as i32 => {
+            let only_nologin = create.options.iter().all(|option| match &option.node {
+                Some(NodeEnum::DefElem(option)) => {
+                    option.defname == "canlogin"
+                        && matches!(
+                            option.arg.as_ref().and_then(|arg| arg.node.as_ref()),
+                            Some(NodeEnum::Boolean(value)) if !value.boolval,
+                        )
+                }
+                _ => false,
+            });
+            if !only_nologin {
+                return None;
+            }
+            vec![format!("CALL impulse.create_role({})", quote_literal(&transform_rolename(&create.role, username)))]
+        }
+        NodeEnum::DropRoleStmt(drop) => {
+            let roles = drop.roles.iter()
+                .map(|role| match &role.node {
+                    Some(NodeEnum::RoleSpec(role)) if role.roletype == RoleSpecType::RolespecCstring as i32 => {
+                        Some(transform_rolename(&role.rolename, username))
+                    }
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            roles.iter()
+                .map(|role| format!("CALL impulse.drop_role({}, {})", quote_literal(role), drop.missing_ok))
+                .collect()
+        }
+        NodeEnum::RenameStmt(rename) if rename.rename_type == ObjectType::ObjectRole as i32 => {
+            vec![format!(
+                "CALL impulse.rename_role({}, {})",
+                quote_literal(&transform_rolename(&rename.subname, username)),
+                quote_literal(&transform_rolename(&rename.newname, username)),
+            )]
+        }
+        _ => return None,
+    };
+    calls.iter()
+        .map(|call| pg_query::parse(call).ok()?.protobuf.stmts.into_iter().next())
+        .collect()
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Suffix the roles named by a statement, returning whether it names any.
+///
+/// # Safety
+///
+/// `node` must point into a live parse tree, as returned by `nodes_mut`.
+pub unsafe fn transform_role_names(node: NodeMut, username: &str) -> bool {
+    match node {
+        NodeMut::CreateRoleStmt(stmt) => {
+            let stmt = &mut *stmt;
+            stmt.role = transform_rolename(&stmt.role, username);
+            transform_role_options(&mut stmt.options, username);
+            true
+        }
+        NodeMut::AlterRoleStmt(stmt) => {
+            let stmt = &mut *stmt;
+            transform_role_options(&mut stmt.options, username);
+            transform_role_spec(stmt.role.as_mut(), username)
+        }
+        NodeMut::AlterRoleSetStmt(stmt) => {
+            let stmt = &mut *stmt;
+            transform_role_spec(stmt.role.as_mut(), username)
+        }
+        NodeMut::DropRoleStmt(stmt) => transform_role_specs(&mut (*stmt).roles, username),
+        NodeMut::GrantStmt(stmt) => transform_grant(&mut *stmt, username),
+        NodeMut::GrantRoleStmt(stmt) => {
+            let stmt = &mut *stmt;
+            for role in stmt.granted_roles.iter_mut() {
+                if let Some(NodeEnum::AccessPriv(role)) = role.node.as_mut() {
+                    role.priv_name = transform_rolename(&role.priv_name, username);
+                }
+            }
+            transform_role_specs(&mut stmt.grantee_roles, username);
+            transform_role_spec(stmt.grantor.as_mut(), username);
+            true
+        }
+        NodeMut::RenameStmt(stmt) if (*stmt).rename_type == ObjectType::ObjectRole as i32 => {
+            let stmt = &mut *stmt;
+            stmt.subname = transform_rolename(&stmt.subname, username);
+            stmt.newname = transform_rolename(&stmt.newname, username);
+            true
+        }
+        // ALTER TABLE ... OWNER TO, among other commands
+        NodeMut::AlterTableStmt(stmt) => {
+            let stmt = &mut *stmt;
+            let mut modified = false;
+            for cmd in stmt.cmds.iter_mut() {
+                if let Some(NodeEnum::AlterTableCmd(cmd)) = cmd.node.as_mut() {
+                    modified = transform_role_spec(cmd.newowner.as_mut(), username) || modified;
+                }
+            }
+            modified
+        }
+        NodeMut::AlterOwnerStmt(stmt) => transform_role_spec((&mut *stmt).newowner.as_mut(), username),
+        NodeMut::CommentStmt(stmt) if (*stmt).objtype == ObjectType::ObjectRole as i32 => {
+            transform_role_name_node((&mut *stmt).object.as_deref_mut(), username)
+        }
+        NodeMut::SecLabelStmt(stmt) if (*stmt).objtype == ObjectType::ObjectRole as i32 => {
+            transform_role_name_node((&mut *stmt).object.as_deref_mut(), username)
+        }
+        // SET ROLE and SET SESSION AUTHORIZATION
+        NodeMut::VariableSetStmt(stmt) => {
+            let stmt = &mut *stmt;
+            if stmt.name != "role" && stmt.name != "session_authorization" {
+                return false;
+            }
+            let mut modified = false;
+            for arg in stmt.args.iter_mut() {
+                if let Some(NodeEnum::AConst(value)) = arg.node.as_mut() {
+                    if let Some(Val::Sval(role)) = value.val.as_mut() {
+                        if role.sval != "none" {
+                            role.sval = transform_rolename(&role.sval, username);
+                            modified = true;
+                        }
+                    }
+                }
+            }
+            modified
+        }
+        NodeMut::ReassignOwnedStmt(stmt) => {
+            let stmt = &mut *stmt;
+            let roles = transform_role_specs(&mut stmt.roles, username);
+            transform_role_spec(stmt.newrole.as_mut(), username) || roles
+        }
+        NodeMut::DropOwnedStmt(stmt) => transform_role_specs(&mut (*stmt).roles, username),
+        NodeMut::CreateSchemaStmt(stmt) => transform_role_spec((&mut *stmt).authrole.as_mut(), username),
+        NodeMut::AlterDefaultPrivilegesStmt(stmt) => {
+            let stmt = &mut *stmt;
+            let roles = transform_role_options(&mut stmt.options, username);
+            stmt.action.as_mut().is_some_and(|action| transform_grant(action, username)) || roles
+        }
+        NodeMut::CreatePolicyStmt(stmt) => transform_role_specs(&mut (*stmt).roles, username),
+        NodeMut::AlterPolicyStmt(stmt) => transform_role_specs(&mut (*stmt).roles, username),
+        NodeMut::CreateUserMappingStmt(stmt) => transform_role_spec((&mut *stmt).user.as_mut(), username),
+        NodeMut::AlterUserMappingStmt(stmt) => transform_role_spec((&mut *stmt).user.as_mut(), username),
+        NodeMut::DropUserMappingStmt(stmt) => transform_role_spec((&mut *stmt).user.as_mut(), username),
+        _ => false,
+    }
+}
+
+fn transform_grant(stmt: &mut GrantStmt, username: &str) -> bool {
+    let grantees = transform_role_specs(&mut stmt.grantees, username);
+    transform_role_spec(stmt.grantor.as_mut(), username) || grantees
+}
+
+/// Suffix a role named by its name, returning whether it is one (rather
+/// than e.g. CURRENT_USER or PUBLIC).
+fn transform_role_spec(role: Option<&mut RoleSpec>, username: &str) -> bool {
+    match role {
+        Some(role) if role.roletype == RoleSpecType::RolespecCstring as i32 => {
+            role.rolename = transform_rolename(&role.rolename, username);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn transform_role_specs(roles: &mut [Node], username: &str) -> bool {
+    let mut modified = false;
+    for role in roles.iter_mut() {
+        if let Some(NodeEnum::RoleSpec(role)) = role.node.as_mut() {
+            modified = transform_role_spec(Some(role), username) || modified;
+        }
+    }
+    modified
+}
+
+/// Suffix the roles of role options such as IN ROLE, ROLE and ADMIN.
+fn transform_role_options(options: &mut [Node], username: &str) -> bool {
+    let mut modified = false;
+    for option in options.iter_mut() {
+        if let Some(NodeEnum::DefElem(option)) = option.node.as_mut() {
+            let names_roles = ["addroleto", "rolemembers", "adminmembers", "roles"].contains(&option.defname.as_str());
+            if let (true, Some(NodeEnum::List(roles))) = (names_roles, option.arg.as_mut().and_then(|arg| arg.node.as_mut())) {
+                modified = transform_role_specs(&mut roles.items, username) || modified;
+            }
+        }
+    }
+    modified
+}
+
+fn transform_role_name_node(node: Option<&mut Node>, username: &str) -> bool {
+    match node.and_then(|node| node.node.as_mut()) {
+        Some(NodeEnum::String(name)) => {
+            name.sval = transform_rolename(&name.sval, username);
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::Utc;
+use diesel::prelude::*;
+use futures::lock::Mutex;
+use uuid::Uuid;
+
+use prew::PostgresqlPacket;
+use prew::postgresql::PostgresqlPacketInfo;
+
+use crate::models::reports::PacketDirection;
+use crate::models::sessions::{NewSession, Session, SessionTraffic, TerminationReason};
+use crate::prew::spawn_db_write;
+
+/// Tracks a client connection through the proxy as a row of `sessions`.
+///
+/// The row is created with the tracker, filled in from the client's startup
+/// message, and completed with the byte counts and termination reason when
+/// the tracker is dropped, i.e. once the last clone of the session's context
+/// goes away with the connection.
+#[derive(Debug)]
+pub struct SessionTracker {
+    session_id: Uuid,
+    conn: Arc<Mutex<PgConnection>>,
+    bytes_in: AtomicI64,
+    bytes_out: AtomicI64,
+    /// Microseconds traffic was held back in each direction
+    throttled_in: AtomicI64,
+    throttled_out: AtomicI64,
+    username: SyncMutex<Option<String>>,
+    termination_reason: SyncMutex<Option<TerminationReason>>,
+}
+impl SessionTracker {
+    /// Record a new session before anything is reported in it.
+    pub fn start(conn: &mut PgConnection, client_addr: Option<String>) -> Result<Session> {
+        NewSession::create(client_addr).commit(conn)
+    }
+
+    pub fn new(conn: Arc<Mutex<PgConnection>>, session_id: Uuid) -> SessionTracker {
+        SessionTracker {
+            session_id,
+            conn,
+            bytes_in: AtomicI64::new(0),
+            bytes_out: AtomicI64::new(0),
+            throttled_in: AtomicI64::new(0),
+            throttled_out: AtomicI64::new(0),
+            username: SyncMutex::new(None),
+            termination_reason: SyncMutex::new(None),
+        }
+    }
+
+    pub fn session_id(&self) -> Uuid {
+        self.session_id
+    }
+
+    /// User named in the client's startup message, once it has been seen.
+    pub fn username(&self) -> Option<String> {
+        self.username.lock().unwrap().clone()
+    }
+
+    /// Record the user, database and application name of the session from
+    /// the client's startup message.
+    pub fn observe(&self, message: &PostgresqlPacket) {
+        if let PostgresqlPacketInfo::Startup(startup) = &message.info {
+            let session_id = self.session_id;
+            let username = startup.get_parameter("user");
+            let database = startup.get_parameter("database");
+            let application_name = startup.get_parameter("application_name");
+            self.username.lock().unwrap().clone_from(&username);
+            spawn_db_write(&self.conn, format!("startup of session {}", &session_id), move |conn| {
+                Session::record_startup(
+                    conn,
+                    &session_id,
+                    username.as_deref(),
+                    database.as_deref(),
+                    application_name.as_deref(),
+                )
+            });
+        }
+    }
+
+    /// Count bytes received from the client (`Forward`) or the server
+    /// (`Backward`).
+    pub fn add_bytes(&self, direction: PacketDirection, count: usize) {
+        let counter = match direction {
+            PacketDirection::Forward => &self.bytes_in,
+            PacketDirection::Backward => &self.bytes_out,
+        };
+        counter.fetch_add(count as i64, Ordering::Relaxed);
+    }
+
+    /// Count time traffic from the client (`Forward`) or the server
+    /// (`Backward`) was held back to keep the user within their bandwidth.
+    pub fn add_throttled(&self, direction: PacketDirection, throttled: Duration) {
+        let counter = match direction {
+            PacketDirection::Forward => &self.throttled_in,
+            PacketDirection::Backward => &self.throttled_out,
+        };
+        counter.fetch_add(throttled.as_micros() as i64, Ordering::Relaxed);
+    }
+
+    /// Set why the connection ended, keeping the first reason given.
+    pub fn end(&self, reason: TerminationReason) {
+        self.termination_reason.lock().unwrap().get_or_insert(reason);
+    }
+}
+impl Drop for SessionTracker {
+    fn drop(&mut self) {
+        let session_id = self.session_id;
+        let traffic = SessionTraffic {
+            bytes_in: *self.bytes_in.get_mut(),
+            bytes_out: *self.bytes_out.get_mut(),
+            throttled_in_ms: *self.throttled_in.get_mut() / 1000,
+            throttled_out_ms: *self.throttled_out.get_mut() / 1000,
+        };
+        let reason = self.termination_reason.get_mut().unwrap().unwrap_or(TerminationReason::Unknown);
+        let ended_at = Utc::now();
+        spawn_db_write(&self.conn, format!("end of session {}", &session_id), move |conn| {
+            Session::record_end(conn, &session_id, &ended_at, &traffic, reason)
+        });
+    }
+}
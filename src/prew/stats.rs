@@ -0,0 +1,216 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Instant;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use futures::lock::Mutex;
+
+use prew::PostgresqlPacket;
+use prew::postgresql::PostgresqlPacketInfo;
+
+use crate::models::query_stats::NewQueryStat;
+use crate::models::reports::PacketDirection;
+use crate::prew::protocol::{BindMessage, CloseMessage, ExecuteMessage, ParseMessage, command_tag, error_code};
+use crate::prew::spawn_db_write;
+
+#[derive(Debug)]
+struct PendingStatement {
+    stat: NewQueryStat,
+    started: Instant,
+}
+
+/// What the server still has to answer, in order.
+#[derive(Debug)]
+enum Pending {
+    Statement(PendingStatement),
+    /// End of a simple query, or a Sync or function call, which the server
+    /// answers with ReadyForQuery
+    Ready,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Query of each prepared statement, by statement name
+    statements: HashMap<String, String>,
+    /// Query of each bound portal, by portal name
+    portals: HashMap<String, String>,
+    /// Statements sent to the server that haven't completed yet, and the
+    /// ReadyForQuery messages ending them
+    pending: VecDeque<Pending>,
+}
+impl TrackerState {
+    fn push(&mut self, username: &str, statement: &str) {
+        self.pending.push_back(Pending::Statement(PendingStatement {
+            stat: NewQueryStat::start(username.to_string(), statement),
+            started: Instant::now(),
+        }));
+    }
+
+    /// The statement the server is currently responding to.
+    fn current(&mut self) -> Option<&mut PendingStatement> {
+        match self.pending.front_mut() {
+            Some(Pending::Statement(pending)) => Some(pending),
+            _ => None,
+        }
+    }
+
+    /// Remove the statement the server is currently responding to. The
+    /// server runs statements one at a time, so the next one starts now.
+    fn pop(&mut self) -> Option<NewQueryStat> {
+        let PendingStatement { mut stat, started } = match self.pending.pop_front()? {
+            Pending::Statement(pending) => pending,
+            ready => {
+                self.pending.push_front(ready);
+                return None;
+            }
+        };
+        stat.duration_seconds = started.elapsed().as_secs_f64();
+        let next = self.pending.iter_mut().find_map(|pending| match pending {
+            Pending::Statement(next) => Some(next),
+            Pending::Ready => None,
+        });
+        if let Some(next) = next {
+            next.started = Instant::now();
+            next.stat.query_time = Utc::now();
+        }
+        Some(stat)
+    }
+
+    /// Forget the statements the server skips after an error: the rest of
+    /// a simple query, or everything up to the next Sync.
+    fn skip_to_sync(&mut self) {
+        while self.current().is_some() {
+            self.pending.pop_front();
+        }
+    }
+}
+
+/// Correlates each statement sent by the client with the server's response
+/// to it, recording a `query_stats` row when the statement completes.
+///
+/// Statements come from simple Query messages (split into their individual
+/// statements) and from Execute messages of the extended protocol, which are
+/// matched to their query through Bind and Parse. A statement completes with
+/// CommandComplete, ErrorResponse or PortalSuspended; rows and bytes returned
+/// are counted from the DataRow messages in between.
+#[derive(Debug)]
+pub struct QueryTracker {
+    conn: Arc<Mutex<PgConnection>>,
+    state: SyncMutex<TrackerState>,
+}
+impl QueryTracker {
+    pub fn new(conn: Arc<Mutex<PgConnection>>) -> QueryTracker {
+        QueryTracker {
+            conn,
+            state: SyncMutex::new(TrackerState::default()),
+        }
+    }
+
+    pub fn observe(
+        &self,
+        message: &PostgresqlPacket,
+        direction: PacketDirection,
+        username: Option<&String>,
+    ) {
+        let username = match username {
+            Some(username) => username,
+            None => return,
+        };
+        let bytes = match &message.bytes {
+            Some(bytes) if !bytes.is_empty() => bytes,
+            _ => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        match (direction, bytes[0]) {
+            (PacketDirection::Forward, b'Q') => {
+                if let PostgresqlPacketInfo::Query(query) = &message.info {
+                    for statement in split_statements(&query.query) {
+                        state.push(username, statement);
+                    }
+                }
+                state.pending.push_back(Pending::Ready);
+            }
+            (PacketDirection::Forward, b'S' | b'F') => {
+                state.pending.push_back(Pending::Ready);
+            }
+            (PacketDirection::Forward, b'P') => {
+                if let Some(parse) = ParseMessage::from_bytes(bytes) {
+                    state.statements.insert(parse.statement, parse.query);
+                }
+            }
+            (PacketDirection::Forward, b'B') => {
+                if let Some(bind) = BindMessage::from_bytes(bytes) {
+                    if let Some(query) = state.statements.get(&bind.statement).cloned() {
+                        state.portals.insert(bind.portal, query);
+                    }
+                }
+            }
+            (PacketDirection::Forward, b'E') => {
+                if let Some(execute) = ExecuteMessage::from_bytes(bytes) {
+                    if let Some(query) = state.portals.get(&execute.portal).cloned() {
+                        state.push(username, &query);
+                    }
+                }
+            }
+            (PacketDirection::Forward, b'C') => {
+                match CloseMessage::from_bytes(bytes) {
+                    Some(CloseMessage::Statement(name)) => { state.statements.remove(&name); }
+                    Some(CloseMessage::Portal(name)) => { state.portals.remove(&name); }
+                    None => {}
+                }
+            }
+            (PacketDirection::Backward, b'D') => {
+                if let Some(pending) = state.current() {
+                    pending.stat.rows_returned += 1;
+                    pending.stat.bytes_returned += bytes.len() as i64;
+                }
+            }
+            (PacketDirection::Backward, b'C') => {
+                if let Some(mut stat) = state.pop() {
+                    stat.command_tag = command_tag(bytes);
+                    self.record(stat);
+                }
+            }
+            (PacketDirection::Backward, b's') => {
+                if let Some(stat) = state.pop() {
+                    self.record(stat);
+                }
+            }
+            (PacketDirection::Backward, b'E') => {
+                if let Some(mut stat) = state.pop() {
+                    stat.error_code = error_code(bytes);
+                    self.record(stat);
+                }
+                state.skip_to_sync();
+            }
+            (PacketDirection::Backward, b'I') => {
+                // EmptyQueryResponse
+                state.pop();
+            }
+            (PacketDirection::Backward, b'Z') => {
+                state.skip_to_sync();
+                state.pending.pop_front();
+            }
+            _ => {}
+        }
+    }
+
+    fn record(&self, stat: NewQueryStat) {
+        spawn_db_write(&self.conn, format!("{:?}", &stat), move |conn| {
+            stat.commit(conn)?;
+            Ok(())
+        });
+    }
+}
+
+/// Individual statements of a simple query, as the server will run them.
+fn split_statements(query: &str) -> Vec<&str> {
+    pg_query::split_with_parser(query)
+        .or_else(|_| pg_query::split_with_scanner(query))
+        .unwrap_or_else(|_| vec![query])
+        .into_iter()
+        .map(|statement| statement.trim())
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
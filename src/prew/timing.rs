@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Instant;
+
+use diesel::prelude::*;
+use futures::lock::Mutex;
+use log::trace;
+use uuid::Uuid;
+
+use prew::PostgresqlPacket;
+use prew::postgresql::{AuthenticationMessage, PostgresqlPacketInfo};
+
+use crate::models::reports::{NewReport, PacketDirection, PostgresqlPacketType};
+use crate::prew::spawn_db_write;
+
+/// Frontend messages that start the server working on a query: simple Query,
+/// and Parse, Bind and Execute from the extended protocol, plus FunctionCall.
+const QUERY_START_IDS: [u8; 5] = [b'Q', b'P', b'B', b'E', b'F'];
+/// ReadyForQuery, sent by the server once it is idle again
+const READY_FOR_QUERY_ID: u8 = b'Z';
+
+#[derive(Debug, Default)]
+struct TimerState {
+    username: Option<String>,
+    connected_at: Option<Instant>,
+    query_started_at: Option<Instant>,
+}
+
+/// Measures how long an authenticated connection stays open and how long the
+/// server spends on its queries, and records both as time reports.
+///
+/// Query time runs from the first query message sent while the server is
+/// idle until the next ReadyForQuery, so pipelined queries are measured
+/// together. Connection time is reported when the timer is dropped, i.e.
+/// once the last clone of the session's context goes away with the
+/// connection.
+#[derive(Debug)]
+pub struct SessionTimer {
+    conn: Arc<Mutex<PgConnection>>,
+    session_id: Uuid,
+    state: SyncMutex<TimerState>,
+}
+impl SessionTimer {
+    pub fn new(conn: Arc<Mutex<PgConnection>>, session_id: Uuid) -> SessionTimer {
+        SessionTimer {
+            conn,
+            session_id,
+            state: SyncMutex::new(TimerState::default()),
+        }
+    }
+
+    /// Update the timer with a packet passing through the proxy, reporting
+    /// the query time if the packet completes a query.
+    pub fn observe(
+        &self,
+        message: &PostgresqlPacket,
+        direction: PacketDirection,
+        username: Option<&String>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if let PostgresqlPacketInfo::Authentication(AuthenticationMessage::AuthenticationOk) = &message.info {
+            state.username = username.cloned();
+            state.connected_at = Some(Instant::now());
+            return;
+        }
+        if state.connected_at.is_none() {
+            return;
+        }
+        let message_id = match message.bytes.as_ref().and_then(|bytes| bytes.first()) {
+            Some(id) => *id,
+            None => return,
+        };
+        match direction {
+            PacketDirection::Forward if QUERY_START_IDS.contains(&message_id) => {
+                state.query_started_at.get_or_insert_with(Instant::now);
+            }
+            PacketDirection::Backward if message_id == READY_FOR_QUERY_ID => {
+                if let (Some(started), Some(username)) = (state.query_started_at.take(), &state.username) {
+                    self.spawn_report(NewReport::time(
+                        username.clone(),
+                        PostgresqlPacketType::QueryTime,
+                        started.elapsed().as_secs_f64(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn spawn_report(&self, report: NewReport) {
+        let report = report.with_session(self.session_id);
+        trace!("Reporting {:?}", &report);
+        spawn_db_write(&self.conn, format!("{:?}", &report), move |conn| {
+            report.commit(conn)?;
+            Ok(())
+        });
+    }
+}
+impl Drop for SessionTimer {
+    fn drop(&mut self) {
+        let state = std::mem::take(self.state.get_mut().unwrap());
+        if let (Some(connected_at), Some(username)) = (state.connected_at, &state.username) {
+            // charge a query that was still running when the connection closed
+            if let Some(started) = state.query_started_at {
+                self.spawn_report(NewReport::time(
+                    username.clone(),
+                    PostgresqlPacketType::QueryTime,
+                    started.elapsed().as_secs_f64(),
+                ));
+            }
+            self.spawn_report(NewReport::time(
+                username.clone(),
+                PostgresqlPacketType::ConnectionTime,
+                connected_at.elapsed().as_secs_f64(),
+            ));
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! TLS for the proxy's connections, so that clients' SSLRequests are
+//! answered by the proxy itself rather than by a separate TLS terminator in
+//! front of it.
+//!
+//! TLS is configured in the `[tls]` (clients) and `[server_tls]` (the
+//! server) sections of the proxy's TOML config:
+//!
+//! ```toml
+//! [tls]
+//! cert_file = "/etc/ssl/certs/ssl-cert.pem"
+//! key_file = "/etc/ssl/private/ssl-cert.key"
+//! require = true
+//!
+//! [server_tls]
+//! ca_file = "/etc/ssl/certs/server-ca.pem"
+//! # the proxy's own certificate, for servers authenticating it by
+//! # certificate when it authenticates clients itself
+//! cert_file = "/etc/ssl/certs/prew.pem"
+//! key_file = "/etc/ssl/private/prew.key"
+//! ```
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Certificate and key clients' connections are encrypted with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain, leaf first
+    pub cert_file: String,
+    /// PEM file of the certificate's private key
+    pub key_file: String,
+    /// Whether clients that don't ask for TLS are refused
+    #[serde(default)]
+    pub require: bool,
+}
+
+/// How connections to the server are encrypted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+    /// PEM file of the certificates the server's certificate is verified
+    /// against
+    pub ca_file: String,
+    /// Name the server's certificate is verified for, the host of the
+    /// address connected to (which differs between clusters) if not set
+    pub server_name: Option<String>,
+    /// PEM file of the certificate chain the proxy presents to the server,
+    /// if any
+    pub cert_file: Option<String>,
+    /// PEM file of the private key of `cert_file`
+    pub key_file: Option<String>,
+}
+
+/// TLS of the proxy's connections, in either direction. The default is
+/// plaintext both ways, with clients' SSLRequests declined.
+#[derive(Default, Clone)]
+pub struct ProxyTls {
+    acceptor: Option<TlsAcceptor>,
+    require: bool,
+    connector: Option<TlsConnector>,
+    /// Name servers' certificates are verified for, whatever their address
+    server_name: Option<ServerName>,
+}
+impl ProxyTls {
+    pub fn new() -> ProxyTls {
+        ProxyTls::default()
+    }
+
+    /// TLS as configured for clients and for the server, loading the files
+    /// configured.
+    pub fn from_config(client_config: Option<&TlsConfig>, server_config: Option<&ServerTlsConfig>) -> Result<ProxyTls> {
+        let mut tls = ProxyTls::new();
+        if let Some(config) = client_config {
+            let certs = load_certs(&config.cert_file)?;
+            let key = load_key(&config.key_file)?;
+            tls = tls.with_server_config(
+                ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, key)
+                    .context("Invalid TLS certificate or key")?,
+                config.require,
+            );
+        }
+        if let Some(config) = server_config {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&config.ca_file)? {
+                roots.add(&cert).with_context(|| format!("Invalid CA certificate in {}", &config.ca_file))?;
+            }
+            let builder = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots);
+            let client_config = match (&config.cert_file, &config.key_file) {
+                (Some(cert_file), Some(key_file)) => builder
+                    .with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)
+                    .context("Invalid TLS client certificate or key")?,
+                (None, None) => builder.with_no_client_auth(),
+                _ => return Err(anyhow!("Both cert_file and key_file are needed for a client certificate")),
+            };
+            tls = tls.with_client_config(client_config, config.server_name.as_deref())?;
+        }
+        Ok(tls)
+    }
+
+    /// Encrypt clients' connections that ask for it, refusing those that
+    /// don't if `require` is set.
+    pub fn with_server_config(mut self, config: ServerConfig, require: bool) -> ProxyTls {
+        self.acceptor = Some(TlsAcceptor::from(Arc::new(config)));
+        self.require = require;
+        self
+    }
+
+    /// Encrypt connections to the server, verifying its certificate is for
+    /// `server_name`, or for the host connected to if None.
+    pub fn with_client_config(mut self, config: ClientConfig, server_name: Option<&str>) -> Result<ProxyTls> {
+        self.server_name = server_name.map(parse_server_name).transpose()?;
+        self.connector = Some(TlsConnector::from(Arc::new(config)));
+        Ok(self)
+    }
+
+    /// Acceptor of clients' TLS connections, if they're offered TLS.
+    pub fn acceptor(&self) -> Option<&TlsAcceptor> {
+        self.acceptor.as_ref()
+    }
+
+    /// Whether clients must use TLS.
+    pub fn required(&self) -> bool {
+        self.acceptor.is_some() && self.require
+    }
+
+    /// Connector to the server, if connections to it are encrypted.
+    pub fn connector(&self) -> Option<&TlsConnector> {
+        self.connector.as_ref()
+    }
+
+    /// Name the certificate of the server at `server_addr` must be for.
+    pub fn server_name(&self, server_addr: &str) -> Result<ServerName> {
+        match &self.server_name {
+            Some(server_name) => Ok(server_name.clone()),
+            None => parse_server_name(server_host(server_addr)),
+        }
+    }
+}
+
+fn parse_server_name(server_name: &str) -> Result<ServerName> {
+    ServerName::try_from(server_name).with_context(|| format!("Invalid server name {}", server_name))
+}
+
+/// Host part of a `host:port` address, without the brackets of IPv6
+/// addresses.
+fn server_host(server_addr: &str) -> &str {
+    let host = match server_addr.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => server_addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Unable to read certificates from {}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Unable to read private key from {}", path))?;
+    items.into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_host_test() {
+        assert_eq!(server_host("db.example.com:5432"), "db.example.com");
+        assert_eq!(server_host("127.0.0.1:5432"), "127.0.0.1");
+        assert_eq!(server_host("[::1]:5432"), "::1");
+        assert_eq!(server_host("db.example.com"), "db.example.com");
+    }
+
+    #[test]
+    fn config_test() {
+        let config: TlsConfig = toml::from_str("cert_file = \"a.pem\"\nkey_file = \"a.key\"").unwrap();
+        assert!(!config.require);
+        assert!(toml::from_str::<TlsConfig>("cert_file = \"a.pem\"").is_err());
+        let config: ServerTlsConfig = toml::from_str("ca_file = \"ca.pem\"").unwrap();
+        assert_eq!(config.cert_file, None);
+        assert!(!ProxyTls::new().required());
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let tls = ProxyTls::new().with_client_config(config.clone(), None).unwrap();
+        assert_eq!(tls.server_name("db1.example.com:5432").unwrap(), ServerName::try_from("db1.example.com").unwrap());
+        let tls = tls.with_client_config(config, Some("db.example.com")).unwrap();
+        assert_eq!(tls.server_name("db1.example.com:5432").unwrap(), ServerName::try_from("db.example.com").unwrap());
+    }
+}
@@ -2,6 +2,7 @@
 
 pub mod sql_types {
     #[derive(diesel::sql_types::SqlType)]
+    #[derive(diesel::query_builder::QueryId)]
     #[diesel(postgres_type(name = "chargetype"))]
     pub struct Chargetype;
 
@@ -10,6 +11,11 @@ pub mod sql_types {
     #[diesel(postgres_type(name = "timechargetype"))]
     pub struct Timechargetype;
 
+    #[derive(diesel::sql_types::SqlType)]
+    #[derive(diesel::query_builder::QueryId)]
+    #[diesel(postgres_type(name = "transactiontype"))]
+    pub struct Transactiontype;
+
     #[derive(diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "userstatus"))]
     pub struct Userstatus;
@@ -35,9 +41,21 @@ diesel::table! {
         charge_type -> Chargetype,
         quantity -> Float8,
         rate -> Float8,
-        amount -> Float8,
         report_ids -> Nullable<Array<Nullable<Int8>>>,
         transacted -> Bool,
+        free_quantity -> Float8,
+        amount -> Float8,
+        tiers -> Nullable<Jsonb>,
+    }
+}
+
+diesel::table! {
+    clusters (cluster_name) {
+        cluster_name -> Text,
+        pg_host -> Text,
+        pg_port -> Int4,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -52,7 +70,83 @@ diesel::table! {
 }
 
 diesel::table! {
-    reports (packet_id) {
+    fingerprint_usage (username, period_start, fingerprint) {
+        username -> Text,
+        period_start -> Timestamptz,
+        fingerprint -> Text,
+        query -> Text,
+        calls -> Int8,
+        bytes -> Int8,
+    }
+}
+
+diesel::table! {
+    invoices (invoice_id) {
+        invoice_id -> Int8,
+        user_id -> Uuid,
+        period_start -> Timestamptz,
+        period_end -> Timestamptz,
+        opening_balance -> Float8,
+        deposits -> Float8,
+        payments -> Float8,
+        adjustments -> Float8,
+        closing_balance -> Float8,
+        charges_total -> Float8,
+        charge_lines -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Chargetype;
+
+    plan_allowances (plan_name, charge_type) {
+        plan_name -> Text,
+        charge_type -> Chargetype,
+        free_quantity -> Float8,
+    }
+}
+
+diesel::table! {
+    plans (plan_name) {
+        plan_name -> Text,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        max_connections -> Nullable<Int4>,
+        max_bytes_in_per_second -> Nullable<Int8>,
+        max_bytes_out_per_second -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Chargetype;
+
+    price_tiers (charge_type, start_quantity) {
+        charge_type -> Chargetype,
+        start_quantity -> Float8,
+        rate -> Float8,
+    }
+}
+
+diesel::table! {
+    query_stats (query_stat_id) {
+        query_stat_id -> Int8,
+        username -> Text,
+        query_time -> Timestamptz,
+        fingerprint -> Nullable<Text>,
+        query -> Text,
+        duration_seconds -> Float8,
+        rows_returned -> Int8,
+        bytes_returned -> Int8,
+        command_tag -> Nullable<Text>,
+        error_code -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    reports (packet_id, packet_time) {
         packet_id -> Int8,
         username -> Nullable<Text>,
         packet_type -> Text,
@@ -61,6 +155,25 @@ diesel::table! {
         packet_info -> Nullable<Jsonb>,
         packet_bytes -> Nullable<Bytea>,
         charged -> Bool,
+        fingerprinted -> Bool,
+        session_id -> Nullable<Uuid>,
+    }
+}
+
+diesel::table! {
+    sessions (session_id) {
+        session_id -> Uuid,
+        client_addr -> Nullable<Text>,
+        username -> Nullable<Text>,
+        database -> Nullable<Text>,
+        application_name -> Nullable<Text>,
+        started_at -> Timestamptz,
+        ended_at -> Nullable<Timestamptz>,
+        bytes_in -> Int8,
+        bytes_out -> Int8,
+        termination_reason -> Nullable<Text>,
+        throttled_in_ms -> Int8,
+        throttled_out_ms -> Int8,
     }
 }
 
@@ -78,6 +191,9 @@ diesel::table! {
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Transactiontype;
+
     transactions (txn_id) {
         txn_id -> Int8,
         txn_time -> Timestamptz,
@@ -85,6 +201,20 @@ diesel::table! {
         to_user -> Uuid,
         charge_ids -> Nullable<Array<Nullable<Int8>>>,
         amount -> Float8,
+        txn_type -> Transactiontype,
+        reason -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Chargetype;
+
+    usage_periods (user_id, charge_type, period_start) {
+        user_id -> Uuid,
+        charge_type -> Chargetype,
+        period_start -> Timestamptz,
+        quantity -> Float8,
     }
 }
 
@@ -101,15 +231,32 @@ diesel::table! {
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
         pg_password_enc -> Nullable<Bytea>,
+        plan_name -> Text,
+        billing_period_months -> Int4,
+        cluster_name -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(invoices -> users (user_id));
+diesel::joinable!(plan_allowances -> plans (plan_name));
+diesel::joinable!(users -> clusters (cluster_name));
+diesel::joinable!(users -> plans (plan_name));
+
 diesel::allow_tables_to_appear_in_same_query!(
     balances,
     charges,
+    clusters,
     exttransactions,
+    fingerprint_usage,
+    invoices,
+    plan_allowances,
+    plans,
+    price_tiers,
+    query_stats,
     reports,
+    sessions,
     timecharges,
     transactions,
+    usage_periods,
     users,
 );
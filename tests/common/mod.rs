@@ -16,6 +16,8 @@ use impulse::manage::postgres::PostgresManager;
 // use docker_api::{Container, Docker};
 // use docker_api::opts::{ContainerCreateOpts, PublishPort};
 
+pub mod proxy;
+
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
 pub const DB_PREFIX: &str = "ImpulseTestingDb_";
 
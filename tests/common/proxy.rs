@@ -0,0 +1,137 @@
+//! Clients and stand-in servers speaking the PostgreSQL protocol, for the
+//! tests of prew's proxy.
+
+// each test uses only some of these
+#![allow(dead_code)]
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use impulse::prew::protocol::{authentication, authentication_code, AUTHENTICATION_OK, AUTHENTICATION_SASL_FINAL};
+
+/// Message of type `id` with `body`.
+pub fn message(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![id];
+    bytes.extend(((body.len() + 4) as u32).to_be_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+/// StartupMessage of `username`, for their database of the same name.
+pub fn startup_message(username: &str) -> Vec<u8> {
+    impulse::prew::protocol::startup_message(&[("user", username), ("database", username)])
+}
+
+pub async fn read_message<S: AsyncRead + Unpin>(socket: &mut S) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; 5];
+    socket.read_exact(&mut bytes).await?;
+    let length = u32::from_be_bytes(bytes[1..5].try_into()?) as usize;
+    bytes.resize(length + 1, 0);
+    socket.read_exact(&mut bytes[5..]).await?;
+    Ok(bytes)
+}
+
+/// Read a client's startup message (or CancelRequest), as the server.
+pub async fn read_startup<S: AsyncRead + Unpin>(socket: &mut S) -> Result<Vec<u8>> {
+    let mut length = [0; 4];
+    socket.read_exact(&mut length).await?;
+    let mut startup = length.to_vec();
+    startup.resize(u32::from_be_bytes(length) as usize, 0);
+    socket.read_exact(&mut startup[4..]).await?;
+    Ok(startup)
+}
+
+/// Stand in for the server on `socket`, answering the startup message with
+/// `auth_reply` and completing every query (simple or extended) with `tag`,
+/// until the client leaves.
+pub async fn answer<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, auth_reply: &[u8], tag: &str) {
+    // clients turned away by the proxy never get here
+    if read_startup(&mut socket).await.is_err() {
+        return;
+    }
+    let ready = message(b'Z', b"I");
+    socket.write_all(&[auth_reply, &ready].concat()).await.unwrap();
+    let complete = message(b'C', format!("{}\0", tag).as_bytes());
+    while let Ok(request) = read_message(&mut socket).await {
+        let reply = match request[0] {
+            b'Q' => [complete.as_slice(), &ready].concat(),
+            b'P' => message(b'1', b""),
+            b'B' => message(b'2', b""),
+            b'E' => complete.clone(),
+            b'S' => ready.clone(),
+            _ => continue,
+        };
+        socket.write_all(&reply).await.unwrap();
+    }
+}
+
+/// A server accepting anyone, completing every query with `tag`.
+pub async fn start_server(tag: &'static str) -> Result<String> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = server.accept().await.unwrap();
+            tokio::spawn(async move { answer(socket, &authentication(AUTHENTICATION_OK, b""), tag).await });
+        }
+    });
+    Ok(server_addr)
+}
+
+/// Start up as `username` through a proxy that leaves authentication to
+/// the server, returning once the server is ready for queries.
+pub async fn connect(proxy_addr: &str, username: &str) -> Result<TcpStream> {
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&startup_message(username)).await?;
+    assert_eq!(read_message(&mut client).await?[0], b'R');
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+    Ok(client)
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Authenticate with `password` by SCRAM-SHA-256 once the startup message
+/// is sent, returning the reply to the client's proof. The server's proof
+/// is checked if it sent one.
+pub async fn authenticate(client: &mut TcpStream, password: &str) -> Result<Vec<u8>> {
+    let sasl = read_message(client).await?;
+    assert_eq!(&sasl[9..], b"SCRAM-SHA-256\0\0");
+
+    let client_first_bare = "n=,r=fyko+d2lbbFgONRv9qkxdawL";
+    let client_first = format!("n,,{}", client_first_bare);
+    let mut initial = b"SCRAM-SHA-256\0".to_vec();
+    initial.extend((client_first.len() as i32).to_be_bytes());
+    initial.extend(client_first.as_bytes());
+    client.write_all(&message(b'p', &initial)).await?;
+    let server_first = String::from_utf8(read_message(client).await?[9..].to_vec())?;
+    let mut attributes = server_first.split(',');
+    let nonce = attributes.next().unwrap().strip_prefix("r=").unwrap();
+    let salt = BASE64.decode(attributes.next().unwrap().strip_prefix("s=").unwrap())?;
+    let iterations = attributes.next().unwrap().strip_prefix("i=").unwrap().parse()?;
+
+    let mut salted_password = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+    let client_key = hmac(&salted_password, b"Client Key");
+    let without_proof = format!("c=biws,r={}", nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+    let client_signature = hmac(&Sha256::digest(&client_key), auth_message.as_bytes());
+    let proof: Vec<u8> = client_key.iter().zip(client_signature).map(|(a, b)| a ^ b).collect();
+    let client_final = format!("{},p={}", without_proof, BASE64.encode(proof));
+    client.write_all(&message(b'p', client_final.as_bytes())).await?;
+    let reply = read_message(client).await?;
+    if authentication_code(&reply) == Some(AUTHENTICATION_SASL_FINAL) {
+        // the server proves it knows the password too
+        let server_signature = hmac(&hmac(&salted_password, b"Server Key"), auth_message.as_bytes());
+        assert_eq!(reply[9..], format!("v={}", BASE64.encode(server_signature)).into_bytes());
+    }
+    Ok(reply)
+}
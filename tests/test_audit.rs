@@ -0,0 +1,136 @@
+mod common;
+
+use anyhow::Result;
+use uuid::Uuid;
+
+use impulse::models::audit::*;
+use impulse::models::charges::{Charge, ChargeType, NewCharge};
+use impulse::models::reports::{NewReport, PacketDirection, PostgresqlPacketType, ReportToCharge};
+use impulse::models::transactions::{NewExtTransaction, NewTransaction};
+use impulse::models::users::NewUser;
+
+
+#[test]
+fn consistent_history_test() -> Result<()> {
+    let context = common::TestContext::new("audit_consistent")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "audituser".to_string(), 0.)?;
+    let report = NewReport::create(
+        Some("audituser".to_string()),
+        PostgresqlPacketType::Other,
+        Some(PacketDirection::Backward),
+        None,
+        Some(vec![1, 2, 3, 4]),
+        false,
+    ).commit(&mut conn)?;
+    let charges = Charge::from_reports(
+        &mut conn,
+        vec![ReportToCharge::with_userid(report, user.user_id)],
+    )?;
+    assert_eq!(charges.len(), 1);
+    NewTransaction::from_charges(&mut conn, &charges)?;
+    let audit = BalanceAudit::run(&mut conn)?;
+    assert!(audit.is_clean(), "Unexpected inconsistencies: {:?}", &audit);
+    Ok(())
+}
+
+#[test]
+fn balance_drift_test() -> Result<()> {
+    let context = common::TestContext::new("audit_drift")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "driftuser".to_string(), 10.)?;
+    let drifts = BalanceDrift::find_all(&mut conn)?;
+    assert_eq!(drifts.len(), 1);
+    assert_eq!(drifts[0].user_id, user.user_id);
+    assert_eq!(drifts[0].expected_balance, 0.);
+    assert_eq!(drifts[0].drift(), 10.);
+
+    // a deposit accounting for the balance resolves the drift
+    NewExtTransaction::create(&mut conn, user.user_id, 10., None, "audit1".to_string())?;
+    assert!(BalanceDrift::find_all(&mut conn)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn unmatched_charge_test() -> Result<()> {
+    let context = common::TestContext::new("audit_unmatched_charge")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user_id = Uuid::new_v4();
+    let charge = NewCharge::new(
+        user_id,
+        ChargeType::DataTransferOutBytes,
+        100.,
+        1.,
+        None,
+        None,
+    ).commit(&mut conn)?;
+    let charge_id = charge.charge_id;
+    let transactions = NewTransaction::from_charges(&mut conn, &vec![charge])?;
+    assert!(UnmatchedCharge::find_all(&mut conn)?.is_empty());
+
+    // a second transaction for the same charge double-bills the user
+    let duplicate = NewTransaction::create(
+        &mut conn,
+        user_id,
+        Uuid::nil(),
+        Some(vec![charge_id]),
+        100.,
+        None,
+    )?;
+    let unmatched = UnmatchedCharge::find_all(&mut conn)?;
+    assert_eq!(
+        unmatched,
+        vec![UnmatchedCharge {
+            charge_id,
+            txn_ids: vec![transactions[0].transaction_id, duplicate.transaction_id],
+        }]
+    );
+    Ok(())
+}
+
+#[test]
+fn unmatched_report_test() -> Result<()> {
+    let context = common::TestContext::new("audit_unmatched_report")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let report = NewReport::create(
+        None,
+        PostgresqlPacketType::Other,
+        Some(PacketDirection::Forward),
+        None,
+        Some(vec![1, 2]),
+        true,
+    ).commit(&mut conn)?;
+    let unmatched = UnmatchedReport::find_all(&mut conn)?;
+    assert_eq!(
+        unmatched,
+        vec![UnmatchedReport { report_id: report.report_id, charge_ids: vec![] }]
+    );
+
+    let charge1 = NewCharge::new(
+        Uuid::nil(),
+        ChargeType::DataTransferInBytes,
+        2.,
+        0.,
+        Some(vec![report.report_id]),
+        None,
+    ).commit(&mut conn)?;
+    assert!(UnmatchedReport::find_all(&mut conn)?.is_empty());
+
+    let charge2 = NewCharge::new(
+        Uuid::nil(),
+        ChargeType::DataTransferInBytes,
+        2.,
+        0.,
+        Some(vec![report.report_id]),
+        None,
+    ).commit(&mut conn)?;
+    let unmatched = UnmatchedReport::find_all(&mut conn)?;
+    assert_eq!(
+        unmatched,
+        vec![UnmatchedReport {
+            report_id: report.report_id,
+            charge_ids: vec![charge1.charge_id, charge2.charge_id],
+        }]
+    );
+    Ok(())
+}
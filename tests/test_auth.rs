@@ -0,0 +1,143 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use diesel::prelude::*;
+use prew::NoTransform;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::users::{NewUser, User};
+use impulse::prew::auth::ScramVerifier;
+use impulse::prew::protocol::{authentication_code, error_code, AUTHENTICATION_SASL_FINAL};
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::{serve_with_options, ProxyOptions};
+use impulse::prew::session_factory;
+
+use common::proxy::{answer, authenticate, message, read_message, startup_message};
+
+
+/// Start up as `username` and go through SCRAM-SHA-256 with `password`,
+/// returning the proxy's answer to the client's proof once checked.
+async fn connect(proxy_addr: &str, username: &str, password: &str) -> Result<(TcpStream, Vec<u8>)> {
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&startup_message(username)).await?;
+    let reply = authenticate(&mut client, password).await?;
+    Ok((client, reply))
+}
+
+/// A server counting its connections, which accepts every startup with
+/// `auth_reply` and answers queries.
+async fn start_server(auth_reply: Vec<u8>) -> Result<(String, Arc<AtomicUsize>)> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = server.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            let auth_reply = auth_reply.clone();
+            tokio::spawn(async move { answer(socket, &auth_reply, "SELECT 1").await });
+        }
+    });
+    Ok((server_addr, connections))
+}
+
+async fn start_proxy(context: &common::TestContext, server_addr: String) -> Result<String> {
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    let options = ProxyOptions { authenticate_clients: true, ..ProxyOptions::default() };
+    tokio::spawn(serve_with_options(listener, server_addr, create_session, Arc::new(options)));
+    Ok(proxy_addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_auth_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_auth")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut alice = NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    alice.set_pg_password_enc(&mut conn, &ScramVerifier::new("alicepw").to_string())?;
+    let mut bob = NewUser::create(&mut conn, Uuid::new_v4(), "bob".to_string(), 1.)?;
+    bob.set_pg_password_enc(&mut conn, &ScramVerifier::new("bobpw").to_string())?;
+    bob.disable(&mut conn)?;
+    // users without a password can't log in
+    NewUser::create(&mut conn, Uuid::new_v4(), "carol".to_string(), 1.)?;
+
+    let ok = message(b'R', &[0, 0, 0, 0]);
+    let (server_addr, connections) = start_server(ok).await?;
+    let proxy_addr = start_proxy(&context, server_addr).await?;
+
+    // the server's AuthenticationOk completes the exchange with the proxy
+    let (mut client, reply) = connect(&proxy_addr, "alice", "alicepw").await?;
+    assert_eq!(authentication_code(&reply), Some(AUTHENTICATION_SASL_FINAL));
+    assert_eq!(authentication_code(&read_message(&mut client).await?), Some(0));
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+    client.write_all(&message(b'Q', b"SELECT 1\0")).await?;
+    assert_eq!(read_message(&mut client).await?[0], b'C');
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // the others never reach the server
+    for (username, password, code) in [
+        ("alice", "bobpw", "28P01"),
+        ("nobody", "alicepw", "28P01"),
+        ("carol", "", "28P01"),
+        ("bob", "bobpw", "28000"),
+    ] {
+        let (mut client, mut reply) = connect(&proxy_addr, username, password).await?;
+        if authentication_code(&reply) == Some(AUTHENTICATION_SASL_FINAL) {
+            // like Postgres, only users who got their password right learn
+            // they can't log in
+            reply = read_message(&mut client).await?;
+        }
+        assert_eq!(reply[0], b'E', "{}", username);
+        assert_eq!(error_code(&reply), Some(code.to_string()), "{}", username);
+        assert_eq!(client.read(&mut [0; 16]).await?, 0);
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // the proxy can't answer servers asking for a password
+    let cleartext = message(b'R', &[0, 0, 0, 3]);
+    let (server_addr, connections) = start_server(cleartext).await?;
+    let proxy_addr = start_proxy(&context, server_addr).await?;
+    let (mut client, reply) = connect(&proxy_addr, "alice", "alicepw").await?;
+    assert_eq!(authentication_code(&reply), Some(AUTHENTICATION_SASL_FINAL));
+    assert!(matches!(client.read(&mut [0; 16]).await, Ok(0) | Err(_)));
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn password_sync_test() -> Result<()> {
+    let context = common::TestContext::new("password_sync")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let managed_db_manager = &context.managed_db_manager;
+    let mut managed_conn = managed_db_manager.pg_connect()?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "syncpwuser".to_string(), 1.)?;
+    assert_eq!(user.pg_password_enc, None);
+    let info = managed_db_manager.create_pg_user_and_database("syncpwuser", None)?;
+    let result = (|| {
+        let verifier = |conn: &mut PgConnection| -> Result<Option<Vec<u8>>> {
+            Ok(User::retrieve(conn, &user.user_id)?.pg_password_enc)
+        };
+        // the verifiers of existing roles are backfilled
+        assert!(managed_db_manager.sync_password_verifiers(&mut conn)? >= 1);
+        assert_eq!(verifier(&mut conn)?, Some(info.password_verifier.as_bytes().to_vec()));
+        assert_eq!(managed_db_manager.sync_password_verifiers(&mut conn)?, 0);
+
+        // and passwords changed on the server are picked up
+        diesel::sql_query("ALTER ROLE syncpwuser PASSWORD 'changed'").execute(&mut managed_conn)?;
+        assert_eq!(managed_db_manager.sync_password_verifiers(&mut conn)?, 1);
+        let changed = String::from_utf8(verifier(&mut conn)?.unwrap())?;
+        assert_ne!(changed, info.password_verifier);
+        assert!(ScramVerifier::parse(&changed).is_some());
+        Ok(())
+    })();
+    managed_db_manager.drop_pg_user("syncpwuser")?;
+    result
+}
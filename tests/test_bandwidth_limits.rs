@@ -0,0 +1,125 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use diesel::prelude::*;
+use prew::NoTransform;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::plans::{NewPlan, Plan};
+use impulse::models::sessions::{Session, Session_};
+use impulse::models::users::NewUser;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::serve;
+use impulse::prew::session_factory;
+
+use common::proxy::{message, read_startup, startup_message};
+
+
+const ROW_BYTES: usize = 1000;
+const ROW_COUNT: usize = 10;
+
+/// DataRow with a single column of `ROW_BYTES` bytes
+fn data_row() -> Vec<u8> {
+    let mut body = 1_u16.to_be_bytes().to_vec();
+    body.extend((ROW_BYTES as u32).to_be_bytes());
+    body.extend([b'x'; ROW_BYTES]);
+    message(b'D', &body)
+}
+
+/// A server that answers any query with `ROW_COUNT` rows.
+async fn start_server() -> Result<String> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = server.accept().await.unwrap();
+            tokio::spawn(async move {
+                read_startup(&mut socket).await.unwrap();
+                let ready = message(b'Z', b"I");
+                socket.write_all(&[message(b'R', &[0, 0, 0, 0]), ready.clone()].concat()).await.unwrap();
+                let mut header = [0; 5];
+                while socket.read_exact(&mut header).await.is_ok() {
+                    let mut body = vec![0; u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize - 4];
+                    socket.read_exact(&mut body).await.unwrap();
+                    let mut reply = vec![];
+                    for _ in 0..ROW_COUNT {
+                        reply.extend(data_row());
+                    }
+                    reply.extend(message(b'C', format!("SELECT {}\0", ROW_COUNT).as_bytes()));
+                    reply.extend(&ready);
+                    socket.write_all(&reply).await.unwrap();
+                }
+            });
+        }
+    });
+    Ok(server_addr)
+}
+
+/// Run a query through the proxy as `username`, returning how long it took
+/// to receive the result.
+async fn query(proxy_addr: &str, username: &str) -> Result<Duration> {
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&startup_message(username)).await?;
+    // AuthenticationOk and ReadyForQuery
+    client.read_exact(&mut [0; 15]).await?;
+    let start = Instant::now();
+    client.write_all(&message(b'Q', b"SELECT x\0")).await?;
+    let mut result = vec![0; ROW_COUNT * data_row().len() + 14 + 6];
+    client.read_exact(&mut result).await?;
+    Ok(start.elapsed())
+}
+
+async fn ended_session(conn: &mut PgConnection, username_: &str) -> Result<Session> {
+    use impulse::schema::sessions::dsl::*;
+    for _ in 0..100 {
+        let ended = sessions
+            .filter(username.eq(username_))
+            .filter(ended_at.is_not_null())
+            .first::<Session_>(conn)
+            .optional()?;
+        if let Some(session) = ended {
+            return Ok(session.into());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Session of {} didn't end", username_);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bandwidth_limit_test() -> Result<()> {
+    let context = common::TestContext::new("bandwidth_limit")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut plan = NewPlan::create(&mut conn, "metered".to_string())?;
+    // half of the result fits in the burst, the rest takes about a second
+    let rate = (ROW_COUNT * ROW_BYTES / 2) as i64;
+    plan.set_bandwidth(&mut conn, None, Some(rate))?;
+    assert_eq!(Plan::for_user(&mut conn, "metereduser")?, None);
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "metereduser".to_string(), 1.)?;
+    user.set_plan(&mut conn, "metered")?;
+    assert_eq!(Plan::for_user(&mut conn, "metereduser")?, Some(plan));
+    NewUser::create(&mut conn, Uuid::new_v4(), "freeuser".to_string(), 1.)?;
+
+    let server_addr = start_server().await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    let elapsed = query(&proxy_addr, "metereduser").await?;
+    assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+    let session = ended_session(&mut conn, "metereduser").await?;
+    assert!(session.throttled_out_ms >= 800, "{:?}", session);
+    assert_eq!(session.throttled_in_ms, 0);
+
+    // users on plans without limits aren't throttled
+    let elapsed = query(&proxy_addr, "freeuser").await?;
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    let session = ended_session(&mut conn, "freeuser").await?;
+    assert_eq!(session.throttled_out_ms, 0);
+    Ok(())
+}
@@ -6,6 +6,7 @@ use uuid::Uuid;
 use impulse::models::charges::*;
 use impulse::models::reports;
 use impulse::models::reports::{PacketDirection, PostgresqlPacketType};
+use impulse::models::users::NewUser;
 use crate::common::ExpectedEquals;
 
 mod common;
@@ -21,6 +22,8 @@ impl ExpectedEquals for Charge {
             && self.amount == other.amount
             && self.report_ids == other.report_ids
             && self.transacted == other.transacted
+            && self.free_quantity == other.free_quantity
+            && self.tiers == other.tiers
     }
 }
 
@@ -46,6 +49,8 @@ fn create_charge_test() -> Result<()> {
         amount: rate * quantity,
         report_ids: report_ids.clone(),
         transacted: false,
+        free_quantity: 0.,
+        tiers: None,
     };
     trace!("Expected charge: {:?}", &expected_charge);
 
@@ -102,7 +107,9 @@ fn create_from_timecharges_test() -> Result<()> {
         rate: expected_rate,
         amount: expected_quantity * expected_rate,
         report_ids: None,
-        transacted: false
+        transacted: false,
+        free_quantity: 0.,
+        tiers: None,
     };
     debug!("Expecting {:?} to roughly equal {:?}", &created_charge1, &expected_charge1);
     assert!(created_charge1.expected_equals(&expected_charge1));
@@ -130,6 +137,8 @@ fn create_from_timecharges_test() -> Result<()> {
         amount: expected_quantity2 * expected_rate,
         report_ids: None,
         transacted: false,
+        free_quantity: 0.,
+        tiers: None,
     };
     debug!("Expecting {:?} to roughly equal {:?}", &created_charge2, &expected_charge2);
     assert!(created_charge2.expected_equals(&expected_charge2));
@@ -170,7 +179,37 @@ fn rate_test() -> Result<()> {
         amount: 4.0 * ChargeType::DataTransferInBytes.rate(),
         report_ids: Some(vec![report_id]),
         transacted: false,
+        free_quantity: 0.,
+        tiers: None,
     };
     assert!(charge.expected_equals(&expected_charge));
     Ok(())
 }
+
+#[test]
+fn time_reports_test() -> Result<()> {
+    let context = common::TestContext::new("time_reports_test")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "timeuser".to_string(), 0.)?;
+    for (packet_type, seconds) in [
+        (PostgresqlPacketType::ConnectionTime, 60.),
+        (PostgresqlPacketType::ConnectionTime, 30.),
+        (PostgresqlPacketType::QueryTime, 1.5),
+    ] {
+        reports::NewReport::time("timeuser".to_string(), packet_type, seconds).commit(&mut conn)?;
+    }
+    let uncharged = reports::ReportToCharge::uncharged(&mut conn)?;
+    assert_eq!(uncharged.len(), 3);
+    assert!(uncharged.iter().all(|report| report.user_id == Some(user.user_id)));
+    let mut charges = Charge::from_reports(&mut conn, uncharged)?;
+    charges.sort_by_key(|charge| charge.report_ids.as_ref().map(|ids| ids.len()));
+    assert_eq!(charges.len(), 2);
+    assert_eq!(charges[0].charge_type, ChargeType::QueryExecutionSeconds);
+    assert_eq!(charges[0].quantity, 1.5);
+    assert_eq!(charges[0].rate, ChargeType::QueryExecutionSeconds.rate());
+    assert_eq!(charges[1].charge_type, ChargeType::ConnectionSeconds);
+    assert_eq!(charges[1].quantity, 90.);
+    assert_eq!(charges[1].rate, ChargeType::ConnectionSeconds.rate());
+    assert!(reports::ReportToCharge::uncharged(&mut conn)?.is_empty());
+    Ok(())
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use diesel::prelude::*;
+use prew::NoTransform;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::clusters::{Cluster, NewCluster};
+use impulse::models::users::NewUser;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::protocol::{
+    authentication_code, cancel_key, cancel_request, error_code, startup_message, startup_parameters, BackendKey,
+};
+use impulse::prew::proxy::{serve_with_options, ProxyOptions};
+use impulse::prew::{session_factory, AppendUserNameTransformer};
+
+use common::proxy::{message, read_message, read_startup};
+
+
+/// A server accepting anyone, logging the users connections start up as,
+/// and "cancel" for CancelRequests for `key`, the key of all its
+/// connections.
+async fn start_server(key: BackendKey) -> Result<(String, Arc<Mutex<Vec<String>>>)> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    let users = Arc::new(Mutex::new(vec![]));
+    let server_users = users.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = server.accept().await.unwrap();
+            let startup = read_startup(&mut socket).await.unwrap();
+            if let Some(cancelled) = cancel_key(&startup) {
+                assert_eq!(cancelled, key);
+                server_users.lock().unwrap().push("cancel".to_string());
+                continue;
+            }
+            let user = startup_parameters(&startup).unwrap().into_iter()
+                .find_map(|(name, value)| (name == "user").then_some(value))
+                .unwrap();
+            server_users.lock().unwrap().push(user);
+            let ok = message(b'R', &[0, 0, 0, 0]);
+            socket.write_all(&[ok, message(b'K', &key), message(b'Z', b"I")].concat()).await.unwrap();
+            // hold on to the connection until the client leaves
+            tokio::spawn(async move { while read_message(&mut socket).await.is_ok() {} });
+        }
+    });
+    Ok((server_addr, users))
+}
+
+/// Start up as `username`, returning once the server answered.
+async fn connect(proxy_addr: &str, username: &str) -> Result<TcpStream> {
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&startup_message(&[("user", username), ("database", "shop")])).await?;
+    assert_eq!(authentication_code(&read_message(&mut client).await?), Some(0));
+    assert_eq!(read_message(&mut client).await?[0], b'K');
+    assert_eq!(read_message(&mut client).await?, message(b'Z', b"I"));
+    Ok(client)
+}
+
+#[test]
+fn cluster_placement_test() -> Result<()> {
+    let context = common::TestContext::new("cluster_placement")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut alice = NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "bob".to_string(), 1.)?;
+    assert_eq!(alice.cluster_name, None);
+    assert_eq!(Cluster::for_user(&mut conn, "alice")?, None);
+
+    let east = NewCluster::create(&mut conn, "east".to_string(), "db-east".to_string(), 5433)?;
+    NewCluster::create(&mut conn, "west".to_string(), "db-west".to_string(), 5432)?;
+    assert_eq!(east.server_addr(), "db-east:5433");
+    let names: Vec<String> = Cluster::all(&mut conn)?.into_iter().map(|cluster| cluster.cluster_name).collect();
+    assert_eq!(names, ["east", "west"]);
+
+    alice.set_cluster(&mut conn, Some("east"))?;
+    assert_eq!(alice.cluster_name.as_deref(), Some("east"));
+    assert_eq!(Cluster::for_user(&mut conn, "alice")?, Some(east));
+    assert_eq!(Cluster::for_user(&mut conn, "bob")?, None);
+    assert_eq!(Cluster::for_user(&mut conn, "nobody")?, None);
+    // users can only be placed on registered clusters
+    assert!(alice.set_cluster(&mut conn, Some("north")).is_err());
+    alice.set_cluster(&mut conn, None)?;
+    assert_eq!(Cluster::for_user(&mut conn, "alice")?, None);
+    Ok(())
+}
+
+#[test]
+fn cluster_management_test() -> Result<()> {
+    let context = common::TestContext::new("cluster_management")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let managed_db_manager = &context.managed_db_manager;
+    let mut placed = NewUser::create(&mut conn, Uuid::new_v4(), "clusterplaced".to_string(), 1.)?;
+    let mut unplaced = NewUser::create(&mut conn, Uuid::new_v4(), "clusterunplaced".to_string(), 1.)?;
+
+    // the managed database under another name, as a cluster of its own
+    let port = common::ENV.get("MANAGED_DB_PORT").unwrap().parse()?;
+    NewCluster::create(&mut conn, "local".to_string(), "127.0.0.1".to_string(), port)?;
+    placed.set_cluster(&mut conn, Some("local"))?;
+    let cluster_manager = managed_db_manager.for_user(&mut conn, &placed)?;
+    assert!(cluster_manager.base_url().ends_with(&format!("@127.0.0.1:{}", port)));
+    let unplaced_manager = managed_db_manager.for_user(&mut conn, &unplaced)?;
+    assert_eq!(unplaced_manager.base_url(), managed_db_manager.base_url());
+
+    let mut cluster_conn = cluster_manager.pg_connect()?;
+    diesel::sql_query(r#"CREATE DATABASE "shop_clusterplaced""#).execute(&mut cluster_conn)?;
+    let storage = managed_db_manager.compute_storage(&mut conn);
+    cluster_manager.drop_database("shop_clusterplaced")?;
+    let storage = storage?;
+    assert!(storage[&placed.user_id] > 0);
+    assert!(!storage.contains_key(&unplaced.user_id));
+
+    // users whose role exists stay on their cluster
+    diesel::sql_query(r#"CREATE ROLE "clusterplaced""#).execute(&mut cluster_conn)?;
+    let moved = managed_db_manager.place_user(&mut conn, &mut placed, None);
+    diesel::sql_query(r#"DROP ROLE "clusterplaced""#).execute(&mut cluster_conn)?;
+    assert!(moved.is_err());
+    assert_eq!(placed.cluster_name.as_deref(), Some("local"));
+    managed_db_manager.place_user(&mut conn, &mut unplaced, Some("local"))?;
+    assert_eq!(unplaced.cluster_name.as_deref(), Some("local"));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_routing_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_routing")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut alice = NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "bob".to_string(), 1.)?;
+
+    let (default_addr, default_users) = start_server([0, 0, 0, 1, 1, 1, 1, 1]).await?;
+    let cluster_key = [0, 0, 0, 2, 2, 2, 2, 2];
+    let (cluster_addr, cluster_users) = start_server(cluster_key).await?;
+    let cluster_port = cluster_addr.rsplit_once(':').unwrap().1.parse()?;
+    NewCluster::create(&mut conn, "east".to_string(), "127.0.0.1".to_string(), cluster_port)?;
+    alice.set_cluster(&mut conn, Some("east"))?;
+
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(
+        report_connstr,
+        AppendUserNameTransformer::new(),
+        NoTransform::new(),
+        StatementPolicy::default(),
+    );
+    let options = ProxyOptions { route_by_user: true, ..ProxyOptions::default() };
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve_with_options(listener, default_addr, create_session, Arc::new(options)));
+
+    // users are connected to their cluster, and those on none to the
+    // proxy's server
+    let _alice = connect(&proxy_addr, "alice").await?;
+    let _bob = connect(&proxy_addr, "bob").await?;
+    let _nobody = connect(&proxy_addr, "nobody").await?;
+    assert_eq!(*cluster_users.lock().unwrap(), ["alice"]);
+    assert_eq!(*default_users.lock().unwrap(), ["bob", "nobody"]);
+
+    // cancels go to the cluster of the connection they cancel the query of
+    let mut cancel = TcpStream::connect(&proxy_addr).await?;
+    cancel.write_all(&cancel_request(&cluster_key)).await?;
+    assert_eq!(cancel.read(&mut [0; 1]).await?, 0);
+    assert_eq!(*cluster_users.lock().unwrap(), ["alice", "cancel"]);
+
+    // clients whose cluster can't be looked up aren't connected anywhere
+    diesel::sql_query("ALTER TABLE clusters RENAME TO clusters_gone").execute(&mut conn)?;
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&startup_message(&[("user", "alice"), ("database", "shop")])).await?;
+    assert_eq!(error_code(&read_message(&mut client).await?).as_deref(), Some("57P03"));
+    assert_eq!(*default_users.lock().unwrap(), ["bob", "nobody"]);
+    Ok(())
+}
@@ -0,0 +1,205 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Text};
+use prew::NoTransform;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::plans::{NewPlan, Plan};
+use impulse::models::sessions::{Session, Session_, TerminationReason};
+use impulse::models::users::{NewUser, User};
+use impulse::prew::protocol::{authentication, authentication_code, error_code, AUTHENTICATION_OK, AUTHENTICATION_SASL};
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::serve;
+use impulse::prew::session_factory;
+
+use common::proxy::{message, read_message, read_startup, start_server, startup_message};
+
+
+#[derive(QueryableByName, Debug)]
+struct RoleConnectionLimit {
+    #[diesel(sql_type = Integer)]
+    rolconnlimit: i32,
+}
+
+fn role_connection_limit(conn: &mut PgConnection, role: &str) -> Result<i32> {
+    let row = diesel::sql_query("SELECT rolconnlimit FROM pg_roles WHERE rolname = $1")
+        .bind::<Text, _>(role)
+        .get_result::<RoleConnectionLimit>(conn)?;
+    Ok(row.rolconnlimit)
+}
+
+#[test]
+fn role_connection_limit_test() -> Result<()> {
+    let context = common::TestContext::new("role_connection_limit")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut plan = NewPlan::create(&mut conn, "limited".to_string())?;
+    assert_eq!(plan.max_connections, None);
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "limiteduser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "limited")?;
+    assert!(!user.status_synced);
+    user.mark_synced(&mut conn)?;
+    assert_eq!(Plan::max_connections_for_user(&mut conn, "limiteduser")?, None);
+
+    plan.set_max_connections(&mut conn, Some(2))?;
+    assert_eq!(plan.max_connections, Some(2));
+    assert_eq!(Plan::max_connections_for_user(&mut conn, "limiteduser")?, Some(2));
+    // users on the plan need their role updated
+    assert!(!User::retrieve(&mut conn, &user.user_id)?.status_synced);
+    assert_eq!(Plan::max_connections_for_user(&mut conn, "nosuchuser")?, None);
+    // limits can't be negative
+    assert!(plan.set_max_connections(&mut conn, Some(-1)).is_err());
+
+    let managed_db_manager = &context.managed_db_manager;
+    let mut managed_conn = managed_db_manager.pg_connect()?;
+    managed_db_manager.create_pg_user_and_database("limiteduser", plan.max_connections)?;
+    let result = (|| {
+        assert_eq!(role_connection_limit(&mut managed_conn, "limiteduser")?, 2);
+        managed_db_manager.set_connection_limit("limiteduser", Some(5))?;
+        assert_eq!(role_connection_limit(&mut managed_conn, "limiteduser")?, 5);
+        managed_db_manager.set_connection_limit("limiteduser", None)?;
+        assert_eq!(role_connection_limit(&mut managed_conn, "limiteduser")?, -1);
+        Ok(())
+    })();
+    managed_db_manager.drop_pg_user("limiteduser")?;
+    result
+}
+
+/// Connect through the proxy, returning the connection and the first
+/// message the proxy answers with.
+async fn connect(proxy_addr: &str, username: &str) -> Result<(TcpStream, Vec<u8>)> {
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&startup_message(username)).await?;
+    let reply = read_message(&mut client).await?;
+    if reply[0] == b'R' {
+        // ReadyForQuery
+        read_message(&mut client).await?;
+    }
+    Ok((client, reply))
+}
+
+async fn ended_sessions(conn: &mut PgConnection, count: usize) -> Result<Vec<Session>> {
+    use impulse::schema::sessions::dsl::*;
+    let mut ended = vec![];
+    for _ in 0..100 {
+        ended = sessions
+            .filter(ended_at.is_not_null())
+            .order(ended_at.asc())
+            .load::<Session_>(conn)?
+            .into_iter()
+            .map(Session::from)
+            .collect();
+        if ended.len() >= count {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    Ok(ended)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_connection_limit_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_connection_limit")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut plan = NewPlan::create(&mut conn, "single".to_string())?;
+    plan.set_max_connections(&mut conn, Some(1))?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "singleuser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "single")?;
+
+    let server_addr = start_server("OK").await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    let (first, reply) = connect(&proxy_addr, "singleuser").await?;
+    assert_eq!(reply[0], b'R');
+    // users without a plan limit aren't held to anyone else's
+    let (_other, reply) = connect(&proxy_addr, "otheruser").await?;
+    assert_eq!(reply[0], b'R');
+
+    let (mut rejected, reply) = connect(&proxy_addr, "singleuser").await?;
+    assert_eq!(reply[0], b'E');
+    assert_eq!(error_code(&reply), Some("53300".to_string()));
+    // the proxy closes the connection after the error
+    assert_eq!(rejected.read(&mut [0; 16]).await?, 0);
+    let sessions = ended_sessions(&mut conn, 1).await?;
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].username.as_deref(), Some("singleuser"));
+    assert_eq!(sessions[0].termination_reason, Some(TerminationReason::ConnectionLimit));
+
+    // the connection is available again once the first client leaves
+    drop(first);
+    let sessions = ended_sessions(&mut conn, 2).await?;
+    assert_eq!(sessions[1].termination_reason, Some(TerminationReason::ClientClosed));
+    let (_second, reply) = connect(&proxy_addr, "singleuser").await?;
+    assert_eq!(reply[0], b'R');
+    Ok(())
+}
+
+/// A server asking clients for a password, which lets them in once they
+/// send one.
+async fn start_password_server() -> Result<String> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = server.accept().await.unwrap();
+            tokio::spawn(async move {
+                if read_startup(&mut socket).await.is_err() {
+                    return;
+                }
+                let sasl = authentication(AUTHENTICATION_SASL, b"SCRAM-SHA-256\0\0");
+                socket.write_all(&sasl).await.unwrap();
+                if read_message(&mut socket).await.is_err() {
+                    return;
+                }
+                let ok = authentication(AUTHENTICATION_OK, b"");
+                socket.write_all(&[ok, message(b'Z', b"I")].concat()).await.unwrap();
+                while read_message(&mut socket).await.is_ok() {}
+            });
+        }
+    });
+    Ok(server_addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unauthenticated_connection_limit_test() -> Result<()> {
+    let context = common::TestContext::new("unauthenticated_connection_limit")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut plan = NewPlan::create(&mut conn, "single".to_string())?;
+    plan.set_max_connections(&mut conn, Some(1))?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "stalleduser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "single")?;
+
+    let server_addr = start_password_server().await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    // a client naming the user but not authenticating doesn't take up
+    // their connection
+    let mut stalled = TcpStream::connect(&proxy_addr).await?;
+    stalled.write_all(&startup_message("stalleduser")).await?;
+    assert_eq!(authentication_code(&read_message(&mut stalled).await?), Some(AUTHENTICATION_SASL));
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&startup_message("stalleduser")).await?;
+    assert_eq!(authentication_code(&read_message(&mut client).await?), Some(AUTHENTICATION_SASL));
+    client.write_all(&message(b'p', b"password\0")).await?;
+    assert_eq!(authentication_code(&read_message(&mut client).await?), Some(AUTHENTICATION_OK));
+    assert_eq!(read_message(&mut client).await?, message(b'Z', b"I"));
+
+    // until it does, when the user's connection is taken
+    stalled.write_all(&message(b'p', b"password\0")).await?;
+    let error = read_message(&mut stalled).await?;
+    assert_eq!(error_code(&error), Some("53300".to_string()));
+    Ok(())
+}
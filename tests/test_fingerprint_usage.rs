@@ -0,0 +1,102 @@
+mod common;
+
+use anyhow::Result;
+use chrono::{Duration, DurationRound, Utc};
+use diesel::prelude::*;
+
+use impulse::models::fingerprint_usage::{FingerprintRanking, FingerprintTotal, FingerprintUsage};
+use impulse::models::reports::{NewReport, PacketDirection, PostgresqlPacketType, Report};
+use impulse::schema::reports;
+
+
+fn query_report(conn: &mut PgConnection, username: Option<&str>, query: &str) -> Result<Report> {
+    let mut bytes = vec![b'Q'];
+    bytes.extend(((query.len() + 5) as u32).to_be_bytes());
+    bytes.extend(query.as_bytes());
+    bytes.push(0);
+    NewReport::create(
+        username.map(String::from),
+        PostgresqlPacketType::Query,
+        Some(PacketDirection::Forward),
+        Some(serde_json::json!({ "Query": { "query": query } })),
+        Some(bytes),
+        false,
+    ).commit(conn)
+}
+
+#[test]
+fn aggregate_reports_test() -> Result<()> {
+    let context = common::TestContext::new("aggregate_fingerprints")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let username = "fpuser";
+    let hour = Utc::now().duration_trunc(Duration::hours(1))?;
+
+    let earlier = query_report(&mut conn, Some(username), "SELECT * FROM t WHERE id = 1")?;
+    diesel::update(reports::table.filter(reports::packet_id.eq(earlier.report_id)))
+        .set(reports::packet_time.eq(hour - Duration::minutes(30)))
+        .execute(&mut conn)?;
+    query_report(&mut conn, Some(username), "SELECT * FROM t WHERE id = 1")?;
+    query_report(&mut conn, Some(username), "select * from t where id = 2")?;
+    let insert = format!("INSERT INTO t VALUES (1, '{}')", "x".repeat(100));
+    query_report(&mut conn, Some(username), &insert)?;
+    query_report(&mut conn, Some(username), "SELEC oops")?;
+    query_report(&mut conn, None, "SELECT 1")?;
+
+    assert_eq!(FingerprintUsage::aggregate_reports(&mut conn)?, 6);
+    let usage = FingerprintUsage::for_user(&mut conn, username)?;
+    assert_eq!(usage.len(), 3);
+    let select_fingerprint = pg_query::fingerprint("SELECT * FROM t WHERE id = 1")?.hex;
+    assert_eq!(usage[0].period_start, hour - Duration::hours(1));
+    assert_eq!(usage[0].fingerprint, select_fingerprint);
+    assert_eq!(usage[0].query, "SELECT * FROM t WHERE id = $1");
+    assert_eq!(usage[0].calls, 1);
+    assert_eq!(usage[0].bytes, 34);
+    let current: Vec<&FingerprintUsage> = usage
+        .iter()
+        .filter(|row| row.period_start == hour)
+        .collect();
+    assert_eq!(current.len(), 2);
+    let select = current.iter().find(|row| row.fingerprint == select_fingerprint).unwrap();
+    assert_eq!(select.calls, 2);
+    assert_eq!(select.bytes, 68);
+    assert!(Report::for_user(&mut conn, username)?.iter().all(|report| report.fingerprinted));
+
+    // new reports add to the existing hourly rows
+    query_report(&mut conn, Some(username), "SELECT * FROM t WHERE id = 3")?;
+    assert_eq!(FingerprintUsage::aggregate_reports(&mut conn)?, 1);
+    assert_eq!(FingerprintUsage::aggregate_reports(&mut conn)?, 0);
+
+    let start = hour - Duration::hours(1);
+    let end = hour + Duration::hours(1);
+    let by_calls = FingerprintTotal::top_for_user(
+        &mut conn, username, &start, &end, FingerprintRanking::Calls, 10,
+    )?;
+    assert_eq!(by_calls.len(), 2);
+    assert_eq!(by_calls[0].fingerprint, select_fingerprint);
+    assert_eq!(by_calls[0].query, "SELECT * FROM t WHERE id = $1");
+    assert_eq!(by_calls[0].calls, 4);
+    assert_eq!(by_calls[0].bytes, 136);
+    assert_eq!(by_calls[1].calls, 1);
+
+    let by_bytes = FingerprintTotal::top_for_user(
+        &mut conn, username, &hour, &end, FingerprintRanking::Bytes, 1,
+    )?;
+    assert_eq!(by_bytes.len(), 1);
+    assert_eq!(by_bytes[0].query, "INSERT INTO t VALUES ($1, $2)");
+    assert_eq!(by_bytes[0].calls, 1);
+    assert_eq!(by_bytes[0].bytes, insert.len() as i64 + 6);
+
+    // each statement of a query counts on its own
+    let multiple = "SELECT * FROM t WHERE id = 4; INSERT INTO t VALUES (2, 'y')";
+    query_report(&mut conn, Some(username), multiple)?;
+    assert_eq!(FingerprintUsage::aggregate_reports(&mut conn)?, 1);
+    let totals = FingerprintTotal::top_for_user(
+        &mut conn, username, &start, &end, FingerprintRanking::Calls, 10,
+    )?;
+    assert_eq!(totals.len(), 2);
+    assert_eq!((totals[0].fingerprint.as_str(), totals[0].calls), (select_fingerprint.as_str(), 5));
+    assert_eq!(totals[1].calls, 2);
+    let added = totals[0].bytes - 136 + totals[1].bytes - (insert.len() as i64 + 6);
+    assert_eq!(added, multiple.len() as i64 + 6);
+    Ok(())
+}
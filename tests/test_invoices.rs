@@ -0,0 +1,116 @@
+mod common;
+
+use anyhow::Result;
+use chrono::Months;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use uuid::Uuid;
+
+use impulse::models::charges::{ChargeType, NewCharge};
+use impulse::models::invoices::*;
+use impulse::models::plans::UsagePeriod;
+use impulse::models::transactions::{NewExtTransaction, NewTransaction};
+use impulse::models::users::NewUser;
+
+
+#[test]
+fn close_periods_test() -> Result<()> {
+    let context = common::TestContext::new("close_periods")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "invoiceuser".to_string(), 0.)?;
+    NewExtTransaction::create(&mut conn, user.user_id, 10., None, "invoice1".to_string())?;
+    let charge = NewCharge::new(
+        user.user_id,
+        ChargeType::DataTransferOutBytes,
+        5.,
+        1.,
+        None,
+        None,
+    ).commit(&mut conn)?;
+    NewTransaction::from_charges(&mut conn, &vec![charge])?;
+    NewTransaction::credit(&mut conn, user.user_id, 2., "welcome")?;
+
+    let first_start = UsagePeriod::period_start(&user.created_at);
+    assert_eq!(Invoice::next_period_start(&mut conn, &user)?, first_start);
+    // the current period hasn't ended yet
+    assert!(Invoice::close_periods(&mut conn, &user, &user.created_at)?.is_empty());
+
+    let until = first_start + Months::new(2);
+    let invoices = Invoice::close_periods(&mut conn, &user, &until)?;
+    assert_eq!(invoices.len(), 2);
+    let first = &invoices[0];
+    assert_eq!(first.period_start, first_start);
+    assert_eq!(first.period_end, first_start + Months::new(1));
+    assert_eq!(first.opening_balance, 0.);
+    assert_eq!(first.deposits, 10.);
+    assert_eq!(first.payments, 5.);
+    assert_eq!(first.adjustments, 2.);
+    assert_eq!(first.closing_balance, 7.);
+    assert_eq!(first.charges_total, 5.);
+    assert_eq!(
+        first.charge_lines,
+        vec![InvoiceLine {
+            charge_type: ChargeType::DataTransferOutBytes,
+            charge_count: 1,
+            quantity: 5.,
+            free_quantity: 0.,
+            amount: 5.,
+        }]
+    );
+    let second = &invoices[1];
+    assert_eq!(second.period_start, first.period_end);
+    assert_eq!(second.opening_balance, 7.);
+    assert_eq!(second.closing_balance, 7.);
+    assert!(second.charge_lines.is_empty());
+
+    // periods are only closed once
+    assert!(Invoice::close_periods(&mut conn, &user, &until)?.is_empty());
+    assert_eq!(Invoice::for_user(&mut conn, &user.user_id)?, invoices);
+    assert_eq!(Invoice::retrieve(&mut conn, first.invoice_id)?, invoices[0]);
+
+    let json: serde_json::Value = serde_json::from_str(&first.to_json()?)?;
+    assert_eq!(json["closing_balance"], 7.);
+    assert_eq!(json["charge_lines"][0]["charge_type"], "DataTransferOutBytes");
+    assert!(first.render_text().contains("DataTransferOutBytes"));
+    assert!(first.render_html().contains("<td>DataTransferOutBytes</td>"));
+    Ok(())
+}
+
+#[test]
+fn billing_period_length_test() -> Result<()> {
+    let context = common::TestContext::new("billing_period_length")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "quarterlyuser".to_string(), 0.)?;
+    user.set_billing_period(&mut conn, 3)?;
+    let first_start = UsagePeriod::period_start(&user.created_at);
+    let invoices = Invoice::close_periods(&mut conn, &user, &(first_start + Months::new(5)))?;
+    assert_eq!(invoices.len(), 1);
+    assert_eq!(invoices[0].period_end, first_start + Months::new(3));
+    Ok(())
+}
+
+#[test]
+fn close_periods_failure_test() -> Result<()> {
+    let context = common::TestContext::new("close_periods_failure")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "failinguser".to_string(), 0.)?;
+    let first_start = UsagePeriod::period_start(&user.created_at);
+
+    // no period is closed if a later one fails to close
+    diesel::sql_query(format!(
+        "ALTER TABLE invoices ADD CONSTRAINT first_only CHECK (period_start < '{}')",
+        (first_start + Months::new(1)).to_rfc3339(),
+    )).execute(&mut conn)?;
+    assert!(Invoice::close_periods(&mut conn, &user, &(first_start + Months::new(2))).is_err());
+    assert!(Invoice::for_user(&mut conn, &user.user_id)?.is_empty());
+    diesel::sql_query("ALTER TABLE invoices DROP CONSTRAINT first_only").execute(&mut conn)?;
+
+    // and invoices whose lines are malformed fail to load
+    let invoices = Invoice::close_periods(&mut conn, &user, &(first_start + Months::new(1)))?;
+    diesel::sql_query("UPDATE invoices SET charge_lines = '{}' WHERE invoice_id = $1")
+        .bind::<BigInt, _>(invoices[0].invoice_id)
+        .execute(&mut conn)?;
+    assert!(Invoice::retrieve(&mut conn, invoices[0].invoice_id).is_err());
+    assert!(Invoice::for_user(&mut conn, &user.user_id).is_err());
+    Ok(())
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use uuid::Uuid;
+
+use impulse::models::charges::{Charge, ChargeType, NewCharge};
+use impulse::models::plans::*;
+use impulse::models::reports::{NewReport, PacketDirection, PostgresqlPacketType, ReportToCharge};
+use impulse::models::users::NewUser;
+
+
+#[test]
+fn default_plan_test() -> Result<()> {
+    let context = common::TestContext::new("default_plan")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "planuser".to_string(), 0.)?;
+    assert_eq!(user.plan_name, DEFAULT_PLAN);
+    assert!(PlanAllowance::for_plan(&mut conn, DEFAULT_PLAN)?.is_empty());
+    let free = PlanAllowance::free_quantity_for_user(
+        &mut conn,
+        &user.user_id,
+        ChargeType::DataTransferOutBytes,
+    )?;
+    assert_eq!(free, 0.);
+    Ok(())
+}
+
+#[test]
+fn allowance_applied_once_test() -> Result<()> {
+    let context = common::TestContext::new("allowance_applied_once")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    NewPlan::create(&mut conn, "starter".to_string())?;
+    PlanAllowance {
+        plan_name: "starter".to_string(),
+        charge_type: ChargeType::DataTransferOutBytes,
+        free_quantity: 10.,
+    }.set(&mut conn)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "starteruser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "starter")?;
+
+    let charge_bytes = |conn: &mut _, num_bytes: usize| -> Result<Charge> {
+        let report = NewReport::create(
+            Some("starteruser".to_string()),
+            PostgresqlPacketType::Other,
+            Some(PacketDirection::Backward),
+            None,
+            Some(vec![0; num_bytes]),
+            false,
+        ).commit(conn)?;
+        let mut charges = Charge::from_reports(
+            conn,
+            vec![ReportToCharge::with_userid(report, user.user_id)],
+        )?;
+        assert_eq!(charges.len(), 1);
+        Ok(charges.remove(0))
+    };
+    // entirely covered by the allowance
+    let charge1 = charge_bytes(&mut conn, 4)?;
+    assert_eq!(charge1.free_quantity, 4.);
+    assert_eq!(charge1.amount, 0.);
+    // partially covered by the remainder of the allowance
+    let charge2 = charge_bytes(&mut conn, 8)?;
+    assert_eq!(charge2.free_quantity, 6.);
+    assert_eq!(charge2.amount, 2. * charge2.rate);
+    // allowance is used up
+    let charge3 = charge_bytes(&mut conn, 3)?;
+    assert_eq!(charge3.free_quantity, 0.);
+    assert_eq!(charge3.amount, 3. * charge3.rate);
+
+    let period_start = UsagePeriod::period_start(&charge3.charge_time);
+    let usage = UsagePeriod::usage(
+        &mut conn,
+        &user.user_id,
+        ChargeType::DataTransferOutBytes,
+        &period_start,
+    )?;
+    assert_eq!(usage, 15.);
+    Ok(())
+}
+
+#[test]
+fn allowance_resets_monthly_test() -> Result<()> {
+    let context = common::TestContext::new("allowance_resets_monthly")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    NewPlan::create(&mut conn, "storage".to_string())?;
+    PlanAllowance {
+        plan_name: "storage".to_string(),
+        charge_type: ChargeType::DataStorageByteHours,
+        free_quantity: 100.,
+    }.set(&mut conn)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "storageuser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "storage")?;
+
+    let charge_at = |conn: &mut _, charge_time| {
+        NewCharge::new(
+            user.user_id,
+            ChargeType::DataStorageByteHours,
+            80.,
+            1.,
+            None,
+            Some(charge_time),
+        ).commit_for_period(conn)
+    };
+    let jan1 = charge_at(&mut conn, Utc.with_ymd_and_hms(2023, 1, 10, 0, 0, 0).unwrap())?;
+    let jan2 = charge_at(&mut conn, Utc.with_ymd_and_hms(2023, 1, 31, 23, 0, 0).unwrap())?;
+    let feb = charge_at(&mut conn, Utc.with_ymd_and_hms(2023, 2, 1, 0, 0, 0).unwrap())?;
+    assert_eq!((jan1.free_quantity, jan1.amount), (80., 0.));
+    assert_eq!((jan2.free_quantity, jan2.amount), (20., 60.));
+    assert_eq!((feb.free_quantity, feb.amount), (80., 0.));
+    Ok(())
+}
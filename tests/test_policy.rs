@@ -0,0 +1,103 @@
+mod common;
+
+use anyhow::Result;
+use prew::NoTransform;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::plans::NewPlan;
+use impulse::models::users::NewUser;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::protocol::error_code;
+use impulse::prew::proxy::serve;
+use impulse::prew::session_factory;
+
+use common::proxy::{connect, message, read_message, start_server};
+
+
+/// Send a simple query, returning the first message answering it.
+async fn query(client: &mut TcpStream, query: &str) -> Result<Vec<u8>> {
+    client.write_all(&message(b'Q', format!("{}\0", query).as_bytes())).await?;
+    let reply = read_message(client).await?;
+    assert_eq!(read_message(client).await?, message(b'Z', b"I"));
+    Ok(reply)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_policy_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_policy")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    NewPlan::create(&mut conn, "pro".to_string())?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "basicuser".to_string(), 1.)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "prouser".to_string(), 1.)?;
+    user.set_plan(&mut conn, "pro")?;
+    let policy = StatementPolicy::from_toml(r#"
+        deny = ["create_extension", "alter_system", "load", "copy_program"]
+        allowed_extensions = ["pgcrypto"]
+
+        [plans.pro]
+        allow = ["load"]
+    "#)?;
+
+    let server_addr = start_server("OK").await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), policy);
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    let mut basic = connect(&proxy_addr, "basicuser").await?;
+    assert_eq!(query(&mut basic, "SELECT 1").await?[0], b'C');
+    assert_eq!(query(&mut basic, "CREATE EXTENSION pgcrypto").await?[0], b'C');
+    for denied in [
+        "CREATE EXTENSION postgis",
+        "ALTER SYSTEM SET work_mem = '1GB'",
+        "LOAD 'auto_explain'",
+        "SELECT 1; COPY t TO PROGRAM 'cat'",
+        "DO $$ BEGIN EXECUTE 'LOAD ''auto_explain'''; END $$",
+        "CREATE FUNCTION f() RETURNS void LANGUAGE plpgsql AS $$ BEGIN EXECUTE 'LOAD ''auto_explain'''; END $$",
+    ] {
+        let error = query(&mut basic, denied).await?;
+        assert_eq!(error[0], b'E', "{}", denied);
+        assert_eq!(error_code(&error), Some("42501".to_string()));
+    }
+
+    // refusals of pipelined queries follow the answers to the queries before
+    // them
+    let queries = [message(b'Q', b"SELECT 1\0"), message(b'Q', b"LOAD 'auto_explain'\0")];
+    basic.write_all(&queries.concat()).await?;
+    assert_eq!(read_message(&mut basic).await?[0], b'C');
+    assert_eq!(read_message(&mut basic).await?, message(b'Z', b"I"));
+    assert_eq!(error_code(&read_message(&mut basic).await?), Some("42501".to_string()));
+    assert_eq!(read_message(&mut basic).await?, message(b'Z', b"I"));
+
+    // plans can make exceptions
+    let mut pro = connect(&proxy_addr, "prouser").await?;
+    assert_eq!(query(&mut pro, "LOAD 'auto_explain'").await?[0], b'C');
+    assert_eq!(error_code(&query(&mut pro, "ALTER SYSTEM RESET ALL").await?), Some("42501".to_string()));
+
+    // extended queries are refused up to their Sync
+    let parse = message(b'P', b"\0COPY t TO PROGRAM 'cat'\0\0\0");
+    let bind = message(b'B', b"\0\0\0\0\0\0\0\0");
+    let execute = message(b'E', b"\0\0\0\0\0");
+    let sync = message(b'S', b"");
+    pro.write_all(&[parse, bind.clone(), execute.clone(), sync.clone()].concat()).await?;
+    let error = read_message(&mut pro).await?;
+    assert_eq!(error_code(&error), Some("42501".to_string()));
+    assert_eq!(read_message(&mut pro).await?, message(b'Z', b"I"));
+    assert_eq!(query(&mut pro, "SELECT 1").await?[0], b'C');
+
+    // and the server still answers the messages before the refused one,
+    // ahead of the refusal and up to the Sync
+    let allowed = message(b'P', b"\0SELECT 1\0\0\0");
+    let denied = message(b'P', b"\0COPY t TO PROGRAM 'cat'\0\0\0");
+    pro.write_all(&[allowed, bind.clone(), execute.clone(), denied, bind, execute, sync].concat()).await?;
+    assert_eq!(read_message(&mut pro).await?, message(b'1', b""));
+    assert_eq!(read_message(&mut pro).await?, message(b'2', b""));
+    assert_eq!(read_message(&mut pro).await?, message(b'C', b"OK\0"));
+    assert_eq!(error_code(&read_message(&mut pro).await?), Some("42501".to_string()));
+    assert_eq!(read_message(&mut pro).await?, message(b'Z', b"I"));
+    assert_eq!(query(&mut pro, "SELECT 1").await?[0], b'C');
+    Ok(())
+}
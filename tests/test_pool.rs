@@ -0,0 +1,258 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use prew::NoTransform;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::users::NewUser;
+use impulse::prew::auth::ScramVerifier;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::pool::{PoolConfig, ServerPools};
+use impulse::prew::protocol::{
+    authentication_code, error_code, query_message, simple_query, startup_message, startup_parameters,
+    transaction_status, AUTHENTICATION_SASL_FINAL,
+};
+use impulse::prew::proxy::{serve_with_options, ProxyOptions};
+use impulse::prew::tls::ProxyTls;
+use impulse::prew::{session_factory, AppendUserNameTransformer};
+
+use common::proxy::{authenticate, message, read_message, read_startup};
+
+
+/// Start up as `username` on `database` and authenticate with `password`,
+/// returning the connection once it's ready for queries.
+async fn connect(proxy_addr: &str, username: &str, database: &str, password: &str) -> Result<TcpStream> {
+    let (client, parameters) = connect_with(proxy_addr, &[("user", username), ("database", database)], password).await?;
+    assert_eq!(parameters, [message(b'S', b"server_version\x0016.0\0")]);
+    Ok(client)
+}
+
+/// Start up with `startup` and authenticate with `password`, returning the
+/// connection once it's ready for queries, with the ParameterStatus
+/// messages of the server connection the session was checked on.
+async fn connect_with(proxy_addr: &str, startup: &[(&str, &str)], password: &str) -> Result<(TcpStream, Vec<Vec<u8>>)> {
+    let mut client = TcpStream::connect(proxy_addr).await?;
+    client.write_all(&startup_message(startup)).await?;
+    let reply = authenticate(&mut client, password).await?;
+    assert_eq!(authentication_code(&reply), Some(AUTHENTICATION_SASL_FINAL));
+    assert_eq!(authentication_code(&read_message(&mut client).await?), Some(0));
+    let mut parameters = vec![];
+    loop {
+        let message = read_message(&mut client).await?;
+        match message[0] {
+            b'S' => parameters.push(message),
+            b'Z' => return Ok((client, parameters)),
+            _ => panic!("Unexpected message {:?}", message),
+        }
+    }
+}
+
+/// Run `query`, returning the transaction status it leaves.
+async fn run_query(client: &mut TcpStream, query: &str) -> Result<u8> {
+    client.write_all(&query_message(query)).await?;
+    assert_eq!(read_message(client).await?[0], b'C');
+    Ok(transaction_status(&read_message(client).await?).unwrap())
+}
+
+/// What a server was sent.
+#[derive(Default)]
+struct ServerLog {
+    /// Databases of the connections, in the order they were opened
+    databases: Vec<String>,
+    /// Startup parameters of the connections other than user and database,
+    /// in the order they were opened
+    parameters: Vec<Vec<(String, String)>>,
+    queries: Vec<String>,
+}
+
+/// A server answering every query, keeping track of transactions, and
+/// logging what it's sent. Its connections report the application_name
+/// they're started with.
+async fn start_server() -> Result<(String, Arc<Mutex<ServerLog>>)> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    let log = Arc::new(Mutex::new(ServerLog::default()));
+    let server_log = log.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = server.accept().await.unwrap();
+            let log = server_log.clone();
+            tokio::spawn(async move {
+                let startup = read_startup(&mut socket).await.unwrap();
+                let (mut database, mut others) = (None, vec![]);
+                for (name, value) in startup_parameters(&startup).unwrap() {
+                    match name.as_str() {
+                        "database" => database = Some(value),
+                        "user" => {}
+                        _ => others.push((name, value)),
+                    }
+                }
+                let mut parameters = message(b'S', b"server_version\x0016.0\0");
+                if let Some((_, name)) = others.iter().find(|(name, _)| name == "application_name") {
+                    parameters.extend(message(b'S', format!("application_name\0{}\0", name).as_bytes()));
+                }
+                {
+                    let mut log = log.lock().unwrap();
+                    log.databases.push(database.unwrap());
+                    log.parameters.push(others);
+                }
+                let ok = message(b'R', &[0, 0, 0, 0]);
+                socket.write_all(&[ok, parameters, message(b'Z', b"I")].concat()).await.unwrap();
+                let mut status = b'I';
+                while let Ok(query) = read_message(&mut socket).await {
+                    if let Some(query) = simple_query(&query) {
+                        match query.as_str() {
+                            "BEGIN" => status = b'T',
+                            "COMMIT" | "ROLLBACK" => status = b'I',
+                            _ => {}
+                        }
+                        log.lock().unwrap().queries.push(query);
+                        let reply = [message(b'C', b"OK\0"), message(b'Z', &[status])];
+                        socket.write_all(&reply.concat()).await.unwrap();
+                    }
+                }
+            });
+        }
+    });
+    Ok((server_addr, log))
+}
+
+/// Start a proxy pooling up to `size` connections to `server_addr` for each
+/// set of startup parameters, returning its address.
+async fn start_proxy(context: &common::TestContext, server_addr: String, size: usize) -> Result<String> {
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(
+        report_connstr,
+        AppendUserNameTransformer::new(),
+        NoTransform::new(),
+        StatementPolicy::default(),
+    );
+    let config = PoolConfig { size, wait_timeout_secs: 1 };
+    let pools = ServerPools::new(ProxyTls::new(), config);
+    let options = ProxyOptions { authenticate_clients: true, pools: Some(Arc::new(pools)), ..ProxyOptions::default() };
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve_with_options(listener, server_addr, create_session, Arc::new(options)));
+    Ok(proxy_addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_pool_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_pool")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut alice = NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    alice.set_pg_password_enc(&mut conn, &ScramVerifier::new("alicepw").to_string())?;
+
+    let (server_addr, log) = start_server().await?;
+    let proxy_addr = start_proxy(&context, server_addr, 1).await?;
+
+    // both clients share the one connection, which is rewritten as usual
+    let mut first = connect(&proxy_addr, "alice", "shop", "alicepw").await?;
+    let mut second = connect(&proxy_addr, "alice", "shop", "alicepw").await?;
+    assert_eq!(run_query(&mut first, "CREATE DATABASE shop").await?, b'I');
+    assert_eq!(run_query(&mut second, "SELECT 1").await?, b'I');
+
+    // for as long as a transaction
+    assert_eq!(run_query(&mut first, "BEGIN").await?, b'T');
+    second.write_all(&query_message("SELECT 2")).await?;
+    let waiting = tokio::time::timeout(Duration::from_millis(300), read_message(&mut second)).await;
+    assert!(waiting.is_err());
+    assert_eq!(run_query(&mut first, "COMMIT").await?, b'I');
+    assert_eq!(read_message(&mut second).await?[0], b'C');
+    assert_eq!(read_message(&mut second).await?, message(b'Z', b"I"));
+
+    // clients left waiting longer than that are disconnected
+    assert_eq!(run_query(&mut first, "BEGIN").await?, b'T');
+    second.write_all(&query_message("SELECT 3")).await?;
+    let error = read_message(&mut second).await?;
+    assert_eq!(error_code(&error), Some("53300".to_string()));
+    assert!(matches!(second.read(&mut [0; 16]).await, Ok(0) | Err(_)));
+    assert_eq!(run_query(&mut first, "COMMIT").await?, b'I');
+    drop(first);
+
+    // the transactions of clients that leave are rolled back, and their
+    // connection lent again
+    let mut third = connect(&proxy_addr, "alice", "shop", "alicepw").await?;
+    assert_eq!(run_query(&mut third, "BEGIN").await?, b'T');
+    third.write_all(&message(b'X', b"")).await?;
+    drop(third);
+    let mut fourth = connect(&proxy_addr, "alice", "shop", "alicepw").await?;
+    assert_eq!(run_query(&mut fourth, "SELECT 4").await?, b'I');
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let log = log.lock().unwrap();
+    assert_eq!(log.databases, ["shop__alice"]);
+    assert_eq!(
+        log.queries,
+        [
+            "CREATE DATABASE shop__alice",
+            "DISCARD ALL",
+            "SELECT 1",
+            "DISCARD ALL",
+            "BEGIN",
+            "COMMIT",
+            "DISCARD ALL",
+            "SELECT 2",
+            "DISCARD ALL",
+            "BEGIN",
+            "COMMIT",
+            "DISCARD ALL",
+            "BEGIN",
+            "ROLLBACK",
+            "DISCARD ALL",
+            "SELECT 4",
+            "DISCARD ALL",
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pool_startup_parameters_test() -> Result<()> {
+    let context = common::TestContext::new("pool_startup_parameters")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut alice = NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    alice.set_pg_password_enc(&mut conn, &ScramVerifier::new("alicepw").to_string())?;
+
+    let (server_addr, log) = start_server().await?;
+    let proxy_addr = start_proxy(&context, server_addr, 1).await?;
+
+    // clients are only lent connections started with their own parameters
+    let startup = |application_name| [
+        ("user", "alice"),
+        ("database", "shop"),
+        ("application_name", application_name),
+        ("TimeZone", "UTC"),
+    ];
+    let application_name = |name: &str| message(b'S', format!("application_name\0{}\0", name).as_bytes());
+    let (mut report, parameters) = connect_with(&proxy_addr, &startup("report"), "alicepw").await?;
+    assert_eq!(parameters[1..], [application_name("report")]);
+    let (mut shell, parameters) = connect_with(&proxy_addr, &startup("shell"), "alicepw").await?;
+    assert_eq!(parameters[1..], [application_name("shell")]);
+    let mut plain = connect(&proxy_addr, "alice", "shop", "alicepw").await?;
+    // even while the others are in a transaction
+    assert_eq!(run_query(&mut report, "BEGIN").await?, b'T');
+    assert_eq!(run_query(&mut shell, "BEGIN").await?, b'T');
+    assert_eq!(run_query(&mut plain, "SELECT 1").await?, b'I');
+    assert_eq!(run_query(&mut report, "COMMIT").await?, b'I');
+    assert_eq!(run_query(&mut shell, "COMMIT").await?, b'I');
+
+    // and share them with clients starting up the same way
+    let (mut second_report, parameters) = connect_with(&proxy_addr, &startup("report"), "alicepw").await?;
+    assert_eq!(parameters[1..], [application_name("report")]);
+    assert_eq!(run_query(&mut second_report, "SELECT 2").await?, b'I');
+
+    let log = log.lock().unwrap();
+    let parameters = |application_name: &str| vec![
+        ("TimeZone".to_string(), "UTC".to_string()),
+        ("application_name".to_string(), application_name.to_string()),
+    ];
+    assert_eq!(log.databases, ["shop__alice"; 3]);
+    assert_eq!(log.parameters, [parameters("report"), parameters("shell"), vec![]]);
+    Ok(())
+}
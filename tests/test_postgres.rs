@@ -36,7 +36,7 @@ pub fn user_creation_test() -> Result<()> {
         username
     );
     user_manager.with(|| {
-        let info = managed_db_manager.create_pg_user_and_database(username)?;
+        let info = managed_db_manager.create_pg_user_and_database(username, None)?;
         info!("User {} created with password {}", &info.username, &info.password);
         debug!("Testing that user can connect to their database ({})", &managed_db_manager);
         let user_config = Rc::new(managed_db_manager.with_user(username, &info.password));
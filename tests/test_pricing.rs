@@ -0,0 +1,121 @@
+mod common;
+
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use uuid::Uuid;
+
+use impulse::models::charges::{Charge, ChargeType, NewCharge};
+use impulse::models::plans::{NewPlan, PlanAllowance};
+use impulse::models::pricing::*;
+use impulse::models::users::NewUser;
+
+
+fn tier(start_quantity: f64, rate: f64) -> PriceTier {
+    PriceTier { charge_type: ChargeType::DataTransferOutBytes, start_quantity, rate }
+}
+
+#[test]
+fn price_range_test() {
+    let tiers = vec![tier(10., 0.5), tier(20., 0.25)];
+    assert_eq!(
+        price_range(&tiers, 1., 5., 25.),
+        vec![
+            TierCharge { start_quantity: 0., quantity: 5., rate: 1., amount: 5. },
+            TierCharge { start_quantity: 10., quantity: 10., rate: 0.5, amount: 5. },
+            TierCharge { start_quantity: 20., quantity: 5., rate: 0.25, amount: 1.25 },
+        ]
+    );
+    // within the last tier
+    let breakdown = price_range(&tiers, 1., 30., 34.);
+    assert_eq!(
+        breakdown,
+        vec![TierCharge { start_quantity: 20., quantity: 4., rate: 0.25, amount: 1. }]
+    );
+    assert_eq!(blended_rate(&breakdown, 1.), 0.25);
+    // nothing charged
+    assert!(price_range(&tiers, 1., 12., 12.).is_empty());
+    assert_eq!(blended_rate(&[], 1.), 1.);
+}
+
+#[test]
+fn tiered_charges_test() -> Result<()> {
+    let context = common::TestContext::new("tiered_charges")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    tier(0., 1.).set(&mut conn)?;
+    tier(100., 0.5).set(&mut conn)?;
+    assert_eq!(
+        PriceTier::for_charge_type(&mut conn, ChargeType::DataTransferOutBytes)?,
+        vec![tier(0., 1.), tier(100., 0.5)]
+    );
+    NewPlan::create(&mut conn, "tiered".to_string())?;
+    PlanAllowance {
+        plan_name: "tiered".to_string(),
+        charge_type: ChargeType::DataTransferOutBytes,
+        free_quantity: 20.,
+    }.set(&mut conn)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "tiereduser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "tiered")?;
+
+    let charge_at = |conn: &mut _, quantity, day| {
+        NewCharge::new(
+            user.user_id,
+            ChargeType::DataTransferOutBytes,
+            quantity,
+            2.,
+            None,
+            Some(Utc.with_ymd_and_hms(2023, 3, day, 0, 0, 0).unwrap()),
+        ).commit_for_period(conn)
+    };
+    // 20 free, 60 at the first tier
+    let charge1 = charge_at(&mut conn, 80., 1)?;
+    assert_eq!(charge1.free_quantity, 20.);
+    assert_eq!(charge1.rate, 1.);
+    assert_eq!(charge1.amount, 60.);
+    assert_eq!(
+        charge1.tiers,
+        Some(vec![TierCharge { start_quantity: 0., quantity: 60., rate: 1., amount: 60. }])
+    );
+    // crosses into the second tier at 100 cumulative usage
+    let charge2 = charge_at(&mut conn, 40., 2)?;
+    assert_eq!(charge2.free_quantity, 0.);
+    assert_eq!(charge2.rate, 0.75);
+    assert_eq!(charge2.amount, 30.);
+    assert_eq!(
+        charge2.tiers,
+        Some(vec![
+            TierCharge { start_quantity: 0., quantity: 20., rate: 1., amount: 20. },
+            TierCharge { start_quantity: 100., quantity: 20., rate: 0.5, amount: 10. },
+        ])
+    );
+    // next month starts from the first tier again
+    let charge3 = NewCharge::new(
+        user.user_id,
+        ChargeType::DataTransferOutBytes,
+        30.,
+        2.,
+        None,
+        Some(Utc.with_ymd_and_hms(2023, 4, 1, 0, 0, 0).unwrap()),
+    ).commit_for_period(&mut conn)?;
+    assert_eq!(charge3.amount, 10.);
+
+    // charge types without tiers keep their rate and have no breakdown
+    let untiered = NewCharge::new(
+        user.user_id,
+        ChargeType::DataStorageByteHours,
+        10.,
+        2.,
+        None,
+        None,
+    ).commit_for_period(&mut conn)?;
+    assert_eq!(untiered.amount, 20.);
+    assert_eq!(untiered.tiers, None);
+
+    // a malformed breakdown fails to load rather than panicking
+    diesel::sql_query("UPDATE charges SET tiers = '{\"quantity\": 20}' WHERE charge_id = $1")
+        .bind::<BigInt, _>(charge3.charge_id)
+        .execute(&mut conn)?;
+    assert!(Charge::retrieve(&mut conn, charge3.charge_id).is_err());
+    Ok(())
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use futures::lock::Mutex;
+use prew::Parser;
+use prew::packet::Packet;
+use prew::rule::DefaultContext;
+
+use impulse::models::query_stats::QueryStat;
+use impulse::models::reports::PacketDirection;
+use impulse::prew::stats::QueryTracker;
+
+use common::proxy::message;
+
+
+/// Message of type `id` with `body`, as parsed by prew.
+fn packet(id: u8, body: &[u8]) -> prew::PostgresqlPacket {
+    prew::PostgresParser::new()
+        .parse(&Packet::new(message(id, body)), &mut DefaultContext::new())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn query_tracker_test() -> Result<()> {
+    let context = common::TestContext::new("query_tracker")?;
+    let conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let conn = Arc::new(Mutex::new(conn));
+    let tracker = QueryTracker::new(conn.clone());
+    let username = "statsuser".to_string();
+    let observe = |id: u8, body: &[u8], direction: PacketDirection| {
+        tracker.observe(&packet(id, body), direction, Some(&username));
+    };
+    use PacketDirection::{Backward, Forward};
+
+    // simple query whose second statement fails, skipping the third
+    observe(b'Q', b"SELECT 1; SELECT * FROM missing WHERE id = 5; SELECT 2\0", Forward);
+    observe(b'D', b"\0\x01\0\0\0\x011", Backward);
+    observe(b'C', b"SELECT 1\0", Backward);
+    observe(b'E', b"SERROR\0C42P01\0Mrelation \"missing\" does not exist\0\0", Backward);
+    observe(b'Z', b"I", Backward);
+    // extended protocol
+    observe(b'P', b"stmt\0SELECT $1::int\0\0\0", Forward);
+    observe(b'B', b"\0stmt\0\0\0\0\0\0\0", Forward);
+    observe(b'E', b"\0\0\0\0\0", Forward);
+    observe(b'S', b"", Forward);
+    observe(b'1', b"", Backward);
+    observe(b'2', b"", Backward);
+    observe(b'D', b"\0\x01\0\0\0\x011", Backward);
+    observe(b'D', b"\0\x01\0\0\0\x012", Backward);
+    observe(b'C', b"SELECT 2\0", Backward);
+    observe(b'Z', b"I", Backward);
+    // not authenticated
+    tracker.observe(&packet(b'Q', b"SELECT 3\0"), Forward, None);
+    tracker.observe(&packet(b'C', b"SELECT 1\0"), Backward, None);
+
+    let mut stats = vec![];
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stats = QueryStat::for_user(&mut *conn.lock().await, &username)?;
+        if stats.len() >= 3 {
+            break;
+        }
+    }
+    stats.sort_by(|a, b| a.query.cmp(&b.query));
+    assert_eq!(stats.len(), 3);
+
+    assert_eq!(stats[0].query, "SELECT $1");
+    assert_eq!(stats[0].command_tag, Some("SELECT 1".to_string()));
+    assert_eq!(stats[0].rows_returned, 1);
+    assert_eq!(stats[0].bytes_returned, 12);
+    assert_eq!(stats[0].error_code, None);
+    assert_eq!(stats[0].fingerprint, Some(pg_query::fingerprint("SELECT 1")?.hex));
+
+    assert_eq!(stats[1].query, "SELECT $1::int");
+    assert_eq!(stats[1].command_tag, Some("SELECT 2".to_string()));
+    assert_eq!(stats[1].rows_returned, 2);
+    assert_eq!(stats[1].bytes_returned, 24);
+
+    assert_eq!(stats[2].query, "SELECT * FROM missing WHERE id = $1");
+    assert_eq!(stats[2].error_code, Some("42P01".to_string()));
+    assert_eq!(stats[2].command_tag, None);
+    assert_eq!(stats[2].rows_returned, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn pipelined_query_tracker_test() -> Result<()> {
+    let context = common::TestContext::new("pipelined_query_tracker")?;
+    let conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let conn = Arc::new(Mutex::new(conn));
+    let tracker = QueryTracker::new(conn.clone());
+    let username = "pipelineuser".to_string();
+    let observe = |id: u8, body: &[u8], direction: PacketDirection| {
+        tracker.observe(&packet(id, body), direction, Some(&username));
+    };
+    use PacketDirection::{Backward, Forward};
+
+    // two extended queries sent before the server answers either, the first
+    // failing, then a simple query sent before the second is answered
+    observe(b'P', b"failing\0SELECT * FROM missing\0\0\0", Forward);
+    observe(b'B', b"\0failing\0\0\0\0\0\0\0", Forward);
+    observe(b'E', b"\0\0\0\0\0", Forward);
+    observe(b'P', b"skipped\0SELECT 'skipped'\0\0\0", Forward);
+    observe(b'B', b"\0skipped\0\0\0\0\0\0\0", Forward);
+    observe(b'E', b"\0\0\0\0\0", Forward);
+    observe(b'S', b"", Forward);
+    observe(b'P', b"next\0SELECT $1::int\0\0\0", Forward);
+    observe(b'B', b"\0next\0\0\0\0\0\0\0", Forward);
+    observe(b'E', b"\0\0\0\0\0", Forward);
+    observe(b'S', b"", Forward);
+    observe(b'E', b"SERROR\0C42P01\0Mrelation \"missing\" does not exist\0\0", Backward);
+    observe(b'Q', b"SELECT 3\0", Forward);
+    observe(b'Z', b"I", Backward);
+    observe(b'1', b"", Backward);
+    observe(b'2', b"", Backward);
+    observe(b'D', b"\0\x01\0\0\0\x011", Backward);
+    observe(b'C', b"SELECT 1\0", Backward);
+    observe(b'Z', b"I", Backward);
+    observe(b'C', b"SELECT 1\0", Backward);
+    observe(b'Z', b"I", Backward);
+
+    let mut stats = vec![];
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stats = QueryStat::for_user(&mut *conn.lock().await, &username)?;
+        if stats.len() >= 3 {
+            break;
+        }
+    }
+    stats.sort_by(|a, b| a.query.cmp(&b.query));
+    assert_eq!(stats.len(), 3);
+    assert_eq!(stats[0].query, "SELECT $1");
+    assert_eq!(stats[0].command_tag, Some("SELECT 1".to_string()));
+    assert_eq!(stats[1].query, "SELECT $1::int");
+    assert_eq!(stats[1].rows_returned, 1);
+    assert_eq!(stats[2].query, "SELECT * FROM missing");
+    assert_eq!(stats[2].error_code, Some("42P01".to_string()));
+    Ok(())
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use anyhow::Result;
+use prew::NoTransform;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+use impulse::models::balances::BalanceEstimate;
+use impulse::models::charges::{Charge, ChargeType};
+use impulse::models::plans::{NewPlan, PlanAllowance};
+use impulse::models::reports::{NewReport, PacketDirection, PostgresqlPacketType, ReportToCharge};
+use impulse::models::transactions::NewTransaction;
+use impulse::models::users::NewUser;
+use impulse::prew::protocol::error_code;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::serve;
+use impulse::prew::session_factory;
+
+use common::proxy::{connect, message, read_message, start_server};
+
+
+#[test]
+fn balance_estimate_test() -> Result<()> {
+    let context = common::TestContext::new("balance_estimate")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    assert_eq!(BalanceEstimate::for_user(&mut conn, "nosuchuser")?, None);
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "estimateuser".to_string(), 1.)?;
+    NewReport::create(
+        Some("estimateuser".to_string()),
+        PostgresqlPacketType::Other,
+        Some(PacketDirection::Backward),
+        None,
+        Some(vec![0; 1000]),
+        false,
+    ).commit(&mut conn)?;
+    NewReport::time("estimateuser".to_string(), PostgresqlPacketType::ConnectionTime, 10.)
+        .commit(&mut conn)?;
+    let cost = 1000. * ChargeType::DataTransferOutBytes.rate() + 10. * ChargeType::ConnectionSeconds.rate();
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-12;
+
+    let estimate = BalanceEstimate::for_user(&mut conn, "estimateuser")?.unwrap();
+    assert_eq!(estimate.user_id, user.user_id);
+    assert_eq!(estimate.balance, 1.);
+    assert_eq!(estimate.untransacted, 0.);
+    assert!(close(estimate.uncharged, cost), "{:?}", estimate);
+
+    // charged but not yet posted to the balance
+    let uncharged = ReportToCharge::uncharged(&mut conn)?;
+    let charges = Charge::from_reports(&mut conn, uncharged)?;
+    let estimate = BalanceEstimate::for_user(&mut conn, "estimateuser")?.unwrap();
+    assert_eq!(estimate.uncharged, 0.);
+    assert!(close(estimate.untransacted, cost), "{:?}", estimate);
+
+    // posted
+    NewTransaction::from_charges(&mut conn, &charges)?;
+    let estimate = BalanceEstimate::for_user(&mut conn, "estimateuser")?.unwrap();
+    assert_eq!(estimate.untransacted, 0.);
+    assert!(close(estimate.balance, 1. - cost), "{:?}", estimate);
+    // users are only disabled below a balance of -1
+    assert!(close(estimate.remaining(), 2. - cost));
+
+    // usage within the plan's allowance is free
+    NewPlan::create(&mut conn, "connections".to_string())?;
+    PlanAllowance {
+        plan_name: "connections".to_string(),
+        charge_type: ChargeType::ConnectionSeconds,
+        free_quantity: 15.,
+    }.set(&mut conn)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "allowanceuser".to_string(), 0.)?;
+    user.set_plan(&mut conn, "connections")?;
+    NewReport::time("allowanceuser".to_string(), PostgresqlPacketType::ConnectionTime, 10.)
+        .commit(&mut conn)?;
+    let estimate = BalanceEstimate::for_user(&mut conn, "allowanceuser")?.unwrap();
+    assert_eq!(estimate.uncharged, 0.);
+    // and only what's left of it this month counts
+    let uncharged = ReportToCharge::uncharged(&mut conn)?;
+    Charge::from_reports(&mut conn, uncharged)?;
+    NewReport::time("allowanceuser".to_string(), PostgresqlPacketType::ConnectionTime, 10.)
+        .commit(&mut conn)?;
+    let estimate = BalanceEstimate::for_user(&mut conn, "allowanceuser")?.unwrap();
+    assert!(close(estimate.uncharged, 5. * ChargeType::ConnectionSeconds.rate()), "{:?}", estimate);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_quota_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_quota")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    // five days of connection time, more than a balance of 0 covers before
+    // users are disabled at -1
+    let seconds = 5. * 86400.;
+    assert!(seconds * ChargeType::ConnectionSeconds.rate() > 1.);
+    NewUser::create(&mut conn, Uuid::new_v4(), "brokeuser".to_string(), 0.)?;
+    NewReport::time("brokeuser".to_string(), PostgresqlPacketType::ConnectionTime, seconds).commit(&mut conn)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "paiduser".to_string(), 1.)?;
+    // unless the plan's allowance covers it
+    NewPlan::create(&mut conn, "free".to_string())?;
+    PlanAllowance {
+        plan_name: "free".to_string(),
+        charge_type: ChargeType::ConnectionSeconds,
+        free_quantity: seconds,
+    }.set(&mut conn)?;
+    let mut free = NewUser::create(&mut conn, Uuid::new_v4(), "freeuser".to_string(), 0.)?;
+    free.set_plan(&mut conn, "free")?;
+    NewReport::time("freeuser".to_string(), PostgresqlPacketType::ConnectionTime, seconds).commit(&mut conn)?;
+
+    let server_addr = start_server("SELECT 1").await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    let query = message(b'Q', b"SELECT 1\0");
+    let mut paid = connect(&proxy_addr, "paiduser").await?;
+    paid.write_all(&query).await?;
+    assert_eq!(read_message(&mut paid).await?[0], b'C');
+    assert_eq!(read_message(&mut paid).await?, message(b'Z', b"I"));
+    let mut free = connect(&proxy_addr, "freeuser").await?;
+    free.write_all(&query).await?;
+    assert_eq!(read_message(&mut free).await?[0], b'C');
+    assert_eq!(read_message(&mut free).await?, message(b'Z', b"I"));
+
+    // the proxy answers in place of the server, and the session stays open
+    let mut broke = connect(&proxy_addr, "brokeuser").await?;
+    for _ in 0..2 {
+        broke.write_all(&query).await?;
+        let error = read_message(&mut broke).await?;
+        assert_eq!(error[0], b'E');
+        assert_eq!(error_code(&error), Some("53400".to_string()));
+        assert_eq!(read_message(&mut broke).await?, message(b'Z', b"I"));
+    }
+    // extended queries are refused up to their Sync
+    let parse = message(b'P', b"\0SELECT 1\0\0\0");
+    let bind = message(b'B', b"\0\0\0\0\0\0\0\0");
+    let execute = message(b'E', b"\0\0\0\0\0");
+    let sync = message(b'S', b"");
+    broke.write_all(&[parse, bind, execute, sync].concat()).await?;
+    let error = read_message(&mut broke).await?;
+    assert_eq!(error_code(&error), Some("53400".to_string()));
+    assert_eq!(read_message(&mut broke).await?, message(b'Z', b"I"));
+    Ok(())
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use anyhow::Result;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use prew::NoTransform;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::users::{NewUser, User, UserStatus};
+use impulse::prew::protocol::error_code;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::serve;
+use impulse::prew::session_factory;
+
+use common::proxy::{connect, message, read_message, start_server};
+
+
+#[derive(QueryableByName, Debug)]
+struct DatabaseSettings {
+    #[diesel(sql_type = Text)]
+    settings: String,
+}
+
+/// Settings of a database for all roles, comma-separated.
+fn database_settings(conn: &mut PgConnection, database: &str) -> Result<String> {
+    let row = diesel::sql_query(
+        "SELECT coalesce((
+            SELECT array_to_string(setconfig, ',') FROM pg_db_role_setting
+            JOIN pg_database ON pg_database.oid = setdatabase
+            WHERE datname = $1 AND setrole = 0
+        ), '') AS settings"
+    )
+        .bind::<Text, _>(database)
+        .get_result::<DatabaseSettings>(conn)?;
+    Ok(row.settings)
+}
+
+#[test]
+fn read_only_user_test() -> Result<()> {
+    let context = common::TestContext::new("read_only_user")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "readonlyuser".to_string(), 0.)?;
+    user.mark_synced(&mut conn)?;
+    user.set_read_only(&mut conn)?;
+    assert_eq!(user.user_status, UserStatus::ReadOnly);
+    assert!(!user.status_synced);
+    assert_eq!(User::for_pg_name(&mut conn, "readonlyuser")?, Some(user));
+    assert_eq!(User::for_pg_name(&mut conn, "nosuchuser")?, None);
+
+    let managed_db_manager = &context.managed_db_manager;
+    let mut managed_conn = managed_db_manager.pg_connect()?;
+    managed_db_manager.create_pg_user_and_database("readonlyuser", None)?;
+    let result = (|| {
+        // databases the user named themselves are made read-only too
+        diesel::sql_query(r#"CREATE DATABASE "My Shop_readonlyuser" OWNER readonlyuser"#)
+            .execute(&mut managed_conn)?;
+        // which can still be connected to, e.g. to install role procedures
+        managed_db_manager.pg_connect_db("My Shop_readonlyuser")?;
+        assert_eq!(database_settings(&mut managed_conn, "readonlyuser")?, "");
+        managed_db_manager.set_read_only("readonlyuser", true)?;
+        for database in ["readonlyuser", "My Shop_readonlyuser"] {
+            assert_eq!(
+                database_settings(&mut managed_conn, database)?,
+                "default_transaction_read_only=on",
+            );
+        }
+        // new sessions can't write
+        let mut user_conn = managed_db_manager.pg_connect_db("readonlyuser")?;
+        assert!(diesel::sql_query("CREATE TABLE t (a int)").execute(&mut user_conn).is_err());
+        managed_db_manager.set_read_only("readonlyuser", false)?;
+        assert_eq!(database_settings(&mut managed_conn, "readonlyuser")?, "");
+        assert_eq!(database_settings(&mut managed_conn, "My Shop_readonlyuser")?, "");
+        Ok(())
+    })();
+    diesel::sql_query(r#"DROP DATABASE IF EXISTS "My Shop_readonlyuser""#).execute(&mut managed_conn)?;
+    managed_db_manager.drop_pg_user("readonlyuser")?;
+    result
+}
+
+/// Send a simple query, returning the first message answering it.
+async fn query(client: &mut TcpStream, query: &str) -> Result<Vec<u8>> {
+    client.write_all(&message(b'Q', format!("{}\0", query).as_bytes())).await?;
+    let reply = read_message(client).await?;
+    assert_eq!(read_message(client).await?, message(b'Z', b"I"));
+    Ok(reply)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_read_only_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_read_only")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut user = NewUser::create(&mut conn, Uuid::new_v4(), "arrearsuser".to_string(), 1.)?;
+    user.set_read_only(&mut conn)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "activeuser".to_string(), 1.)?;
+
+    let server_addr = start_server("OK").await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    let mut active = connect(&proxy_addr, "activeuser").await?;
+    assert_eq!(query(&mut active, "INSERT INTO t VALUES (1)").await?[0], b'C');
+
+    // reads go through to the server, writes are answered by the proxy
+    let mut read_only = connect(&proxy_addr, "arrearsuser").await?;
+    assert_eq!(query(&mut read_only, "SELECT * FROM t").await?[0], b'C');
+    assert_eq!(query(&mut read_only, "COPY t TO STDOUT").await?[0], b'C');
+    for write in [
+        "INSERT INTO t VALUES (1)",
+        "WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d",
+        "DROP TABLE t",
+        "SET default_transaction_read_only = off",
+    ] {
+        let error = query(&mut read_only, write).await?;
+        assert_eq!(error[0], b'E', "{}", write);
+        assert_eq!(error_code(&error), Some("25006".to_string()));
+    }
+
+    // extended queries are refused up to their Sync
+    let parse = message(b'P', b"\0UPDATE t SET a = 2\0\0\0");
+    let bind = message(b'B', b"\0\0\0\0\0\0\0\0");
+    let execute = message(b'E', b"\0\0\0\0\0");
+    let sync = message(b'S', b"");
+    read_only.write_all(&[parse, bind, execute, sync].concat()).await?;
+    let error = read_message(&mut read_only).await?;
+    assert_eq!(error_code(&error), Some("25006".to_string()));
+    assert_eq!(read_message(&mut read_only).await?, message(b'Z', b"I"));
+    assert_eq!(query(&mut read_only, "SELECT 1").await?[0], b'C');
+    Ok(())
+}
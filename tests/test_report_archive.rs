@@ -0,0 +1,111 @@
+mod common;
+
+use std::fs;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use diesel::prelude::*;
+
+use impulse::models::report_archive::ReportArchive;
+use impulse::models::reports::{NewReport, PacketDirection, PostgresqlPacketType, Report};
+use impulse::schema::reports;
+
+
+fn report_at(
+    conn: &mut PgConnection,
+    packet_type: PostgresqlPacketType,
+    time: DateTime<Utc>,
+    charged: bool,
+) -> Result<Report> {
+    let report = NewReport::create(
+        Some("archiveuser".to_string()),
+        packet_type,
+        Some(PacketDirection::Forward),
+        Some(serde_json::json!({ "Other": null })),
+        Some(vec![b'X', 0, 0, 0, 4]),
+        charged,
+    ).commit(conn)?;
+    diesel::update(reports::table.filter(reports::packet_id.eq(report.report_id)))
+        .set(reports::packet_time.eq(time))
+        .execute(conn)?;
+    Ok(report)
+}
+
+fn report_ids(conn: &mut PgConnection) -> Result<Vec<i64>> {
+    let mut ids: Vec<i64> = Report::for_user(conn, "archiveuser")?
+        .into_iter()
+        .map(|report| report.report_id)
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+#[test]
+fn archive_restore_test() -> Result<()> {
+    let context = common::TestContext::new("report_archive")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let dir = std::env::temp_dir().join(format!("impulse-{}", &context.db_name));
+    let _ = fs::remove_dir_all(&dir);
+
+    let day1 = Utc.with_ymd_and_hms(2020, 1, 15, 10, 0, 0).unwrap();
+    let day2 = Utc.with_ymd_and_hms(2020, 1, 16, 23, 59, 59).unwrap();
+    let archived1 = report_at(&mut conn, PostgresqlPacketType::Other, day1, true)?;
+    // not aggregated by fingerprint yet
+    let query = report_at(&mut conn, PostgresqlPacketType::Query, day1, true)?;
+    let archived2 = report_at(&mut conn, PostgresqlPacketType::Other, day2, true)?;
+    let uncharged = report_at(&mut conn, PostgresqlPacketType::Other, day2, false)?;
+    let recent = report_at(&mut conn, PostgresqlPacketType::Other, Utc::now(), true)?;
+
+    let mut archive = ReportArchive::open(&dir)?;
+    let files = archive.archive(&mut conn, &(Utc::now() - Duration::days(1)))?;
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].file_name, "reports-2020-01-15-001.ndjson.gz");
+    assert_eq!(files[0].day, NaiveDate::from_ymd_opt(2020, 1, 15).unwrap());
+    assert_eq!(files[0].report_count, 1);
+    assert_eq!(files[0].first_report_id, archived1.report_id);
+    assert_eq!(files[1].first_report_id, archived2.report_id);
+    assert_eq!(files[1].last_report_id, archived2.report_id);
+    assert_eq!(files[0].sha256.len(), 64);
+    assert_eq!(
+        report_ids(&mut conn)?,
+        vec![query.report_id, uncharged.report_id, recent.report_id],
+    );
+    // the manifest is saved with the archive
+    assert_eq!(ReportArchive::open(&dir)?.manifest, archive.manifest);
+    assert!(archive.archive(&mut conn, &(Utc::now() - Duration::days(1)))?.is_empty());
+
+    let restored = archive.restore(
+        &mut conn,
+        &Utc.with_ymd_and_hms(2020, 1, 15, 0, 0, 0).unwrap(),
+        &Utc.with_ymd_and_hms(2020, 1, 16, 0, 0, 0).unwrap(),
+    )?;
+    assert_eq!(restored, 1);
+    let report = Report::for_user(&mut conn, "archiveuser")?
+        .into_iter()
+        .find(|report| report.report_id == archived1.report_id)
+        .unwrap();
+    assert_eq!(report.packet_time, day1);
+    assert_eq!(report.packet_bytes, archived1.packet_bytes);
+    assert_eq!(report.packet_info, archived1.packet_info);
+    assert_eq!(report.direction, Some(PacketDirection::Forward));
+    assert!(report.charged);
+    // restoring again doesn't duplicate reports
+    let restored = archive.restore(
+        &mut conn,
+        &Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+        &Utc.with_ymd_and_hms(2020, 2, 1, 0, 0, 0).unwrap(),
+    )?;
+    assert_eq!(restored, 1);
+    assert_eq!(report_ids(&mut conn)?.len(), 5);
+
+    // archiving restored reports again doesn't overwrite the earlier files
+    let files = archive.archive(&mut conn, &(Utc::now() - Duration::days(1)))?;
+    assert_eq!(files[0].file_name, "reports-2020-01-15-002.ndjson.gz");
+    assert_eq!(archive.manifest.files.len(), 4);
+
+    // corrupted files aren't restored
+    fs::write(dir.join("reports-2020-01-16-001.ndjson.gz"), b"corrupted")?;
+    assert!(archive.restore(&mut conn, &day2, &(day2 + Duration::days(1))).is_err());
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use diesel::prelude::*;
+use diesel::dsl::sql;
+use diesel::sql_types::Bool;
+
+use impulse::models::report_partitions::{ReportPartition, RetentionAction, RetentionPolicy};
+use impulse::models::reports::{NewReport, PostgresqlPacketType, Report};
+use impulse::schema::reports;
+
+
+fn report_at(conn: &mut PgConnection, time: DateTime<Utc>) -> Result<Report> {
+    let report = NewReport::create(
+        Some("partitionuser".to_string()),
+        PostgresqlPacketType::Other,
+        None,
+        None,
+        Some(vec![0; 10]),
+        false,
+    ).commit(conn)?;
+    diesel::update(reports::table.filter(reports::packet_id.eq(report.report_id)))
+        .set(reports::packet_time.eq(time))
+        .execute(conn)?;
+    Ok(report)
+}
+
+fn table_exists(conn: &mut PgConnection, name: &str) -> Result<bool> {
+    Ok(
+        diesel::select(sql::<Bool>(&format!("to_regclass('{}') IS NOT NULL", name)))
+            .get_result(conn)?
+    )
+}
+
+#[test]
+fn partitions_test() -> Result<()> {
+    let context = common::TestContext::new("report_partitions")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+
+    // the migration creates partitions for the next few months
+    let partitions = ReportPartition::all(&mut conn)?;
+    let now = Utc::now();
+    assert!(partitions.iter().any(|partition| {
+        partition.range_start.unwrap() <= now && now < partition.range_end.unwrap()
+    }));
+    let default = partitions.last().unwrap();
+    assert_eq!(default.partition_name, "reports_default");
+    assert_eq!(default.range_start, None);
+
+    // old reports land in the default partition until their month's
+    // partition is created
+    let january = report_at(&mut conn, Utc.with_ymd_and_hms(2020, 1, 15, 12, 0, 0).unwrap())?;
+    let february = report_at(&mut conn, Utc.with_ymd_and_hms(2020, 2, 1, 0, 0, 0).unwrap())?;
+    assert_eq!(default.pending_reports(&mut conn)?, 2);
+    let created = ReportPartition::create(
+        &mut conn,
+        &Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap(),
+        &Utc.with_ymd_and_hms(2020, 2, 1, 0, 0, 0).unwrap(),
+    )?;
+    assert_eq!(created, 2);
+    assert_eq!(default.pending_reports(&mut conn)?, 0);
+    let partitions = ReportPartition::all(&mut conn)?;
+    assert_eq!(partitions[0].partition_name, "reports_2020_01");
+    assert_eq!(partitions[0].range_start, Some(Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap()));
+    assert_eq!(partitions[0].range_end, Some(Utc.with_ymd_and_hms(2020, 2, 1, 0, 0, 0).unwrap()));
+    assert_eq!(partitions[0].pending_reports(&mut conn)?, 1);
+    assert_eq!(partitions[1].partition_name, "reports_2020_02");
+
+    // uncharged reports keep their partition
+    let detach = RetentionPolicy {
+        cutoff: Utc::now() - Duration::days(30),
+        action: RetentionAction::Detach,
+    };
+    let outcome = detach.apply(&mut conn, false)?;
+    assert!(outcome.removed.is_empty());
+    assert_eq!(outcome.retained.len(), 2);
+    assert_eq!(outcome.retained[0], (partitions[0].clone(), 1));
+    assert!(partitions[0].remove(&mut conn, false).is_err());
+
+    Report::mark_charged(january.report_id, &mut conn)?;
+    let outcome = detach.apply(&mut conn, true)?;
+    assert_eq!(outcome.removed, vec![partitions[0].clone()]);
+    assert!(table_exists(&mut conn, "reports_2020_01")?);
+    let outcome = detach.apply(&mut conn, false)?;
+    assert_eq!(outcome.removed, vec![partitions[0].clone()]);
+    assert_eq!(outcome.retained, vec![(partitions[1].clone(), 1)]);
+    assert!(table_exists(&mut conn, "reports_2020_01")?);
+    assert!(!ReportPartition::all(&mut conn)?.contains(&partitions[0]));
+
+    Report::mark_charged(february.report_id, &mut conn)?;
+    let drop = RetentionPolicy { action: RetentionAction::Drop, ..detach };
+    let outcome = drop.apply(&mut conn, false)?;
+    assert_eq!(outcome.removed, vec![partitions[1].clone()]);
+    assert!(!table_exists(&mut conn, "reports_2020_02")?);
+    assert!(Report::for_user(&mut conn, "partitionuser")?.is_empty());
+
+    // the default partition can't be removed
+    assert!(ReportPartition::all(&mut conn)?.last().unwrap().remove(&mut conn, false).is_err());
+    Ok(())
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use anyhow::Result;
+use prew::NoTransform;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use impulse::models::users::NewUser;
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::serve;
+use impulse::prew::{session_factory, RemoveAppendedUserNameTransformer};
+
+use common::proxy::{message, read_message, read_startup, startup_message};
+
+
+/// RowDescription of `SELECT datname, label, note FROM pg_database, t`,
+/// where `label` is a `name` column of the user's table `t` and `note` is
+/// a text column.
+fn row_description() -> Vec<u8> {
+    let mut body = 3_i16.to_be_bytes().to_vec();
+    for (name, table_oid, type_oid) in [("datname", 1262_u32, 19_u32), ("label", 16500, 19), ("note", 16500, 25)] {
+        body.extend(name.as_bytes());
+        body.push(0);
+        body.extend(table_oid.to_be_bytes());
+        body.extend(1_i16.to_be_bytes());
+        body.extend(type_oid.to_be_bytes());
+        body.extend([0; 8]);
+    }
+    message(b'T', &body)
+}
+
+/// DataRow with every column set to `value`.
+fn data_row(value: &str) -> Vec<u8> {
+    let mut body = 3_i16.to_be_bytes().to_vec();
+    for _ in 0..3 {
+        body.extend((value.len() as i32).to_be_bytes());
+        body.extend(value.as_bytes());
+    }
+    message(b'D', &body)
+}
+
+/// Values of a DataRow's columns.
+fn columns(data_row: &[u8]) -> Vec<String> {
+    let count = i16::from_be_bytes(data_row[5..7].try_into().unwrap());
+    let mut offset = 7;
+    let mut values = vec![];
+    for _ in 0..count {
+        let length = i32::from_be_bytes(data_row[offset..offset + 4].try_into().unwrap()) as usize;
+        values.push(String::from_utf8(data_row[offset + 4..offset + 4 + length].to_vec()).unwrap());
+        offset += 4 + length;
+    }
+    values
+}
+
+/// A server answering every query with one row, each column of which holds
+/// `shop__alice`.
+async fn start_server() -> Result<String> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = server.accept().await.unwrap();
+            tokio::spawn(async move {
+                read_startup(&mut socket).await.unwrap();
+                let ready = message(b'Z', b"I");
+                socket.write_all(&[message(b'R', &[0, 0, 0, 0]), ready.clone()].concat()).await.unwrap();
+                let complete = message(b'C', b"SELECT 1\0");
+                while let Ok(query) = read_message(&mut socket).await {
+                    let reply = match query[0] {
+                        b'Q' => vec![row_description(), data_row("shop__alice"), complete.clone(), ready.clone()],
+                        b'P' => vec![message(b'1', b"")],
+                        b'B' => vec![message(b'2', b"")],
+                        b'D' => vec![row_description()],
+                        b'E' => vec![data_row("shop__alice"), complete.clone()],
+                        b'S' => vec![ready.clone()],
+                        _ => vec![],
+                    };
+                    socket.write_all(&reply.concat()).await.unwrap();
+                }
+            });
+        }
+    });
+    Ok(server_addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_results_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_results")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+
+    let server_addr = start_server().await?;
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(
+        report_connstr,
+        NoTransform::new(),
+        RemoveAppendedUserNameTransformer::new(),
+        StatementPolicy::default(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&startup_message("alice")).await?;
+    assert_eq!(read_message(&mut client).await?[0], b'R');
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+
+    // only the catalog's name loses its suffix
+    client.write_all(&message(b'Q', b"SELECT datname, label, note FROM pg_database, t\0")).await?;
+    assert_eq!(read_message(&mut client).await?[0], b'T');
+    assert_eq!(columns(&read_message(&mut client).await?), ["shop", "shop__alice", "shop__alice"]);
+    assert_eq!(read_message(&mut client).await?[0], b'C');
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+
+    // described portals of the extended protocol too
+    let parse = message(b'P', b"\0SELECT datname, label, note FROM pg_database, t\0\0\0");
+    let bind = message(b'B', b"\0\0\0\0\0\0\0\0");
+    let describe = message(b'D', b"P\0");
+    let execute = message(b'E', b"\0\0\0\0\0");
+    let sync = message(b'S', b"");
+    client.write_all(&[parse.clone(), bind.clone(), describe, execute.clone(), sync.clone()].concat()).await?;
+    for id in [b'1', b'2', b'T'] {
+        assert_eq!(read_message(&mut client).await?[0], id);
+    }
+    assert_eq!(columns(&read_message(&mut client).await?), ["shop", "shop__alice", "shop__alice"]);
+    assert_eq!(read_message(&mut client).await?[0], b'C');
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+
+    // and statements described once, whatever the format of their results
+    let parse_statement = message(b'P', b"s1\0SELECT datname, label, note FROM pg_database, t\0\0\0");
+    let describe_statement = message(b'D', b"Ss1\0");
+    client.write_all(&[parse_statement, describe_statement, sync.clone()].concat()).await?;
+    for id in [b'1', b'T', b'Z'] {
+        assert_eq!(read_message(&mut client).await?[0], id);
+    }
+    let bind_binary = message(b'B', b"\0s1\0\0\0\0\0\0\x01\0\x01");
+    client.write_all(&[bind_binary, execute.clone(), sync.clone()].concat()).await?;
+    assert_eq!(read_message(&mut client).await?[0], b'2');
+    assert_eq!(columns(&read_message(&mut client).await?), ["shop", "shop__alice", "shop__alice"]);
+    assert_eq!(read_message(&mut client).await?[0], b'C');
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+
+    // rows of portals that weren't described are left alone
+    client.write_all(&[parse, bind, execute, sync].concat()).await?;
+    for id in [b'1', b'2'] {
+        assert_eq!(read_message(&mut client).await?[0], id);
+    }
+    assert_eq!(columns(&read_message(&mut client).await?), ["shop__alice", "shop__alice", "shop__alice"]);
+    assert_eq!(read_message(&mut client).await?[0], b'C');
+    assert_eq!(read_message(&mut client).await?[0], b'Z');
+    Ok(())
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use std::rc::Rc;
+
+use anyhow::Result;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+
+use impulse::manage::postgres::PostgresManager;
+
+
+#[derive(QueryableByName, Debug)]
+struct RoleCount {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+/// Number of roles in the namespace of `username`.
+fn namespaced_roles(conn: &mut PgConnection, username: &str) -> Result<i64> {
+    let row = diesel::sql_query("SELECT count(*) AS count FROM pg_roles WHERE rolname LIKE '%\\_\\_' || $1")
+        .bind::<Text, _>(username)
+        .get_result::<RoleCount>(conn)?;
+    Ok(row.count)
+}
+
+#[test]
+fn role_procedures_test() -> Result<()> {
+    let context = common::TestContext::new("role_procedures")?;
+    let managed_db_manager = &context.managed_db_manager;
+    let mut managed_conn = managed_db_manager.pg_connect()?;
+    // usernames can't be in another user's namespace
+    assert!(managed_db_manager.create_pg_user_and_database("eve__roleuser", None).is_err());
+    let info = managed_db_manager.create_pg_user_and_database("roleuser", None)?;
+    // roles in the user's namespace that they didn't create
+    diesel::sql_query("CREATE ROLE login__roleuser LOGIN").execute(&mut managed_conn)?;
+    diesel::sql_query("CREATE ROLE foreign__roleuser").execute(&mut managed_conn)?;
+    let result = (|| {
+        let user_config = Rc::new(managed_db_manager.with_user("roleuser", &info.password));
+        let mut user_conn = PostgresManager::new(user_config).pg_connect_db("roleuser")?;
+        let mut run = |query: &str| diesel::sql_query(query).execute(&mut user_conn);
+
+        // users can't create roles themselves, only those in their namespace
+        assert!(run("CREATE ROLE reader__roleuser").is_err());
+        run("CALL impulse.create_role('reader__roleuser')")?;
+        for role in ["reader", "__roleuser", "reader__otheruser", "postgres"] {
+            assert!(run(&format!("CALL impulse.create_role('{}')", role)).is_err(), "{}", role);
+        }
+        // and are given admin over the roles they create
+        run("GRANT reader__roleuser TO roleuser")?;
+        run("SET ROLE reader__roleuser")?;
+        run("RESET ROLE")?;
+
+        run("CALL impulse.rename_role('reader__roleuser', 'writer__roleuser')")?;
+        assert!(run("CALL impulse.rename_role('writer__roleuser', 'postgres2')").is_err());
+        assert!(run("CALL impulse.rename_role('postgres', 'postgres__roleuser')").is_err());
+
+        assert!(run("CALL impulse.drop_role('missing__roleuser')").is_err());
+        run("CALL impulse.drop_role('missing__roleuser', true)")?;
+        assert!(run("CALL impulse.drop_role('postgres', true)").is_err());
+        run("CALL impulse.drop_role('writer__roleuser')")?;
+
+        // roles the user didn't create, or that can log in, are out of reach
+        for role in ["login__roleuser", "foreign__roleuser"] {
+            assert!(run(&format!("CALL impulse.drop_role('{}')", role)).is_err(), "{}", role);
+            let rename = format!("CALL impulse.rename_role('{}', 'taken__roleuser')", role);
+            assert!(run(&rename).is_err(), "{}", role);
+        }
+        assert!(run("GRANT foreign__roleuser TO roleuser").is_err());
+        assert_eq!(namespaced_roles(&mut managed_conn, "roleuser")?, 2);
+
+        // roles left behind are dropped with the user
+        run("CALL impulse.create_role('leftover__roleuser')")?;
+        assert_eq!(namespaced_roles(&mut managed_conn, "roleuser")?, 3);
+        Ok(())
+    })();
+    managed_db_manager.drop_pg_user("roleuser")?;
+    // but not the roles they didn't create
+    assert_eq!(namespaced_roles(&mut managed_conn, "roleuser")?, 2);
+    diesel::sql_query("DROP ROLE login__roleuser, foreign__roleuser").execute(&mut managed_conn)?;
+    result
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use diesel::prelude::*;
+use prew::NoTransform;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use impulse::models::reports::Report;
+use impulse::models::sessions::{Session, Session_, TerminationReason};
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::protocol::startup_message;
+use impulse::prew::proxy::serve;
+use impulse::prew::session_factory;
+
+use common::proxy::{message, read_message, read_startup};
+
+
+async fn read_exact(socket: &mut TcpStream, count: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0; count];
+    socket.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Start a proxy to `server_addr` reporting to the test database, returning
+/// its address.
+async fn start_proxy(context: &common::TestContext, server_addr: String) -> Result<String> {
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    tokio::spawn(serve(listener, server_addr, create_session));
+    Ok(proxy_addr)
+}
+
+/// Sessions of the test database, once at least `count` of them have ended.
+async fn ended_sessions(conn: &mut PgConnection, count: usize) -> Result<Vec<Session>> {
+    use impulse::schema::sessions::dsl::*;
+    let mut ended = vec![];
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        ended = sessions
+            .filter(ended_at.is_not_null())
+            .load::<Session_>(conn)?
+            .into_iter()
+            .map(Session::from)
+            .collect();
+        if ended.len() >= count {
+            break;
+        }
+    }
+    Ok(ended)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_session_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_sessions")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+
+    let authentication_ok = message(b'R', &[0, 0, 0, 0]);
+    let ready = message(b'Z', b"I");
+    let complete = message(b'C', b"SELECT 0\0");
+    // a server that accepts any client and answers a single query
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    let server_replies = ([authentication_ok.clone(), ready.clone()].concat(), [complete, ready].concat());
+    let (startup_reply, query_reply) = server_replies.clone();
+    tokio::spawn(async move {
+        let (mut socket, _) = server.accept().await.unwrap();
+        read_startup(&mut socket).await.unwrap();
+        socket.write_all(&startup_reply).await.unwrap();
+        read_message(&mut socket).await.unwrap();
+        socket.write_all(&query_reply).await.unwrap();
+        // wait for the proxy to close the connection
+        let _ = socket.read_to_end(&mut vec![]).await;
+    });
+    let proxy_addr = start_proxy(&context, server_addr).await?;
+
+    let startup = startup_message(&[
+        ("user", "sessionuser"),
+        ("database", "sessiondb"),
+        ("application_name", "sessiontest"),
+    ]);
+    let query = message(b'Q', b"SELECT 0\0");
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&startup).await?;
+    assert_eq!(read_exact(&mut client, server_replies.0.len()).await?, server_replies.0);
+    client.write_all(&query).await?;
+    assert_eq!(read_exact(&mut client, server_replies.1.len()).await?, server_replies.1);
+    drop(client);
+
+    let sessions = ended_sessions(&mut conn, 1).await?;
+    assert_eq!(sessions.len(), 1);
+    let session = &sessions[0];
+    assert!(session.client_addr.as_ref().unwrap().starts_with("127.0.0.1:"));
+    assert_eq!(session.username.as_deref(), Some("sessionuser"));
+    assert_eq!(session.database.as_deref(), Some("sessiondb"));
+    assert_eq!(session.application_name.as_deref(), Some("sessiontest"));
+    assert!(session.ended_at.unwrap() >= session.started_at);
+    assert_eq!(session.bytes_in, (startup.len() + query.len()) as i64);
+    assert_eq!(session.bytes_out, (server_replies.0.len() + server_replies.1.len()) as i64);
+    assert_eq!(session.termination_reason, Some(TerminationReason::ClientClosed));
+    assert_eq!(Session::for_user(&mut conn, "sessionuser")?, sessions);
+
+    // packets are reported in the session once the user is authenticated,
+    // along with its query and connection time
+    let mut reports = vec![];
+    for _ in 0..50 {
+        reports = Report::for_user(&mut conn, "sessionuser")?;
+        if reports.len() >= 7 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(reports.len(), 7);
+    assert!(reports.iter().all(|report| report.session_id == Some(session.session_id)));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_unavailable_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_server_unavailable")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    // nothing listens on a port that was just released
+    let unused = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.to_string();
+    let proxy_addr = start_proxy(&context, unused).await?;
+    let _client = TcpStream::connect(&proxy_addr).await?;
+
+    let sessions = ended_sessions(&mut conn, 1).await?;
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].termination_reason, Some(TerminationReason::ServerUnavailable));
+    assert_eq!(sessions[0].bytes_in, 0);
+    assert_eq!(sessions[0].username, None);
+    Ok(())
+}
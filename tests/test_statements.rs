@@ -0,0 +1,78 @@
+mod common;
+
+use anyhow::Result;
+use chrono::{Duration, TimeZone, Utc};
+use uuid::Uuid;
+
+use impulse::models::charges::{ChargeType, NewCharge};
+use impulse::models::statements::*;
+use impulse::models::transactions::{NewExtTransaction, NewTransaction};
+use impulse::models::users::NewUser;
+
+
+#[test]
+fn statement_test() -> Result<()> {
+    let context = common::TestContext::new("statement")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "statementuser".to_string(), 0.)?;
+    let other = NewUser::create(&mut conn, Uuid::new_v4(), "otheruser".to_string(), 0.)?;
+    let t0 = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+    NewExtTransaction::create(&mut conn, user.user_id, 10., Some(t0), "ext,\"1\"".to_string())?;
+    let charge = NewCharge::new(
+        user.user_id,
+        ChargeType::DataTransferOutBytes,
+        4.,
+        0.5,
+        None,
+        Some(t0 + Duration::hours(1)),
+    ).commit(&mut conn)?;
+    NewTransaction::create(
+        &mut conn,
+        user.user_id,
+        Uuid::nil(),
+        Some(vec![charge.charge_id]),
+        2.,
+        Some(t0 + Duration::hours(2)),
+    )?;
+    // outside the statement range
+    NewCharge::new(
+        user.user_id,
+        ChargeType::DataTransferOutBytes,
+        4.,
+        0.5,
+        None,
+        Some(t0 + Duration::days(1)),
+    ).commit(&mut conn)?;
+    // another user's activity
+    NewCharge::new(
+        other.user_id,
+        ChargeType::DataTransferOutBytes,
+        1.,
+        1.,
+        None,
+        Some(t0 + Duration::hours(1)),
+    ).commit(&mut conn)?;
+
+    let statement = Statement::generate(&mut conn, &user.user_id, &t0, &(t0 + Duration::days(1)))?;
+    assert_eq!(statement.charges, vec![charge]);
+    assert_eq!(statement.transactions.len(), 1);
+    assert_eq!(statement.deposits.len(), 1);
+
+    let csv = statement.to_csv();
+    let lines = csv.split("\r\n").collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], CSV_HEADER.join(","));
+    assert!(lines[1].starts_with("deposit,"));
+    assert!(lines[1].ends_with(",10,\"ext,\"\"1\"\"\""));
+    assert!(lines[2].starts_with("charge,"));
+    assert!(lines[2].ends_with(",DataTransferOutBytes,4,0,0.5,2,"));
+    assert!(lines[3].starts_with("transaction,"));
+    assert!(lines[3].ends_with(",Charge,,,,-2,"));
+    assert_eq!(lines[4], "");
+
+    let json: serde_json::Value = serde_json::from_str(&statement.to_json()?)?;
+    assert_eq!(json["charges"][0]["amount"], 2.);
+    assert_eq!(json["transactions"][0]["txn_type"], "Charge");
+    assert_eq!(json["deposits"][0]["exttransaction_extid"], "ext,\"1\"");
+    Ok(())
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+
+use anyhow::Result;
+use prew::NoTransform;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use uuid::Uuid;
+
+use impulse::models::users::NewUser;
+use impulse::prew::protocol::{authentication, error_code, ssl_request, AUTHENTICATION_OK};
+use impulse::prew::policy::StatementPolicy;
+use impulse::prew::proxy::{serve_with_options, ProxyOptions};
+use impulse::prew::session_factory;
+use impulse::prew::tls::{ProxyTls, TlsConfig};
+
+use common::proxy::{answer, message, read_message, startup_message};
+
+
+/// Start up as `username` and run a query, checking the replies.
+async fn run_query<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S, username: &str) -> Result<()> {
+    socket.write_all(&startup_message(username)).await?;
+    assert_eq!(read_message(socket).await?[0], b'R');
+    assert_eq!(read_message(socket).await?[0], b'Z');
+    socket.write_all(&message(b'Q', b"SELECT 1\0")).await?;
+    assert_eq!(read_message(socket).await?[0], b'C');
+    assert_eq!(read_message(socket).await?[0], b'Z');
+    Ok(())
+}
+
+/// Self-signed certificate for localhost, with its private key.
+struct TestCert {
+    cert: Certificate,
+    key: PrivateKey,
+    cert_pem: String,
+    key_pem: String,
+}
+impl TestCert {
+    fn new() -> Result<TestCert> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        Ok(TestCert {
+            cert: Certificate(cert.serialize_der()?),
+            key: PrivateKey(cert.serialize_private_key_der()),
+            cert_pem: cert.serialize_pem()?,
+            key_pem: cert.serialize_private_key_pem(),
+        })
+    }
+
+    fn server_config(&self) -> Result<ServerConfig> {
+        Ok(ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![self.cert.clone()], self.key.clone())?)
+    }
+
+    fn client_config(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self.cert)?;
+        Ok(ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+
+    /// Ask for TLS on `socket` and connect over it as a client trusting
+    /// the certificate.
+    async fn connect(&self, mut socket: TcpStream) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        socket.write_all(&ssl_request()).await?;
+        let mut answer = [0; 1];
+        socket.read_exact(&mut answer).await?;
+        assert_eq!(&answer, b"S");
+        let connector = TlsConnector::from(Arc::new(self.client_config()?));
+        Ok(connector.connect(ServerName::try_from("localhost")?, socket).await?)
+    }
+}
+
+/// A server answering queries, only over TLS with `acceptor` if given.
+async fn start_server(acceptor: Option<TlsAcceptor>) -> Result<String> {
+    let server = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?.to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = server.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => {
+                        let mut request = [0; 8];
+                        socket.read_exact(&mut request).await.unwrap();
+                        assert_eq!(request.as_slice(), ssl_request());
+                        socket.write_all(b"S").await.unwrap();
+                        if let Ok(socket) = acceptor.accept(socket).await {
+                            answer(socket, &authentication(AUTHENTICATION_OK, b""), "SELECT 1").await;
+                        }
+                    }
+                    None => answer(socket, &authentication(AUTHENTICATION_OK, b""), "SELECT 1").await,
+                }
+            });
+        }
+    });
+    Ok(server_addr)
+}
+
+async fn start_proxy(context: &common::TestContext, server_addr: String, tls: ProxyTls) -> Result<String> {
+    let report_connstr = format!("{}/{}", context.impulse_manager.base_url(), &context.db_name);
+    let create_session = session_factory(report_connstr, NoTransform::new(), NoTransform::new(), StatementPolicy::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = listener.local_addr()?.to_string();
+    let options = ProxyOptions { tls, ..ProxyOptions::default() };
+    tokio::spawn(serve_with_options(listener, server_addr, create_session, Arc::new(options)));
+    Ok(proxy_addr)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_tls_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_tls")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    let cert = TestCert::new()?;
+    let server_addr = start_server(None).await?;
+
+    // the proxy answers SSLRequests itself, with the configured certificate
+    let dir = std::env::temp_dir().join(format!("proxy_tls_{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+    let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("cert.key"));
+    fs::write(&cert_file, &cert.cert_pem)?;
+    fs::write(&key_file, &cert.key_pem)?;
+    let config = TlsConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        require: false,
+    };
+    let tls = ProxyTls::from_config(Some(&config), None);
+    fs::remove_dir_all(&dir)?;
+    let proxy_addr = start_proxy(&context, server_addr.clone(), tls?).await?;
+    let mut client = cert.connect(TcpStream::connect(&proxy_addr).await?).await?;
+    run_query(&mut client, "alice").await?;
+    // and still lets plaintext clients in unless TLS is required
+    run_query(&mut TcpStream::connect(&proxy_addr).await?, "alice").await?;
+
+    let tls = ProxyTls::new().with_server_config(cert.server_config()?, true);
+    let proxy_addr = start_proxy(&context, server_addr.clone(), tls).await?;
+    let mut client = cert.connect(TcpStream::connect(&proxy_addr).await?).await?;
+    run_query(&mut client, "alice").await?;
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&startup_message("alice")).await?;
+    let reply = read_message(&mut client).await?;
+    assert_eq!(reply[0], b'E');
+    assert_eq!(error_code(&reply), Some("28000".to_string()));
+    assert_eq!(client.read(&mut [0; 16]).await?, 0);
+
+    // without TLS configured, SSLRequests are declined
+    let proxy_addr = start_proxy(&context, server_addr, ProxyTls::new()).await?;
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&ssl_request()).await?;
+    let mut answer = [0; 1];
+    client.read_exact(&mut answer).await?;
+    assert_eq!(&answer, b"N");
+    run_query(&mut client, "alice").await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn proxy_server_tls_test() -> Result<()> {
+    let context = common::TestContext::new("proxy_server_tls")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    NewUser::create(&mut conn, Uuid::new_v4(), "alice".to_string(), 1.)?;
+    let cert = TestCert::new()?;
+    let acceptor = TlsAcceptor::from(Arc::new(cert.server_config()?));
+    let server_addr = start_server(Some(acceptor)).await?;
+
+    let tls = ProxyTls::new().with_client_config(cert.client_config()?, Some("localhost"))?;
+    let proxy_addr = start_proxy(&context, server_addr.clone(), tls).await?;
+    run_query(&mut TcpStream::connect(&proxy_addr).await?, "alice").await?;
+
+    // the server's certificate must be for the configured name
+    let tls = ProxyTls::new().with_client_config(cert.client_config()?, Some("db.example.com"))?;
+    let proxy_addr = start_proxy(&context, server_addr, tls).await?;
+    let mut client = TcpStream::connect(&proxy_addr).await?;
+    client.write_all(&startup_message("alice")).await?;
+    assert!(matches!(client.read(&mut [0; 16]).await, Ok(0) | Err(_)));
+    Ok(())
+}
@@ -2,7 +2,9 @@ mod common;
 
 use anyhow::{Result};
 use uuid::Uuid;
-use impulse::models::charges::{ChargeType, NewCharge};
+use impulse::models::audit::BalanceAudit;
+use impulse::models::charges::{Charge, ChargeType, NewCharge};
+use impulse::models::users::{NewUser, User, UserStatus};
 
 use impulse::models::transactions::*;
 use impulse::models::transactions::NewTransaction;
@@ -18,6 +20,8 @@ impl ExpectedEquals for Transaction {
             && self.to_user == other.to_user
             && self.from_user == other.from_user
             && self.txn_time.expected_equals(&other.txn_time)
+            && self.txn_type == other.txn_type
+            && self.reason == other.reason
     }
 }
 
@@ -47,6 +51,8 @@ fn create_transaction_test() -> Result<()> {
         to_user: to_user.clone(),
         charge_ids: Some(txn_charge_ids.clone()),
         amount,
+        txn_type: TransactionType::Charge,
+        reason: None,
     };
 
     let new_txn = NewTransaction::create(
@@ -107,6 +113,7 @@ fn create_txn_from_charges_test() -> Result<()> {
     assert_eq!(&transaction.from_user, &from_user_id);
     assert_eq!(transaction.amount, expected_amount);
     assert_eq!(&transaction.to_user, &to_user_id);
+    assert_eq!(transaction.txn_type, TransactionType::Charge);
     Ok(())
 }
 
@@ -139,3 +146,117 @@ fn create_exttransaction_test() -> Result<()> {
     assert_eq!(&retrieved, &new_txn);
     Ok(())
 }
+
+#[test]
+fn credit_adjustment_test() -> Result<()> {
+    let context = common::TestContext::new("credit_adjustment")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "credituser".to_string(), 0.)?;
+    let reason = "Launch promotion";
+    let credit = NewTransaction::credit(&mut conn, user.user_id, 25., reason)?;
+    let expected_txn = Transaction {
+        transaction_id: 0,
+        txn_time: chrono::offset::Utc::now(),
+        from_user: Uuid::nil(),
+        to_user: user.user_id,
+        charge_ids: None,
+        amount: 25.,
+        txn_type: TransactionType::Credit,
+        reason: Some(reason.to_string()),
+    };
+    assert!(credit.expected_equals(&expected_txn));
+    assert_eq!(User::retrieve(&mut conn, &user.user_id)?.balance, 25.);
+    assert_eq!(User::retrieve(&mut conn, &Uuid::nil())?.balance, -25.);
+
+    // a reason is mandatory
+    assert!(NewTransaction::goodwill(&mut conn, user.user_id, 5., " ").is_err());
+    assert!(BalanceAudit::run(&mut conn)?.is_clean());
+    Ok(())
+}
+
+#[test]
+fn refund_adjustment_test() -> Result<()> {
+    let context = common::TestContext::new("refund_adjustment")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let user = NewUser::create(&mut conn, Uuid::new_v4(), "refunduser".to_string(), 0.)?;
+    let charge = NewCharge::new(
+        user.user_id,
+        ChargeType::DataTransferOutBytes,
+        4.,
+        2.5,
+        None,
+        None,
+    ).commit(&mut conn)?;
+    let charge_id = charge.charge_id;
+
+    // charges can only be refunded once they have been transacted
+    assert!(NewTransaction::refund(&mut conn, user.user_id, vec![charge_id], "Bad charge").is_err());
+    NewTransaction::from_charges(&mut conn, &vec![charge])?;
+    assert_eq!(User::retrieve(&mut conn, &user.user_id)?.balance, -10.);
+
+    let refund = NewTransaction::refund(&mut conn, user.user_id, vec![charge_id], "Bad charge")?;
+    assert_eq!(refund.txn_type, TransactionType::Refund);
+    assert_eq!(refund.charge_ids, Some(vec![charge_id]));
+    assert_eq!(refund.amount, 10.);
+    assert_eq!(User::retrieve(&mut conn, &user.user_id)?.balance, 0.);
+
+    // the charge is kept, and can't be refunded twice
+    assert!(Charge::retrieve(&mut conn, charge_id)?.transacted);
+    assert!(NewTransaction::refund(&mut conn, user.user_id, vec![charge_id], "Again").is_err());
+    assert!(BalanceAudit::run(&mut conn)?.is_clean());
+    Ok(())
+}
+
+#[test]
+fn arrears_status_test() -> Result<()> {
+    let context = common::TestContext::new("arrears_status")?;
+    let mut conn = context.impulse_manager.pg_connect_db(&context.db_name)?;
+    let mut charges = vec![];
+    let mut users = vec![];
+    for (pg_name, balance) in [("creditor", 2.), ("debtor", 1.), ("defaulter", 0.)] {
+        let mut user = NewUser::create(&mut conn, Uuid::new_v4(), pg_name.to_string(), balance)?;
+        user.mark_synced(&mut conn)?;
+        charges.push(NewCharge::new(
+            user.user_id,
+            ChargeType::DataTransferOutBytes,
+            1.5,
+            1.,
+            None,
+            None,
+        ).commit(&mut conn)?);
+        users.push(user);
+    }
+    NewTransaction::from_charges(&mut conn, &charges)?;
+    let statuses = users.iter()
+        .map(|user| {
+            let user = User::retrieve(&mut conn, &user.user_id)?;
+            Ok((user.pg_name, user.user_status))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(statuses, vec![
+        ("creditor".to_string(), UserStatus::Active),
+        // in arrears, the user can only read their data
+        ("debtor".to_string(), UserStatus::ReadOnly),
+        ("defaulter".to_string(), UserStatus::Disabled),
+    ]);
+
+    // read-only users aren't made read-only again by later charges
+    let debtor = &users[1];
+    let mut debtor_user = User::retrieve(&mut conn, &debtor.user_id)?;
+    // and their databases are made read-only on the next sync
+    assert!(!debtor_user.status_synced);
+    debtor_user.mark_synced(&mut conn)?;
+    let charge = NewCharge::new(
+        debtor.user_id,
+        ChargeType::DataTransferOutBytes,
+        0.1,
+        1.,
+        None,
+        None,
+    ).commit(&mut conn)?;
+    NewTransaction::from_charges(&mut conn, &vec![charge])?;
+    let debtor_user = User::retrieve(&mut conn, &debtor.user_id)?;
+    assert_eq!(debtor_user.user_status, UserStatus::ReadOnly);
+    assert!(debtor_user.status_synced);
+    Ok(())
+}
@@ -15,6 +15,9 @@ impl ExpectedEquals for User {
             && self.balance == other.balance
             && self.status_synced == other.status_synced
             && self.pg_password_enc == other.pg_password_enc
+            && self.plan_name == other.plan_name
+            && self.billing_period_months == other.billing_period_months
+            && self.cluster_name == other.cluster_name
     }
 }
 
@@ -41,6 +44,9 @@ pub fn create_user_test() -> Result<()> {
         created_at: chrono::offset::Utc::now(),
         updated_at: chrono::offset::Utc::now(),
         pg_password_enc: None,
+        plan_name: "default".to_string(),
+        billing_period_months: 1,
+        cluster_name: None,
     };
     assert!(new_user.expected_equals(&expected_user));
     let retrieved = User::retrieve(&mut conn, &user_id)?;